@@ -1,21 +1,44 @@
+use std::net::SocketAddr;
 use std::time::Duration;
 use std::default::Default;
+use std::u8;
+use std::usize;
+
+use message::protocol::Protocol;
+use mse::EncryptionPolicy;
+use proxy::Socks5Proxy;
 
 const DEFAULT_HANDSHAKE_BUFFER_SIZE: usize = 1000;
 const DEFAULT_WAIT_BUFFER_SIZE:      usize = 10;
 const DEFAULT_DONE_BUFFER_SIZE:      usize = 10;
 
-/// Once we get parallel handshake support (requires
-/// mpmc future channel support, we can bump this up).
+const DEFAULT_MAX_PARALLEL_HANDSHAKES: usize = 20;
+
+/// Handshakes are processed in parallel, so a slow peer only
+/// holds up its own slot, not every handshake behind it.
 const DEFAULT_HANDSHAKE_TIMEOUT_MILLIS: u64 = 1000;
 
+const DEFAULT_CONNECT_RETRIES:      usize = 0;
+const DEFAULT_RETRY_BACKOFF_MILLIS: u64   = 1000;
+
 /// Configures the internals of a `Handshaker`.
-#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub struct HandshakerConfig {
     sink_buffer_size:  usize,
     wait_buffer_size:  usize,
     done_buffer_size:  usize,
-    handshake_timeout: Duration
+    max_parallel:      usize,
+    handshake_timeout: Duration,
+    protocol:          Protocol,
+    max_connections:   usize,
+    max_torrent_conns: usize,
+    max_half_open:     usize,
+    proxy:             Option<Socks5Proxy>,
+    bind_addr:         Option<SocketAddr>,
+    encryption:        EncryptionPolicy,
+    connect_retries:   usize,
+    retry_backoff:     Duration,
+    served_only:       bool
 }
 
 impl HandshakerConfig {
@@ -52,6 +75,19 @@ impl HandshakerConfig {
         self.done_buffer_size
     }
 
+    /// Sets the maximum number of handshakes that `Handshaker` will
+    /// process at once, before holding back new connections.
+    ///
+    /// A value of zero will be treated as one.
+    pub fn set_max_parallel_handshakes(&mut self, max: usize) {
+        self.max_parallel = max;
+    }
+
+    /// Gets the maximum number of parallel handshakes.
+    pub fn max_parallel_handshakes(&self) -> usize {
+        self.max_parallel
+    }
+
     /// Sets the handshake timeout that `Handshaker` uses to
     /// make sure peers dont take too long to respond to us.
     pub fn set_handshake_timeout(&mut self, timeout: Duration) {
@@ -62,6 +98,139 @@ impl HandshakerConfig {
     pub fn handshake_timeout(&self) -> Duration {
         self.handshake_timeout
     }
+
+    /// Sets the protocol that `Handshaker` will accept from peers
+    /// connecting to us; handshakes for any other protocol are dropped.
+    ///
+    /// Panics if the protocol is longer than 255 bytes.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        if protocol.write_len() > u8::max_value() as usize {
+            panic!("bip_handshake: Handshaker Config With Protocol Length Greater Than {} Found", u8::max_value())
+        }
+
+        self.protocol = protocol;
+    }
+
+    /// Gets the protocol.
+    pub fn protocol(&self) -> &Protocol {
+        &self.protocol
+    }
+
+    /// Sets the maximum number of connections that `Handshaker` will
+    /// hand off before new connections are refused.
+    ///
+    /// Connections are counted until they are released through the `HandshakerSink`.
+    /// Defaults to no limit.
+    pub fn set_max_connections(&mut self, max: usize) {
+        self.max_connections = max;
+    }
+
+    /// Gets the maximum number of connections.
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    /// Sets the maximum number of connections that `Handshaker` will
+    /// hand off for any single torrent before new connections are refused.
+    ///
+    /// Defaults to no limit.
+    pub fn set_max_connections_per_torrent(&mut self, max: usize) {
+        self.max_torrent_conns = max;
+    }
+
+    /// Gets the maximum number of connections per torrent.
+    pub fn max_connections_per_torrent(&self) -> usize {
+        self.max_torrent_conns
+    }
+
+    /// Sets the maximum number of connections that `Handshaker` will have
+    /// initiated but not finished handshaking (half open) at once.
+    ///
+    /// Further `InitiateMessage`s are queued until a connection either fails or
+    /// completes its handshake. A value of zero will be treated as one. Defaults to no limit.
+    pub fn set_max_half_open(&mut self, max: usize) {
+        self.max_half_open = max;
+    }
+
+    /// Gets the maximum number of half open connections.
+    pub fn max_half_open(&self) -> usize {
+        self.max_half_open
+    }
+
+    /// Sets the SOCKS5 proxy that `Handshaker` will route
+    /// outgoing connections through, or `None` to connect directly.
+    pub fn set_proxy(&mut self, proxy: Option<Socks5Proxy>) {
+        self.proxy = proxy;
+    }
+
+    /// Gets the SOCKS5 proxy.
+    pub fn proxy(&self) -> Option<&Socks5Proxy> {
+        self.proxy.as_ref()
+    }
+
+    /// Sets the local address that `Handshaker` will bind outgoing connections
+    /// to before connecting, or `None` to let the operating system pick one.
+    ///
+    /// Useful for forcing connections out of a specific network interface. Connections,
+    /// including those to our proxy, are never made from any other address while this is set.
+    pub fn set_bind_address(&mut self, addr: Option<SocketAddr>) {
+        self.bind_addr = addr;
+    }
+
+    /// Gets the bind address.
+    pub fn bind_address(&self) -> Option<SocketAddr> {
+        self.bind_addr
+    }
+
+    /// Sets the policy that `Handshaker` uses to decide whether
+    /// connections should be encrypted with Message Stream Encryption.
+    ///
+    /// Only applies to a `Handshaker` built with `HandshakerBuilder::build_encrypted`.
+    pub fn set_encryption_policy(&mut self, policy: EncryptionPolicy) {
+        self.encryption = policy;
+    }
+
+    /// Gets the encryption policy.
+    pub fn encryption_policy(&self) -> EncryptionPolicy {
+        self.encryption
+    }
+
+    /// Sets the number of times that `Handshaker` will retry an `InitiateMessage`
+    /// whose connection or handshake failed, before giving up on the peer.
+    ///
+    /// Attempts are tracked per address and info hash. Defaults to no retries.
+    pub fn set_connect_retries(&mut self, retries: usize) {
+        self.connect_retries = retries;
+    }
+
+    /// Gets the number of connect retries.
+    pub fn connect_retries(&self) -> usize {
+        self.connect_retries
+    }
+
+    /// Sets the delay before the first retry of a failed `InitiateMessage`,
+    /// which is doubled for every retry after it, up to a maximum of five minutes.
+    pub fn set_retry_backoff(&mut self, backoff: Duration) {
+        self.retry_backoff = backoff;
+    }
+
+    /// Gets the retry backoff.
+    pub fn retry_backoff(&self) -> Duration {
+        self.retry_backoff
+    }
+
+    /// Sets whether or not `Handshaker` only accepts handshakes from peers connecting to us
+    /// for hashes added through `HandshakerSink::add_served_hash`.
+    ///
+    /// Handshakes for any other hash are dropped before we respond to them. Defaults to accepting any hash.
+    pub fn set_served_only(&mut self, served_only: bool) {
+        self.served_only = served_only;
+    }
+
+    /// Gets whether or not only served hashes are accepted.
+    pub fn served_only(&self) -> bool {
+        self.served_only
+    }
 }
 
 impl Default for HandshakerConfig {
@@ -70,7 +239,18 @@ impl Default for HandshakerConfig {
             sink_buffer_size: DEFAULT_HANDSHAKE_BUFFER_SIZE,
             wait_buffer_size: DEFAULT_WAIT_BUFFER_SIZE,
             done_buffer_size: DEFAULT_DONE_BUFFER_SIZE,
-            handshake_timeout: Duration::from_millis(DEFAULT_HANDSHAKE_TIMEOUT_MILLIS)
+            max_parallel: DEFAULT_MAX_PARALLEL_HANDSHAKES,
+            handshake_timeout: Duration::from_millis(DEFAULT_HANDSHAKE_TIMEOUT_MILLIS),
+            protocol: Protocol::BitTorrent,
+            max_connections: usize::MAX,
+            max_torrent_conns: usize::MAX,
+            max_half_open: usize::MAX,
+            proxy: None,
+            bind_addr: None,
+            encryption: EncryptionPolicy::Disabled,
+            connect_retries: DEFAULT_CONNECT_RETRIES,
+            retry_backoff: Duration::from_millis(DEFAULT_RETRY_BACKOFF_MILLIS),
+            served_only: false
          }
     }
 }
\ No newline at end of file
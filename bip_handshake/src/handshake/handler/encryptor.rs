@@ -0,0 +1,57 @@
+use std::net::SocketAddr;
+
+use handshake::handler::HandshakeType;
+use handshake::handler::initiator;
+use handshake::handler::timer::HandshakeTimer;
+use handshake::limit::ConnectionLimits;
+use handshake::retry::ConnectRetries;
+use mse::{self, EncryptionPolicy, MseHashes};
+use mse::stream::MseStream;
+use proxy::Socks5Proxy;
+use transport::Transport;
+
+use futures::future::{self, Future};
+use tokio_core::reactor::Handle;
+
+/// Handle the encryption of connections, which are returned as a HandshakeType over an `MseStream`.
+///
+/// If we prefer encryption and an initiated connection fails to negotiate it, we will reconnect in plaintext.
+/// Initiated connections that still fail are handed to our retries to be initiated again later.
+pub fn encryptor_handler<T>(item: HandshakeType<T::Socket>, context: &(EncryptionPolicy, MseHashes, Vec<u8>, Option<Socks5Proxy>, Option<SocketAddr>, Handle, ConnectionLimits, HandshakeTimer, ConnectRetries))
+    -> Box<Future<Item=Option<HandshakeType<MseStream<T::Socket>>>, Error=()>> where T: Transport {
+    let &(policy, ref hashes, ref plaintext_prefix, ref opt_proxy, opt_bind_addr, ref handle, ref limits, ref timer, ref retries) = context;
+
+    match (policy, item) {
+        (EncryptionPolicy::Disabled, HandshakeType::Initiate(sock, init_msg)) => {
+            Box::new(future::ok(Some(HandshakeType::Initiate(MseStream::plaintext(sock), init_msg))))
+        },
+        (EncryptionPolicy::Disabled, HandshakeType::Complete(sock, addr)) => {
+            Box::new(future::ok(Some(HandshakeType::Complete(MseStream::plaintext(sock), addr))))
+        },
+        (_, HandshakeType::Initiate(sock, init_msg)) => {
+            let (opt_proxy, handle, limits, retries) = (opt_proxy.clone(), handle.clone(), limits.clone(), retries.clone());
+
+            Box::new(timer.timeout(mse::handshake::initiate(sock, *init_msg.hash(), policy).map_err(|_| ()))
+                .then(move |result| -> Box<Future<Item=Option<HandshakeType<MseStream<T::Socket>>>, Error=()>> {
+                    match result {
+                        Ok(stream)                                   => Box::new(future::ok(Some(HandshakeType::Initiate(stream, init_msg)))),
+                        Err(_) if policy == EncryptionPolicy::Prefer => {
+                            Box::new(initiator::connect::<T>(init_msg.address(), opt_proxy.as_ref(), opt_bind_addr.as_ref(), &handle)
+                                .then(move |result| -> Result<Option<HandshakeType<MseStream<T::Socket>>>, ()> {
+                                    match result {
+                                        Ok(sock) => Ok(Some(HandshakeType::Initiate(MseStream::plaintext(sock), init_msg))),
+                                        Err(_)   => { limits.release_half_open(); retries.retry(init_msg); Ok(None) }
+                                    }
+                                }))
+                        },
+                        Err(_) => { limits.release_half_open(); retries.retry(init_msg); Box::new(future::ok(None)) }
+                    }
+                }))
+        },
+        (_, HandshakeType::Complete(sock, addr)) => {
+            Box::new(timer.timeout(mse::handshake::respond(sock, hashes.clone(), plaintext_prefix.clone(), policy).map_err(|_| ()))
+                .map(move |stream| Some(HandshakeType::Complete(stream, addr)))
+                .or_else(|_| Ok(None)))
+        }
+    }
+}
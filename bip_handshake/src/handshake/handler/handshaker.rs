@@ -1,32 +1,87 @@
 use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Instant;
 
 use bittorrent::message::HandshakeMessage;
 use bittorrent::framed::FramedHandshake;
 use message::extensions::Extensions;
-use handshake::handler::HandshakeType;
+use message::protocol::Protocol;
+use handshake::handler::{HandshakeType, PeerFilter};
 use message::initiate::InitiateMessage;
 use message::complete::CompleteMessage;
 use filter::filters::Filters;
 use handshake::handler;
 use handshake::handler::timer::HandshakeTimer;
+use handshake::limit::ConnectionLimits;
+use handshake::retry::ConnectRetries;
+use handshake::served::ServedHashes;
+use metrics::{HandshakeMetric, HandshakeRecorder};
 
 use bip_util::bt::{PeerId};
-use futures::future::Future;
+use futures::future::{self, Future};
 use futures::stream::Stream;
 use futures::sink::Sink;
 use tokio_io::{AsyncRead, AsyncWrite};
 
-pub fn execute_handshake<S>(item: HandshakeType<S>, context: &(Extensions, PeerId, Filters, HandshakeTimer))
+pub fn execute_handshake<S>(item: HandshakeType<S>, context: &(Extensions, PeerId, Protocol, Filters, Option<PeerFilter>, ConnectionLimits, HandshakeTimer, ConnectRetries,
+                                                    Rc<HandshakeRecorder>, ServedHashes))
     -> Box<Future<Item=Option<CompleteMessage<S>>, Error=()>> where S: AsyncRead + AsyncWrite + 'static {
-    let &(ref ext, ref pid, ref filters, ref timer) = context;
-
-    match item {
-        HandshakeType::Initiate(sock, init_msg) => initiate_handshake(sock, init_msg, *ext, *pid, filters.clone(), timer.clone()),
-        HandshakeType::Complete(sock, addr)     => complete_handshake(sock, addr, *ext, *pid, filters.clone(), timer.clone())
-    }
+    let &(ref ext, ref pid, ref prot, ref filters, ref opt_peer_filter, ref limits, ref timer, ref retries, ref recorder, ref served) = context;
+
+    let start = Instant::now();
+    recorder.incr(HandshakeMetric::Started);
+
+    // Refuse connections up front if we are already at our limit
+    let handshake_future: Box<Future<Item=Option<CompleteMessage<S>>, Error=()>> = match item {
+        HandshakeType::Initiate(_, ref init_msg) if !limits.can_connect(Some(init_msg.hash())) => {
+            limits.release_half_open();
+            recorder.incr(HandshakeMetric::Failed);
+
+            return Box::new(future::ok(None))
+        },
+        HandshakeType::Complete(_, _) if !limits.can_connect(None) => {
+            recorder.incr(HandshakeMetric::Failed);
+
+            return Box::new(future::ok(None))
+        },
+        HandshakeType::Initiate(sock, init_msg) => {
+            // Handshakes that time out or error are retried, those that are rejected by us are not
+            let (half_open, retries) = (limits.clone(), retries.clone());
+            let retry_msg = init_msg.clone();
+
+            Box::new(initiate_handshake(sock, init_msg, *ext, *pid, filters.clone(), opt_peer_filter.clone(), timer.clone())
+                .then(move |result| -> Result<Option<CompleteMessage<S>>, ()> {
+                    half_open.release_half_open();
+
+                    result.or_else(|_| { retries.retry(retry_msg); Ok(None) })
+                }))
+        },
+        HandshakeType::Complete(sock, addr) => complete_handshake(sock, addr, *ext, *pid, prot.clone(), filters.clone(), opt_peer_filter.clone(), served.clone(),
+                                                                  recorder.clone(), timer.clone())
+    };
+
+    // Other handshakes may have finished in the meantime, so check our limit again once we know the torrent
+    let (limits, retries, recorder) = (limits.clone(), retries.clone(), recorder.clone());
+    Box::new(handshake_future.map(move |opt_complete| {
+        let opt_complete = opt_complete.and_then(|complete| {
+            retries.clear(complete.address(), complete.hash());
+
+            if limits.try_add(complete.hash()) { Some(complete) } else { None }
+        });
+
+        if opt_complete.is_some() {
+            recorder.incr(HandshakeMetric::Completed);
+            recorder.record_duration(HandshakeMetric::Completed, start.elapsed());
+        } else {
+            recorder.incr(HandshakeMetric::Failed);
+        }
+
+        opt_complete
+    }))
 }
 
-fn initiate_handshake<S>(sock: S, init_msg: InitiateMessage, ext: Extensions, pid: PeerId, filters: Filters, timer: HandshakeTimer)
+fn initiate_handshake<S>(sock: S, init_msg: InitiateMessage, ext: Extensions, pid: PeerId, filters: Filters, opt_peer_filter: Option<PeerFilter>,
+                         timer: HandshakeTimer)
     -> Box<Future<Item=Option<CompleteMessage<S>>, Error=()>> where S: AsyncRead + AsyncWrite + 'static {
     let framed = FramedHandshake::new(sock);
     
@@ -51,19 +106,20 @@ fn initiate_handshake<S>(sock: S, init_msg: InitiateMessage, ext: Extensions, pi
                 // Check that it responds with the same hash and protocol, also check our filters
                 if remote_hash != hash ||
                     remote_prot != prot ||
-                    handler::should_filter(Some(&addr), Some(&remote_prot), Some(&remote_ext), Some(&remote_hash), Some(&remote_pid), &filters) {
-                    Err(())
+                    handler::should_filter(Some(&addr), Some(&remote_prot), Some(&remote_ext), Some(&remote_hash), Some(&remote_pid), &filters) ||
+                    handler::should_filter_peer(&addr, &remote_pid, opt_peer_filter.as_ref()) {
+                    Ok(None)
                 } else {
-                    Ok(Some(CompleteMessage::new(prot, ext.union(&remote_ext), hash, remote_pid, addr, socket)))
+                    Ok(Some(CompleteMessage::new(prot, ext.union(&remote_ext), remote_ext, hash, remote_pid, addr, socket)))
                 }
             })
-        })
-        .or_else(|_| Ok(None));
+        });
 
     Box::new(composed_future)
 }
 
-fn complete_handshake<S>(sock: S, addr: SocketAddr, ext: Extensions, pid: PeerId, filters: Filters, timer: HandshakeTimer)
+fn complete_handshake<S>(sock: S, addr: SocketAddr, ext: Extensions, pid: PeerId, prot: Protocol, filters: Filters, opt_peer_filter: Option<PeerFilter>,
+                         served: ServedHashes, recorder: Rc<HandshakeRecorder>, timer: HandshakeTimer)
     -> Box<Future<Item=Option<CompleteMessage<S>>, Error=()>> where S: AsyncRead + AsyncWrite + 'static {
     let framed = FramedHandshake::new(sock);
 
@@ -77,9 +133,18 @@ fn complete_handshake<S>(sock: S, addr: SocketAddr, ext: Extensions, pid: PeerId
         )
         .and_then(move |(msg, framed)| {
             let (remote_prot, remote_ext, remote_hash, remote_pid) = msg.into_parts();
-            
-            // Check our filters
-            if handler::should_filter(Some(&addr), Some(&remote_prot), Some(&remote_ext), Some(&remote_hash), Some(&remote_pid), &filters) {
+
+            // Don't respond to peers asking for a torrent we can't service, they will see the connection close
+            if !served.is_served(&remote_hash) {
+                recorder.incr(HandshakeMetric::UnservedHash);
+
+                return Err(());
+            }
+
+            // Check that they are speaking our protocol, also check our filters
+            if remote_prot != prot ||
+                handler::should_filter(Some(&addr), Some(&remote_prot), Some(&remote_ext), Some(&remote_hash), Some(&remote_pid), &filters) ||
+                handler::should_filter_peer(&addr, &remote_pid, opt_peer_filter.as_ref()) {
                 Err(())
             } else {
                 let handshake_msg = HandshakeMessage::from_parts(remote_prot.clone(), ext, remote_hash, pid);
@@ -89,7 +154,7 @@ fn complete_handshake<S>(sock: S, addr: SocketAddr, ext: Extensions, pid: PeerId
                         .map(move |framed| {
                             let socket = framed.into_inner();
 
-                            Some(CompleteMessage::new(remote_prot, ext.union(&remote_ext), remote_hash, remote_pid, addr, socket))
+                            Some(CompleteMessage::new(remote_prot, ext.union(&remote_ext), remote_ext, remote_hash, remote_pid, addr, socket))
                         })
                 ))
             }
@@ -103,6 +168,8 @@ fn complete_handshake<S>(sock: S, addr: SocketAddr, ext: Extensions, pid: PeerId
 #[cfg(test)]
 mod tests {
     use std::io::{Cursor};
+    use std::net::SocketAddr;
+    use std::rc::Rc;
     use std::time::Duration;
 
     use super::{HandshakeMessage};
@@ -110,7 +177,10 @@ mod tests {
     use message::protocol::Protocol;
     use message::initiate::InitiateMessage;
     use filter::filters::Filters;
+    use handshake::handler::PeerFilter;
     use handshake::handler::timer::HandshakeTimer;
+    use handshake::served::ServedHashes;
+    use metrics::{HandshakeRecorder, NoopHandshakeRecorder};
 
     use bip_util::bt::{self, PeerId, InfoHash};
     use tokio_timer;
@@ -136,6 +206,10 @@ mod tests {
         HandshakeTimer::new(tokio_timer::wheel().build(), Duration::from_millis(100))
     }
 
+    fn any_recorder() -> Rc<HandshakeRecorder> {
+        Rc::new(NoopHandshakeRecorder)
+    }
+
     #[test]
     fn positive_initiate_handshake() {
         let remote_pid = any_peer_id();
@@ -163,7 +237,7 @@ mod tests {
         let init_timer = any_handshake_timer();
 
         // Wrap in lazy since we can call wait on non sized types...
-        let complete_message = future::lazy(|| super::initiate_handshake(writer, init_message, init_ext, init_pid, init_filters, init_timer)).wait().unwrap().unwrap();
+        let complete_message = future::lazy(|| super::initiate_handshake(writer, init_message, init_ext, init_pid, init_filters, None, init_timer)).wait().unwrap().unwrap();
 
         assert_eq!(init_prot, *complete_message.protocol());
         assert_eq!(init_ext, *complete_message.extensions());
@@ -202,7 +276,8 @@ mod tests {
         let comp_timer = any_handshake_timer();
 
         // Wrap in lazy since we can call wait on non sized types...
-        let complete_message = future::lazy(|| super::complete_handshake(writer, remote_addr, comp_ext, comp_pid, comp_filters, comp_timer)).wait().unwrap().unwrap();
+        let complete_message = future::lazy(|| super::complete_handshake(writer, remote_addr, comp_ext, comp_pid, Protocol::BitTorrent, comp_filters, None,
+                                                                      ServedHashes::new(false), any_recorder(), comp_timer)).wait().unwrap().unwrap();
 
         assert_eq!(remote_protocol, *complete_message.protocol());
         assert_eq!(comp_ext, *complete_message.extensions());
@@ -218,4 +293,55 @@ mod tests {
         assert_eq!(local_message, sent_message);
         assert_eq!(remote_message, recv_message);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn negative_complete_handshake_different_protocol() {
+        let remote_message = HandshakeMessage::from_parts(Protocol::Custom(b"Other Protocol".to_vec()), any_extensions(), any_info_hash(), any_peer_id());
+
+        let mut writer = Cursor::new(vec![0u8; remote_message.write_len() * 2]);
+        remote_message.write_bytes(&mut writer).unwrap();
+        writer.set_position(0);
+
+        let opt_complete_message = future::lazy(|| super::complete_handshake(writer, "1.2.3.4:5".parse().unwrap(), any_extensions(), any_other_peer_id(),
+                                                                             Protocol::BitTorrent, Filters::new(), None, ServedHashes::new(false), any_recorder(),
+                                                                             any_handshake_timer())).wait().unwrap();
+
+        assert!(opt_complete_message.is_none());
+    }
+
+    #[test]
+    fn negative_complete_handshake_peer_filter() {
+        let remote_pid = any_peer_id();
+        let remote_message = HandshakeMessage::from_parts(Protocol::BitTorrent, any_extensions(), any_info_hash(), remote_pid);
+
+        let mut writer = Cursor::new(vec![0u8; remote_message.write_len() * 2]);
+        remote_message.write_bytes(&mut writer).unwrap();
+        writer.set_position(0);
+
+        let peer_filter: PeerFilter = Rc::new(move |_: &SocketAddr, pid: &PeerId| *pid != remote_pid);
+
+        let opt_complete_message = future::lazy(|| super::complete_handshake(writer, "1.2.3.4:5".parse().unwrap(), any_extensions(), any_other_peer_id(),
+                                                                             Protocol::BitTorrent, Filters::new(), Some(peer_filter), ServedHashes::new(false),
+                                                                             any_recorder(), any_handshake_timer())).wait().unwrap();
+
+        assert!(opt_complete_message.is_none());
+    }
+
+    #[test]
+    fn negative_complete_handshake_unserved_hash() {
+        let remote_message = HandshakeMessage::from_parts(Protocol::BitTorrent, any_extensions(), any_info_hash(), any_peer_id());
+
+        let mut writer = Cursor::new(vec![0u8; remote_message.write_len() * 2]);
+        remote_message.write_bytes(&mut writer).unwrap();
+        writer.set_position(0);
+
+        let served = ServedHashes::new(true);
+        served.add([66u8; bt::INFO_HASH_LEN].into());
+
+        let opt_complete_message = future::lazy(|| super::complete_handshake(writer, "1.2.3.4:5".parse().unwrap(), any_extensions(), any_other_peer_id(),
+                                                                             Protocol::BitTorrent, Filters::new(), None, served, any_recorder(),
+                                                                             any_handshake_timer())).wait().unwrap();
+
+        assert!(opt_complete_message.is_none());
+    }
+}
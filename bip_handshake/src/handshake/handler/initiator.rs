@@ -1,35 +1,82 @@
+use std::io;
+use std::net::SocketAddr;
+
 use handshake::handler::HandshakeType;
 use transport::Transport;
 use message::initiate::InitiateMessage;
 use filter::filters::Filters;
 use handshake::handler;
+use handshake::limit::ConnectionLimits;
+use handshake::retry::ConnectRetries;
+use proxy::{self, Socks5Proxy};
 
 use futures::future::{self, Future};
 use tokio_core::reactor::Handle;
 
 /// Handle the initiation of connections, which are returned as a HandshakeType.
-pub fn initiator_handler<T>(item: InitiateMessage, context: &(Filters, Handle)) -> Box<Future<Item=Option<HandshakeType<T::Socket>>,Error=()>>
-    where T: Transport {
-    let &(ref filters, ref handle) = context;
+///
+/// If a proxy is given, we will connect to the proxy and have it tunnel the connection to the peer. If a
+/// bind address is given, connections will be made from that address.
+/// If the connection fails, the item will be handed to our retries to be initiated again later.
+///
+/// Connections are held back while we are at our half open limit; the slot is released once the connection
+/// fails, or once it has been handshaked.
+pub fn initiator_handler<T>(item: InitiateMessage, context: &(Filters, Option<Socks5Proxy>, Option<SocketAddr>, Handle, ConnectionLimits, ConnectRetries))
+    -> Box<Future<Item=Option<HandshakeType<T::Socket>>,Error=()>> where T: Transport {
+    let &(ref filters, ref opt_proxy, opt_bind_addr, ref handle, ref limits, ref retries) = context;
 
     if handler::should_filter(Some(item.address()), Some(item.protocol()), None, Some(item.hash()), None, filters) {
         Box::new(future::ok(None))
     } else {
-        let res_connect = T::connect(item.address(), handle);
+        let (opt_proxy, handle, limits, retries) = (opt_proxy.clone(), handle.clone(), limits.clone(), retries.clone());
+
+        Box::new(limits.acquire_half_open()
+            .and_then(move |_| {
+                connect::<T>(item.address(), opt_proxy.as_ref(), opt_bind_addr.as_ref(), &handle)
+                    .then(move |result| -> Result<Option<HandshakeType<T::Socket>>, ()> {
+                        match result {
+                            Ok(socket) => Ok(Some(HandshakeType::Initiate(socket, item))),
+                            Err(_)     => { limits.release_half_open(); retries.retry(item); Ok(None) }
+                        }
+                    })
+            }))
+    }
+}
+
+/// Connect to the given address, optionally through the given proxy, and optionally from the given local address.
+pub fn connect<T>(addr: &SocketAddr, opt_proxy: Option<&Socks5Proxy>, opt_bind_addr: Option<&SocketAddr>, handle: &Handle)
+    -> Box<Future<Item=T::Socket, Error=io::Error>> where T: Transport {
+    let connect_to = |addr: &SocketAddr| {
+        match opt_bind_addr {
+            Some(bind_addr) => T::connect_from(bind_addr, addr, handle),
+            None            => T::connect(addr, handle)
+        }
+    };
+
+    if let Some(proxy) = opt_proxy {
+        let res_connect = connect_to(proxy.address());
+        let (proxy, addr) = (proxy.clone(), *addr);
 
         Box::new(future::lazy(|| res_connect)
             .flatten()
-            .map_err(|_| ())
-            .map(|socket| {
-                Some(HandshakeType::Initiate(socket, item))
-            }))
+            .and_then(move |socket| proxy::socks5_connect(socket, &proxy, addr)))
+    } else {
+        let res_connect = connect_to(addr);
+
+        Box::new(future::lazy(|| res_connect)
+            .flatten())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+    use std::usize;
+
     use filter::filters::Filters;
     use handshake::handler::HandshakeType;
+    use handshake::limit::ConnectionLimits;
+    use handshake::retry::ConnectRetries;
     use filter::filters::test_filters::{BlockAddrFilter, BlockProtocolFilter, BlockPeerIdFilter};
     use message::protocol::Protocol;
     use message::initiate::InitiateMessage;
@@ -37,7 +84,8 @@ mod tests {
 
     use bip_util::bt::{self, InfoHash, PeerId};
     use futures::Future;
-    use tokio_core::reactor::{Core};
+    use futures::sync::mpsc;
+    use tokio_core::reactor::{Core, Handle};
 
     fn any_peer_id() -> PeerId {
         [22u8; bt::PEER_ID_LEN].into()
@@ -47,12 +95,22 @@ mod tests {
         [55u8; bt::INFO_HASH_LEN].into()
     }
 
+    fn no_limits() -> ConnectionLimits {
+        ConnectionLimits::new(usize::MAX, usize::MAX, usize::MAX)
+    }
+
+    fn no_retries(handle: Handle) -> ConnectRetries {
+        let (send, _) = mpsc::channel(1);
+
+        ConnectRetries::new(0, Duration::from_millis(0), send, handle)
+    }
+
     #[test]
     fn positive_empty_filter() {
         let core = Core::new().unwrap();
         let exp_message = InitiateMessage::new(Protocol::BitTorrent, any_info_hash(), "1.2.3.4:5".parse().unwrap());
 
-        let recv_enum_item = super::initiator_handler::<MockTransport>(exp_message.clone(), &(Filters::new(), core.handle())).wait().unwrap();
+        let recv_enum_item = super::initiator_handler::<MockTransport>(exp_message.clone(), &(Filters::new(), None, None, core.handle(), no_limits(), no_retries(core.handle()))).wait().unwrap();
         let recv_item = match recv_enum_item {
             Some(HandshakeType::Initiate(_, msg)) => msg,
             Some(HandshakeType::Complete(_, _))   |
@@ -71,7 +129,7 @@ mod tests {
 
         let exp_message = InitiateMessage::new(Protocol::BitTorrent, any_info_hash(), "1.2.3.4:5".parse().unwrap());
 
-        let recv_enum_item = super::initiator_handler::<MockTransport>(exp_message.clone(), &(filters, core.handle())).wait().unwrap();
+        let recv_enum_item = super::initiator_handler::<MockTransport>(exp_message.clone(), &(filters, None, None, core.handle(), no_limits(), no_retries(core.handle()))).wait().unwrap();
         let recv_item = match recv_enum_item {
             Some(HandshakeType::Initiate(_, msg)) => msg,
             Some(HandshakeType::Complete(_, _))   |
@@ -90,7 +148,7 @@ mod tests {
 
         let exp_message = InitiateMessage::new(Protocol::BitTorrent, any_info_hash(), "1.2.3.4:5".parse().unwrap());
 
-        let recv_enum_item = super::initiator_handler::<MockTransport>(exp_message.clone(), &(filters, core.handle())).wait().unwrap();
+        let recv_enum_item = super::initiator_handler::<MockTransport>(exp_message.clone(), &(filters, None, None, core.handle(), no_limits(), no_retries(core.handle()))).wait().unwrap();
         let recv_item = match recv_enum_item {
             Some(HandshakeType::Initiate(_, msg)) => msg,
             Some(HandshakeType::Complete(_, _))   |
@@ -109,7 +167,7 @@ mod tests {
 
         let exp_message = InitiateMessage::new(Protocol::Custom(vec![1, 2, 3, 4]), any_info_hash(), "1.2.3.4:5".parse().unwrap());
 
-        let recv_enum_item = super::initiator_handler::<MockTransport>(exp_message.clone(), &(filters, core.handle())).wait().unwrap();
+        let recv_enum_item = super::initiator_handler::<MockTransport>(exp_message.clone(), &(filters, None, None, core.handle(), no_limits(), no_retries(core.handle()))).wait().unwrap();
         match recv_enum_item {
             None                                => (),
             Some(HandshakeType::Initiate(_, _)) |
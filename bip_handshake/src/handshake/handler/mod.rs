@@ -1,4 +1,6 @@
+use std::cmp;
 use std::net::SocketAddr;
+use std::rc::Rc;
 
 use filter::FilterDecision;
 use message::initiate::InitiateMessage;
@@ -12,11 +14,15 @@ use futures::sink::Sink;
 use futures::future::{self, IntoFuture, Loop, Future};
 use tokio_core::reactor::Handle;
 
+pub mod encryptor;
 pub mod handshaker;
 pub mod initiator;
 pub mod listener;
 pub mod timer;
 
+/// Callback consulted with the address and id of a peer once their handshake has been read.
+pub type PeerFilter = Rc<Fn(&SocketAddr, &PeerId) -> bool>;
+
 pub enum HandshakeType<S> {
     Initiate(S, InitiateMessage),
     Complete(S, SocketAddr)
@@ -77,6 +83,29 @@ pub fn loop_handler<M, H, K, F, R, C>(stream: M, handler: H, sink: K, context: C
     }));
 }
 
+/// Create loop for feeding the handler with the items coming from the stream, and forwarding the result to the sink.
+///
+/// Unlike `loop_handler`, up to `max_parallel` handler futures will be driven at once, and results are forwarded in the
+/// order that they complete. If the stream is used up, or an error is propogated from any of the elements, the loop will terminate.
+pub fn loop_handler_parallel<M, H, K, F, R, C>(stream: M, mut handler: H, sink: K, context: C, max_parallel: usize, handle: &Handle)
+    where M: Stream + 'static,
+          H: FnMut(M::Item, &C) -> F + 'static,
+          K: Sink<SinkItem=R> + 'static,
+          F: IntoFuture<Item=Option<R>> + 'static,
+          R: 'static,
+          C: 'static {
+    // Handlers give us Ok(None) when the item should be dropped, so we filter those out before they reach the sink
+    let results = stream
+        .map_err(|_| ())
+        .map(move |item| handler(item, &context).into_future().map_err(|_| ()))
+        .buffer_unordered(cmp::max(max_parallel, 1))
+        .filter_map(|opt_result| opt_result);
+
+    handle.spawn(sink.sink_map_err(|_| ())
+        .send_all(results)
+        .map(|_| ()));
+}
+
 /// Computes whether or not we should filter given the parameters and filters.
 pub fn should_filter(addr: Option<&SocketAddr>, prot: Option<&Protocol>, ext: Option<&Extensions>,
                      hash: Option<&InfoHash>, pid: Option<&PeerId>, filters: &Filters) -> bool {
@@ -100,4 +129,9 @@ pub fn should_filter(addr: Option<&SocketAddr>, prot: Option<&Protocol>, ext: Op
 
     // Choose across the results of individual fields
     addr_filter.choose(prot_filter).choose(ext_filter).choose(hash_filter).choose(pid_filter) == FilterDecision::Block
-}
\ No newline at end of file
+}
+
+/// Computes whether or not we should filter the given peer, based on the optional peer filter.
+pub fn should_filter_peer(addr: &SocketAddr, pid: &PeerId, opt_peer_filter: Option<&PeerFilter>) -> bool {
+    opt_peer_filter.map(|peer_filter| !peer_filter(addr, pid)).unwrap_or(false)
+}
@@ -1,5 +1,6 @@
 use std::net::{SocketAddr, Ipv4Addr, SocketAddrV4};
 use std::io;
+use std::rc::Rc;
 use std::time::Duration;
 
 use discovery::DiscoveryInfo;
@@ -7,17 +8,24 @@ use message::initiate::InitiateMessage;
 use message::complete::CompleteMessage;
 use message::extensions::Extensions;
 use handshake::handler::handshaker;
+use handshake::handler::encryptor;
 use handshake::handler::initiator;
 use handshake::handler::listener::ListenerHandler;
-use handshake::handler;
+use handshake::handler::{self, PeerFilter};
 use transport::Transport;
 use local_addr::LocalAddr;
 use filter::filters::Filters;
 use filter::{HandshakeFilter, HandshakeFilters};
 use handshake::config::HandshakerConfig;
 use handshake::handler::timer::HandshakeTimer;
-
-use bip_util::bt::PeerId;
+use handshake::limit::ConnectionLimits;
+use handshake::retry::ConnectRetries;
+use handshake::served::ServedHashes;
+use metrics::{HandshakeRecorder, NoopHandshakeRecorder};
+use mse::MseHashes;
+use mse::stream::MseStream;
+
+use bip_util::bt::{PeerId, InfoHash};
 use bip_util::convert;
 use futures::{StartSend, Poll};
 use futures::sync::mpsc::{self, Sender, Receiver, SendError};
@@ -29,12 +37,14 @@ use tokio_timer::{self};
 use rand::{self, Rng};
 
 /// Build configuration for `Handshaker` object creation.
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct HandshakerBuilder {
     bind:   SocketAddr,
     port:   u16,
     pid:    PeerId,
     ext:    Extensions,
+    filter: Option<PeerFilter>,
+    recorder: Rc<HandshakeRecorder>,
     config: HandshakerConfig
 }
 
@@ -51,7 +61,8 @@ impl HandshakerBuilder {
         let default_peer_id = PeerId::from_bytes(&convert::four_bytes_to_array(seed));
 
         HandshakerBuilder{ bind: default_sock_addr, port: default_v4_port, pid: default_peer_id,
-                           ext: Extensions::new(), config: HandshakerConfig::default() }
+                           ext: Extensions::new(), filter: None, recorder: Rc::new(NoopHandshakeRecorder),
+                           config: HandshakerConfig::default() }
     }
 
     /// Address that the host will listen on.
@@ -91,6 +102,27 @@ impl HandshakerBuilder {
         self
     }
 
+    /// Filter that will be consulted with the address and peer id of every peer, after their handshake
+    /// has been read, for both initiated and received connections.
+    ///
+    /// Returning false will drop the connection; useful for maintaining ban lists or rejecting our own peer id.
+    pub fn with_peer_filter<F>(&mut self, filter: F) -> &mut HandshakerBuilder
+        where F: Fn(&SocketAddr, &PeerId) -> bool + 'static {
+        self.filter = Some(Rc::new(filter));
+
+        self
+    }
+
+    /// Recorder that will be notified as handshakes are started, completed, or failed.
+    ///
+    /// Defaults to a `NoopHandshakeRecorder`.
+    pub fn with_recorder<R>(&mut self, recorder: R) -> &mut HandshakerBuilder
+        where R: HandshakeRecorder + 'static {
+        self.recorder = Rc::new(recorder);
+
+        self
+    }
+
     /// Configuration that will be used to alter the internal behavior of handshaking.
     ///
     /// This will typically not need to be set unless you know what you are doing.
@@ -105,6 +137,15 @@ impl HandshakerBuilder {
         where T: Transport + 'static {
         Handshaker::<T::Socket>::with_builder::<T>(self, handle)
     }
+
+    /// Build a `Handshaker` over the given `Transport` with a `Remote` instance, where connections
+    /// may be encrypted according to the configured `EncryptionPolicy`.
+    ///
+    /// Peers connecting to us can only be accepted for hashes added through `HandshakerSink::add_encryption_hash`.
+    pub fn build_encrypted<T>(&self, handle: Handle) -> io::Result<Handshaker<MseStream<T::Socket>>>
+        where T: Transport + 'static {
+        Handshaker::<MseStream<T::Socket>>::with_encrypted_builder::<T>(self, handle)
+    }
 }
 
 //----------------------------------------------------------------------------------//
@@ -123,6 +164,33 @@ impl<S> Handshaker<S> {
     pub fn into_parts(self) -> (HandshakerSink, HandshakerStream<S>) {
         (self.sink, self.stream)
     }
+
+    /// Release a connection for the given torrent, freeing up room for new connections.
+    ///
+    /// Should be called once a connection yielded from the `Handshaker` has been closed.
+    pub fn release_connection(&self, hash: &InfoHash) {
+        self.sink.release_connection(hash)
+    }
+
+    /// Accept encrypted connections from peers for the given hash.
+    pub fn add_encryption_hash(&self, hash: InfoHash) {
+        self.sink.add_encryption_hash(hash)
+    }
+
+    /// Stop accepting encrypted connections from peers for the given hash.
+    pub fn remove_encryption_hash(&self, hash: &InfoHash) {
+        self.sink.remove_encryption_hash(hash)
+    }
+
+    /// Accept connections from peers for the given hash.
+    pub fn add_served_hash(&self, hash: InfoHash) {
+        self.sink.add_served_hash(hash)
+    }
+
+    /// Stop accepting connections from peers for the given hash.
+    pub fn remove_served_hash(&self, hash: &InfoHash) {
+        self.sink.remove_served_hash(hash)
+    }
 }
 
 impl<S> DiscoveryInfo for Handshaker<S> {
@@ -138,33 +206,81 @@ impl<S> DiscoveryInfo for Handshaker<S> {
 impl<S> Handshaker<S> where S: AsyncRead + AsyncWrite + 'static {
     fn with_builder<T>(builder: &HandshakerBuilder, handle: Handle) -> io::Result<Handshaker<T::Socket>>
         where T: Transport<Socket=S> + 'static {
-        let listener = try!(T::listen(&builder.bind, &handle));
-
-        // Resolve our "real" public port
-        let open_port = if builder.port == 0 {
-            try!(listener.local_addr()).port()
-        } else { builder.port };
+        let (listener, open_port) = try!(listen_transport::<T>(builder, &handle));
 
-        let config = builder.config;
+        let config = builder.config.clone();
         let (addr_send, addr_recv) = mpsc::channel(config.sink_buffer_size());
         let (hand_send, hand_recv) = mpsc::channel(config.wait_buffer_size());
         let (sock_send, sock_recv) = mpsc::channel(config.done_buffer_size());
         
         let filters = Filters::new();
+        let limits = ConnectionLimits::new(config.max_connections(), config.max_connections_per_torrent(), config.max_half_open());
         let timer = configured_handshake_timer(config.handshake_timeout());
+        let retries = ConnectRetries::new(config.connect_retries(), config.retry_backoff(), addr_send.clone(), handle.clone());
+        let served = ServedHashes::new(config.served_only());
 
         // Hook up our pipeline of handlers which will take some connection info, process it, and forward it
-        handler::loop_handler(addr_recv, initiator::initiator_handler::<T>, hand_send.clone(), (filters.clone(), handle.clone()), &handle);
+        handler::loop_handler(addr_recv, initiator::initiator_handler::<T>, hand_send.clone(), (filters.clone(), config.proxy().cloned(), config.bind_address(), handle.clone(), limits.clone(), retries.clone()), &handle);
         handler::loop_handler(listener, ListenerHandler::new, hand_send, filters.clone(), &handle);
-        handler::loop_handler(hand_recv, handshaker::execute_handshake, sock_send, (builder.ext, builder.pid, filters.clone(), timer), &handle);
+        handler::loop_handler_parallel(hand_recv, handshaker::execute_handshake, sock_send, (builder.ext, builder.pid, config.protocol().clone(), filters.clone(), builder.filter.clone(), limits.clone(), timer,
+                                       retries, builder.recorder.clone(), served.clone()), config.max_parallel_handshakes(), &handle);
 
-        let sink = HandshakerSink::new(addr_send, open_port, builder.pid, filters);
+        let sink = HandshakerSink::new(addr_send, open_port, builder.pid, filters, limits, MseHashes::new(), served);
         let stream = HandshakerStream::new(sock_recv);
 
         Ok(Handshaker{ sink: sink, stream: stream })
     }
 }
 
+impl<S> Handshaker<MseStream<S>> where S: AsyncRead + AsyncWrite + 'static {
+    fn with_encrypted_builder<T>(builder: &HandshakerBuilder, handle: Handle) -> io::Result<Handshaker<MseStream<T::Socket>>>
+        where T: Transport<Socket=S> + 'static {
+        let (listener, open_port) = try!(listen_transport::<T>(builder, &handle));
+
+        let config = builder.config.clone();
+        let (addr_send, addr_recv) = mpsc::channel(config.sink_buffer_size());
+        let (hand_send, hand_recv) = mpsc::channel(config.wait_buffer_size());
+        let (encr_send, encr_recv) = mpsc::channel(config.wait_buffer_size());
+        let (sock_send, sock_recv) = mpsc::channel(config.done_buffer_size());
+
+        let filters = Filters::new();
+        let limits = ConnectionLimits::new(config.max_connections(), config.max_connections_per_torrent(), config.max_half_open());
+        let hashes = MseHashes::new();
+        let timer = configured_handshake_timer(config.handshake_timeout());
+        let retries = ConnectRetries::new(config.connect_retries(), config.retry_backoff(), addr_send.clone(), handle.clone());
+        let served = ServedHashes::new(config.served_only());
+
+        // Peers connecting to us in plaintext will start their handshake with our protocol
+        let mut plaintext_prefix = Vec::new();
+        try!(config.protocol().write_bytes(&mut plaintext_prefix));
+
+        // Same pipeline as an unencrypted handshaker, but connections are encrypted before they are handshaked
+        handler::loop_handler(addr_recv, initiator::initiator_handler::<T>, hand_send.clone(), (filters.clone(), config.proxy().cloned(), config.bind_address(), handle.clone(), limits.clone(), retries.clone()), &handle);
+        handler::loop_handler(listener, ListenerHandler::new, hand_send, filters.clone(), &handle);
+        handler::loop_handler_parallel(hand_recv, encryptor::encryptor_handler::<T>, encr_send, (config.encryption_policy(), hashes.clone(), plaintext_prefix,
+                                       config.proxy().cloned(), config.bind_address(), handle.clone(), limits.clone(), timer.clone(), retries.clone()), config.max_parallel_handshakes(), &handle);
+        handler::loop_handler_parallel(encr_recv, handshaker::execute_handshake, sock_send, (builder.ext, builder.pid, config.protocol().clone(), filters.clone(), builder.filter.clone(), limits.clone(), timer,
+                                       retries, builder.recorder.clone(), served.clone()), config.max_parallel_handshakes(), &handle);
+
+        let sink = HandshakerSink::new(addr_send, open_port, builder.pid, filters, limits, hashes, served);
+        let stream = HandshakerStream::new(sock_recv);
+
+        Ok(Handshaker{ sink: sink, stream: stream })
+    }
+}
+
+/// Listen on the given `Transport`, and resolve our "real" public port.
+fn listen_transport<T>(builder: &HandshakerBuilder, handle: &Handle) -> io::Result<(T::Listener, u16)>
+    where T: Transport {
+    let listener = try!(T::listen(&builder.bind, handle));
+
+    let open_port = if builder.port == 0 {
+        try!(listener.local_addr()).port()
+    } else { builder.port };
+
+    Ok((listener, open_port))
+}
+
 /// Configure a timer wheel and create a `HandshakeTimer`.
 fn configured_handshake_timer(duration: Duration) -> HandshakeTimer {
     let timer = tokio_timer::wheel()
@@ -221,12 +337,47 @@ pub struct HandshakerSink {
     send:    Sender<InitiateMessage>,
     port:    u16,
     pid:     PeerId,
-    filters: Filters
+    filters: Filters,
+    limits:  ConnectionLimits,
+    hashes:  MseHashes,
+    served:  ServedHashes
 }
 
 impl HandshakerSink {
-    fn new(send: Sender<InitiateMessage>, port: u16, pid: PeerId, filters: Filters) -> HandshakerSink {
-        HandshakerSink{ send: send, port: port, pid: pid, filters: filters }
+    fn new(send: Sender<InitiateMessage>, port: u16, pid: PeerId, filters: Filters, limits: ConnectionLimits, hashes: MseHashes,
+           served: ServedHashes) -> HandshakerSink {
+        HandshakerSink{ send: send, port: port, pid: pid, filters: filters, limits: limits, hashes: hashes, served: served }
+    }
+
+    /// Release a connection for the given torrent, freeing up room for new connections.
+    ///
+    /// Should be called once a connection yielded from the `Handshaker` has been closed.
+    pub fn release_connection(&self, hash: &InfoHash) {
+        self.limits.remove(hash)
+    }
+
+    /// Accept encrypted connections from peers for the given hash.
+    pub fn add_encryption_hash(&self, hash: InfoHash) {
+        self.hashes.add(hash)
+    }
+
+    /// Stop accepting encrypted connections from peers for the given hash.
+    pub fn remove_encryption_hash(&self, hash: &InfoHash) {
+        self.hashes.remove(hash)
+    }
+
+    /// Accept connections from peers for the given hash.
+    ///
+    /// Only consulted if `HandshakerConfig::set_served_only` is set, otherwise we accept any hash.
+    pub fn add_served_hash(&self, hash: InfoHash) {
+        self.served.add(hash)
+    }
+
+    /// Stop accepting connections from peers for the given hash.
+    ///
+    /// Connections that were already handed off for the hash are unaffected.
+    pub fn remove_served_hash(&self, hash: &InfoHash) {
+        self.served.remove(hash)
     }
 }
 
@@ -0,0 +1,165 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use bip_util::bt::InfoHash;
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+
+/// Shared count of connections that have been handed off from the `Handshaker`,
+/// as well as connections that we have initiated but not finished handshaking.
+#[derive(Clone)]
+pub struct ConnectionLimits {
+    state: Rc<RefCell<LimitsState>>
+}
+
+struct LimitsState {
+    max:             usize,
+    max_per_torrent: usize,
+    total:           usize,
+    torrents:        HashMap<InfoHash, usize>,
+    max_half_open:   usize,
+    half_open:       usize,
+    waiting:         Vec<Task>
+}
+
+impl ConnectionLimits {
+    pub fn new(max: usize, max_per_torrent: usize, max_half_open: usize) -> ConnectionLimits {
+        let state = LimitsState{ max: max, max_per_torrent: max_per_torrent, total: 0, torrents: HashMap::new(),
+                                 max_half_open: max_half_open, half_open: 0, waiting: Vec::new() };
+
+        ConnectionLimits{ state: Rc::new(RefCell::new(state)) }
+    }
+
+    /// Wait until we have room for another half open connection, and count it.
+    ///
+    /// A value of zero for the half open limit will be treated as one.
+    pub fn acquire_half_open(&self) -> HalfOpenSlot {
+        HalfOpenSlot{ limits: self.clone() }
+    }
+
+    /// Release a half open connection, once it has either failed or finished handshaking.
+    pub fn release_half_open(&self) {
+        let mut state = self.state.borrow_mut();
+
+        state.half_open -= 1;
+        for task in state.waiting.drain(..) {
+            task.notify();
+        }
+    }
+
+    /// Whether or not we have room for another connection, optionally for the given torrent.
+    pub fn can_connect(&self, opt_hash: Option<&InfoHash>) -> bool {
+        let state = self.state.borrow();
+
+        let torrent_count = opt_hash.and_then(|hash| state.torrents.get(hash).cloned()).unwrap_or(0);
+
+        state.total < state.max && torrent_count < state.max_per_torrent
+    }
+
+    /// Attempt to count a new connection for the given torrent, returning false if we are at our limit.
+    pub fn try_add(&self, hash: &InfoHash) -> bool {
+        if !self.can_connect(Some(hash)) {
+            return false
+        }
+
+        let mut state = self.state.borrow_mut();
+        state.total += 1;
+        *state.torrents.entry(*hash).or_insert(0) += 1;
+
+        true
+    }
+
+    /// Remove a connection that was counted for the given torrent.
+    pub fn remove(&self, hash: &InfoHash) {
+        let mut state = self.state.borrow_mut();
+
+        let remove_torrent = match state.torrents.get_mut(hash) {
+            Some(count) => { *count -= 1; *count == 0 },
+            None        => return
+        };
+
+        if remove_torrent {
+            state.torrents.remove(hash);
+        }
+        state.total -= 1;
+    }
+}
+
+/// Future that resolves once a half open connection has been counted.
+pub struct HalfOpenSlot {
+    limits: ConnectionLimits
+}
+
+impl Future for HalfOpenSlot {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let mut state = self.limits.state.borrow_mut();
+
+        if state.half_open < cmp::max(state.max_half_open, 1) {
+            state.half_open += 1;
+
+            Ok(Async::Ready(()))
+        } else {
+            state.waiting.push(task::current());
+
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::usize;
+
+    use super::ConnectionLimits;
+
+    use bip_util::bt::{self, InfoHash};
+    use futures::{Async, Future};
+    use futures::future;
+
+    fn any_info_hash() -> InfoHash {
+        [55u8; bt::INFO_HASH_LEN].into()
+    }
+
+    fn any_other_info_hash() -> InfoHash {
+        [66u8; bt::INFO_HASH_LEN].into()
+    }
+
+    #[test]
+    fn positive_per_torrent_limit_reached() {
+        let limits = ConnectionLimits::new(3, 1, usize::MAX);
+
+        assert!(limits.try_add(&any_info_hash()));
+        assert!(!limits.try_add(&any_info_hash()));
+        assert!(limits.try_add(&any_other_info_hash()));
+    }
+
+    #[test]
+    fn positive_remove_frees_connection() {
+        let limits = ConnectionLimits::new(1, 1, usize::MAX);
+
+        assert!(limits.try_add(&any_info_hash()));
+        assert!(!limits.can_connect(None));
+
+        limits.remove(&any_info_hash());
+        assert!(limits.try_add(&any_other_info_hash()));
+    }
+
+    #[test]
+    fn positive_release_frees_half_open() {
+        let limits = ConnectionLimits::new(usize::MAX, usize::MAX, 1);
+
+        limits.acquire_half_open().wait().unwrap();
+
+        // Polled from within a task, since we will be parked waiting for the slot
+        let mut second = limits.acquire_half_open();
+        assert_eq!(Ok(Async::NotReady), future::lazy(|| Ok::<_, ()>(second.poll())).wait().unwrap());
+
+        limits.release_half_open();
+        assert_eq!(Ok(Async::Ready(())), future::lazy(|| Ok::<_, ()>(second.poll())).wait().unwrap());
+    }
+}
@@ -1,3 +1,6 @@
 pub mod config;
 pub mod handler;
-pub mod handshaker;
\ No newline at end of file
+pub mod handshaker;
+pub mod limit;
+pub mod retry;
+pub mod served;
\ No newline at end of file
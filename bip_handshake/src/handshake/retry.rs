@@ -0,0 +1,116 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+use std::u32;
+
+use message::initiate::InitiateMessage;
+
+use bip_util::bt::InfoHash;
+use futures::future::Future;
+use futures::sink::Sink;
+use futures::sync::mpsc::Sender;
+use tokio_core::reactor::Handle;
+use tokio_timer::{self, Timer};
+
+// Longest we will wait before a retry, no matter how many attempts came before it; this also
+// bounds the size of the timer wheel, which needs a slot for every tick up to the longest delay.
+const MAX_BACKOFF_SECS: u64 = 5 * 60;
+
+/// Shared record of failed connection attempts, used to re-initiate connections with exponential backoff.
+#[derive(Clone)]
+pub struct ConnectRetries {
+    max_retries: usize,
+    backoff:     Duration,
+    opt_timer:   Option<Timer>,
+    send:        Sender<InitiateMessage>,
+    handle:      Handle,
+    attempts:    Rc<RefCell<HashMap<(SocketAddr, InfoHash), usize>>>
+}
+
+impl ConnectRetries {
+    pub fn new(max_retries: usize, backoff: Duration, send: Sender<InitiateMessage>, handle: Handle) -> ConnectRetries {
+        // No need to spin up a timer if we will never be retrying
+        let opt_timer = if max_retries == 0 {
+            None
+        } else {
+            // Sleeps longer than the max timeout fail, so size the wheel for the last (and capped) delay
+            Some(tokio_timer::wheel()
+                .max_timeout(backoff_delay(backoff, max_retries - 1))
+                .build())
+        };
+
+        ConnectRetries{ max_retries: max_retries, backoff: backoff, opt_timer: opt_timer, send: send,
+                        handle: handle, attempts: Rc::new(RefCell::new(HashMap::new())) }
+    }
+
+    /// Schedule the given message to be initiated again, returning false if it has used up all of its retries.
+    pub fn retry(&self, init_msg: InitiateMessage) -> bool {
+        let timer = match self.opt_timer {
+            Some(ref timer) => timer,
+            None            => return false
+        };
+        let key = (*init_msg.address(), *init_msg.hash());
+
+        let attempt = {
+            let mut attempts = self.attempts.borrow_mut();
+            let attempt = attempts.get(&key).cloned().unwrap_or(0);
+
+            if attempt >= self.max_retries {
+                attempts.remove(&key);
+                return false
+            }
+            attempts.insert(key, attempt + 1);
+
+            attempt
+        };
+
+        let send = self.send.clone();
+        self.handle.spawn(timer.sleep(backoff_delay(self.backoff, attempt))
+            .map_err(|_| ())
+            .and_then(move |_| send.send(init_msg).map_err(|_| ()))
+            .map(|_| ()));
+
+        true
+    }
+
+    /// Forget any failed attempts for the given peer and torrent, once a connection has been made.
+    pub fn clear(&self, addr: &SocketAddr, hash: &InfoHash) {
+        self.attempts.borrow_mut().remove(&(*addr, *hash));
+    }
+}
+
+/// Delay before the given (zero based) retry attempt, doubling the backoff for each attempt before it.
+///
+/// Delays are capped at `MAX_BACKOFF_SECS`.
+fn backoff_delay(backoff: Duration, attempt: usize) -> Duration {
+    let max_backoff = Duration::from_secs(MAX_BACKOFF_SECS);
+    let factor = if attempt < 32 { 1u32 << attempt } else { u32::MAX };
+
+    backoff.checked_mul(factor).map_or(max_backoff, |delay| cmp::min(delay, max_backoff))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    #[test]
+    fn positive_backoff_delay_doubles() {
+        let backoff = Duration::from_millis(500);
+
+        assert_eq!(Duration::from_millis(500), super::backoff_delay(backoff, 0));
+        assert_eq!(Duration::from_millis(1000), super::backoff_delay(backoff, 1));
+        assert_eq!(Duration::from_millis(4000), super::backoff_delay(backoff, 3));
+    }
+
+    #[test]
+    fn positive_backoff_delay_capped() {
+        let max_backoff = Duration::from_secs(super::MAX_BACKOFF_SECS);
+
+        assert_eq!(max_backoff, super::backoff_delay(Duration::from_millis(500), 20));
+        assert_eq!(max_backoff, super::backoff_delay(Duration::from_millis(500), 1000));
+        assert_eq!(max_backoff, super::backoff_delay(Duration::from_secs(3600), 0));
+    }
+}
@@ -0,0 +1,57 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use bip_util::bt::InfoHash;
+
+/// Shared set of info hashes for the torrents we are serving, which peers connecting to us must ask for.
+///
+/// If unrestricted, every hash is considered served, so peers can connect to us for any torrent.
+#[derive(Clone)]
+pub struct ServedHashes {
+    restricted: bool,
+    hashes:     Rc<RefCell<HashSet<InfoHash>>>
+}
+
+impl ServedHashes {
+    pub fn new(restricted: bool) -> ServedHashes {
+        ServedHashes{ restricted: restricted, hashes: Rc::new(RefCell::new(HashSet::new())) }
+    }
+
+    pub fn add(&self, hash: InfoHash) {
+        self.hashes.borrow_mut().insert(hash);
+    }
+
+    pub fn remove(&self, hash: &InfoHash) {
+        self.hashes.borrow_mut().remove(hash);
+    }
+
+    /// Whether or not we will accept connections from peers for the given hash.
+    pub fn is_served(&self, hash: &InfoHash) -> bool {
+        !self.restricted || self.hashes.borrow().contains(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ServedHashes;
+
+    use bip_util::bt::{self, InfoHash};
+
+    fn any_info_hash() -> InfoHash {
+        [55u8; bt::INFO_HASH_LEN].into()
+    }
+
+    #[test]
+    fn positive_restricted_only_serves_added_hashes() {
+        let served = ServedHashes::new(true);
+        assert!(!served.is_served(&any_info_hash()));
+
+        served.add(any_info_hash());
+        assert!(served.is_served(&any_info_hash()));
+
+        served.remove(&any_info_hash());
+        assert!(!served.is_served(&any_info_hash()));
+        assert!(ServedHashes::new(false).is_served(&any_info_hash()));
+    }
+}
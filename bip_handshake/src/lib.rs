@@ -1,8 +1,10 @@
 extern crate bip_util;
 extern crate bytes;
 extern crate futures;
+extern crate net2;
 #[macro_use]
 extern crate nom;
+extern crate num;
 extern crate rand;
 extern crate tokio_core;
 #[macro_use]
@@ -15,12 +17,15 @@ mod message;
 mod filter;
 mod discovery;
 mod local_addr;
+mod metrics;
+mod mse;
+mod proxy;
 mod transport;
 
 pub use message::complete::CompleteMessage;
 pub use message::initiate::InitiateMessage;
 pub use message::protocol::Protocol;
-pub use message::extensions::Extensions;
+pub use message::extensions::{Extensions, Extension};
 
 pub use handshake::config::HandshakerConfig;
 pub use handshake::handshaker::{HandshakerBuilder, Handshaker, HandshakerStream, HandshakerSink};
@@ -29,6 +34,10 @@ pub use filter::{FilterDecision, HandshakeFilter, HandshakeFilters};
 
 pub use discovery::DiscoveryInfo;
 pub use local_addr::LocalAddr;
+pub use metrics::{HandshakeMetric, HandshakeRecorder, NoopHandshakeRecorder};
+pub use mse::EncryptionPolicy;
+pub use mse::stream::MseStream;
+pub use proxy::Socks5Proxy;
 pub use transport::Transport;
 
 /// Built in objects implementing `Transport`.
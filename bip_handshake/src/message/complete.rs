@@ -9,6 +9,7 @@ use bip_util::bt::{InfoHash, PeerId};
 pub struct CompleteMessage<S> {
     prot: Protocol,
     ext:  Extensions,
+    rext: Extensions,
     hash: InfoHash,
     pid:  PeerId,
     addr: SocketAddr,
@@ -17,8 +18,10 @@ pub struct CompleteMessage<S> {
 
 impl<S> CompleteMessage<S> {
     /// Create a new `CompleteMessage` over the given socket S.
-    pub fn new(prot: Protocol, ext: Extensions, hash: InfoHash, pid: PeerId, addr: SocketAddr, sock: S) -> CompleteMessage<S> {
-        CompleteMessage{ prot: prot, ext: ext, hash: hash, pid: pid, addr: addr, sock: sock }
+    ///
+    /// Extensions are the ones that both you and the peer support, remote extensions are the ones the peer advertised.
+    pub fn new(prot: Protocol, ext: Extensions, rext: Extensions, hash: InfoHash, pid: PeerId, addr: SocketAddr, sock: S) -> CompleteMessage<S> {
+        CompleteMessage{ prot: prot, ext: ext, rext: rext, hash: hash, pid: pid, addr: addr, sock: sock }
     }
 
     /// Protocol that this peer is operating over.
@@ -31,6 +34,11 @@ impl<S> CompleteMessage<S> {
         &self.ext
     }
 
+    /// Extensions that the peer advertised in its handshake.
+    pub fn remote_extensions(&self) -> &Extensions {
+        &self.rext
+    }
+
     /// Hash that the peer is interested in.
     pub fn hash(&self) -> &InfoHash {
         &self.hash
@@ -6,6 +6,20 @@ use nom::{IResult, be_u8};
 /// Number of bytes that the extension protocol takes.
 pub const NUM_EXTENSION_BYTES: usize = 8;
 
+/// Enumeration of all extensions that can be activated in the reserved bytes.
+///
+/// Values are the bit index (starting from the most significant bit of the
+/// first reserved byte) that the extension occupies.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Extension {
+    /// Support for the extension protocol (BEP 10).
+    ExtensionProtocol = 43,
+    /// Support for the fast extension (BEP 6).
+    Fast = 61,
+    /// Support for the dht extension (BEP 5).
+    Dht = 63
+}
+
 /// `Extensions` supported by either end of a handshake.
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub struct Extensions {
@@ -18,6 +32,42 @@ impl Extensions {
         Extensions::with_bytes([0u8; NUM_EXTENSION_BYTES])
     }
 
+    /// Add a single extension to the `Extensions`.
+    pub fn add(&mut self, extension: Extension) {
+        let (byte_index, bit_mask) = extension_bit(extension);
+
+        self.bytes[byte_index] |= bit_mask;
+    }
+
+    /// Remove a single extension from the `Extensions`.
+    pub fn remove(&mut self, extension: Extension) {
+        let (byte_index, bit_mask) = extension_bit(extension);
+
+        self.bytes[byte_index] &= !bit_mask;
+    }
+
+    /// Check if a given extension is activated in the `Extensions`.
+    pub fn contains(&self, extension: Extension) -> bool {
+        let (byte_index, bit_mask) = extension_bit(extension);
+
+        self.bytes[byte_index] & bit_mask != 0
+    }
+
+    /// Check if the fast extension is activated in the `Extensions`.
+    pub fn supports_fast(&self) -> bool {
+        self.contains(Extension::Fast)
+    }
+
+    /// Check if the dht extension is activated in the `Extensions`.
+    pub fn supports_dht(&self) -> bool {
+        self.contains(Extension::Dht)
+    }
+
+    /// Check if the extension protocol is activated in the `Extensions`.
+    pub fn supports_extended(&self) -> bool {
+        self.contains(Extension::ExtensionProtocol)
+    }
+
     /// Create a new `Extensions` by parsing the given bytes.
     pub fn from_bytes(bytes: &[u8]) -> IResult<&[u8], Extensions> {
         parse_extension_bits(bytes)
@@ -54,10 +104,56 @@ impl From<[u8; NUM_EXTENSION_BYTES]> for Extensions {
     }
 }
 
+/// Calculate the byte index and bit mask for the given extension.
+fn extension_bit(extension: Extension) -> (usize, u8) {
+    let bit_index = extension as usize;
+
+    (bit_index / 8, 0x80 >> (bit_index % 8))
+}
+
 /// Parse the given bytes for extension bits.
 fn parse_extension_bits(bytes: &[u8]) -> IResult<&[u8], Extensions> {
     do_parse!(bytes,
         bytes: count_fixed!(u8, be_u8, NUM_EXTENSION_BYTES) >>
         (Extensions::with_bytes(bytes))
     )
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Extensions, Extension};
+
+    #[test]
+    fn positive_add_extension_protocol() {
+        let mut extensions = Extensions::new();
+        extensions.add(Extension::ExtensionProtocol);
+
+        let mut bytes = Vec::new();
+        extensions.write_bytes(&mut bytes).unwrap();
+
+        assert_eq!(&[0, 0, 0, 0, 0, 0x10, 0, 0], &bytes[..]);
+        assert!(extensions.contains(Extension::ExtensionProtocol));
+    }
+
+    #[test]
+    fn positive_add_dht() {
+        let mut extensions = Extensions::new();
+        extensions.add(Extension::Dht);
+
+        let mut bytes = Vec::new();
+        extensions.write_bytes(&mut bytes).unwrap();
+
+        assert_eq!(&[0, 0, 0, 0, 0, 0, 0, 0x01], &bytes[..]);
+        assert!(extensions.supports_dht());
+        assert!(!extensions.supports_fast());
+    }
+
+    #[test]
+    fn positive_remove_extension_protocol() {
+        let mut extensions = Extensions::new();
+        extensions.add(Extension::ExtensionProtocol);
+        extensions.remove(Extension::ExtensionProtocol);
+
+        assert_eq!(Extensions::new(), extensions);
+    }
+}
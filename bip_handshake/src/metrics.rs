@@ -0,0 +1,35 @@
+use std::time::Duration;
+
+/// Events in the life of a handshake that are reported to a `HandshakeRecorder`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum HandshakeMetric {
+    /// Handshake with a peer was started, either by us or by the peer.
+    Started,
+    /// Handshake completed, and the connection was handed off.
+    ///
+    /// Durations recorded for this metric span from the start of the handshake to its completion.
+    Completed,
+    /// Handshake timed out, errored, or was rejected by a filter or connection limit.
+    Failed,
+    /// Peer connected to us for a hash that we are not serving, so its handshake was rejected.
+    ///
+    /// These handshakes are also counted as `Failed`.
+    UnservedHash
+}
+
+/// Receives metrics from a `Handshaker`, for forwarding to a monitoring system.
+///
+/// All methods default to doing nothing, so implementors only override what they care about.
+pub trait HandshakeRecorder {
+    /// Increment the counter for the given metric.
+    fn incr(&self, _metric: HandshakeMetric) { }
+
+    /// Record a duration for the given metric.
+    fn record_duration(&self, _metric: HandshakeMetric, _duration: Duration) { }
+}
+
+/// `HandshakeRecorder` that ignores all metrics; the default for a `Handshaker`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NoopHandshakeRecorder;
+
+impl HandshakeRecorder for NoopHandshakeRecorder { }
@@ -0,0 +1,444 @@
+use std::cmp;
+use std::io;
+
+use mse::{EncryptionPolicy, MseHashes};
+use mse::rc4::Rc4;
+use mse::stream::MseStream;
+
+use bip_util::bt::InfoHash;
+use bip_util::sha::{ShaHash, ShaHashBuilder};
+use futures::future::{self, Future, Loop};
+use num::{BigUint, One};
+use rand::{self, Rng};
+use tokio_io::{io as async_io, AsyncRead, AsyncWrite};
+
+/// Prime used for the Diffie-Hellman key exchange.
+const DH_PRIME: &'static [u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const DH_GENERATOR: u8 = 2;
+
+const PUBLIC_KEY_LEN:  usize = 96;
+const PRIVATE_KEY_LEN: usize = 20;
+const MAX_PAD_LEN:     usize = 512;
+const HASH_LEN:        usize = 20;
+
+/// Verification constant, sent encrypted so each side can find where encryption starts.
+const VC: [u8; 8] = [0u8; 8];
+
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4:       u32 = 0x02;
+
+/// Number of bytes we read to check if a peer connecting to us is using a plaintext handshake.
+const PLAINTEXT_CHECK_LEN: usize = 20;
+
+type MseFuture<T> = Box<Future<Item=T, Error=io::Error>>;
+
+/// Perform the initiating side of an MSE handshake for the given hash.
+///
+/// Resolves to an `MseStream` that the regular handshake can be sent over.
+pub fn initiate<S>(sock: S, hash: InfoHash, policy: EncryptionPolicy) -> MseFuture<MseStream<S>>
+    where S: AsyncRead + AsyncWrite + 'static {
+    let private_key = random_private_key();
+    let crypto_provide = match policy {
+        EncryptionPolicy::Require => CRYPTO_RC4,
+        _                         => CRYPTO_RC4 | CRYPTO_PLAINTEXT
+    };
+
+    let mut public_message = public_key(&private_key);
+    public_message.extend_from_slice(&random_pad());
+
+    Box::new(async_io::write_all(sock, public_message)
+        .and_then(|(sock, _)| async_io::read_exact(sock, vec![0u8; PUBLIC_KEY_LEN]))
+        .and_then(move |(sock, remote_public)| {
+            let secret = shared_secret(&remote_public, &private_key);
+            let mut encrypt = Rc4::new(hash_parts(&[&b"keyA"[..], &secret[..], hash.as_ref()]).as_ref());
+            let mut decrypt = Rc4::new(hash_parts(&[&b"keyB"[..], &secret[..], hash.as_ref()]).as_ref());
+
+            let mut message = Vec::new();
+            message.extend_from_slice(hash_parts(&[&b"req1"[..], &secret[..]]).as_ref());
+            message.extend_from_slice((hash_parts(&[&b"req2"[..], hash.as_ref()]) ^ hash_parts(&[&b"req3"[..], &secret[..]])).as_ref());
+
+            // We dont send a PadC or an initial payload; the regular handshake will follow in the stream
+            let mut encrypted = VC.to_vec();
+            encrypted.extend_from_slice(&u32_to_bytes(crypto_provide));
+            encrypted.extend_from_slice(&[0, 0, 0, 0]);
+            encrypt.process(&mut encrypted);
+            message.extend_from_slice(&encrypted);
+
+            // Remote will send an encrypted VC after their PadB, so look for that
+            let mut encrypted_vc = VC.to_vec();
+            decrypt.process(&mut encrypted_vc);
+
+            async_io::write_all(sock, message)
+                .and_then(move |(sock, _)| read_until(sock, encrypted_vc, MAX_PAD_LEN + VC.len()))
+                .map(move |sock| (sock, encrypt, decrypt))
+        })
+        .and_then(move |(sock, encrypt, mut decrypt)| {
+            async_io::read_exact(sock, [0u8; 6]).and_then(move |(sock, mut select_bytes)| {
+                decrypt.process(&mut select_bytes);
+
+                let crypto_select = bytes_to_u32(&select_bytes[0..4]);
+                let pad_len = bytes_to_u16(&select_bytes[4..6]) as usize;
+
+                async_io::read_exact(sock, vec![0u8; pad_len]).and_then(move |(sock, mut pad)| {
+                    decrypt.process(&mut pad);
+
+                    if crypto_select == CRYPTO_RC4 && crypto_provide & CRYPTO_RC4 != 0 {
+                        Ok(MseStream::with_prefix(sock, Vec::new(), Some(encrypt), Some(decrypt)))
+                    } else if crypto_select == CRYPTO_PLAINTEXT && crypto_provide & CRYPTO_PLAINTEXT != 0 {
+                        Ok(MseStream::plaintext(sock))
+                    } else {
+                        Err(invalid_data("Peer Selected A Crypto Method We Did Not Provide"))
+                    }
+                })
+            })
+        }))
+}
+
+/// Perform the receiving side of an MSE handshake, for any of the given hashes.
+///
+/// If the peer sends a plaintext handshake starting with the given prefix, and the policy allows it,
+/// the stream will yield the bytes we read during detection before reading from the socket.
+pub fn respond<S>(sock: S, hashes: MseHashes, plaintext_prefix: Vec<u8>, policy: EncryptionPolicy) -> MseFuture<MseStream<S>>
+    where S: AsyncRead + AsyncWrite + 'static {
+    let check_len = cmp::min(PLAINTEXT_CHECK_LEN, plaintext_prefix.len());
+
+    Box::new(async_io::read_exact(sock, vec![0u8; check_len])
+        .and_then(move |(sock, check_bytes)| -> MseFuture<MseStream<S>> {
+            if check_bytes[..] == plaintext_prefix[..check_len] {
+                if policy == EncryptionPolicy::Require {
+                    Box::new(future::err(invalid_data("Peer Sent A Plaintext Handshake")))
+                } else {
+                    Box::new(future::ok(MseStream::with_prefix(sock, check_bytes, None, None)))
+                }
+            } else {
+                respond_encrypted(sock, check_bytes, hashes, policy)
+            }
+        }))
+}
+
+/// Continue the receiving side of an MSE handshake, given the start of the remote public key.
+fn respond_encrypted<S>(sock: S, public_start: Vec<u8>, hashes: MseHashes, policy: EncryptionPolicy) -> MseFuture<MseStream<S>>
+    where S: AsyncRead + AsyncWrite + 'static {
+    let private_key = random_private_key();
+
+    let mut public_message = public_key(&private_key);
+    public_message.extend_from_slice(&random_pad());
+
+    Box::new(async_io::read_exact(sock, vec![0u8; PUBLIC_KEY_LEN - public_start.len()])
+        .and_then(|(sock, public_end)| {
+            let mut remote_public = public_start;
+            remote_public.extend_from_slice(&public_end);
+
+            async_io::write_all(sock, public_message).map(|(sock, _)| (sock, remote_public))
+        })
+        .and_then(move |(sock, remote_public)| {
+            let secret = shared_secret(&remote_public, &private_key);
+            let req_one = hash_parts(&[&b"req1"[..], &secret[..]]).as_ref().to_vec();
+
+            read_until(sock, req_one, HASH_LEN + MAX_PAD_LEN)
+                .and_then(|sock| async_io::read_exact(sock, [0u8; HASH_LEN]))
+                .and_then(move |(sock, obfuscated_hash)| {
+                    let req_two = ShaHash::from(obfuscated_hash) ^ hash_parts(&[&b"req3"[..], &secret[..]]);
+
+                    hashes.find(|hash| hash_parts(&[&b"req2"[..], hash.as_ref()]) == req_two)
+                        .map(|hash| (sock, secret, hash))
+                        .ok_or(invalid_data("Peer Requested A Hash We Are Not Serving"))
+                })
+        })
+        .and_then(|(sock, secret, hash)| {
+            let encrypt = Rc4::new(hash_parts(&[&b"keyB"[..], &secret[..], hash.as_ref()]).as_ref());
+            let mut decrypt = Rc4::new(hash_parts(&[&b"keyA"[..], &secret[..], hash.as_ref()]).as_ref());
+
+            async_io::read_exact(sock, [0u8; 14]).and_then(move |(sock, mut provide_bytes)| {
+                decrypt.process(&mut provide_bytes);
+
+                if provide_bytes[0..8] != VC[..] {
+                    return Err(invalid_data("Peer Sent An Invalid Verification Constant"))
+                }
+                let crypto_provide = bytes_to_u32(&provide_bytes[8..12]);
+                let pad_len = bytes_to_u16(&provide_bytes[12..14]) as usize;
+
+                Ok((sock, crypto_provide, pad_len, encrypt, decrypt))
+            })
+        })
+        .and_then(|(sock, crypto_provide, pad_len, encrypt, mut decrypt)| {
+            // PadC is followed by the length of the initial payload
+            async_io::read_exact(sock, vec![0u8; pad_len + 2]).and_then(move |(sock, mut pad)| {
+                decrypt.process(&mut pad);
+
+                let payload_len = bytes_to_u16(&pad[pad_len..]) as usize;
+
+                async_io::read_exact(sock, vec![0u8; payload_len])
+                    .map(move |(sock, mut payload)| {
+                        decrypt.process(&mut payload);
+
+                        (sock, crypto_provide, payload, encrypt, decrypt)
+                    })
+            })
+        })
+        .and_then(move |(sock, crypto_provide, payload, mut encrypt, decrypt)| {
+            let crypto_select = if crypto_provide & CRYPTO_RC4 != 0 {
+                CRYPTO_RC4
+            } else if crypto_provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Require {
+                CRYPTO_PLAINTEXT
+            } else {
+                return Err(invalid_data("Peer Did Not Provide A Crypto Method We Accept"))
+            };
+
+            let mut message = VC.to_vec();
+            message.extend_from_slice(&u32_to_bytes(crypto_select));
+            message.extend_from_slice(&[0, 0]);
+            encrypt.process(&mut message);
+
+            Ok(async_io::write_all(sock, message).map(move |(sock, _)| {
+                // Initial payload is always encrypted, so it has already been decrypted for us
+                if crypto_select == CRYPTO_RC4 {
+                    MseStream::with_prefix(sock, payload, Some(encrypt), Some(decrypt))
+                } else {
+                    MseStream::with_prefix(sock, payload, None, None)
+                }
+            }))
+        })
+        .flatten())
+}
+
+/// Read from the socket, one byte at a time, until we have read the given pattern.
+///
+/// We read one byte at a time so that we dont consume any bytes past the pattern.
+fn read_until<S>(sock: S, pattern: Vec<u8>, max_len: usize) -> MseFuture<S>
+    where S: AsyncRead + 'static {
+    Box::new(future::loop_fn((sock, pattern, Vec::new()), move |(sock, pattern, mut window)| {
+        async_io::read_exact(sock, [0u8; 1]).and_then(move |(sock, byte)| {
+            window.push(byte[0]);
+
+            if window.ends_with(&pattern) {
+                Ok(Loop::Break(sock))
+            } else if window.len() >= max_len {
+                Err(invalid_data("Peer Did Not Send The Expected Synchronization Bytes"))
+            } else {
+                Ok(Loop::Continue((sock, pattern, window)))
+            }
+        })
+    }))
+}
+
+//----------------------------------------------------------------------------------//
+
+fn random_private_key() -> Vec<u8> {
+    let mut private_key = vec![0u8; PRIVATE_KEY_LEN];
+    rand::thread_rng().fill_bytes(&mut private_key);
+
+    private_key
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+
+    let mut pad = vec![0u8; rng.gen_range(0, MAX_PAD_LEN + 1)];
+    rng.fill_bytes(&mut pad);
+
+    pad
+}
+
+/// Calculate our public key for the given private key.
+fn public_key(private_key: &[u8]) -> Vec<u8> {
+    mod_pow(&BigUint::from_bytes_be(&[DH_GENERATOR]), private_key)
+}
+
+/// Calculate the shared secret given the remote public key and our private key.
+fn shared_secret(remote_public: &[u8], private_key: &[u8]) -> Vec<u8> {
+    mod_pow(&BigUint::from_bytes_be(remote_public), private_key)
+}
+
+/// Calculate the base to the power of the exponent modulo our prime, as big endian bytes padded to the public key length.
+fn mod_pow(base: &BigUint, exponent: &[u8]) -> Vec<u8> {
+    let prime = BigUint::parse_bytes(DH_PRIME, 16)
+        .expect("bip_handshake: Failed To Parse Diffie-Hellman Prime");
+
+    let mut result = BigUint::one();
+    for byte in exponent {
+        for bit in (0..8).rev() {
+            result = &(&result * &result) % &prime;
+
+            if (byte >> bit) & 0x01 == 0x01 {
+                result = &(&result * base) % &prime;
+            }
+        }
+    }
+
+    let result_bytes = result.to_bytes_be();
+    let mut padded_bytes = vec![0u8; PUBLIC_KEY_LEN - result_bytes.len()];
+    padded_bytes.extend_from_slice(&result_bytes);
+
+    padded_bytes
+}
+
+fn hash_parts(parts: &[&[u8]]) -> ShaHash {
+    parts.iter()
+        .fold(ShaHashBuilder::new(), |builder, part| builder.add_bytes(part))
+        .build()
+}
+
+fn u32_to_bytes(value: u32) -> [u8; 4] {
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+fn bytes_to_u32(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |value, &byte| (value << 8) | byte as u32)
+}
+
+fn bytes_to_u16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |value, &byte| (value << 8) | byte as u16)
+}
+
+fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+
+    use mse::{EncryptionPolicy, MseHashes};
+    use super::{random_private_key, public_key, shared_secret, PUBLIC_KEY_LEN};
+
+    use bip_util::bt::{self, InfoHash};
+    use futures::{task, Async, Future, Poll};
+    use tokio_io::{AsyncRead, AsyncWrite};
+
+    const PLAINTEXT_PREFIX: &'static [u8] = b"\x13BitTorrent protocol";
+
+    /// One end of an in-memory duplex, reading what the other end writes.
+    struct Pipe {
+        read:  Rc<RefCell<VecDeque<u8>>>,
+        write: Rc<RefCell<VecDeque<u8>>>
+    }
+
+    fn duplex() -> (Pipe, Pipe) {
+        let (one, two) = (Rc::new(RefCell::new(VecDeque::new())), Rc::new(RefCell::new(VecDeque::new())));
+
+        (Pipe{ read: one.clone(), write: two.clone() }, Pipe{ read: two, write: one })
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut read = self.read.borrow_mut();
+
+            // Both ends are driven by the same task, so have it polled again to give the other end a chance to write
+            if read.is_empty() {
+                task::current().notify();
+
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "Pipe Is Empty"))
+            }
+
+            let bytes_read = ::std::cmp::min(buf.len(), read.len());
+            for (dest, src) in buf.iter_mut().zip(read.drain(..bytes_read)) {
+                *dest = src;
+            }
+
+            Ok(bytes_read)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write.borrow_mut().extend(buf.iter().cloned());
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Pipe { }
+
+    impl AsyncWrite for Pipe {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn any_info_hash() -> InfoHash {
+        [55u8; bt::INFO_HASH_LEN].into()
+    }
+
+    #[test]
+    fn positive_shared_secret_matches() {
+        let (private_one, private_two) = (random_private_key(), random_private_key());
+        let (public_one, public_two) = (public_key(&private_one), public_key(&private_two));
+
+        assert_eq!(PUBLIC_KEY_LEN, public_one.len());
+        assert_eq!(shared_secret(&public_two, &private_one), shared_secret(&public_one, &private_two));
+    }
+
+    #[test]
+    fn positive_encrypted_round_trip() {
+        let (initiator_sock, responder_sock) = duplex();
+        let hashes = MseHashes::new();
+        hashes.add(any_info_hash());
+
+        let (mut initiator, mut responder) = super::initiate(initiator_sock, any_info_hash(), EncryptionPolicy::Require)
+            .join(super::respond(responder_sock, hashes, PLAINTEXT_PREFIX.to_vec(), EncryptionPolicy::Require))
+            .wait()
+            .unwrap();
+        assert!(initiator.is_encrypted());
+        assert!(responder.is_encrypted());
+
+        initiator.write_all(b"initiator").unwrap();
+        responder.write_all(b"responder").unwrap();
+        // Nothing on the wire is sent in the clear
+        assert!(!initiator.get_ref().write.borrow().iter().zip(b"initiator".iter()).all(|(a, b)| a == b));
+
+        let mut buffer = [0u8; 9];
+        responder.read_exact(&mut buffer).unwrap();
+        assert_eq!(b"initiator", &buffer);
+        initiator.read_exact(&mut buffer).unwrap();
+        assert_eq!(b"responder", &buffer);
+    }
+
+    #[test]
+    fn positive_plaintext_fallback_yields_prefix() {
+        let (mut initiator_sock, responder_sock) = duplex();
+        let mut handshake = PLAINTEXT_PREFIX.to_vec();
+        handshake.extend_from_slice(&[0u8; 8]);
+        initiator_sock.write_all(&handshake).unwrap();
+
+        let mut responder = super::respond(responder_sock, MseHashes::new(), PLAINTEXT_PREFIX.to_vec(), EncryptionPolicy::Prefer)
+            .wait()
+            .unwrap();
+        assert!(!responder.is_encrypted());
+
+        let mut buffer = vec![0u8; handshake.len()];
+        responder.read_exact(&mut buffer).unwrap();
+        assert_eq!(handshake, buffer);
+    }
+
+    #[test]
+    fn negative_require_rejects_plaintext() {
+        let (mut initiator_sock, responder_sock) = duplex();
+        initiator_sock.write_all(PLAINTEXT_PREFIX).unwrap();
+
+        let error = super::respond(responder_sock, MseHashes::new(), PLAINTEXT_PREFIX.to_vec(), EncryptionPolicy::Require)
+            .wait()
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+
+    #[test]
+    fn negative_unknown_hash_rejected() {
+        let (initiator_sock, responder_sock) = duplex();
+
+        let error = super::initiate(initiator_sock, any_info_hash(), EncryptionPolicy::Prefer)
+            .join(super::respond(responder_sock, MseHashes::new(), PLAINTEXT_PREFIX.to_vec(), EncryptionPolicy::Prefer))
+            .wait()
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+}
@@ -0,0 +1,50 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use bip_util::bt::InfoHash;
+
+pub mod handshake;
+pub mod rc4;
+pub mod stream;
+
+/// Policy for encrypting peer connections with Message Stream Encryption.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EncryptionPolicy {
+    /// Only make and accept plaintext connections.
+    Disabled,
+    /// Prefer encrypted connections, falling back to plaintext if the peer does not support them.
+    Prefer,
+    /// Only make and accept encrypted connections.
+    Require
+}
+
+//----------------------------------------------------------------------------------//
+
+/// Info hashes that we will accept encrypted connections for.
+///
+/// Peers connecting to us identify their torrent with an obfuscated hash, so we have to know our torrents up front.
+#[derive(Clone)]
+pub struct MseHashes {
+    hashes: Rc<RefCell<HashSet<InfoHash>>>
+}
+
+impl MseHashes {
+    pub fn new() -> MseHashes {
+        MseHashes{ hashes: Rc::new(RefCell::new(HashSet::new())) }
+    }
+
+    pub fn add(&self, hash: InfoHash) {
+        self.hashes.borrow_mut().insert(hash);
+    }
+
+    pub fn remove(&self, hash: &InfoHash) {
+        self.hashes.borrow_mut().remove(hash);
+    }
+
+    /// Find the first hash that matches the given predicate.
+    pub fn find<P>(&self, predicate: P) -> Option<InfoHash>
+        where P: Fn(&InfoHash) -> bool {
+        self.hashes.borrow().iter().find(|hash| predicate(hash)).cloned()
+    }
+}
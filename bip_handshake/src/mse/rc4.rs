@@ -0,0 +1,61 @@
+/// Number of keystream bytes discarded after key setup, as required by MSE.
+const KEYSTREAM_DISCARD_LEN: usize = 1024;
+
+/// RC4 keystream used to encrypt or decrypt one direction of an `MseStream`.
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i:     u8,
+    j:     u8
+}
+
+impl Rc4 {
+    /// Create a new `Rc4` with the given key, discarding the start of the keystream.
+    pub fn new(key: &[u8]) -> Rc4 {
+        let mut state = [0u8; 256];
+        for (index, value) in state.iter_mut().enumerate() {
+            *value = index as u8;
+        }
+
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        let mut rc4 = Rc4{ state: state, i: 0, j: 0 };
+        rc4.process(&mut [0u8; KEYSTREAM_DISCARD_LEN][..]);
+
+        rc4
+    }
+
+    /// Encrypt or decrypt the given bytes in place.
+    pub fn process(&mut self, bytes: &mut [u8]) {
+        for byte in bytes.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rc4;
+
+    #[test]
+    fn positive_encrypt_decrypt() {
+        let mut encrypt = Rc4::new(b"Secret Key");
+        let mut decrypt = Rc4::new(b"Secret Key");
+
+        let mut bytes = b"Hello World".to_vec();
+        encrypt.process(&mut bytes);
+        assert!(&bytes[..] != &b"Hello World"[..]);
+
+        decrypt.process(&mut bytes);
+        assert_eq!(&b"Hello World"[..], &bytes[..]);
+    }
+}
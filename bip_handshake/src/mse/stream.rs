@@ -0,0 +1,124 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+
+use mse::rc4::Rc4;
+
+use futures::Poll;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// Socket which may be encrypted using Message Stream Encryption.
+///
+/// If the peer did not negotiate encryption, bytes are passed through as is.
+///
+/// When encrypted, writes are buffered: see the `Write` impl.
+pub struct MseStream<S> {
+    sock:       S,
+    prefix:     Vec<u8>,
+    prefix_pos: usize,
+    encrypt:    Option<Rc4>,
+    decrypt:    Option<Rc4>,
+    pending:    Vec<u8>
+}
+
+impl<S> MseStream<S> {
+    /// Create a new `MseStream` which passes bytes through as is.
+    pub fn plaintext(sock: S) -> MseStream<S> {
+        MseStream::with_prefix(sock, Vec::new(), None, None)
+    }
+
+    /// Create a new `MseStream` which will yield the prefix bytes (already decrypted) before reading from the socket.
+    pub fn with_prefix(sock: S, prefix: Vec<u8>, encrypt: Option<Rc4>, decrypt: Option<Rc4>) -> MseStream<S> {
+        MseStream{ sock: sock, prefix: prefix, prefix_pos: 0, encrypt: encrypt, decrypt: decrypt, pending: Vec::new() }
+    }
+
+    /// Whether or not the stream is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+
+    /// Access the underlying socket.
+    pub fn get_ref(&self) -> &S {
+        &self.sock
+    }
+}
+
+impl<S> MseStream<S> where S: Write {
+    /// Write out any bytes that we have encrypted but not yet written.
+    fn write_pending(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            let written = try!(self.sock.write(&self.pending));
+
+            if written == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "Failed To Write Bytes"))
+            }
+            self.pending.drain(..written);
+        }
+
+        Ok(())
+    }
+}
+
+impl<S> Read for MseStream<S> where S: Read {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.prefix_pos < self.prefix.len() {
+            let read = cmp::min(buf.len(), self.prefix.len() - self.prefix_pos);
+
+            buf[..read].copy_from_slice(&self.prefix[self.prefix_pos..self.prefix_pos + read]);
+            self.prefix_pos += read;
+
+            return Ok(read)
+        }
+
+        let read = try!(self.sock.read(buf));
+        if let Some(ref mut decrypt) = self.decrypt {
+            decrypt.process(&mut buf[..read]);
+        }
+
+        Ok(read)
+    }
+}
+
+/// Once encrypted, bytes can not be handed back, so an encrypted write accepts the whole buffer even if the socket
+/// would block part way through; the rest is held on to, and written out ahead of the next write, or on flush.
+///
+/// Writes return `WouldBlock` (accepting nothing) while held bytes are still waiting on the socket, so at most one
+/// write worth of bytes is ever held, and callers must flush to know that everything has reached the socket.
+impl<S> Write for MseStream<S> where S: Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.encrypt.is_none() {
+            return self.sock.write(buf)
+        }
+
+        // Our keystream has already advanced past any pending bytes, so they have to go out first
+        try!(self.write_pending());
+
+        let start = self.pending.len();
+        self.pending.extend_from_slice(buf);
+        if let Some(ref mut encrypt) = self.encrypt {
+            encrypt.process(&mut self.pending[start..]);
+        }
+
+        // Bytes are accepted once encrypted, so a partial write here will be picked up later
+        match self.write_pending() {
+            Ok(())                                                      => Ok(buf.len()),
+            Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+            Err(error)                                                  => Err(error)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.write_pending());
+
+        self.sock.flush()
+    }
+}
+
+impl<S> AsyncRead for MseStream<S> where S: AsyncRead { }
+
+impl<S> AsyncWrite for MseStream<S> where S: AsyncWrite {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_nb!(self.write_pending());
+
+        self.sock.shutdown()
+    }
+}
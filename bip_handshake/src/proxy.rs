@@ -0,0 +1,185 @@
+use std::io;
+use std::net::SocketAddr;
+use std::u8;
+
+use futures::future::{self, Future};
+use tokio_io::{io as async_io, AsyncRead, AsyncWrite};
+
+const SOCKS_VERSION:       u8 = 0x05;
+const SOCKS_AUTH_VERSION:  u8 = 0x01;
+
+const METHOD_NO_AUTH:      u8 = 0x00;
+const METHOD_USER_PASS:    u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xFF;
+
+const COMMAND_CONNECT:     u8 = 0x01;
+
+const ADDRESS_TYPE_IPV4:   u8 = 0x01;
+const ADDRESS_TYPE_DOMAIN: u8 = 0x03;
+const ADDRESS_TYPE_IPV6:   u8 = 0x04;
+
+const REPLY_SUCCEEDED:     u8 = 0x00;
+
+/// SOCKS5 proxy that outgoing peer connections will be routed through.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Socks5Proxy {
+    addr: SocketAddr,
+    auth: Option<(String, String)>
+}
+
+impl Socks5Proxy {
+    /// Create a new `Socks5Proxy` at the given address, with no authentication.
+    pub fn new(addr: SocketAddr) -> Socks5Proxy {
+        Socks5Proxy{ addr: addr, auth: None }
+    }
+
+    /// Create a new `Socks5Proxy` at the given address, using username/password authentication.
+    ///
+    /// Panics if either the username or password are longer than 255 bytes.
+    pub fn with_auth(addr: SocketAddr, username: String, password: String) -> Socks5Proxy {
+        if username.len() > u8::max_value() as usize || password.len() > u8::max_value() as usize {
+            panic!("bip_handshake: Socks5 Proxy With Credential Length Greater Than {} Found", u8::max_value())
+        }
+
+        Socks5Proxy{ addr: addr, auth: Some((username, password)) }
+    }
+
+    /// Address of the proxy.
+    pub fn address(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    /// Username and password used to authenticate with the proxy, if any.
+    pub fn auth(&self) -> Option<(&str, &str)> {
+        self.auth.as_ref().map(|&(ref user, ref pass)| (&user[..], &pass[..]))
+    }
+}
+
+//----------------------------------------------------------------------------------//
+
+/// Perform a SOCKS5 CONNECT to the target address over a socket already connected to the proxy.
+///
+/// Resolves to the same socket, which will then be tunneled to the target.
+pub fn socks5_connect<S>(sock: S, proxy: &Socks5Proxy, target: SocketAddr) -> Box<Future<Item=S, Error=io::Error>>
+    where S: AsyncRead + AsyncWrite + 'static {
+    let greeting = match proxy.auth {
+        Some(_) => vec![SOCKS_VERSION, 2, METHOD_NO_AUTH, METHOD_USER_PASS],
+        None    => vec![SOCKS_VERSION, 1, METHOD_NO_AUTH]
+    };
+    let opt_auth = proxy.auth.clone();
+
+    let connect_future = async_io::write_all(sock, greeting)
+        .and_then(|(sock, _)| async_io::read_exact(sock, [0u8; 2]))
+        .and_then(move |(sock, method_reply)| -> Box<Future<Item=S, Error=io::Error>> {
+            match (method_reply, opt_auth) {
+                ([SOCKS_VERSION, METHOD_NO_AUTH], _)                    => Box::new(future::ok(sock)),
+                ([SOCKS_VERSION, METHOD_USER_PASS], Some((user, pass))) => authenticate(sock, user, pass),
+                ([SOCKS_VERSION, METHOD_UNACCEPTABLE], _)               => proxy_error("bip_handshake: Proxy Rejected Our Authentication Methods"),
+                _                                                       => proxy_error("bip_handshake: Proxy Sent An Invalid Method Reply")
+            }
+        })
+        .and_then(move |sock| async_io::write_all(sock, connect_request(target)))
+        .and_then(|(sock, _)| async_io::read_exact(sock, [0u8; 4]))
+        .and_then(|(sock, reply_header)| -> Box<Future<Item=S, Error=io::Error>> {
+            let remaining_len = match reply_header {
+                [SOCKS_VERSION, REPLY_SUCCEEDED, _, ADDRESS_TYPE_IPV4]   => 4 + 2,
+                [SOCKS_VERSION, REPLY_SUCCEEDED, _, ADDRESS_TYPE_IPV6]   => 16 + 2,
+                [SOCKS_VERSION, REPLY_SUCCEEDED, _, ADDRESS_TYPE_DOMAIN] => {
+                    // Domain is prefixed with its length, so read that before the rest of the bound address
+                    return Box::new(async_io::read_exact(sock, [0u8; 1])
+                        .and_then(|(sock, domain_len)| async_io::read_exact(sock, vec![0u8; domain_len[0] as usize + 2]))
+                        .map(|(sock, _)| sock))
+                },
+                [SOCKS_VERSION, REPLY_SUCCEEDED, _, _] => return proxy_error("bip_handshake: Proxy Sent An Invalid Address Type"),
+                _                                      => return proxy_error("bip_handshake: Proxy Failed To Connect To The Peer")
+            };
+
+            Box::new(async_io::read_exact(sock, vec![0u8; remaining_len])
+                .map(|(sock, _)| sock))
+        });
+
+    Box::new(connect_future)
+}
+
+/// Perform username/password authentication with the proxy.
+fn authenticate<S>(sock: S, user: String, pass: String) -> Box<Future<Item=S, Error=io::Error>>
+    where S: AsyncRead + AsyncWrite + 'static {
+    let mut request = Vec::with_capacity(3 + user.len() + pass.len());
+
+    request.push(SOCKS_AUTH_VERSION);
+    request.push(user.len() as u8);
+    request.extend_from_slice(user.as_bytes());
+    request.push(pass.len() as u8);
+    request.extend_from_slice(pass.as_bytes());
+
+    Box::new(async_io::write_all(sock, request)
+        .and_then(|(sock, _)| async_io::read_exact(sock, [0u8; 2]))
+        .and_then(|(sock, auth_reply)| {
+            match auth_reply {
+                [SOCKS_AUTH_VERSION, REPLY_SUCCEEDED] => Ok(sock),
+                _                                     => Err(io::Error::new(io::ErrorKind::PermissionDenied, "bip_handshake: Proxy Rejected Our Credentials"))
+            }
+        }))
+}
+
+/// Create a CONNECT request for the given target address.
+fn connect_request(target: SocketAddr) -> Vec<u8> {
+    let mut request = vec![SOCKS_VERSION, COMMAND_CONNECT, 0];
+
+    match target {
+        SocketAddr::V4(v4_addr) => {
+            request.push(ADDRESS_TYPE_IPV4);
+            request.extend_from_slice(&v4_addr.ip().octets()[..]);
+        },
+        SocketAddr::V6(v6_addr) => {
+            request.push(ADDRESS_TYPE_IPV6);
+            request.extend_from_slice(&v6_addr.ip().octets()[..]);
+        }
+    }
+    request.push((target.port() >> 8) as u8);
+    request.push(target.port() as u8);
+
+    request
+}
+
+fn proxy_error<S>(message: &'static str) -> Box<Future<Item=S, Error=io::Error>> where S: 'static {
+    Box::new(future::err(io::Error::new(io::ErrorKind::Other, message)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::Socks5Proxy;
+
+    use futures::Future;
+
+    #[test]
+    fn positive_connect_no_auth() {
+        let proxy = Socks5Proxy::new("5.6.7.8:1080".parse().unwrap());
+
+        // Our writes and the proxy replies are interleaved in the buffer, in the order they happen
+        let mut buffer = vec![0u8; 3];
+        buffer.extend_from_slice(&[5, 0]);
+        buffer.extend_from_slice(&[0u8; 10]);
+        buffer.extend_from_slice(&[5, 0, 0, 1, 5, 6, 7, 8, 0x04, 0x38]);
+
+        let sock = super::socks5_connect(Cursor::new(buffer), &proxy, "1.2.3.4:6881".parse().unwrap()).wait().unwrap();
+        let buffer = sock.into_inner();
+
+        assert_eq!(&[5, 1, 0], &buffer[0..3]);
+        assert_eq!(&[5, 1, 0, 1, 1, 2, 3, 4, 0x1A, 0xE1], &buffer[5..15]);
+    }
+
+    #[test]
+    fn negative_connect_refused() {
+        let proxy = Socks5Proxy::new("5.6.7.8:1080".parse().unwrap());
+
+        let mut buffer = vec![0u8; 3];
+        buffer.extend_from_slice(&[5, 0]);
+        buffer.extend_from_slice(&[0u8; 10]);
+        buffer.extend_from_slice(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        assert!(super::socks5_connect(Cursor::new(buffer), &proxy, "1.2.3.4:6881".parse().unwrap()).wait().is_err());
+    }
+}
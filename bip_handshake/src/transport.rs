@@ -6,7 +6,8 @@ use local_addr::LocalAddr;
 use futures::Poll;
 use futures::future::Future;
 use futures::stream::Stream;
-use tokio_core::net::{TcpStream, TcpStreamNew, Incoming, TcpListener};
+use net2::TcpBuilder;
+use tokio_core::net::{TcpStream, Incoming, TcpListener};
 use tokio_core::reactor::Handle;
 use tokio_io::{AsyncRead, AsyncWrite};
 
@@ -24,6 +25,15 @@ pub trait Transport {
     /// Connect to the given address over this transport, using the supplied `Handle`.
     fn connect(addr: &SocketAddr, handle: &Handle) -> io::Result<Self::FutureSocket>;
 
+    /// Connect to the given address over this transport, from the given local address, using the supplied `Handle`.
+    ///
+    /// Transports that can't choose the local address of a connection return an error, rather than
+    /// connecting from whatever address the operating system picks for them.
+    #[allow(unused)]
+    fn connect_from(bind_addr: &SocketAddr, addr: &SocketAddr, handle: &Handle) -> io::Result<Self::FutureSocket> {
+        Err(io::Error::new(io::ErrorKind::Other, "Transport Does Not Support Binding Outgoing Connections"))
+    }
+
     /// Listen to the given address for this transport, using the supplied `Handle`.
     fn listen(addr: &SocketAddr, handle: &Handle) -> io::Result<Self::Listener>;
 }
@@ -35,11 +45,21 @@ pub struct TcpTransport;
 
 impl Transport for TcpTransport {
     type Socket = TcpStream;
-    type FutureSocket = TcpStreamNew;
+    type FutureSocket = Box<Future<Item=TcpStream, Error=io::Error>>;
     type Listener = TcpListenerStream<Incoming>;
 
     fn connect(addr: &SocketAddr, handle: &Handle) -> io::Result<Self::FutureSocket> {
-        Ok(TcpStream::connect(addr, handle))
+        Ok(Box::new(TcpStream::connect(addr, handle)))
+    }
+
+    fn connect_from(bind_addr: &SocketAddr, addr: &SocketAddr, handle: &Handle) -> io::Result<Self::FutureSocket> {
+        let builder = match *bind_addr {
+            SocketAddr::V4(_) => try!(TcpBuilder::new_v4()),
+            SocketAddr::V6(_) => try!(TcpBuilder::new_v6())
+        };
+        let stream = try!(builder.bind(bind_addr).and_then(|builder| builder.to_tcp_stream()));
+
+        Ok(TcpStream::connect_stream(stream, addr, handle))
     }
 
     fn listen(addr: &SocketAddr, handle: &Handle) -> io::Result<Self::Listener> {
@@ -63,6 +63,7 @@ extern crate bip_bencode;
 extern crate bip_util;
 extern crate chrono;
 extern crate crossbeam;
+extern crate crypto;
 extern crate walkdir;
 #[macro_use]
 extern crate error_chain;
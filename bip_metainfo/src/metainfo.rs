@@ -1,5 +1,6 @@
 //! Accessing the fields of a MetainfoFile.
 use std::path::{Path, PathBuf};
+use std::str;
 
 use bip_bencode::{Bencode, Dictionary};
 use bip_util::bt::InfoHash;
@@ -78,8 +79,12 @@ fn parse_from_bytes(bytes: &[u8]) -> ParseResult<MetainfoFile> {
     let opt_created_by = parse::parse_created_by(root_dict).map(|e| e.to_owned());
     let opt_creation_date = parse::parse_creation_date(root_dict);
 
-    let info_hash = try!(parse::parse_info_hash(root_dict));
     let info_dict = try!(parse::parse_info_dict(root_dict));
+    let info_hash = if is_v2_only_torrent(info_dict) {
+        try!(parse::parse_v2_info_hash(root_dict))
+    } else {
+        try!(parse::parse_info_hash(root_dict))
+    };
     let info_dictionary = try!(InfoDictionary::new(info_dict));
 
     Ok(MetainfoFile {
@@ -95,6 +100,9 @@ fn parse_from_bytes(bytes: &[u8]) -> ParseResult<MetainfoFile> {
 
 // ----------------------------------------------------------------------------//
 
+/// Size of the blocks that the files of a v2 torrent are hashed in.
+const V2_BLOCK_SIZE: u64 = 16 * 1024;
+
 /// Contains files and checksums for the torrent.
 #[derive(Debug)]
 pub struct InfoDictionary {
@@ -104,12 +112,17 @@ pub struct InfoDictionary {
     is_private:     bool,
     // Present only for multi file torrents.
     file_directory: Option<String>,
+    is_v2_only:     bool,
 }
 
 impl InfoDictionary {
     /// Builds the InfoDictionary from the root bencode of the metainfo file.
     fn new<'a>(info_dict: &Dictionary<'a, Bencode<'a>>) -> ParseResult<InfoDictionary> {
-        parse_from_info_dictionary(info_dict)
+        if is_v2_only_torrent(info_dict) {
+            parse_from_v2_info_dictionary(info_dict)
+        } else {
+            parse_from_info_dictionary(info_dict)
+        }
     }
 
     /// Some file directory if this is a multi-file torrent, otherwise None.
@@ -131,6 +144,16 @@ impl InfoDictionary {
         self.is_private
     }
 
+    /// Whether or not this is a pure v2 (BEP 52) torrent.
+    ///
+    /// Pure v2 torrents have no SHA-1 piece hashes, so every piece yielded by `pieces` is
+    /// zeroed out; pieces have to be checked against the piece layers of the torrent instead.
+    /// Files are laid out with BEP 47 padding files so that, like in v2, every file starts on
+    /// a piece boundary, and the info hash is the v2 info hash truncated to 20 bytes.
+    pub fn is_v2_only(&self) -> bool {
+        self.is_v2_only
+    }
+
     /// Iterator over each of the pieces SHA-1 hash.
     ///
     /// Ordering of pieces yielded in the iterator is guaranteed to be the order in
@@ -177,6 +200,7 @@ fn parse_from_info_dictionary<'a>(info_dict: &Dictionary<'a, Bencode<'a>>)
             piece_len: piece_len,
             is_private: is_private,
             file_directory: Some(file_directory),
+            is_v2_only: false,
         })
     } else {
         let file = try!(File::as_single_file(info_dict));
@@ -187,10 +211,102 @@ fn parse_from_info_dictionary<'a>(info_dict: &Dictionary<'a, Bencode<'a>>)
             piece_len: piece_len,
             is_private: is_private,
             file_directory: None,
+            is_v2_only: false,
         })
     }
 }
 
+/// Parses the given v2 info dictionary and builds an InfoDictionary from it.
+fn parse_from_v2_info_dictionary<'a>(info_dict: &Dictionary<'a, Bencode<'a>>)
+                                     -> ParseResult<InfoDictionary> {
+    let piece_len = try!(parse::parse_piece_length(info_dict));
+    let is_private = parse::parse_private(info_dict);
+    let name = try!(parse::parse_name(info_dict)).to_owned();
+
+    if piece_len < V2_BLOCK_SIZE || !piece_len.is_power_of_two() {
+        let error_msg = format!("Piece Length Of {} Is Invalid For A V2 Torrent", piece_len);
+        return Err(ParseError::from_kind(ParseErrorKind::MissingData { details: error_msg }));
+    }
+
+    let file_tree = try!(parse::parse_file_tree(info_dict));
+    let mut tree_files = Vec::new();
+    try!(collect_file_tree(file_tree, &mut PathBuf::new(), &mut tree_files));
+
+    if tree_files.is_empty() {
+        let error_msg = "File Tree Has No Files".to_owned();
+        return Err(ParseError::from_kind(ParseErrorKind::MissingData { details: error_msg }));
+    }
+
+    // Single file torrents have their file at the root of the tree, under the name of the torrent
+    let file_directory = if tree_files.len() == 1 && tree_files[0].path() == Path::new(&name) {
+        None
+    } else {
+        Some(name)
+    };
+
+    let num_tree_files = tree_files.len();
+    let mut files = Vec::with_capacity(num_tree_files * 2);
+    let mut num_pieces = 0;
+    for (index, file) in tree_files.into_iter().enumerate() {
+        let tail_len = file.length() % piece_len;
+        num_pieces += (file.length() + piece_len - 1) / piece_len;
+
+        files.push(file);
+        if tail_len != 0 && index != num_tree_files - 1 {
+            files.push(File::as_padding_file(piece_len - tail_len));
+        }
+    }
+
+    Ok(InfoDictionary {
+        files: files,
+        pieces: vec![[0u8; sha::SHA_HASH_LEN]; num_pieces as usize],
+        piece_len: piece_len,
+        is_private: is_private,
+        file_directory: file_directory,
+        is_v2_only: true,
+    })
+}
+
+/// Walks the file tree in order, collecting each file found under it.
+fn collect_file_tree<'a>(node_dict: &Dictionary<'a, Bencode<'a>>,
+                         node_path: &mut PathBuf,
+                         files: &mut Vec<File>)
+                         -> ParseResult<()> {
+    // Files are ordered by their path, which is the order of the keys in each dictionary
+    let mut entries = node_dict.to_list();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (key, value) in entries {
+        let child_dict = try!(parse::parse_file_tree_node(value));
+
+        if key.is_empty() {
+            let length = try!(parse::parse_length(child_dict));
+
+            files.push(File {
+                len: length,
+                path: node_path.clone(),
+                md5sum: None,
+            });
+        } else {
+            let name = try!(str::from_utf8(key).map_err(|_| {
+                let error_msg = format!("File Tree Path {:?} Is Not Valid UTF-8", node_path);
+                ParseError::from_kind(ParseErrorKind::MissingData { details: error_msg })
+            }));
+
+            node_path.push(name);
+            try!(collect_file_tree(child_dict, node_path, files));
+            node_path.pop();
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns whether or not this is a v2 torrent without any v1 piece hashes.
+fn is_v2_only_torrent<'a>(info_dict: &Dictionary<'a, Bencode<'a>>) -> bool {
+    parse::parse_meta_version(info_dict) == Some(2) && parse::parse_pieces(info_dict).is_err()
+}
+
 /// Returns whether or not this is a multi file torrent.
 fn is_multi_file_torrent<'a>(info_dict: &Dictionary<'a, Bencode<'a>>) -> bool {
     parse::parse_length(info_dict).is_err()
@@ -262,6 +378,15 @@ impl File {
         })
     }
 
+    /// Generate a BEP 47 padding file of the given length.
+    fn as_padding_file(length: u64) -> File {
+        File {
+            len: length,
+            path: Path::new(".pad").join(length.to_string()),
+            md5sum: None,
+        }
+    }
+
     /// Length of the file in bytes.
     pub fn length(&self) -> u64 {
         self.len
@@ -284,10 +409,13 @@ impl File {
 mod tests {
     use std::collections::BTreeMap;
     use std::path::{Path, PathBuf};
+use std::str;
 
     use bip_bencode::Bencode;
     use bip_util::sha;
     use bip_util::bt::InfoHash;
+    use crypto::digest::Digest;
+    use crypto::sha2::Sha256;
 
     use metainfo::MetainfoFile;
     use parse;
@@ -769,4 +897,58 @@ mod tests {
                                    None,
                                    Some(vec![(Some(file_len), None, None)]));
     }
+
+    /// Bencode a v2 file tree node holding a file of the given length.
+    fn v2_file_node<'a>(length: i64) -> Bencode<'a> {
+        let mut file_dict = BTreeMap::new();
+        file_dict.insert(parse::LENGTH_KEY, ben_int!(length));
+
+        let mut node_dict = BTreeMap::new();
+        node_dict.insert(&b""[..], Bencode::Dict(file_dict));
+
+        Bencode::Dict(node_dict)
+    }
+
+    #[test]
+    fn positive_parse_from_v2_multi_file() {
+        let piece_len = 16 * 1024;
+
+        let mut sub_dir = BTreeMap::new();
+        sub_dir.insert(&b"c"[..], v2_file_node(piece_len + 1));
+
+        let mut file_tree = BTreeMap::new();
+        file_tree.insert(&b"b"[..], Bencode::Dict(sub_dir));
+        file_tree.insert(&b"a"[..], v2_file_node(100));
+
+        let mut info_dict = BTreeMap::new();
+        info_dict.insert(parse::META_VERSION_KEY, ben_int!(2));
+        info_dict.insert(parse::NAME_KEY, ben_bytes!("dummy_directory"));
+        info_dict.insert(parse::PIECE_LENGTH_KEY, ben_int!(piece_len));
+        info_dict.insert(parse::FILE_TREE_KEY, Bencode::Dict(file_tree));
+        let bencode_info_dict = Bencode::Dict(info_dict);
+
+        let mut v2_hash = [0u8; 32];
+        let mut hasher = Sha256::new();
+        hasher.input(&bencode_info_dict.encode());
+        hasher.result(&mut v2_hash);
+
+        let mut root_dict = BTreeMap::new();
+        root_dict.insert(parse::INFO_KEY, bencode_info_dict);
+
+        let metainfo_file = MetainfoFile::from_bytes(Bencode::Dict(root_dict).encode()).unwrap();
+
+        assert_eq!(metainfo_file.info_hash(), InfoHash::from_hash(&v2_hash[..sha::SHA_HASH_LEN]).unwrap());
+        assert!(metainfo_file.info().is_v2_only());
+        assert_eq!(metainfo_file.info().directory(), Some("dummy_directory"));
+        assert_eq!(metainfo_file.info().pieces().count(), 3);
+
+        let files: Vec<(u64, PathBuf)> = metainfo_file.info()
+            .files()
+            .map(|file| (file.length(), file.path().to_path_buf()))
+            .collect();
+        assert_eq!(files,
+                   vec![(100, PathBuf::from("a")),
+                        (piece_len as u64 - 100, Path::new(".pad").join((piece_len - 100).to_string())),
+                        (piece_len as u64 + 1, Path::new("b").join("c"))]);
+    }
 }
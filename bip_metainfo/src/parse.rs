@@ -1,5 +1,8 @@
 use bip_bencode::{Bencode, Dictionary, BencodeConvert, BencodeConvertError};
 use bip_util::bt::InfoHash;
+use bip_util::sha;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 
 use error::{ParseError, ParseResult};
 
@@ -34,6 +37,8 @@ pub const PIECES_KEY: &'static [u8] = b"pieces";
 pub const PRIVATE_KEY: &'static [u8] = b"private";
 pub const NAME_KEY: &'static [u8] = b"name";
 pub const FILES_KEY: &'static [u8] = b"files";
+pub const META_VERSION_KEY: &'static [u8] = b"meta version";
+pub const FILE_TREE_KEY: &'static [u8] = b"file tree";
 
 /// Keys found within the files dictionary of a metainfo file.
 pub const LENGTH_KEY: &'static [u8] = b"length";
@@ -85,6 +90,23 @@ pub fn parse_info_hash<'a>(root_dict: &Dictionary<'a, Bencode<'a>>) -> ParseResu
     Ok(InfoHash::from_bytes(&encoded_info_dict))
 }
 
+/// Parses the v2 info hash, truncated to the length of a v1 info hash, from the root dictionary.
+pub fn parse_v2_info_hash<'a>(root_dict: &Dictionary<'a, Bencode<'a>>) -> ParseResult<InfoHash> {
+    let info_dict_bencode = try!(CONVERT.lookup(root_dict, INFO_KEY));
+    let encoded_info_dict = info_dict_bencode.encode();
+
+    let mut hasher = Sha256::new();
+    hasher.input(&encoded_info_dict);
+
+    let mut v2_hash = [0u8; 32];
+    hasher.result(&mut v2_hash);
+
+    let mut info_hash = [0u8; sha::SHA_HASH_LEN];
+    info_hash.copy_from_slice(&v2_hash[..sha::SHA_HASH_LEN]);
+
+    Ok(info_hash.into())
+}
+
 // ----------------------------------------------------------------------------//
 
 /// Parses the piece length from the info dictionary.
@@ -107,6 +129,17 @@ pub fn parse_name<'a>(info_dict: &Dictionary<'a, Bencode<'a>>) -> ParseResult<&'
     CONVERT.lookup_and_convert_str(info_dict, NAME_KEY)
 }
 
+/// Parses the meta version from the info dictionary.
+pub fn parse_meta_version<'a>(info_dict: &Dictionary<'a, Bencode<'a>>) -> Option<i64> {
+    CONVERT.lookup_and_convert_int(info_dict, META_VERSION_KEY).ok()
+}
+
+/// Parses the file tree from the info dictionary.
+pub fn parse_file_tree<'a, 'b>(info_dict: &'b Dictionary<'a, Bencode<'a>>)
+                               -> ParseResult<&'b Dictionary<'a, Bencode<'a>>> {
+    CONVERT.lookup_and_convert_dict(info_dict, FILE_TREE_KEY)
+}
+
 /// Parses the files list from the info dictionary.
 pub fn parse_files_list<'a, 'b>(info_dict: &'b Dictionary<'a, Bencode<'a>>)
                                 -> ParseResult<&'b [Bencode<'a>]> {
@@ -121,6 +154,12 @@ pub fn parse_file_dict<'a, 'b>(file_bencode: &'b Bencode<'a>)
     CONVERT.convert_dict(file_bencode, FILES_KEY)
 }
 
+/// Parses a directory or file node from the file tree.
+pub fn parse_file_tree_node<'a, 'b>(node_bencode: &'b Bencode<'a>)
+                                    -> ParseResult<&'b Dictionary<'a, Bencode<'a>>> {
+    CONVERT.convert_dict(node_bencode, FILE_TREE_KEY)
+}
+
 /// Parses the length from the info or file dictionary.
 pub fn parse_length<'a>(info_or_file_dict: &Dictionary<'a, Bencode<'a>>) -> ParseResult<u64> {
     CONVERT.lookup_and_convert_int(info_or_file_dict, LENGTH_KEY).map(|len| len as u64)
@@ -0,0 +1,113 @@
+use std::default::Default;
+
+use disk::durability::DurabilityMode;
+use disk::location::DownloadLocation;
+use disk::preallocation::PreallocationMode;
+use disk::verification::VerificationOrder;
+
+// Enough to hold a handful of pieces for most torrents, which is what a popular piece being seeded looks like.
+const DEFAULT_READ_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Configures the internals of a `DiskManager`.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct DiskConfig {
+    preallocation: PreallocationMode,
+    read_cache_size: usize,
+    durability: DurabilityMode,
+    verification: VerificationOrder,
+    location: DownloadLocation,
+    max_active_torrents: Option<usize>,
+    verify_on_complete: bool,
+}
+
+impl DiskConfig {
+    /// Sets how files are allocated on disk when a torrent is added.
+    pub fn set_preallocation_mode(&mut self, preallocation: PreallocationMode) {
+        self.preallocation = preallocation;
+    }
+
+    /// Gets the preallocation mode.
+    pub fn preallocation_mode(&self) -> PreallocationMode {
+        self.preallocation
+    }
+
+    /// Sets the maximum number of bytes worth of pieces that will be
+    /// cached in memory for serving blocks to peers; zero disables the cache.
+    pub fn set_read_cache_size(&mut self, bytes: usize) {
+        self.read_cache_size = bytes;
+    }
+
+    /// Gets the read cache size.
+    pub fn read_cache_size(&self) -> usize {
+        self.read_cache_size
+    }
+
+    /// Sets when verified pieces are synced to stable storage.
+    pub fn set_durability_mode(&mut self, durability: DurabilityMode) {
+        self.durability = durability;
+    }
+
+    /// Gets the durability mode.
+    pub fn durability_mode(&self) -> DurabilityMode {
+        self.durability
+    }
+
+    /// Sets the order in which pieces are verified when several complete at once.
+    pub fn set_verification_order(&mut self, verification: VerificationOrder) {
+        self.verification = verification;
+    }
+
+    /// Gets the verification order.
+    pub fn verification_order(&self) -> VerificationOrder {
+        self.verification
+    }
+
+    /// Sets where the files for torrents are placed within the file system.
+    pub fn set_download_location(&mut self, location: DownloadLocation) {
+        self.location = location;
+    }
+
+    /// Gets the download location.
+    pub fn download_location(&self) -> &DownloadLocation {
+        &self.location
+    }
+
+    /// Sets the maximum number of torrents that can be added to the disk manager at once; unlimited by default.
+    ///
+    /// Torrents added past the limit are rejected with a `TorrentErrorKind::MaxActiveTorrents` error.
+    pub fn set_max_active_torrents(&mut self, opt_max: Option<usize>) {
+        self.max_active_torrents = opt_max;
+    }
+
+    /// Gets the maximum number of active torrents.
+    pub fn max_active_torrents(&self) -> Option<usize> {
+        self.max_active_torrents
+    }
+
+    /// Sets whether or not every piece is hashed again, straight from disk, once a torrent appears complete; off by default.
+    ///
+    /// Pieces that fail the second check are reported with `ODiskMessage::FoundBadPiece` so that they are
+    /// downloaded again, and `ODiskMessage::TorrentComplete` is held back until every piece passes.
+    pub fn set_verify_on_complete(&mut self, verify: bool) {
+        self.verify_on_complete = verify;
+    }
+
+    /// Gets whether or not torrents are verified on completion.
+    pub fn verify_on_complete(&self) -> bool {
+        self.verify_on_complete
+    }
+}
+
+impl Default for DiskConfig {
+    fn default() -> DiskConfig {
+        DiskConfig {
+            preallocation: PreallocationMode::default(),
+            read_cache_size: DEFAULT_READ_CACHE_SIZE,
+            durability: DurabilityMode::default(),
+            verification: VerificationOrder::default(),
+            location: DownloadLocation::default(),
+            max_active_torrents: None,
+            verify_on_complete: false,
+        }
+    }
+}
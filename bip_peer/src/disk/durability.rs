@@ -0,0 +1,24 @@
+use std::time::Duration;
+
+/// How pieces are flushed through to stable storage once they have been verified.
+///
+/// Without syncing, a verified piece may still be sitting in the OS page cache when the
+/// process (or machine) goes down, in which case it would have to be downloaded again.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DurabilityMode {
+    /// Leave it up to the OS to decide when data is written out.
+    None,
+    /// Sync the files for each piece as soon as it is verified.
+    PerPiece,
+    /// Sync the files for all pieces verified since the last sync, at most once per interval.
+    ///
+    /// The interval is checked as pieces are verified, so the last few pieces of a torrent may
+    /// not be synced until the torrent is removed.
+    Periodic(Duration)
+}
+
+impl Default for DurabilityMode {
+    fn default() -> DurabilityMode {
+        DurabilityMode::None
+    }
+}
@@ -1,4 +1,5 @@
 use std::io;
+use std::path::PathBuf;
 
 use bip_util::bt::InfoHash;
 
@@ -19,12 +20,36 @@ error_chain! {
 
     errors {
         ExistingFileSizeCheck {
-            file_path:     String,
+            file_path:     PathBuf,
             expected_size: u64,
             actual_size:   u64
         } {
             description("Failed To Add Torrent Because Size Checker Failed For A File")
-            display("Failed To Add Torrent Because Size Checker Failed For {} Where File Size Was {} But Should Have Been {}", file_path, actual_size, expected_size)
+            display("Failed To Add Torrent Because Size Checker Failed For {} Where File Size Was {} But Should Have Been {}", file_path.display(), actual_size, expected_size)
+        }
+        FileSizeChanged {
+            file_path:     PathBuf,
+            offset:        u64,
+            expected_size: u64,
+            actual_size:   u64
+        } {
+            description("Failed To Access File Because Its Size Changed After The Size Check")
+            display("Failed To Access {} At Offset {} Where File Size Was {} But Should Have Been {}", file_path.display(), offset, actual_size, expected_size)
+        }
+        IncompleteAccess {
+            file_path:      PathBuf,
+            offset:         u64,
+            expected_bytes: u64,
+            actual_bytes:   u64
+        } {
+            description("Failed To Access File Because It Ended Before All Bytes Were Accessed")
+            display("Failed To Access {} At Offset {} Where Only {} Of {} Bytes Were Accessed", file_path.display(), offset, actual_bytes, expected_bytes)
+        }
+        ReadHashMismatch {
+            piece_index: u32
+        } {
+            description("Failed To Read Piece Because Its Data No Longer Matches Its Hash")
+            display("Failed To Read Piece {} Because Its Data No Longer Matches Its Hash", piece_index)
         }
         ExistingInfoHash {
             hash: InfoHash
@@ -32,11 +57,63 @@ error_chain! {
             description("Failed To Add Torrent Because Another Torrent With The Same InfoHash Is Already Added")
             display("Failed To Add Torrent Because Another Torrent With The Same InfoHash {:?} Is Already Added", hash)
         }
+        MaxActiveTorrents {
+            max_active: usize
+        } {
+            description("Failed To Add Torrent Because The Maximum Number Of Active Torrents Was Reached")
+            display("Failed To Add Torrent Because The Maximum Number Of Active Torrents ({}) Was Reached", max_active)
+        }
         InfoHashNotFound {
             hash: InfoHash
         } {
             description("Failed To Remove Torrent Because It Is Not Currently Added")
             display("Failed To Remove Torrent Because The InfoHash {:?} It Is Not Currently Added", hash)
         }
+        InvalidRange {
+            offset:       u64,
+            length:       u64,
+            total_length: u64
+        } {
+            description("Failed To Read Range Because It Extends Past The End Of The Torrent")
+            display("Failed To Read {} Bytes At Offset {} Because The Torrent Is Only {} Bytes", length, offset, total_length)
+        }
+        UnverifiedRange {
+            offset:      u64,
+            length:      u64,
+            piece_index: u32
+        } {
+            description("Failed To Read Range Because A Piece Covering It Has Not Been Verified")
+            display("Failed To Read {} Bytes At Offset {} Because Piece {} Has Not Been Verified", length, offset, piece_index)
+        }
+        PieceCountMismatch {
+            expected_pieces: u64,
+            actual_pieces:   u64
+        } {
+            description("Failed To Add Torrent Because Its Piece Hashes Do Not Cover Its Files")
+            display("Failed To Add Torrent Because Its Files Span {} Pieces But It Has {} Piece Hashes", expected_pieces, actual_pieces)
+        }
+        InvalidPieceLayers {
+            description("Failed To Parse Piece Layers Because They Were Malformed Or Did Not Match Their Files")
+            display("Failed To Parse Piece Layers Because They Were Malformed Or Did Not Match Their Files")
+        }
+        PieceLayersMismatch {
+            expected_pieces: u64,
+            actual_pieces:   u64
+        } {
+            description("Failed To Add Torrent Because Its Piece Layers Do Not Cover Its Pieces")
+            display("Failed To Add Torrent Because It Has {} Pieces But Its Piece Layers Cover {}", expected_pieces, actual_pieces)
+        }
+        MissingPieceLayers {
+            hash: InfoHash
+        } {
+            description("Failed To Add Torrent Because It Is A Pure V2 Torrent And No Piece Layers Were Given")
+            display("Failed To Add Torrent With InfoHash {:?} Because It Is A Pure V2 Torrent And No Piece Layers Were Given", hash)
+        }
+        InvalidResumeData {
+            hash: InfoHash
+        } {
+            description("Failed To Load Resume Data Because It Was Malformed Or For A Different Torrent")
+            display("Failed To Load Resume Data For InfoHash {:?} Because It Was Malformed Or For A Different Torrent", hash)
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::io;
+
+use disk::fs::FileSystem;
+
+/// File system that wraps another file system, keeping recently used files open.
+///
+/// Opening a file that is already open hands back the same underlying file, rather than opening it again,
+/// so sustained transfers don't pay for an open on every block. At most `max_open` files are kept open; once
+/// that is exceeded, the least recently opened file is closed, as soon as nobody is still using it.
+pub struct CachedFileSystem<F> where F: FileSystem {
+    fs:       F,
+    max_open: usize,
+    cache:    Mutex<FileCache<F::File>>
+}
+
+struct FileCache<T> {
+    files: HashMap<PathBuf, (Arc<Mutex<T>>, u64)>,
+    clock: u64
+}
+
+/// File that may be shared with other openers of the same path.
+pub struct CachedFile<T> {
+    path: Option<PathBuf>,
+    file: Arc<Mutex<T>>
+}
+
+impl<F> CachedFileSystem<F> where F: FileSystem {
+    /// Create a new CachedFileSystem wrapping the given file system, keeping at most max_open files open.
+    ///
+    /// Panics if max_open is zero.
+    pub fn new(fs: F, max_open: usize) -> CachedFileSystem<F> {
+        if max_open == 0 {
+            panic!("bip_peer: CachedFileSystem Max Open Files Must Be Non Zero")
+        }
+
+        CachedFileSystem{ fs: fs, max_open: max_open, cache: Mutex::new(FileCache{ files: HashMap::new(), clock: 0 }) }
+    }
+
+    /// Number of files currently held open by the cache.
+    pub fn open_files(&self) -> usize {
+        self.lock_cache().files.len()
+    }
+
+    fn lock_cache(&self) -> MutexGuard<FileCache<F::File>> {
+        self.cache.lock().expect("bip_peer: CachedFileSystem Failed To Lock Cache")
+    }
+}
+
+impl<F> FileSystem for CachedFileSystem<F> where F: FileSystem {
+    type File = CachedFile<F::File>;
+
+    fn open_file<P>(&self, opt_path: Option<P>) -> io::Result<Self::File>
+        where P: AsRef<Path> {
+        let path = match opt_path {
+            Some(path) => path.as_ref().to_path_buf(),
+            // Scratch files get a new name every time, so there is nothing to share
+            None       => return self.fs.open_file(None::<&Path>).map(|file| CachedFile{ path: None, file: Arc::new(Mutex::new(file)) })
+        };
+
+        let mut cache = self.lock_cache();
+        cache.clock += 1;
+        let clock = cache.clock;
+
+        if let Some(&mut (ref file, ref mut last_used)) = cache.files.get_mut(&path) {
+            *last_used = clock;
+
+            return Ok(CachedFile{ path: Some(path.clone()), file: file.clone() });
+        }
+
+        let file = Arc::new(Mutex::new(try!(self.fs.open_file(Some(&path)))));
+        cache.files.insert(path.clone(), (file.clone(), clock));
+
+        // Evicting only drops our reference, the file is closed once any outstanding references are dropped
+        if cache.files.len() > self.max_open {
+            let opt_oldest = cache.files.iter()
+                .min_by_key(|&(_, &(_, last_used))| last_used)
+                .map(|(path, _)| path.clone());
+
+            if let Some(oldest) = opt_oldest {
+                cache.files.remove(&oldest);
+            }
+        }
+
+        Ok(CachedFile{ path: Some(path), file: file })
+    }
+
+    fn file_size(&self, file: &Self::File) -> io::Result<u64> {
+        self.fs.file_size(&*lock_file(file))
+    }
+
+    fn remove_file(&self, file: Self::File) -> io::Result<()> {
+        if let Some(ref path) = file.path {
+            self.lock_cache().files.remove(path);
+        }
+
+        match Arc::try_unwrap(file.file) {
+            Ok(inner) => self.fs.remove_file(inner.into_inner().expect("bip_peer: CachedFileSystem Failed To Lock File")),
+            Err(_)    => Err(io::Error::new(io::ErrorKind::Other, "File Is Still In Use"))
+        }
+    }
+
+    fn read_file(&self, file: &mut Self::File, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        self.fs.read_file(&mut *lock_file(file), offset, buffer)
+    }
+
+    fn write_file(&self, file: &mut Self::File, offset: u64, buffer: &[u8]) -> io::Result<usize> {
+        self.fs.write_file(&mut *lock_file(file), offset, buffer)
+    }
+
+    fn sync_file(&self, file: &mut Self::File) -> io::Result<()> {
+        self.fs.sync_file(&mut *lock_file(file))
+    }
+}
+
+fn lock_file<T>(file: &CachedFile<T>) -> MutexGuard<T> {
+    file.file.lock().expect("bip_peer: CachedFileSystem Failed To Lock File")
+}
+
+#[cfg(test)]
+mod tests {
+    use disk::fs::FileSystem;
+    use disk::fs::memory::InMemoryFileSystem;
+    use super::CachedFileSystem;
+
+    #[test]
+    fn positive_open_files_capped() {
+        let fs = CachedFileSystem::new(InMemoryFileSystem::new(), 2);
+
+        for path in &["a", "b", "a", "c"] {
+            fs.open_file(Some(path)).unwrap();
+        }
+
+        // File b was the least recently opened, so it was closed to make room for c
+        assert_eq!(2, fs.open_files());
+        assert!(fs.lock_cache().files.keys().all(|path| path.to_str() != Some("b")));
+    }
+
+    #[test]
+    fn negative_remove_file_still_in_use() {
+        let fs = CachedFileSystem::new(InMemoryFileSystem::new(), 2);
+
+        let file = fs.open_file(Some("a")).unwrap();
+        let other_file = fs.open_file(Some("a")).unwrap();
+
+        assert!(fs.remove_file(file).is_err());
+        assert!(fs.remove_file(other_file).is_ok());
+        assert_eq!(0, fs.open_files());
+    }
+}
@@ -1,2 +1,124 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::cmp;
+use std::io;
+
+use rand;
+
+use disk::fs::FileSystem;
+
 /// File system that stores all data in memory.
-pub struct MemFileSystem { }
\ No newline at end of file
+///
+/// Useful for testing components that are generic over a FileSystem without touching the disk.
+pub struct InMemoryFileSystem {
+    files: Mutex<HashMap<String, Vec<u8>>>
+}
+
+/// File that exists in memory.
+pub struct InMemoryFile {
+    path: String
+}
+
+impl InMemoryFileSystem {
+    /// Create a new, empty, InMemoryFileSystem.
+    pub fn new() -> InMemoryFileSystem {
+        InMemoryFileSystem{ files: Mutex::new(HashMap::new()) }
+    }
+
+    /// Run the given closure with the contents of the file at the given path, if it exists.
+    pub fn run_with_file<P, C, R>(&self, path: P, callback: C) -> Option<R>
+        where P: AsRef<Path>,
+              C: FnOnce(&mut Vec<u8>) -> R {
+        let mut files = self.files.lock()
+            .expect("bip_peer: InMemoryFileSystem Failed To Lock Files");
+
+        files.get_mut(&path_to_key(path)).map(callback)
+    }
+}
+
+impl FileSystem for InMemoryFileSystem {
+    type File = InMemoryFile;
+
+    fn open_file<P>(&self, opt_path: Option<P>) -> io::Result<Self::File>
+        where P: AsRef<Path> {
+        let mut files = self.files.lock()
+            .expect("bip_peer: InMemoryFileSystem Failed To Lock Files");
+
+        let path = match opt_path {
+            Some(path) => path_to_key(path),
+            None       => {
+                // Keep generating names until we find one not in use
+                let mut scratch_path = format!("{:08X}", rand::random::<u32>());
+                while files.contains_key(&scratch_path) {
+                    scratch_path = format!("{:08X}", rand::random::<u32>());
+                }
+
+                scratch_path
+            }
+        };
+        files.entry(path.clone()).or_insert(Vec::new());
+
+        Ok(InMemoryFile{ path: path })
+    }
+
+    fn file_size(&self, file: &InMemoryFile) -> io::Result<u64> {
+        self.run_with_file(&file.path, |bytes| bytes.len() as u64)
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, "File Not Found"))
+    }
+
+    fn remove_file(&self, file: InMemoryFile) -> io::Result<()> {
+        let mut files = self.files.lock()
+            .expect("bip_peer: InMemoryFileSystem Failed To Lock Files");
+
+        files.remove(&file.path)
+            .map(|_| ())
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, "File Not Found"))
+    }
+
+    fn read_file(&self, file: &mut InMemoryFile, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        self.run_with_file(&file.path, |bytes| {
+                if offset >= bytes.len() as u64 {
+                    return 0
+                }
+
+                let offset = offset as usize;
+                let bytes_to_read = cmp::min(buffer.len(), bytes.len() - offset);
+                buffer[..bytes_to_read].copy_from_slice(&bytes[offset..offset + bytes_to_read]);
+
+                bytes_to_read
+            })
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, "File Not Found"))
+    }
+
+    fn write_file(&self, file: &mut InMemoryFile, offset: u64, buffer: &[u8]) -> io::Result<usize> {
+        self.run_with_file(&file.path, |bytes| {
+                let offset = offset as usize;
+                let end = offset + buffer.len();
+
+                if end > bytes.len() {
+                    bytes.resize(end, 0);
+                }
+                bytes[offset..end].copy_from_slice(buffer);
+
+                buffer.len()
+            })
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, "File Not Found"))
+    }
+
+    fn sync_file(&self, file: &mut InMemoryFile) -> io::Result<()> {
+        // Nothing to persist, but syncing a removed file should still fail like it would on disk
+        self.run_with_file(&file.path, |_| ())
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, "File Not Found"))
+    }
+}
+
+/// Normalize the path so that the same file is found regardless of separator.
+fn path_to_key<P>(path: P) -> String
+    where P: AsRef<Path> {
+    path.as_ref()
+        .iter()
+        .map(|piece| piece.to_string_lossy().into_owned())
+        .collect::<Vec<String>>()
+        .join("/")
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::fs::{self, File};
+use std::cmp;
+use std::io;
+
+use memmap::{Mmap, Protection};
+
+use disk::fs::FileSystem;
+use disk::fs::native::NativeFileSystem;
+
+/// File system that maps files into memory, letting the OS page cache handle reads and writes.
+///
+/// Mappings are shared between every open handle to the same file and are flushed whenever
+/// a file is grown, as well as when the last handle to the file is dropped, at which point the
+/// mapping is released. Wrap this in a `CachedFileSystem` to keep mappings around between accesses.
+///
+/// Files are checked against our mapping before every access, so that a file truncated underneath
+/// us is read short, rather than faulting on pages past its end; however, a file truncated in the
+/// middle of an access can still fault, so files should not be modified outside of this file system.
+pub struct MmapFileSystem {
+    native: NativeFileSystem,
+    mapped: Arc<Mutex<MappedFiles>>
+}
+
+type MappedFiles = HashMap<PathBuf, Arc<Mutex<MappedFile>>>;
+
+/// File that is mapped into memory.
+pub struct MmapFile {
+    mapped: Arc<Mutex<MappedFile>>,
+    files:  Arc<Mutex<MappedFiles>>,
+    path:   PathBuf
+}
+
+struct MappedFile {
+    file: File,
+    // Zero length files can not be mapped
+    mmap: Option<Mmap>
+}
+
+impl MappedFile {
+    fn new(file: File) -> io::Result<MappedFile> {
+        let mut mapped_file = MappedFile{ file: file, mmap: None };
+        try!(mapped_file.remap());
+
+        Ok(mapped_file)
+    }
+
+    /// Flush any outstanding writes and map the file again at its current size.
+    fn remap(&mut self) -> io::Result<()> {
+        if let Some(mut mmap) = self.mmap.take() {
+            try!(mmap.flush());
+        }
+
+        let file_size = try!(self.file.metadata()).len();
+        if file_size != 0 {
+            self.mmap = Some(try!(Mmap::open(&self.file, Protection::ReadWrite)));
+        }
+
+        Ok(())
+    }
+
+    /// Map the file again if its size no longer matches our mapping, returning the current size of the file.
+    ///
+    /// This happens when the file was truncated or grown by someone other than us.
+    fn check_mapping(&mut self) -> io::Result<u64> {
+        let file_size = try!(self.file.metadata()).len();
+        let mapped_size = self.mmap.as_ref().map_or(0, |mmap| mmap.len() as u64);
+
+        if file_size != mapped_size {
+            try!(self.remap());
+        }
+
+        Ok(file_size)
+    }
+
+    /// Grow the file, and our mapping, so that it is at least the given size.
+    fn ensure_size(&mut self, size: u64) -> io::Result<()> {
+        let file_size = try!(self.check_mapping());
+
+        if size > file_size {
+            try!(self.file.set_len(size));
+            try!(self.remap());
+        }
+
+        Ok(())
+    }
+
+    fn read(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        try!(self.check_mapping());
+
+        let mapped_bytes = match self.mmap {
+            Some(ref mmap) => unsafe { mmap.as_slice() },
+            None           => return Ok(0)
+        };
+        if offset >= mapped_bytes.len() as u64 {
+            return Ok(0)
+        }
+
+        let offset = offset as usize;
+        let bytes_to_read = cmp::min(buffer.len(), mapped_bytes.len() - offset);
+        buffer[..bytes_to_read].copy_from_slice(&mapped_bytes[offset..offset + bytes_to_read]);
+
+        Ok(bytes_to_read)
+    }
+
+    fn write(&mut self, offset: u64, buffer: &[u8]) -> io::Result<usize> {
+        if buffer.is_empty() {
+            return Ok(0)
+        }
+        try!(self.ensure_size(offset + buffer.len() as u64));
+
+        let mapped_bytes = unsafe {
+            self.mmap.as_mut()
+                .expect("bip_peer: MmapFileSystem Failed To Map Non Empty File")
+                .as_mut_slice()
+        };
+        let offset = offset as usize;
+        mapped_bytes[offset..offset + buffer.len()].copy_from_slice(buffer);
+
+        Ok(buffer.len())
+    }
+
+    /// Flush the mapping, followed by the file itself so that any change in size is persisted.
+    fn sync(&mut self) -> io::Result<()> {
+        if let Some(ref mut mmap) = self.mmap {
+            try!(mmap.flush());
+        }
+
+        self.file.sync_all()
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if let Some(ref mut mmap) = self.mmap {
+            // Nothing we can do about a failure here, callers that need to know should not rely on drop
+            let _ = mmap.flush();
+        }
+    }
+}
+
+impl MmapFileSystem {
+    /// Initialize a new MmapFileSystem with the default directory set.
+    pub fn with_directory<P>(default: P) -> MmapFileSystem
+        where P: AsRef<Path> {
+        MmapFileSystem{ native: NativeFileSystem::with_directory(default), mapped: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Number of files currently mapped into memory.
+    pub fn mapped_files(&self) -> usize {
+        self.mapped.lock()
+            .expect("bip_peer: MmapFileSystem Failed To Lock Mapped Files")
+            .len()
+    }
+}
+
+impl Drop for MmapFile {
+    fn drop(&mut self) {
+        let mut files = self.files.lock()
+            .expect("bip_peer: MmapFileSystem Failed To Lock Mapped Files");
+
+        // Handles are only cloned while holding the lock, so if only the map and us hold on to the mapping, we are the last handle
+        let is_last_handle = files.get(&self.path).map_or(false, |mapped| {
+            Arc::ptr_eq(mapped, &self.mapped) && Arc::strong_count(&self.mapped) == 2
+        });
+
+        if is_last_handle {
+            files.remove(&self.path);
+        }
+    }
+}
+
+impl FileSystem for MmapFileSystem {
+    type File = MmapFile;
+
+    fn open_file<P>(&self, opt_path: Option<P>) -> io::Result<Self::File>
+        where P: AsRef<Path> {
+        let native_file = try!(self.native.open_file(opt_path));
+        let path = native_file.path().to_path_buf();
+
+        let mut mapped = self.mapped.lock()
+            .expect("bip_peer: MmapFileSystem Failed To Lock Mapped Files");
+        let mapped_file = match mapped.entry(path.clone()) {
+            Entry::Occupied(occ) => occ.get().clone(),
+            Entry::Vacant(vac)   => {
+                let mapped_file = try!(MappedFile::new(native_file.into_file()));
+
+                vac.insert(Arc::new(Mutex::new(mapped_file))).clone()
+            }
+        };
+
+        Ok(MmapFile{ mapped: mapped_file, files: self.mapped.clone(), path: path })
+    }
+
+    fn file_size(&self, file: &MmapFile) -> io::Result<u64> {
+        let mapped_file = file.mapped.lock()
+            .expect("bip_peer: MmapFileSystem Failed To Lock Mapped File");
+
+        mapped_file.file.metadata().map(|metadata| metadata.len())
+    }
+
+    fn remove_file(&self, file: MmapFile) -> io::Result<()> {
+        self.mapped.lock()
+            .expect("bip_peer: MmapFileSystem Failed To Lock Mapped Files")
+            .remove(&file.path);
+
+        fs::remove_file(&file.path)
+    }
+
+    fn read_file(&self, file: &mut MmapFile, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut mapped_file = file.mapped.lock()
+            .expect("bip_peer: MmapFileSystem Failed To Lock Mapped File");
+
+        mapped_file.read(offset, buffer)
+    }
+
+    fn write_file(&self, file: &mut MmapFile, offset: u64, buffer: &[u8]) -> io::Result<usize> {
+        let mut mapped_file = file.mapped.lock()
+            .expect("bip_peer: MmapFileSystem Failed To Lock Mapped File");
+
+        mapped_file.write(offset, buffer)
+    }
+
+    fn sync_file(&self, file: &mut MmapFile) -> io::Result<()> {
+        let mut mapped_file = file.mapped.lock()
+            .expect("bip_peer: MmapFileSystem Failed To Lock Mapped File");
+
+        mapped_file.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::path::PathBuf;
+
+    use disk::fs::FileSystem;
+    use super::MmapFileSystem;
+
+    /// Fresh directory, unique to the given test, for the file system to work in.
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = env::temp_dir().join(format!("bip_peer_mmap_{}_{}", name, ::std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        directory
+    }
+
+    #[test]
+    fn positive_truncated_file_read_short() {
+        let directory = test_directory("truncated");
+        let fs = MmapFileSystem::with_directory(&directory);
+
+        let mut file = fs.open_file(Some("a")).unwrap();
+        assert_eq!(8192, fs.write_file(&mut file, 0, &[5u8; 8192]).unwrap());
+
+        OpenOptions::new().write(true).open(directory.join("a")).unwrap().set_len(100).unwrap();
+
+        let mut buffer = [0u8; 8192];
+        assert_eq!(0, fs.read_file(&mut file, 4096, &mut buffer).unwrap());
+        assert_eq!(100, fs.read_file(&mut file, 0, &mut buffer).unwrap());
+        assert!(buffer[..100].iter().all(|&byte| byte == 5));
+
+        drop(file);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn positive_write_past_externally_grown_mapping() {
+        let directory = test_directory("grown");
+        let fs = MmapFileSystem::with_directory(&directory);
+
+        let mut file = fs.open_file(Some("a")).unwrap();
+        fs.write_file(&mut file, 0, &[5u8; 100]).unwrap();
+
+        OpenOptions::new().write(true).open(directory.join("a")).unwrap().set_len(8192).unwrap();
+
+        assert_eq!(100, fs.write_file(&mut file, 8000, &[6u8; 100]).unwrap());
+        assert_eq!(8192, fs.file_size(&file).unwrap());
+
+        let mut buffer = [0u8; 100];
+        assert_eq!(100, fs.read_file(&mut file, 8000, &mut buffer).unwrap());
+        assert!(buffer.iter().all(|&byte| byte == 6));
+
+        drop(file);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn positive_mapping_released_with_last_handle() {
+        let directory = test_directory("released");
+        let fs = MmapFileSystem::with_directory(&directory);
+
+        let mut file = fs.open_file(Some("a")).unwrap();
+        let other_file = fs.open_file(Some("a")).unwrap();
+        fs.write_file(&mut file, 0, &[5u8; 100]).unwrap();
+        assert_eq!(1, fs.mapped_files());
+
+        drop(file);
+        assert_eq!(1, fs.mapped_files());
+
+        drop(other_file);
+        assert_eq!(0, fs.mapped_files());
+
+        // Re-opening maps the file again, with what we wrote before
+        let mut file = fs.open_file(Some("a")).unwrap();
+        let mut buffer = [0u8; 100];
+        assert_eq!(100, fs.read_file(&mut file, 0, &mut buffer).unwrap());
+        assert!(buffer.iter().all(|&byte| byte == 5));
+
+        drop(file);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,8 +1,11 @@
 use std::path::{Path};
 use std::io::{self};
 
+pub mod cache;
 pub mod memory;
+pub mod mmap;
 pub mod native;
+pub mod read_only;
 
 /// Trait for performing operations on some file system.
 ///
@@ -34,6 +37,11 @@ pub trait FileSystem {
     /// On success, return the number of bytes written. If offset is
     /// past the current size of the file, zeroes will be filled in.
     fn write_file(&self, file: &mut Self::File, offset: u64, buffer: &[u8]) -> io::Result<usize>;
+
+    /// Flush any writes to the file through to stable storage.
+    ///
+    /// Returns once the data, and the metadata needed to read it back, has been persisted.
+    fn sync_file(&self, file: &mut Self::File) -> io::Result<()>;
 }
 
 impl<'a, F> FileSystem for &'a F where F: FileSystem {
@@ -59,4 +67,8 @@ impl<'a, F> FileSystem for &'a F where F: FileSystem {
     fn write_file(&self, file: &mut Self::File, offset: u64, buffer: &[u8]) -> io::Result<usize> {
         FileSystem::write_file(*self, file, offset, buffer)
     }
+
+    fn sync_file(&self, file: &mut Self::File) -> io::Result<()> {
+        FileSystem::sync_file(*self, file)
+    }
 }
\ No newline at end of file
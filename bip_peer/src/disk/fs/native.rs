@@ -28,6 +28,16 @@ impl NativeFile {
     fn new(file: File, path: PathBuf) -> NativeFile {
         NativeFile{ file: file, path: path }
     }
+
+    /// Path to the file on disk.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Consume the NativeFile, returning the underlying file.
+    pub fn into_file(self) -> File {
+        self.file
+    }
 }
 
 impl NativeFileSystem {
@@ -112,6 +122,10 @@ impl FileSystem for NativeFileSystem {
 
         file.file.write(buffer)
     }
+
+    fn sync_file(&self, file: &mut NativeFile) -> io::Result<()> {
+        file.file.sync_all()
+    }
 }
 
 /// Create a new file with read and write options.
@@ -0,0 +1,65 @@
+use std::path::Path;
+use std::io;
+
+use disk::fs::FileSystem;
+
+/// File system that wraps another file system, refusing to modify any files.
+///
+/// Useful for seeding, or verifying, data that should never be touched. Reads are passed through to the
+/// wrapped file system, while writes and removals fail with `io::ErrorKind::PermissionDenied`. Note that
+/// opening a file that does not exist will still create it, since `FileSystem` has no notion of existence;
+/// to keep the file system from being grown, files found to be empty should not be preallocated, see
+/// `PreallocationMode::None`.
+pub struct ReadOnlyFileSystem<F> {
+    fs: F
+}
+
+impl<F> ReadOnlyFileSystem<F> {
+    /// Create a new ReadOnlyFileSystem wrapping the given file system.
+    pub fn new(fs: F) -> ReadOnlyFileSystem<F> {
+        ReadOnlyFileSystem{ fs: fs }
+    }
+
+    /// Consume the ReadOnlyFileSystem, returning the wrapped file system.
+    pub fn into_inner(self) -> F {
+        self.fs
+    }
+}
+
+impl<F> FileSystem for ReadOnlyFileSystem<F> where F: FileSystem {
+    type File = F::File;
+
+    fn open_file<P>(&self, opt_path: Option<P>) -> io::Result<Self::File>
+        where P: AsRef<Path> {
+        match opt_path {
+            Some(path) => self.fs.open_file(Some(path)),
+            // Scratch files only exist to be written to
+            None       => Err(read_only_error())
+        }
+    }
+
+    fn file_size(&self, file: &Self::File) -> io::Result<u64> {
+        self.fs.file_size(file)
+    }
+
+    fn remove_file(&self, _file: Self::File) -> io::Result<()> {
+        Err(read_only_error())
+    }
+
+    fn read_file(&self, file: &mut Self::File, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        self.fs.read_file(file, offset, buffer)
+    }
+
+    fn write_file(&self, _file: &mut Self::File, _offset: u64, _buffer: &[u8]) -> io::Result<usize> {
+        Err(read_only_error())
+    }
+
+    fn sync_file(&self, _file: &mut Self::File) -> io::Result<()> {
+        // We never write anything, so there is nothing of ours to sync
+        Ok(())
+    }
+}
+
+fn read_only_error() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "File System Is Read Only")
+}
@@ -0,0 +1,73 @@
+use std::mem;
+
+use bip_util::sha::ShaHashBuilder;
+
+/// Computes the SHA-1 hashes that pieces are checked against.
+///
+/// Pieces are usually hashed incrementally, a chunk at a time as they are read from disk, and
+/// hashers are shared across the threads that hash pieces in parallel.
+pub trait PieceHasher: Send + Sync {
+    /// Start a new hash, which bytes can be added to incrementally.
+    fn begin(&self) -> Box<PieceHash>;
+
+    /// Hash the given bytes in one go.
+    fn hash(&self, bytes: &[u8]) -> [u8; 20] {
+        let mut piece_hash = self.begin();
+        piece_hash.update(bytes);
+
+        piece_hash.finish()
+    }
+}
+
+/// Hash that is in the process of being computed by a `PieceHasher`.
+pub trait PieceHash {
+    /// Add the given bytes to the hash.
+    fn update(&mut self, bytes: &[u8]);
+
+    /// Finish the hash, returning the digest.
+    fn finish(self: Box<Self>) -> [u8; 20];
+}
+
+/// `PieceHasher` using the SHA-1 implementation from `bip_util`; the default for a `DiskManager`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ShaPieceHasher;
+
+impl PieceHasher for ShaPieceHasher {
+    fn begin(&self) -> Box<PieceHash> {
+        Box::new(ShaPieceHash(ShaHashBuilder::new()))
+    }
+}
+
+struct ShaPieceHash(ShaHashBuilder);
+
+impl PieceHash for ShaPieceHash {
+    fn update(&mut self, bytes: &[u8]) {
+        let builder = mem::replace(&mut self.0, ShaHashBuilder::new());
+
+        self.0 = builder.add_bytes(bytes);
+    }
+
+    fn finish(self: Box<Self>) -> [u8; 20] {
+        self.0.build().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bip_util::sha::ShaHash;
+
+    use super::{PieceHasher, ShaPieceHasher};
+
+    #[test]
+    fn positive_incremental_hash_matches_whole_hash() {
+        let hasher = ShaPieceHasher;
+
+        let mut piece_hash = hasher.begin();
+        piece_hash.update(b"0123");
+        piece_hash.update(b"4567");
+
+        let expected: [u8; 20] = ShaHash::from_bytes(b"01234567").into();
+        assert_eq!(expected, piece_hash.finish());
+        assert_eq!(expected, hasher.hash(b"01234567"));
+    }
+}
@@ -0,0 +1,377 @@
+use bip_bencode::{Bencode, Dictionary};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use disk::error::{TorrentError, TorrentErrorKind, TorrentResult};
+
+/// Length of a SHA-256 hash in bytes.
+pub const SHA256_HASH_LEN: usize = 32;
+
+/// Size of the blocks that make up the leaves of a v2 merkle tree.
+pub const MERKLE_BLOCK_SIZE: usize = 16 * 1024;
+
+const META_VERSION_KEY: &'static [u8] = b"meta version";
+const FILE_TREE_KEY: &'static [u8] = b"file tree";
+const PIECE_LAYERS_KEY: &'static [u8] = b"piece layers";
+const PIECES_ROOT_KEY: &'static [u8] = b"pieces root";
+
+/// SHA-256 hash of a block, or of a node within a merkle tree.
+pub type MerkleHash = [u8; SHA256_HASH_LEN];
+
+/// Piece layers of a v2 (BEP 52) torrent, which pieces can be checked against instead of their v1 SHA-1 hashes.
+///
+/// In a v2 torrent, every file is hashed as its own merkle tree of 16 KiB blocks, and pieces never span files;
+/// each piece hash is the root of the subtree covering that piece. Both hybrid torrents and pure v2 torrents
+/// are supported, since bip_metainfo lays out the files of either with padding so that pieces line up with
+/// the v2 files. Pure v2 torrents have no v1 hashes at all, so they can only be checked with piece layers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PieceLayers {
+    piece_length: u64,
+    files: Vec<LayerFile>
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct LayerFile {
+    first_piece: u32,
+    length: u64,
+    pieces_root: MerkleHash,
+    // Empty for files that fit within a single piece, which are checked against their pieces root directly
+    layer: Vec<MerkleHash>
+}
+
+impl PieceLayers {
+    /// Parse the piece layers out of the bencoded bytes of a v2 or hybrid torrent file.
+    ///
+    /// Every piece layer is checked against the pieces root of its file, so once parsed,
+    /// pieces only need to be checked against the layer.
+    pub fn from_bytes(bytes: &[u8]) -> TorrentResult<PieceLayers> {
+        let bencode = try!(Bencode::decode(bytes).map_err(|_| invalid_layers()));
+        let root_dict = try!(bencode.dict().ok_or_else(invalid_layers));
+        let info_dict = try!(root_dict.lookup(b"info").and_then(|info| info.dict()).ok_or_else(invalid_layers));
+
+        if info_dict.lookup(META_VERSION_KEY).and_then(|version| version.int()) != Some(2) {
+            return Err(invalid_layers());
+        }
+        let piece_length = try!(info_dict.lookup(b"piece length").and_then(|length| length.int()).ok_or_else(invalid_layers));
+        if piece_length < MERKLE_BLOCK_SIZE as i64 || !(piece_length as u64).is_power_of_two() {
+            return Err(invalid_layers());
+        }
+        let file_tree = try!(info_dict.lookup(FILE_TREE_KEY).ok_or_else(invalid_layers));
+
+        let mut file_entries = Vec::new();
+        try!(collect_files(file_tree, &mut file_entries));
+
+        let mut layers = PieceLayers { piece_length: piece_length as u64, files: Vec::new() };
+        let mut first_piece = 0u64;
+        for (length, pieces_root) in file_entries {
+            // Empty files have no data to check, and take up no pieces
+            let pieces_root = match pieces_root {
+                Some(pieces_root) => pieces_root,
+                None if length == 0 => continue,
+                None => return Err(invalid_layers())
+            };
+            let num_pieces = (length + layers.piece_length - 1) / layers.piece_length;
+
+            let layer = if length > layers.piece_length {
+                let layer_bytes = try!(root_dict.lookup(PIECE_LAYERS_KEY)
+                    .and_then(|piece_layers| piece_layers.dict())
+                    .and_then(|piece_layers| piece_layers.lookup(&pieces_root))
+                    .and_then(|layer| layer.bytes())
+                    .ok_or_else(invalid_layers));
+                if layer_bytes.len() as u64 != num_pieces * SHA256_HASH_LEN as u64 {
+                    return Err(invalid_layers());
+                }
+
+                layer_bytes.chunks(SHA256_HASH_LEN).map(to_merkle_hash).collect()
+            } else {
+                Vec::new()
+            };
+
+            let file = LayerFile { first_piece: first_piece as u32, length: length, pieces_root: pieces_root, layer: layer };
+            if !file.layer.is_empty() && layer_root(&file.layer, layers.blocks_per_piece()) != file.pieces_root {
+                return Err(invalid_layers());
+            }
+
+            layers.files.push(file);
+            first_piece += num_pieces;
+        }
+
+        if first_piece > u32::max_value() as u64 {
+            return Err(invalid_layers());
+        }
+
+        Ok(layers)
+    }
+
+    /// Number of pieces covered by the piece layers.
+    pub fn piece_count(&self) -> usize {
+        self.files.last().map_or(0, |file| file.first_piece as usize + self.pieces_in_file(file))
+    }
+
+    /// Number of bytes of file data in the given piece, or None if the piece does not exist.
+    ///
+    /// The last piece of each file is cut short at the end of the file; in a hybrid
+    /// torrent, the rest of the piece is made up of padding that is not hashed.
+    pub fn piece_data_length(&self, piece_index: u32) -> Option<usize> {
+        self.file_for_piece(piece_index).map(|file| {
+            let piece_offset = (piece_index - file.first_piece) as u64 * self.piece_length;
+
+            ::std::cmp::min(self.piece_length, file.length - piece_offset) as usize
+        })
+    }
+
+    /// Check the hashes of the 16 KiB blocks making up the given piece against its expected hash.
+    pub fn verify_piece(&self, piece_index: u32, block_hashes: &[MerkleHash]) -> bool {
+        let file = match self.file_for_piece(piece_index) {
+            Some(file) => file,
+            None => return false
+        };
+
+        if file.layer.is_empty() {
+            // File fits within a single piece, so its tree is only as wide as it needs to be
+            merkle_root(block_hashes, block_hashes.len().next_power_of_two(), [0u8; SHA256_HASH_LEN]) == file.pieces_root
+        } else {
+            let expected_hash = file.layer[(piece_index - file.first_piece) as usize];
+
+            block_hashes.len() <= self.blocks_per_piece() &&
+                merkle_root(block_hashes, self.blocks_per_piece(), [0u8; SHA256_HASH_LEN]) == expected_hash
+        }
+    }
+
+    fn blocks_per_piece(&self) -> usize {
+        self.piece_length as usize / MERKLE_BLOCK_SIZE
+    }
+
+    fn pieces_in_file(&self, file: &LayerFile) -> usize {
+        ((file.length + self.piece_length - 1) / self.piece_length) as usize
+    }
+
+    fn file_for_piece(&self, piece_index: u32) -> Option<&LayerFile> {
+        self.files.iter()
+            .take_while(|file| file.first_piece <= piece_index)
+            .last()
+            .and_then(|file| {
+                if ((piece_index - file.first_piece) as usize) < self.pieces_in_file(file) {
+                    Some(file)
+                } else {
+                    None
+                }
+            })
+    }
+}
+
+/// Walk the file tree in order, collecting the (length, pieces root) of each file.
+fn collect_files(node: &Bencode, files: &mut Vec<(u64, Option<MerkleHash>)>) -> TorrentResult<()> {
+    let node_dict = try!(node.dict().ok_or_else(invalid_layers));
+
+    // Files are ordered by their path, which is the order of the keys in each bencoded dictionary
+    let mut entries = node_dict.to_list();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (key, value) in entries {
+        if key.is_empty() {
+            let file_dict = try!(value.dict().ok_or_else(invalid_layers));
+            let length = try!(file_dict.lookup(b"length").and_then(|length| length.int()).ok_or_else(invalid_layers));
+            if length < 0 {
+                return Err(invalid_layers());
+            }
+            let pieces_root = match file_dict.lookup(PIECES_ROOT_KEY).and_then(|root| root.bytes()) {
+                Some(root) if root.len() == SHA256_HASH_LEN => Some(to_merkle_hash(root)),
+                Some(_) => return Err(invalid_layers()),
+                None => None
+            };
+
+            files.push((length as u64, pieces_root));
+        } else {
+            try!(collect_files(value, files));
+        }
+    }
+
+    Ok(())
+}
+
+/// Root of the tree that the given piece layer hashes up to.
+///
+/// Pieces past the end of the file stand in for blocks that are all zero hashes.
+fn layer_root(layer: &[MerkleHash], blocks_per_piece: usize) -> MerkleHash {
+    let mut pad_hash = [0u8; SHA256_HASH_LEN];
+    let mut width = 1;
+    while width < blocks_per_piece {
+        pad_hash = hash_pair(&pad_hash, &pad_hash);
+        width *= 2;
+    }
+
+    merkle_root(layer, layer.len().next_power_of_two(), pad_hash)
+}
+
+/// Root of a tree with the given number of leaves, where leaves past the given hashes are filled with the pad hash.
+fn merkle_root(hashes: &[MerkleHash], width: usize, pad_hash: MerkleHash) -> MerkleHash {
+    let mut nodes = hashes.to_vec();
+    nodes.resize(width, pad_hash);
+
+    while nodes.len() > 1 {
+        nodes = nodes.chunks(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect();
+    }
+
+    nodes.pop().unwrap_or(pad_hash)
+}
+
+fn hash_pair(left: &MerkleHash, right: &MerkleHash) -> MerkleHash {
+    let mut hasher = Sha256::new();
+    hasher.input(left);
+    hasher.input(right);
+
+    let mut hash = [0u8; SHA256_HASH_LEN];
+    hasher.result(&mut hash);
+
+    hash
+}
+
+fn to_merkle_hash(bytes: &[u8]) -> MerkleHash {
+    let mut hash = [0u8; SHA256_HASH_LEN];
+    hash.copy_from_slice(bytes);
+
+    hash
+}
+
+fn invalid_layers() -> TorrentError {
+    TorrentError::from_kind(TorrentErrorKind::InvalidPieceLayers)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use bip_bencode::Bencode;
+    use bip_metainfo::MetainfoFile;
+    use crypto::digest::Digest;
+    use crypto::sha2::Sha256;
+
+    use super::{PieceLayers, MERKLE_BLOCK_SIZE};
+
+    const PIECE_LENGTH: usize = MERKLE_BLOCK_SIZE * 4;
+
+    fn sha256(bytes: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.input(bytes);
+
+        let mut hash = [0u8; 32];
+        hasher.result(&mut hash);
+
+        hash
+    }
+
+    /// Build a v2 torrent for files with the given names and contents.
+    fn create_torrent(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let blocks_per_piece = PIECE_LENGTH / MERKLE_BLOCK_SIZE;
+
+        // (pieces root, piece layer) of each file
+        let hashes: Vec<([u8; 32], Vec<u8>)> = files.iter()
+            .map(|&(_, contents)| {
+                let block_hashes: Vec<_> = contents.chunks(MERKLE_BLOCK_SIZE).map(sha256).collect();
+                let piece_hashes: Vec<_> = block_hashes.chunks(blocks_per_piece)
+                    .map(|blocks| super::merkle_root(blocks, blocks_per_piece, [0u8; 32]))
+                    .collect();
+
+                if contents.len() > PIECE_LENGTH {
+                    (super::layer_root(&piece_hashes, blocks_per_piece), piece_hashes.concat())
+                } else {
+                    (super::merkle_root(&block_hashes, block_hashes.len().next_power_of_two(), [0u8; 32]), Vec::new())
+                }
+            })
+            .collect();
+
+        let mut file_tree = BTreeMap::new();
+        let mut piece_layers = BTreeMap::new();
+        for (&(name, contents), &(ref pieces_root, ref layer)) in files.iter().zip(hashes.iter()) {
+            let mut file_dict = BTreeMap::new();
+            file_dict.insert(&b"length"[..], ben_int!(contents.len() as i64));
+            if !contents.is_empty() {
+                file_dict.insert(&b"pieces root"[..], ben_bytes!(&pieces_root[..]));
+            }
+            let mut node_dict = BTreeMap::new();
+            node_dict.insert(&b""[..], Bencode::Dict(file_dict));
+            file_tree.insert(name.as_bytes(), Bencode::Dict(node_dict));
+
+            if !layer.is_empty() {
+                piece_layers.insert(&pieces_root[..], ben_bytes!(&layer[..]));
+            }
+        }
+
+        let mut info_dict = BTreeMap::new();
+        info_dict.insert(&b"meta version"[..], ben_int!(2));
+        info_dict.insert(&b"name"[..], ben_bytes!("test"));
+        info_dict.insert(&b"piece length"[..], ben_int!(PIECE_LENGTH as i64));
+        info_dict.insert(&b"file tree"[..], Bencode::Dict(file_tree));
+
+        let mut root_dict = BTreeMap::new();
+        root_dict.insert(&b"info"[..], Bencode::Dict(info_dict));
+        root_dict.insert(&b"piece layers"[..], Bencode::Dict(piece_layers));
+
+        Bencode::Dict(root_dict).encode()
+    }
+
+    fn piece_block_hashes(contents: &[u8], piece: usize) -> Vec<[u8; 32]> {
+        let end = ::std::cmp::min(contents.len(), (piece + 1) * PIECE_LENGTH);
+
+        contents[piece * PIECE_LENGTH..end].chunks(MERKLE_BLOCK_SIZE).map(sha256).collect()
+    }
+
+    #[test]
+    fn positive_verify_pieces_across_files() {
+        let large: Vec<u8> = (0..PIECE_LENGTH * 2 + 100).map(|index| (index % 251) as u8).collect();
+        let small: Vec<u8> = (0..MERKLE_BLOCK_SIZE + 7).map(|index| (index % 7) as u8).collect();
+        let layers = PieceLayers::from_bytes(&create_torrent(&[("a", &large), ("b", &[]), ("c", &small)])).unwrap();
+
+        assert_eq!(4, layers.piece_count());
+        assert_eq!(Some(100), layers.piece_data_length(2));
+        assert_eq!(Some(MERKLE_BLOCK_SIZE + 7), layers.piece_data_length(3));
+
+        for piece in 0..3 {
+            assert!(layers.verify_piece(piece as u32, &piece_block_hashes(&large, piece)));
+        }
+        assert!(layers.verify_piece(3, &piece_block_hashes(&small, 0)));
+    }
+
+    #[test]
+    fn positive_pure_v2_metainfo_matches_piece_layers() {
+        let large: Vec<u8> = (0..PIECE_LENGTH * 2 + 100).map(|index| (index % 251) as u8).collect();
+        let small: Vec<u8> = (0..MERKLE_BLOCK_SIZE + 7).map(|index| (index % 7) as u8).collect();
+        let bytes = create_torrent(&[("a", &large), ("b", &[]), ("c", &small)]);
+
+        let metainfo = MetainfoFile::from_bytes(&bytes).unwrap();
+        let layers = PieceLayers::from_bytes(&bytes).unwrap();
+
+        assert!(metainfo.info().is_v2_only());
+        assert_eq!(metainfo.info().pieces().count(), layers.piece_count());
+
+        // The padding after the first file starts the last file on the piece checked against its pieces root
+        let file_lengths: Vec<u64> = metainfo.info().files().map(|file| file.length()).collect();
+        assert_eq!(vec![large.len() as u64, (PIECE_LENGTH - 100) as u64, 0, small.len() as u64], file_lengths);
+        assert!(layers.verify_piece(3, &piece_block_hashes(&small, 0)));
+    }
+
+    #[test]
+    fn negative_verify_corrupted_piece() {
+        let mut large: Vec<u8> = (0..PIECE_LENGTH * 2).map(|index| (index % 251) as u8).collect();
+        let layers = PieceLayers::from_bytes(&create_torrent(&[("a", &large)])).unwrap();
+
+        large[PIECE_LENGTH + 1] ^= 1;
+
+        assert!(layers.verify_piece(0, &piece_block_hashes(&large, 0)));
+        assert!(!layers.verify_piece(1, &piece_block_hashes(&large, 1)));
+        assert!(!layers.verify_piece(2, &piece_block_hashes(&large, 1)));
+    }
+
+    #[test]
+    fn negative_layer_does_not_match_pieces_root() {
+        let large: Vec<u8> = (0..PIECE_LENGTH * 2).map(|index| (index % 251) as u8).collect();
+        let mut bytes = create_torrent(&[("a", &large)]);
+
+        // Flip a bit in the last byte of the piece layer, which comes right before the closing of the dictionaries
+        let last_layer_byte = bytes.len() - 3;
+        bytes[last_layer_byte] ^= 1;
+
+        assert!(PieceLayers::from_bytes(&bytes).is_err());
+    }
+}
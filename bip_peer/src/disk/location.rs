@@ -0,0 +1,61 @@
+use std::path::{Path, PathBuf};
+
+use bip_metainfo::{InfoDictionary, File};
+
+/// Where the files for a torrent are placed, relative to the `FileSystem`.
+///
+/// By default, files are placed in the torrent's own directory (if it has one) under the
+/// current directory of the file system.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DownloadLocation {
+    root:              PathBuf,
+    torrent_directory: bool
+}
+
+impl DownloadLocation {
+    /// Create a new DownloadLocation rooted at the given directory.
+    ///
+    /// The torrent's directory, if it has one, will be appended to the root.
+    pub fn new<P>(root: P) -> DownloadLocation
+        where P: AsRef<Path> {
+        DownloadLocation{ root: root.as_ref().to_path_buf(), torrent_directory: true }
+    }
+
+    /// Sets whether or not the torrent's directory is appended to the root.
+    ///
+    /// If not, files are placed directly in the root directory.
+    pub fn set_torrent_directory(&mut self, torrent_directory: bool) {
+        self.torrent_directory = torrent_directory;
+    }
+
+    /// Gets whether or not the torrent's directory is appended to the root.
+    pub fn torrent_directory(&self) -> bool {
+        self.torrent_directory
+    }
+
+    /// Directory that files are placed under.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Build the path to the file, relative to the file system, using platform specific separators.
+    pub fn file_path(&self, info_dict: &InfoDictionary, file: &File) -> PathBuf {
+        let mut path = self.root.clone();
+
+        if let (true, Some(directory)) = (self.torrent_directory, info_dict.directory()) {
+            path.push(directory);
+        }
+
+        file.paths().fold(path, |mut acc, item| {
+            acc.push(item);
+
+            acc
+        })
+    }
+}
+
+impl Default for DownloadLocation {
+    fn default() -> DownloadLocation {
+        DownloadLocation::new(".")
+    }
+}
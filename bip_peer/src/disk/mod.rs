@@ -11,20 +11,45 @@ use disk::worker::{DiskMessage, SyncBlockMessage, AsyncBlockMessage, ReserveBloc
 use disk::worker::shared::clients::Clients;
 use disk::worker::shared::blocks::Blocks;
 use disk::error::{RequestError, TorrentError};
+use disk::layers::PieceLayers;
+use metrics::{NoopRecorder, Recorder};
 use registration::LayerRegistration;
-use token::{Token, TokenGenerator};
+use token::{Token, TokenGenerator, TokenPool};
 use message::standard::PieceMessage;
 
 pub mod fs;
+mod config;
+mod durability;
 mod error;
+mod hasher;
+mod layers;
+mod location;
+mod preallocation;
+mod priority;
+mod verification;
 mod worker;
 
+pub use disk::config::DiskConfig;
+pub use disk::durability::DurabilityMode;
 pub use disk::fs::{FileSystem};
+pub use disk::hasher::{PieceHasher, PieceHash, ShaPieceHasher};
+pub use disk::layers::PieceLayers;
+pub use disk::location::DownloadLocation;
+pub use disk::preallocation::PreallocationMode;
+pub use disk::priority::{FilePriority, FilePriorities};
+pub use disk::verification::VerificationOrder;
+pub use disk::worker::{expected_hashes, ExpectedHashes};
 
 const DISK_MANAGER_WORKER_THREADS: usize = 1;
 
-// Maximum as well as the default block size for our requests.
-const DEFAULT_BLOCK_SIZE: usize = 16 * 1024;
+// Number of threads used to hash pieces when a torrent is first added.
+const DISK_MANAGER_HASHING_THREADS: usize = 4;
+
+// Maximum number of unused block sized buffers the disk workers will hold on to.
+const DISK_MANAGER_MAX_FREE_BLOCKS: usize = 64;
+
+/// Maximum as well as the default block size for our requests.
+pub const DEFAULT_BLOCK_SIZE: usize = 16 * 1024;
 
 // Maximum allowed block size for peers requesting from us.
 //const MAX_ALLOWED_BLOCK_SIZE: usize = 32 * 1024;
@@ -48,14 +73,36 @@ pub enum IDiskMessage {
     /// Add the given torrent at the specified path to the DiskManager.
     ///
     /// The sender will also be signed up to receive `ODiskMessage::FoundGoodPiece`,
-    /// `ODiskMessage::FoundBadpiece`, and `ODiskMessage::TorrentError` messages.
+    /// `ODiskMessage::FoundBadpiece`, `ODiskMessage::TorrentComplete`, and
+    /// `ODiskMessage::TorrentError` messages.
     AddTorrent(MetainfoFile),
-    /// Remove the torrent from the disk manager.
+    /// Same as `IDiskMessage::AddTorrent`, except files are only allocated and checked
+    /// according to the given priorities.
+    AddTorrentWithPriorities(MetainfoFile, FilePriorities),
+    /// Same as `IDiskMessage::AddTorrentWithPriorities`, except pieces are checked against the given piece
+    /// layers, parsed from the same hybrid or pure v2 torrent file, instead of against their v1 hashes.
+    ///
+    /// Pure v2 torrents have no v1 hashes, so they must be added with this message.
+    AddTorrentWithPieceLayers(MetainfoFile, FilePriorities, PieceLayers),
+    /// Remove the torrent from the disk manager, dropping any data cached for it.
     ///
     /// This does NOT delete anything from disk.
     ///
-    /// The sender MAY receive a single `ODiskMessage::TorrentError` message.
+    /// The sender will receive an `ODiskMessage::TorrentRemoved` message once the torrent
+    /// has been removed, which MAY be preceded by a single `ODiskMessage::TorrentError` message.
     RemoveTorrent(InfoHash),
+    /// Same as `IDiskMessage::RemoveTorrent`, except the files for the torrent are also deleted from disk.
+    RemoveTorrentAndFiles(InfoHash),
+    /// List the torrents currently added to the disk manager.
+    ///
+    /// The sender will receive an `ODiskMessage::TorrentList` message in response.
+    ListTorrents,
+    /// Set whether or not pieces for the torrent are checked against their hash every time
+    /// they are read from disk, to catch corruption before it is served to peers; off by default.
+    ///
+    /// If a piece fails the check, the sender will receive an `ODiskMessage::BlockLoadFailed` instead of
+    /// the `ODiskMessage::BlockLoaded` message for the block.
+    SetVerifyReads(InfoHash, bool),
     /// Load the block from the InfoHash into memory.
     LoadBlock(Token, InfoHash, PieceMessage),
     /// Reclaim and mark the block as unused.
@@ -71,15 +118,27 @@ pub enum IDiskMessage {
 pub enum ODiskMessage {
     /// Torrent has been added to the disk manager.
     TorrentAdded(InfoHash),
-    /// Torrent has been removed from the disk manager.
+    /// Torrent has been removed from the disk manager, and can be added again.
     TorrentRemoved(InfoHash),
+    /// Torrents currently added to the disk manager, in no particular order.
+    ///
+    /// Torrents that are still being checked after being added are not included.
+    TorrentList(Vec<InfoHash>),
     /// DiskManager has assembled and verified a good the given piece at the index.
     FoundGoodPiece(InfoHash, u32),
     /// DiskManager has assembled and verified a bad piece at the index.
     FoundBadPiece(InfoHash, u32),
+    /// Every piece in the torrent has been verified as good.
+    ///
+    /// Sent after the `ODiskMessage::FoundGoodPiece` for the last piece, including
+    /// when the torrent was already complete on disk when it was added.
+    TorrentComplete(InfoHash),
     /// Block for the given token has been loaded.
     /// (Namespace, Request)
     BlockLoaded(Token, Token),
+    /// Block for the given token could not be loaded, and has already been reclaimed.
+    /// (Namespace, Request, Error)
+    BlockLoadFailed(Token, Token, TorrentError),
     /// Block for the given token has been reserved.
     /// Because of problems with disk manager communication, (TODO), we give back
     /// both the namespace and request token that the block can be accessed with.
@@ -106,6 +165,35 @@ impl DiskManagerRegistration {
     /// Create a new DiskManagerRegistration using the given FileSystem.
     pub fn with_fs<F>(fs: F) -> DiskManagerRegistration
         where F: FileSystem + Send + Sync + 'static {
+        DiskManagerRegistration::with_fs_config(fs, DiskConfig::default())
+    }
+
+    /// Create a new DiskManagerRegistration using the given FileSystem, allocating files for new torrents
+    /// using the given PreallocationMode.
+    pub fn with_fs_preallocation<F>(fs: F, preallocation: PreallocationMode) -> DiskManagerRegistration
+        where F: FileSystem + Send + Sync + 'static {
+        let mut config = DiskConfig::default();
+        config.set_preallocation_mode(preallocation);
+
+        DiskManagerRegistration::with_fs_config(fs, config)
+    }
+
+    /// Create a new DiskManagerRegistration using the given FileSystem and DiskConfig.
+    pub fn with_fs_config<F>(fs: F, config: DiskConfig) -> DiskManagerRegistration
+        where F: FileSystem + Send + Sync + 'static {
+        DiskManagerRegistration::with_fs_config_recorder(fs, config, Arc::new(NoopRecorder))
+    }
+
+    /// Create a new DiskManagerRegistration using the given FileSystem and DiskConfig, reporting
+    /// verified pieces and disk latencies to the given Recorder.
+    pub fn with_fs_config_recorder<F>(fs: F, config: DiskConfig, recorder: Arc<Recorder>) -> DiskManagerRegistration
+        where F: FileSystem + Send + Sync + 'static {
+        DiskManagerRegistration::with_fs_config_recorder_hasher(fs, config, recorder, Arc::new(ShaPieceHasher))
+    }
+
+    /// Same as `DiskManagerRegistration::with_fs_config_recorder`, except pieces are hashed using the given PieceHasher.
+    pub fn with_fs_config_recorder_hasher<F>(fs: F, config: DiskConfig, recorder: Arc<Recorder>, hasher: Arc<PieceHasher>)
+        -> DiskManagerRegistration where F: FileSystem + Send + Sync + 'static {
         // Create the shared data structures.
         let clients = Arc::new(Clients::new());
         let blocks = Arc::new(Blocks::new(DEFAULT_BLOCK_SIZE));
@@ -113,8 +201,8 @@ impl DiskManagerRegistration {
         let mut namespace_gen = TokenGenerator::new();
 
         // Spin up new worker threads for allocating blocks and writing them to disk.
-        let (disk_sender, sb_sender, ab_sender) = worker::create_workers(fs, clients.clone(),
-            blocks.clone(), namespace_gen.generate());
+        let (disk_sender, sb_sender, ab_sender) = worker::create_workers(fs, config, clients.clone(),
+            blocks.clone(), recorder, hasher, namespace_gen.generate());
 
         DiskManagerRegistration {
             namespace_gen: namespace_gen,
@@ -153,13 +241,21 @@ pub trait DiskManagerAccess {
 
     /// Generate a new request token.
     fn new_request_token(&mut self) -> Token;
+
+    /// Release a request token once we are done with it, so that it can be reused.
+    ///
+    /// The token may still be in use by the disk manager (for example, if the block is
+    /// being reclaimed), in which case it won't be reused until the disk manager is done.
+    fn release_request_token(&mut self, token: Token);
 }
 
 /// DiskManager that allows clients to send messages to workers in charge
 /// of allocating blocks of memory, as well as writing blocks to disk.
 pub struct DiskManager {
     namespace:          Token,
-    request_gen:        TokenGenerator,
+    request_pool:       TokenPool,
+    // Tokens that have been released, but may still be in use by the disk workers
+    released_tokens:    Vec<Token>,
     clients:            Arc<Clients<ReserveBlockClientMetadata>>,
     blocks:             Arc<Blocks>,
     disk_sender:        Sender<DiskMessage>,
@@ -177,7 +273,8 @@ impl DiskManager {
 
         DiskManager {
             namespace: namespace,
-            request_gen: TokenGenerator::new(),
+            request_pool: TokenPool::new(),
+            released_tokens: Vec::new(),
             clients: clients,
             blocks: blocks,
             disk_sender: disk_sender,
@@ -206,7 +303,22 @@ impl DiskManagerAccess for DiskManager {
     }
 
     fn new_request_token(&mut self) -> Token {
-        self.request_gen.generate()
+        // Blocks are reclaimed asynchronously, so only recycle tokens whose blocks are gone
+        let (namespace, blocks, request_pool) = (self.namespace, &self.blocks, &mut self.request_pool);
+        self.released_tokens.retain(|&token| {
+            let in_use = blocks.is_used(namespace, token);
+            if !in_use {
+                request_pool.release(token);
+            }
+
+            in_use
+        });
+
+        self.request_pool.acquire()
+    }
+
+    fn release_request_token(&mut self, token: Token) {
+        self.released_tokens.push(token);
     }
 }
 
@@ -214,10 +326,25 @@ impl TrySender<IDiskMessage> for DiskManager {
     fn try_send(&self, data: IDiskMessage) -> Option<IDiskMessage> {
         match data {
             IDiskMessage::AddTorrent(metainfo) => {
-                self.disk_sender.send(DiskMessage::AddTorrent(self.namespace, metainfo))
+                self.disk_sender.send(DiskMessage::AddTorrent(self.namespace, metainfo, FilePriorities::new(), None))
+            },
+            IDiskMessage::AddTorrentWithPriorities(metainfo, priorities) => {
+                self.disk_sender.send(DiskMessage::AddTorrent(self.namespace, metainfo, priorities, None))
+            },
+            IDiskMessage::AddTorrentWithPieceLayers(metainfo, priorities, layers) => {
+                self.disk_sender.send(DiskMessage::AddTorrent(self.namespace, metainfo, priorities, Some(layers)))
             },
             IDiskMessage::RemoveTorrent(hash) => {
-                self.disk_sender.send(DiskMessage::RemoveTorrent(self.namespace, hash))
+                self.disk_sender.send(DiskMessage::RemoveTorrent(self.namespace, hash, false))
+            },
+            IDiskMessage::RemoveTorrentAndFiles(hash) => {
+                self.disk_sender.send(DiskMessage::RemoveTorrent(self.namespace, hash, true))
+            },
+            IDiskMessage::ListTorrents => {
+                self.disk_sender.send(DiskMessage::ListTorrents(self.namespace))
+            },
+            IDiskMessage::SetVerifyReads(hash, verify_reads) => {
+                self.disk_sender.send(DiskMessage::SetVerifyReads(hash, verify_reads))
             },
             IDiskMessage::LoadBlock(request, hash, message) => {
                 self.disk_sender.send(DiskMessage::LoadBlock(self.namespace, request, hash, message))
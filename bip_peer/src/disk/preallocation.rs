@@ -0,0 +1,18 @@
+/// How files are allocated on disk when a torrent is added.
+///
+/// Existing files that are of the correct size are always left alone.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PreallocationMode {
+    /// Write zeroes throughout each file, guaranteeing that space is available up front.
+    Full,
+    /// Write a single byte at the end of each file, which on most file systems produces a sparse file.
+    Sparse,
+    /// Do not allocate files, they will grow as pieces are written out.
+    None
+}
+
+impl Default for PreallocationMode {
+    fn default() -> PreallocationMode {
+        PreallocationMode::Sparse
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::cmp;
+
+use bip_metainfo::InfoDictionary;
+
+/// Priority for a single file within a torrent.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FilePriority {
+    /// File should not be downloaded.
+    Skip,
+    /// File should be downloaded.
+    Normal,
+    /// File should be downloaded before any normal priority files.
+    High
+}
+
+/// Priorities for files within a torrent, indexed by their position in the info dictionary.
+///
+/// Files without an explicit priority are treated as `FilePriority::Normal`.
+#[derive(Clone, Debug, Default)]
+pub struct FilePriorities {
+    priorities: HashMap<usize, FilePriority>
+}
+
+impl FilePriorities {
+    /// Create a new FilePriorities where every file has a normal priority.
+    pub fn new() -> FilePriorities {
+        FilePriorities{ priorities: HashMap::new() }
+    }
+
+    /// Set the priority for the file at the given index.
+    pub fn set_priority(&mut self, file_index: usize, priority: FilePriority) {
+        self.priorities.insert(file_index, priority);
+    }
+
+    /// Priority for the file at the given index.
+    pub fn priority(&self, file_index: usize) -> FilePriority {
+        self.priorities.get(&file_index).map_or(FilePriority::Normal, |priority| *priority)
+    }
+
+    /// Priority for the given piece, which is the highest priority of all files the piece overlaps.
+    ///
+    /// A piece only has a skip priority if every file it overlaps is skipped.
+    pub fn piece_priority(&self, info_dict: &InfoDictionary, piece_index: u32) -> FilePriority {
+        let piece_length = info_dict.piece_length() as u64;
+        let piece_start = piece_index as u64 * piece_length;
+        let piece_end = piece_start + piece_length;
+
+        let mut priority = FilePriority::Skip;
+        let mut file_start = 0;
+        for (file_index, file) in info_dict.files().enumerate() {
+            let file_end = file_start + file.length() as u64;
+
+            if file_start < piece_end && piece_start < file_end {
+                priority = cmp::max(priority, self.priority(file_index));
+            }
+            file_start = file_end;
+        }
+
+        priority
+    }
+
+    /// Whether or not the file at the given index has to exist on disk.
+    ///
+    /// Skipped files that share a piece with a file we want are still needed, since the
+    /// whole piece has to be written out before it can be hashed.
+    pub fn file_is_wanted(&self, info_dict: &InfoDictionary, file_index: usize) -> bool {
+        if self.priority(file_index) != FilePriority::Skip {
+            return true;
+        }
+        let piece_length = info_dict.piece_length() as u64;
+
+        let file_start: u64 = info_dict.files().take(file_index).map(|file| file.length() as u64).sum();
+        let file_length = info_dict.files().nth(file_index).map_or(0, |file| file.length() as u64);
+        if file_length == 0 {
+            return false;
+        }
+
+        let first_piece = file_start / piece_length;
+        let last_piece = (file_start + file_length - 1) / piece_length;
+
+        (first_piece..(last_piece + 1)).any(|piece_index| {
+            self.piece_priority(info_dict, piece_index as u32) != FilePriority::Skip
+        })
+    }
+}
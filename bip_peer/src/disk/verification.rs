@@ -0,0 +1,17 @@
+/// Order in which pieces are verified when more than one is ready to be checked at once.
+///
+/// Pieces are reported as good (or bad) in the order they are verified, so this also decides
+/// which pieces become available to clients first.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VerificationOrder {
+    /// Verify pieces in whatever order is most convenient.
+    Unordered,
+    /// Verify pieces with lower indices first, which is what streaming playback wants.
+    Ascending
+}
+
+impl Default for VerificationOrder {
+    fn default() -> VerificationOrder {
+        VerificationOrder::Unordered
+    }
+}
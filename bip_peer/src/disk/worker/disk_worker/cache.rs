@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bip_util::bt::InfoHash;
+
+/// Least recently used cache of pieces read from disk, bounded by the number of bytes held.
+///
+/// Pieces are handed out behind an `Arc`, so evicting a piece won't pull it out from under a read using it.
+pub struct PieceCache {
+    max_bytes: usize,
+    state:     Mutex<CacheState>
+}
+
+struct CacheState {
+    // Piece along with the time it was last used
+    pieces: HashMap<(InfoHash, u32), (Arc<Vec<u8>>, u64)>,
+    bytes:  usize,
+    clock:  u64
+}
+
+impl PieceCache {
+    /// Create a new PieceCache holding at most max_bytes worth of pieces.
+    pub fn new(max_bytes: usize) -> PieceCache {
+        PieceCache{
+            max_bytes: max_bytes,
+            state: Mutex::new(CacheState{ pieces: HashMap::new(), bytes: 0, clock: 0 })
+        }
+    }
+
+    /// Whether or not the cache is able to hold anything.
+    pub fn is_enabled(&self) -> bool {
+        self.max_bytes != 0
+    }
+
+    /// Lookup the piece, marking it as recently used.
+    pub fn get(&self, hash: InfoHash, piece_index: u32) -> Option<Arc<Vec<u8>>> {
+        let mut state = self.lock_state();
+        state.clock += 1;
+
+        let clock = state.clock;
+        state.pieces.get_mut(&(hash, piece_index)).map(|entry| {
+            entry.1 = clock;
+
+            entry.0.clone()
+        })
+    }
+
+    /// Add the piece to the cache, evicting the least recently used pieces to make room for it.
+    ///
+    /// Pieces larger than the whole cache are not added, but are still returned.
+    pub fn insert(&self, hash: InfoHash, piece_index: u32, piece: Vec<u8>) -> Arc<Vec<u8>> {
+        let piece = Arc::new(piece);
+        if piece.len() > self.max_bytes {
+            return piece;
+        }
+
+        let mut state = self.lock_state();
+        remove_piece(&mut state, hash, piece_index);
+
+        while state.bytes + piece.len() > self.max_bytes {
+            let opt_oldest = state.pieces.iter().min_by_key(|&(_, &(_, last_used))| last_used).map(|(key, _)| *key);
+
+            match opt_oldest {
+                Some((old_hash, old_index)) => remove_piece(&mut state, old_hash, old_index),
+                None => break
+            }
+        }
+
+        state.clock += 1;
+        state.bytes += piece.len();
+
+        let clock = state.clock;
+        state.pieces.insert((hash, piece_index), (piece.clone(), clock));
+
+        piece
+    }
+
+    /// Remove the piece from the cache, if it is cached.
+    pub fn remove(&self, hash: InfoHash, piece_index: u32) {
+        remove_piece(&mut self.lock_state(), hash, piece_index);
+    }
+
+    /// Remove all pieces for the torrent from the cache.
+    pub fn remove_torrent(&self, hash: InfoHash) {
+        let mut state = self.lock_state();
+
+        let indices: Vec<u32> = state.pieces.keys().filter(|&&(piece_hash, _)| piece_hash == hash).map(|&(_, index)| index).collect();
+        for index in indices {
+            remove_piece(&mut state, hash, index);
+        }
+    }
+
+    fn lock_state(&self) -> ::std::sync::MutexGuard<CacheState> {
+        self.state.lock().expect("bip_peer: Failed To Lock Piece Cache")
+    }
+}
+
+fn remove_piece(state: &mut CacheState, hash: InfoHash, piece_index: u32) {
+    if let Some((piece, _)) = state.pieces.remove(&(hash, piece_index)) {
+        state.bytes -= piece.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bip_util::bt::InfoHash;
+
+    use super::PieceCache;
+
+    #[test]
+    fn positive_evicts_least_recently_used() {
+        let cache = PieceCache::new(20);
+        let hash = InfoHash::from_bytes(b"torrent");
+
+        cache.insert(hash, 0, vec![0u8; 10]);
+        let evicted = cache.insert(hash, 1, vec![1u8; 10]);
+        cache.get(hash, 0);
+        cache.insert(hash, 2, vec![2u8; 10]);
+
+        assert!(cache.get(hash, 0).is_some());
+        assert!(cache.get(hash, 1).is_none());
+        assert!(cache.get(hash, 2).is_some());
+        // Anyone still holding on to the evicted piece can keep reading from it
+        assert_eq!(&[1u8; 10][..], &evicted[..]);
+    }
+}
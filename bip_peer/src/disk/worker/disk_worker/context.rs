@@ -1,8 +1,10 @@
+use std::cmp;
 use std::collections::HashMap;
 use std::collections::hash_map::{Entry};
 use std::sync::{Arc, Mutex, RwLock};
 use std::mem;
 use std::io::Write;
+use std::time::Instant;
 
 use bip_metainfo::MetainfoFile;
 use bip_util::bt::InfoHash;
@@ -10,69 +12,312 @@ use bip_util::send::TrySender;
 use bip_util::contiguous::ContiguousBuffer;
 use chan::{Sender};
 
+use disk::worker::shared::allocator::BlockAllocator;
 use disk::worker::shared::blocks::Blocks;
 use disk::worker::shared::clients::Clients;
-use disk::worker::disk_worker::piece_accessor::PieceAccessor;
-use disk::{ODiskMessage};
+use disk::worker::disk_worker::cache::PieceCache;
+use disk::worker::disk_worker::piece_accessor::{self, PieceAccessor};
+use disk::{self, ODiskMessage, DiskConfig, DurabilityMode, VerificationOrder, DownloadLocation};
 use disk::error::{RequestError, TorrentError, TorrentResult, TorrentErrorKind};
 use disk::fs::{FileSystem};
+use disk::hasher::PieceHasher;
+use disk::layers::PieceLayers;
+use disk::preallocation::PreallocationMode;
+use disk::priority::FilePriorities;
 use disk::worker::disk_worker::piece_checker::{PieceChecker, PieceState, PieceCheckerState};
 use disk::worker::{ReserveBlockClientMetadata, SyncBlockMessage, DiskMessage, AsyncBlockMessage};
+use metrics::{Metric, Recorder};
 use token::{Token};
 use message::standard::PieceMessage;
 
+// Max bytes of received blocks we will hold on to for a torrent while waiting for their pieces to
+// complete; past this, everything we are holding on to is written out, complete or not.
+const MAX_WRITE_BUFFER_BYTES: usize = 16 * 1024 * 1024;
+
 pub struct DiskWorkerContext<F> {
     fs:              F,
+    preallocation:   PreallocationMode,
+    durability:      DurabilityMode,
+    verification:    VerificationOrder,
+    location:        DownloadLocation,
+    max_active:      Option<usize>,
+    verify_complete: bool,
+    cache:           PieceCache,
     torrents:        RwLock<HashMap<InfoHash, Mutex<TorrentEntry>>>,
     clients:         Arc<Clients<ReserveBlockClientMetadata>>,
     blocks:          Arc<Blocks>,
+    allocator:       Arc<BlockAllocator>,
     sync_worker:     Sender<SyncBlockMessage>,
     async_worker:    Sender<AsyncBlockMessage>,
+    recorder:        Arc<Recorder>,
+    hasher:          Arc<PieceHasher>,
     namespace_token: Token
 }
 
 struct TorrentEntry {
-    metainfo:         MetainfoFile,
-    checker_state:    PieceCheckerState,
-    client_namespace: Token
+    metainfo:           MetainfoFile,
+    location:           DownloadLocation,
+    checker_state:      PieceCheckerState,
+    client_namespace:   Token,
+    // Blocks received for each piece that have not been written out yet
+    write_buffer:       HashMap<u32, Vec<(PieceMessage, Vec<u8>)>>,
+    write_buffer_bytes: usize,
+    // Pieces that were verified but have not been synced to disk yet
+    unsynced_pieces:    Vec<u32>,
+    last_sync:          Instant,
+    // Whether or not pieces are checked against their hash whenever they are read
+    verify_reads:       bool,
+    // Piece layers of a hybrid torrent, which pieces are checked against instead of their v1 hashes
+    piece_layers:       Option<Arc<PieceLayers>>
 }
 
 impl TorrentEntry {
-    fn new(metainfo: MetainfoFile, checker_state: PieceCheckerState, client_namespace: Token) -> TorrentEntry {
+    fn new(metainfo: MetainfoFile, location: DownloadLocation, checker_state: PieceCheckerState, client_namespace: Token,
+        piece_layers: Option<Arc<PieceLayers>>) -> TorrentEntry {
         TorrentEntry{
             metainfo: metainfo,
+            location: location,
             checker_state: checker_state,
-            client_namespace: client_namespace
+            client_namespace: client_namespace,
+            write_buffer: HashMap::new(),
+            write_buffer_bytes: 0,
+            unsynced_pieces: Vec::new(),
+            last_sync: Instant::now(),
+            verify_reads: false,
+            piece_layers: piece_layers
+        }
+    }
+
+    /// Hold on to the block until we have the rest of its piece, dropping it if we already have it.
+    ///
+    /// Blocks already written out, but not yet checked, count towards having the rest of the piece.
+    ///
+    /// Returns the pieces that should now be written out.
+    fn buffer_block(&mut self, message: PieceMessage, block: Vec<u8>) -> Vec<u32> {
+        let piece_index = message.piece_index();
+        let piece_size = piece_size(&self.metainfo, piece_index);
+
+        // Duplicates (common in endgame) for pieces we verified or wrote out would never complete a buffer
+        if self.checker_state.has_block(&message) {
+            return Vec::new();
+        }
+
+        let covered_size = {
+            let blocks = self.write_buffer.entry(piece_index).or_insert_with(Vec::new);
+            if !blocks.iter().any(|&(ref existing, _)| existing.block_offset() == message.block_offset()) {
+                self.write_buffer_bytes += block.len();
+                blocks.push((message, block));
+            }
+
+            let pending = self.checker_state.pending_blocks(piece_index);
+            covered_bytes(pending.iter().chain(blocks.iter().map(|&(ref existing, _)| existing)))
+        };
+
+        if covered_size >= piece_size {
+            vec![piece_index]
+        } else if self.write_buffer_bytes > MAX_WRITE_BUFFER_BYTES {
+            self.write_buffer.keys().cloned().collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Write out all buffered blocks for the piece, coalescing contiguous blocks into a single write.
+    ///
+    /// Returns the messages for the blocks that were written.
+    fn flush_piece<F>(&mut self, fs: F, preallocation: PreallocationMode, piece_index: u32) -> TorrentResult<Vec<PieceMessage>>
+        where F: FileSystem {
+        let mut blocks = self.write_buffer.remove(&piece_index).unwrap_or(Vec::new());
+        blocks.sort_by_key(|&(ref message, _)| message.block_offset());
+        self.write_buffer_bytes -= blocks.iter().map(|&(_, ref block)| block.len()).sum::<usize>();
+
+        let mut piece_accessor = PieceAccessor::with_preallocation(fs, self.metainfo.info(), preallocation);
+        piece_accessor.set_download_location(self.location.clone());
+        let mut run: Option<(u32, Vec<u8>)> = None;
+        let mut messages = Vec::with_capacity(blocks.len());
+
+        for (message, block) in blocks {
+            // Extend the current run if this block starts right where it ends, otherwise write it out and start a new one
+            run = match run {
+                Some((run_offset, mut run_bytes)) if run_offset as usize + run_bytes.len() == message.block_offset() as usize => {
+                    run_bytes.extend_from_slice(&block);
+
+                    Some((run_offset, run_bytes))
+                },
+                Some((run_offset, run_bytes)) => {
+                    try!(piece_accessor.write_piece(&run_bytes[..], &PieceMessage::new(piece_index, run_offset, run_bytes.len())));
+
+                    Some((message.block_offset(), block))
+                },
+                None => Some((message.block_offset(), block))
+            };
+            messages.push(message);
+        }
+
+        if let Some((run_offset, run_bytes)) = run {
+            try!(piece_accessor.write_piece(&run_bytes[..], &PieceMessage::new(piece_index, run_offset, run_bytes.len())));
         }
+
+        Ok(messages)
     }
+
+    /// Sync the newly verified pieces to disk, if the DurabilityMode calls for it.
+    fn sync_verified<F>(&mut self, fs: F, durability: DurabilityMode, pieces: &[u32]) -> TorrentResult<()>
+        where F: FileSystem {
+        match durability {
+            DurabilityMode::None               => Ok(()),
+            DurabilityMode::PerPiece           => self.sync_pieces(fs, pieces),
+            DurabilityMode::Periodic(interval) => {
+                self.unsynced_pieces.extend_from_slice(pieces);
+
+                if self.last_sync.elapsed() >= interval {
+                    self.sync_unsynced(fs)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Sync any verified pieces that are still waiting on a periodic sync.
+    fn sync_unsynced<F>(&mut self, fs: F) -> TorrentResult<()>
+        where F: FileSystem {
+        let pieces = mem::replace(&mut self.unsynced_pieces, Vec::new());
+        self.last_sync = Instant::now();
+
+        self.sync_pieces(fs, &pieces[..])
+    }
+
+    /// Delete every file in the torrent from disk.
+    fn remove_files<F>(&self, fs: F) -> TorrentResult<()>
+        where F: FileSystem {
+        for file in self.metainfo.info().files().filter(|file| !piece_accessor::is_padding_file(file)) {
+            let file_path = self.location.file_path(self.metainfo.info(), file);
+            let fs_file = try!(fs.open_file(Some(&file_path)));
+
+            try!(fs.remove_file(fs_file));
+        }
+
+        Ok(())
+    }
+
+    fn sync_pieces<F>(&self, fs: F, pieces: &[u32]) -> TorrentResult<()>
+        where F: FileSystem {
+        let mut piece_accessor = PieceAccessor::new(fs, self.metainfo.info());
+        piece_accessor.set_download_location(self.location.clone());
+
+        for &piece_index in pieces {
+            let piece_length = piece_size(&self.metainfo, piece_index);
+
+            try!(piece_accessor.sync_piece(&PieceMessage::new(piece_index, 0, piece_length)));
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns an error if adding another torrent would put us over the maximum number of active torrents.
+fn check_max_active(opt_max_active: Option<usize>, active: usize) -> TorrentResult<()> {
+    match opt_max_active {
+        Some(max_active) if active >= max_active => Err(TorrentError::from_kind(TorrentErrorKind::MaxActiveTorrents{ max_active: max_active })),
+        _ => Ok(())
+    }
+}
+
+/// Returns an error if the piece layers, if any, do not cover the same pieces as the torrent, or if
+/// the torrent is a pure v2 torrent, which has nothing but its piece layers to check pieces against.
+fn check_piece_layers(metainfo: &MetainfoFile, opt_layers: Option<&PieceLayers>) -> TorrentResult<()> {
+    let expected_pieces = metainfo.info().pieces().count() as u64;
+
+    match opt_layers.map(|layers| layers.piece_count() as u64) {
+        Some(actual_pieces) if actual_pieces != expected_pieces => {
+            Err(TorrentError::from_kind(TorrentErrorKind::PieceLayersMismatch{ expected_pieces: expected_pieces, actual_pieces: actual_pieces }))
+        },
+        None if metainfo.info().is_v2_only() => {
+            Err(TorrentError::from_kind(TorrentErrorKind::MissingPieceLayers{ hash: metainfo.info_hash() }))
+        },
+        _ => Ok(())
+    }
+}
+
+/// Number of bytes within a piece covered by the given blocks, which may overlap.
+fn covered_bytes<'a, I>(blocks: I) -> usize
+    where I: Iterator<Item = &'a PieceMessage> {
+    let mut ranges: Vec<(usize, usize)> = blocks
+        .map(|block| (block.block_offset() as usize, block.block_offset() as usize + block.block_length()))
+        .collect();
+    ranges.sort();
+
+    let (mut covered, mut covered_end) = (0, 0);
+    for (start, end) in ranges {
+        let start = cmp::max(start, covered_end);
+
+        if end > start {
+            covered += end - start;
+            covered_end = end;
+        }
+    }
+
+    covered
+}
+
+/// Size of the given piece, accounting for the last piece being smaller than the rest.
+fn piece_size(metainfo: &MetainfoFile, piece_index: u32) -> usize {
+    let piece_length = metainfo.info().piece_length() as u64;
+    let total_length: u64 = metainfo.info().files().map(|file| file.length() as u64).sum();
+    let piece_start = piece_index as u64 * piece_length;
+
+    cmp::min(piece_length, total_length.saturating_sub(piece_start)) as usize
 }
 
-impl<F> DiskWorkerContext<F> where F: FileSystem {
-    pub fn new(send: Sender<DiskMessage>, fs: F, clients: Arc<Clients<ReserveBlockClientMetadata>>, blocks: Arc<Blocks>,
-        sync_worker: Sender<SyncBlockMessage>, async_worker: Sender<AsyncBlockMessage>, disk_worker_namespace: Token)
-        -> DiskWorkerContext<F> {
+impl<F> DiskWorkerContext<F> where F: FileSystem + Sync {
+    pub fn new(send: Sender<DiskMessage>, fs: F, config: DiskConfig, clients: Arc<Clients<ReserveBlockClientMetadata>>, blocks: Arc<Blocks>,
+        sync_worker: Sender<SyncBlockMessage>, async_worker: Sender<AsyncBlockMessage>, recorder: Arc<Recorder>, hasher: Arc<PieceHasher>,
+        disk_worker_namespace: Token) -> DiskWorkerContext<F> {
         // Add ourselves to the clients structure, this allows us to request blocks to be reserved
         // from the block worker when we, for example, need to load a block from disk.
         clients.add_client(disk_worker_namespace, Box::new(DiskSender(send)));
 
         DiskWorkerContext {
             fs: fs,
+            preallocation: config.preallocation_mode(),
+            durability: config.durability_mode(),
+            verification: config.verification_order(),
+            location: config.download_location().clone(),
+            max_active: config.max_active_torrents(),
+            verify_complete: config.verify_on_complete(),
+            cache: PieceCache::new(config.read_cache_size()),
             torrents: RwLock::new(HashMap::new()),
             clients: clients,
             blocks: blocks,
+            allocator: Arc::new(BlockAllocator::new(disk::DEFAULT_BLOCK_SIZE, disk::DISK_MANAGER_MAX_FREE_BLOCKS)),
             sync_worker: sync_worker,
             async_worker: async_worker,
+            recorder: recorder,
+            hasher: hasher,
             namespace_token: disk_worker_namespace
         }
     }
 
-    pub fn add_torrent(&self, namespace: Token, metainfo: MetainfoFile) {
+    pub fn add_torrent(&self, namespace: Token, metainfo: MetainfoFile, priorities: FilePriorities, opt_layers: Option<PieceLayers>) {
         let hash = metainfo.info_hash();
-
-        let res_checker_state = PieceChecker::new(&self.fs, metainfo.info())
-            .and_then(|checker| checker.calculate_diff())
+        let piece_layers = opt_layers.map(Arc::new);
+
+        // Check the limit up front so that we don't allocate or hash files for a torrent we would reject anyway
+        let active = self.torrents.read().expect("bip_peer: Failed To Get Read Lock On Torrents Map").len();
+        let res_checker_state = check_max_active(self.max_active, active)
+            .and_then(|_| check_piece_layers(&metainfo, piece_layers.as_ref().map(|layers| &**layers)))
+            .and_then(|_| PieceChecker::with_priorities(&self.fs, metainfo.info(), priorities, self.preallocation, self.location.clone()))
+            .and_then(|mut checker| {
+                checker.set_allocator(self.allocator.clone());
+                checker.set_hasher(self.hasher.clone());
+                checker.set_verification_order(self.verification);
+                checker.set_piece_layers(piece_layers.clone());
+
+                checker.calculate_diff_parallel(disk::DISK_MANAGER_HASHING_THREADS, |_, _| ())
+            })
             .and_then(|checker_state| {
-                let torrent_entry = TorrentEntry::new(metainfo, checker_state, namespace);
+                let torrent_entry = TorrentEntry::new(metainfo, self.location.clone(), checker_state, namespace, piece_layers);
 
                 self.insert_torrent_entry(torrent_entry)
             });
@@ -89,31 +334,61 @@ impl<F> DiskWorkerContext<F> where F: FileSystem {
                             &PieceState::Bad(_)      => ()
                         }
                     });
+
+                    if entry.checker_state.is_complete() {
+                        self.clients.message_client(namespace, ODiskMessage::TorrentComplete(hash));
+                    }
                 });
             },
             Err(torrent_error) => self.clients.message_client(namespace, ODiskMessage::TorrentError(torrent_error))
         }
     }
 
-    pub fn remove_torrent(&self, namespace: Token, hash: InfoHash) {
+    pub fn remove_torrent(&self, namespace: Token, hash: InfoHash, delete_files: bool) {
+        self.cache.remove_torrent(hash);
+
         match self.remove_torrent_entry(hash) {
-            Ok(_)              => (),
+            Ok(mut entry) => {
+                // Pieces waiting on a periodic sync won't get another chance once the torrent is gone
+                let result = if delete_files {
+                    entry.remove_files(&self.fs)
+                } else {
+                    entry.sync_unsynced(&self.fs)
+                };
+
+                if let Err(torrent_error) = result {
+                    self.clients.message_client(namespace, ODiskMessage::TorrentError(torrent_error));
+                }
+                // Even if we failed to sync or remove files, we are no longer tracking the torrent
+                self.clients.message_client(namespace, ODiskMessage::TorrentRemoved(hash));
+            },
             Err(torrent_error) => self.clients.message_client(namespace, ODiskMessage::TorrentError(torrent_error))
         }
     }
 
+    pub fn list_torrents(&self, namespace: Token) {
+        let hashes = self.torrents.read()
+            .expect("bip_peer: Failed To Get Read Lock On Torrents Map")
+            .keys()
+            .cloned()
+            .collect();
+
+        self.clients.message_client(namespace, ODiskMessage::TorrentList(hashes));
+    }
+
     pub fn load_block(&self, namespace: Token, request: Token, hash: InfoHash, piece_msg: PieceMessage) {
         self.sync_worker.send(SyncBlockMessage::ReserveBlock(self.namespace_token, namespace, request, hash, piece_msg));
     }
 
     pub fn process_block(&self, namespace: Token, request: Token) {
+        let start = Instant::now();
         let metadata = self.clients.remove_metadata(namespace, request);
         let (hash, piece_message) = (metadata.hash, metadata.message);
 
         // Well, the API I spent so long on, Blocks, is useless since we eventually have to pass
         // a mutable reference to a byte array (which most OS's require, barring using a smallish
         // buffer to transfer data from disk). Big TODO here...
-        let mut buffer = vec![0u8; piece_message.block_length()];
+        let mut buffer = self.allocator.allocate(piece_message.block_length());
         (*self.blocks).access_block(namespace, request, |buffers| {
             let mut bytes_read = 0;
 
@@ -124,61 +399,187 @@ impl<F> DiskWorkerContext<F> where F: FileSystem {
             });
         });
 
+        // Blocks are written out once we have their whole piece, so contiguous blocks can share a single write
+        let mut opt_block = Some(buffer[..].to_vec());
+
         // TODO: Handle fs failures
         self.access_torrent_entry_mut(&hash, |mut entry| {
-            let piece_accessor = PieceAccessor::new(&self.fs, entry.metainfo.info());
-
-            piece_accessor.write_piece(&buffer[..], &piece_message)
-                .expect("bip_peer: Failed To Write Piece To Disk");
-
-            // Add piece message to piece checker state
-            entry.checker_state.add_pending_block(piece_message);
+            let flush_pieces = entry.buffer_block(piece_message, opt_block.take().unwrap());
+            if flush_pieces.is_empty() {
+                return;
+            }
+
+            for piece_index in flush_pieces {
+                self.cache.remove(hash, piece_index);
+
+                match entry.flush_piece(&self.fs, self.preallocation, piece_index) {
+                    Ok(messages) => {
+                        // Add piece messages to piece checker state
+                        for message in messages {
+                            entry.checker_state.add_pending_block(message);
+                        }
+                    },
+                    Err(torrent_error) => {
+                        // Files may have been modified underneath us, let the client decide what to do
+                        self.clients.message_client(entry.client_namespace, ODiskMessage::TorrentError(torrent_error));
+                        return;
+                    }
+                }
+            }
 
             // Its more efficient to swap here, otherwise, we would have to take a write
             // lock on the outer HashMap to remove, then again to add this back.
             let checker_state = mem::replace(&mut entry.checker_state, PieceCheckerState::new(0, 0));
-            let piece_checker = PieceChecker::with_state(&self.fs, entry.metainfo.info(), checker_state);
+            let mut piece_checker = PieceChecker::with_state(&self.fs, entry.metainfo.info(), checker_state);
+            piece_checker.set_allocator(self.allocator.clone());
+            piece_checker.set_hasher(self.hasher.clone());
+            piece_checker.set_preallocation_mode(self.preallocation);
+            piece_checker.set_verification_order(self.verification);
+            piece_checker.set_download_location(entry.location.clone());
+            piece_checker.set_piece_layers(entry.piece_layers.clone());
             
             // TODO: Handle failure here
             let mut new_checker_state = piece_checker.calculate_diff()
                 .expect("bip_peer: Failed To Access Disk For Hashing");
             
+            let mut good_pieces = Vec::new();
             new_checker_state.run_with_diff(|piece_state| {
-                // Since this is the initial diff, don't let clients know of bad pieces since these were reloaded from disk
                 match piece_state {
-                    &PieceState::Good(index) => self.clients.message_client(entry.client_namespace, ODiskMessage::FoundGoodPiece(hash, index)),
-                    &PieceState::Bad(index)  => self.clients.message_client(entry.client_namespace, ODiskMessage::FoundBadPiece(hash, index))
+                    &PieceState::Good(index) => {
+                        self.recorder.incr(Metric::PieceGood);
+                        good_pieces.push(index)
+                    },
+                    &PieceState::Bad(index)  => {
+                        self.recorder.incr(Metric::PieceBad);
+                        self.clients.message_client(entry.client_namespace, ODiskMessage::FoundBadPiece(hash, index))
+                    }
                 }
             });
 
             entry.checker_state = new_checker_state;
+
+            // Pieces written earlier may have been corrupted since they were verified, so make sure before calling it complete
+            if self.verify_complete && !good_pieces.is_empty() && entry.checker_state.is_complete() {
+                match self.verify_all_pieces(&mut entry) {
+                    Ok(bad_pieces) => {
+                        for index in bad_pieces {
+                            good_pieces.retain(|&good_index| good_index != index);
+                            self.clients.message_client(entry.client_namespace, ODiskMessage::FoundBadPiece(hash, index));
+                        }
+                    },
+                    Err(torrent_error) => {
+                        self.clients.message_client(entry.client_namespace, ODiskMessage::TorrentError(torrent_error));
+                    }
+                }
+            }
+
+            // Sync before announcing good pieces, so clients don't pass along pieces we could lose on a crash
+            if let Err(torrent_error) = entry.sync_verified(&self.fs, self.durability, &good_pieces[..]) {
+                self.clients.message_client(entry.client_namespace, ODiskMessage::TorrentError(torrent_error));
+            }
+            let found_good = !good_pieces.is_empty();
+            for index in good_pieces {
+                self.clients.message_client(entry.client_namespace, ODiskMessage::FoundGoodPiece(hash, index));
+            }
+
+            // Only the diff that found the last good piece can complete the torrent, so this is sent once
+            if found_good && entry.checker_state.is_complete() {
+                self.clients.message_client(entry.client_namespace, ODiskMessage::TorrentComplete(hash));
+            }
         });
 
         // Reclaim the block
         self.async_worker.send(AsyncBlockMessage::ReclaimBlock(namespace, request));
+
+        self.recorder.record_duration(Metric::DiskProcess, start.elapsed());
     }
 
     pub fn block_reserved(&self, namespace: Token, request: Token) {
+        let start = Instant::now();
         let metadata = self.clients.remove_metadata(namespace, request);
         let (hash, piece_message) = (metadata.hash, metadata.message);
-        
+
+        let mut read_result = Ok(());
+        let opt_piece = if self.cache.is_enabled() {
+            self.cache.get(hash, piece_message.piece_index()).or_else(|| {
+                // Read in the whole piece, since peers will likely be requesting the rest of it soon
+                let mut opt_piece_bytes = None;
+
+                self.access_torrent_entry(&hash, |entry| {
+                    let mut piece_accessor = PieceAccessor::with_preallocation(&self.fs, entry.metainfo.info(), self.preallocation);
+                    piece_accessor.set_download_location(entry.location.clone());
+                    piece_accessor.set_verify_reads(entry.verify_reads);
+                    piece_accessor.set_hasher(self.hasher.clone());
+                    piece_accessor.set_piece_layers(entry.piece_layers.clone());
+                    let piece_length = piece_size(&entry.metainfo, piece_message.piece_index());
+
+                    let mut piece_bytes = vec![0u8; piece_length];
+                    read_result = piece_accessor.read_piece(&mut piece_bytes[..], &PieceMessage::new(piece_message.piece_index(), 0, piece_length));
+
+                    if read_result.is_ok() {
+                        opt_piece_bytes = Some(piece_bytes);
+                    }
+                });
+
+                opt_piece_bytes.map(|piece_bytes| self.cache.insert(hash, piece_message.piece_index(), piece_bytes))
+            })
+        } else {
+            None
+        };
+
         // Well, the API I spent so long on, Blocks, is useless since we eventually have to pass
         // a mutable reference to a byte array (which most OS's require, barring using a smallish
         // buffer to transfer data from disk). Big TODO here...
-        let mut buffer = vec![0u8; piece_message.block_length()];
-        (*self.blocks).access_block(namespace, request, |mut buffers| {
-                buffers.write(&buffer[..]);
-        });
+        let mut buffer = self.allocator.allocate(piece_message.block_length());
+        match opt_piece {
+            Some(piece) => {
+                let block_start = piece_message.block_offset() as usize;
 
-        // TODO: Handle fs failures
-        self.access_torrent_entry(&hash, |entry| {
-            let piece_accessor = PieceAccessor::new(&self.fs, entry.metainfo.info());
+                buffer.copy_from_slice(&piece[block_start..block_start + piece_message.block_length()]);
+            },
+            None if read_result.is_ok() => {
+                self.access_torrent_entry(&hash, |entry| {
+                    let mut piece_accessor = PieceAccessor::with_preallocation(&self.fs, entry.metainfo.info(), self.preallocation);
+                    piece_accessor.set_download_location(entry.location.clone());
+                    piece_accessor.set_verify_reads(entry.verify_reads);
+                    piece_accessor.set_hasher(self.hasher.clone());
+                    piece_accessor.set_piece_layers(entry.piece_layers.clone());
+
+                    read_result = piece_accessor.read_piece(&mut buffer[..], &piece_message);
+                });
+            },
+            None => ()
+        }
 
-            piece_accessor.read_piece(&mut buffer[..], &piece_message)
-                .expect("bip_peer: Failed To Read Piece From Disk");
-        });
+        match read_result {
+            Ok(()) => {
+                (*self.blocks).access_block(namespace, request, |mut buffers| {
+                        buffers.write(&buffer[..]);
+                });
+
+                self.recorder.record_duration(Metric::DiskLoad, start.elapsed());
+                self.clients.message_client(namespace, ODiskMessage::BlockLoaded(namespace, request));
+            },
+            // Rather than serve the peer data we can't vouch for, give the block back and let the client know it failed
+            Err(torrent_error) => {
+                self.async_worker.send(AsyncBlockMessage::ReclaimBlock(namespace, request));
+
+                self.clients.message_client(namespace, ODiskMessage::BlockLoadFailed(namespace, request, torrent_error));
+            }
+        }
+    }
+
+    pub fn set_verify_reads(&self, hash: InfoHash, verify_reads: bool) {
+        let read_torrents = self.torrents.read()
+            .expect("bip_peer: Failed To Get Read Lock On Torrents Map");
+
+        // The torrent may have been removed in the meantime, in which case there is nothing to do
+        if let Some(entry) = read_torrents.get(&hash) {
+            entry.lock().expect("bip_peer: Failed To Lock Torrent Entry In Map").verify_reads = verify_reads;
 
-        self.clients.message_client(namespace, ODiskMessage::BlockLoaded(namespace, request));
+            // Pieces already in the cache may have been read without being verified
+            self.cache.remove_torrent(hash);
+        }
     }
 
     pub fn request_error(&self, _request_error: RequestError) {
@@ -198,6 +599,35 @@ impl<F> DiskWorkerContext<F> where F: FileSystem {
         callback(&mut write_torrent);
     }
 
+    /// Hash every piece in the torrent again, returning the pieces that are no longer good.
+    ///
+    /// If the pieces could not be hashed, the checker state of the torrent is left as it was.
+    fn verify_all_pieces(&self, entry: &mut TorrentEntry) -> TorrentResult<Vec<u32>> {
+        let mut checker_state = entry.checker_state.clone();
+        checker_state.recheck_all(entry.metainfo.info().piece_length() as usize);
+
+        let mut piece_checker = PieceChecker::with_state(&self.fs, entry.metainfo.info(), checker_state);
+        piece_checker.set_allocator(self.allocator.clone());
+        piece_checker.set_hasher(self.hasher.clone());
+        piece_checker.set_preallocation_mode(self.preallocation);
+        piece_checker.set_verification_order(self.verification);
+        piece_checker.set_download_location(entry.location.clone());
+        piece_checker.set_piece_layers(entry.piece_layers.clone());
+
+        let mut checker_state = try!(piece_checker.calculate_diff());
+
+        let mut bad_pieces = Vec::new();
+        checker_state.run_with_diff(|piece_state| {
+            if let &PieceState::Bad(index) = piece_state {
+                self.recorder.incr(Metric::PieceBad);
+                bad_pieces.push(index);
+            }
+        });
+        entry.checker_state = checker_state;
+
+        Ok(bad_pieces)
+    }
+
     fn access_torrent_entry<C>(&self, hash: &InfoHash, mut callback: C)
         where C: FnMut(&TorrentEntry) {
         let read_torrents = self.torrents.read()
@@ -214,6 +644,8 @@ impl<F> DiskWorkerContext<F> where F: FileSystem {
         let mut write_torrents = self.torrents.write()
             .expect("bip_peer: Failed To Get Write Lock On Torrents Map");
         let hash = entry.metainfo.info_hash();
+        // Another torrent may have been added while this one was being checked
+        try!(check_max_active(self.max_active, write_torrents.len()));
 
         match write_torrents.entry(hash) {
             Entry::Vacant(vac) => {
@@ -224,12 +656,12 @@ impl<F> DiskWorkerContext<F> where F: FileSystem {
         }
     }
 
-    fn remove_torrent_entry(&self, hash: InfoHash) -> TorrentResult<()> {
+    fn remove_torrent_entry(&self, hash: InfoHash) -> TorrentResult<TorrentEntry> {
         let mut write_torrents = self.torrents.write()
             .expect("bip_peer: Failed To Get Write Lock On Torrents Map");
 
         write_torrents.remove(&hash)
-            .map(|_| ())
+            .map(|entry| entry.into_inner().expect("bip_peer: Failed To Lock Torrent Entry In Map"))
             .ok_or(TorrentError::from_kind(TorrentErrorKind::InfoHashNotFound{ hash: hash }))
     }
 }
@@ -257,4 +689,99 @@ impl TrySender<ODiskMessage> for DiskSender {
 
         None
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use bip_bencode::Bencode;
+    use bip_metainfo::MetainfoFile;
+
+    use disk::location::DownloadLocation;
+    use disk::worker::disk_worker::piece_checker::PieceCheckerState;
+    use message::standard::PieceMessage;
+    use token::TokenGenerator;
+    use super::TorrentEntry;
+
+    const PIECE_LENGTH: usize = 8;
+    const BLOCK_LENGTH: usize = 4;
+    const NUM_PIECES: usize = 2;
+
+    fn create_metainfo() -> MetainfoFile {
+        let mut file_dict = BTreeMap::new();
+        file_dict.insert(&b"length"[..], ben_int!((NUM_PIECES * PIECE_LENGTH) as i64));
+        file_dict.insert(&b"path"[..], Bencode::List(vec![ben_bytes!(&b"file"[..])]));
+
+        let pieces = vec![0u8; NUM_PIECES * 20];
+        let mut info_dict = BTreeMap::new();
+        info_dict.insert(&b"name"[..], ben_bytes!(&b"test"[..]));
+        info_dict.insert(&b"piece length"[..], ben_int!(PIECE_LENGTH as i64));
+        info_dict.insert(&b"pieces"[..], ben_bytes!(&pieces[..]));
+        info_dict.insert(&b"files"[..], Bencode::List(vec![Bencode::Dict(file_dict)]));
+
+        let mut root_dict = BTreeMap::new();
+        root_dict.insert(&b"announce"[..], ben_bytes!(&b"udp://localhost:6969"[..]));
+        root_dict.insert(&b"info"[..], Bencode::Dict(info_dict));
+
+        MetainfoFile::from_bytes(Bencode::Dict(root_dict).encode()).unwrap()
+    }
+
+    fn create_entry(checker_state: PieceCheckerState) -> TorrentEntry {
+        TorrentEntry::new(create_metainfo(), DownloadLocation::default(), checker_state, TokenGenerator::new().generate(), None)
+    }
+
+    fn block(piece_index: u32, block: usize) -> (PieceMessage, Vec<u8>) {
+        (PieceMessage::new(piece_index, (block * BLOCK_LENGTH) as u32, BLOCK_LENGTH), vec![0u8; BLOCK_LENGTH])
+    }
+
+    #[test]
+    fn positive_buffer_block_flushes_complete_piece() {
+        let mut entry = create_entry(PieceCheckerState::new(NUM_PIECES, PIECE_LENGTH));
+
+        let (message, bytes) = block(0, 0);
+        assert!(entry.buffer_block(message, bytes).is_empty());
+
+        let (message, bytes) = block(0, 1);
+        assert_eq!(vec![0], entry.buffer_block(message, bytes));
+    }
+
+    #[test]
+    fn positive_buffer_block_completes_partially_flushed_piece() {
+        let mut checker_state = PieceCheckerState::new(NUM_PIECES, PIECE_LENGTH);
+        // First half of the piece was already written out, as it would be on overflowing the write buffer
+        checker_state.add_pending_block(block(0, 0).0);
+        let mut entry = create_entry(checker_state);
+
+        let (message, bytes) = block(0, 1);
+        assert_eq!(vec![0], entry.buffer_block(message, bytes));
+    }
+
+    #[test]
+    fn negative_buffer_block_drops_duplicate_of_flushed_block() {
+        let mut checker_state = PieceCheckerState::new(NUM_PIECES, PIECE_LENGTH);
+        checker_state.add_pending_block(block(0, 0).0);
+        let mut entry = create_entry(checker_state);
+
+        let (message, bytes) = block(0, 0);
+        assert!(entry.buffer_block(message, bytes).is_empty());
+        assert!(entry.write_buffer.is_empty());
+        assert_eq!(0, entry.write_buffer_bytes);
+    }
+
+    #[test]
+    fn negative_buffer_block_drops_block_of_good_piece() {
+        let metainfo = create_metainfo();
+        let mut resume_dict = BTreeMap::new();
+        resume_dict.insert(&b"info_hash"[..], ben_bytes!(metainfo.info_hash().as_ref()));
+        resume_dict.insert(&b"good_pieces"[..], Bencode::List(vec![ben_int!(0)]));
+        let resume_bytes = Bencode::Dict(resume_dict).encode();
+
+        let checker_state = PieceCheckerState::from_resume_bytes(&resume_bytes, metainfo.info_hash(), NUM_PIECES, PIECE_LENGTH).unwrap();
+        let mut entry = create_entry(checker_state);
+
+        let (message, bytes) = block(0, 1);
+        assert!(entry.buffer_block(message, bytes).is_empty());
+        assert!(entry.write_buffer.is_empty());
+        assert_eq!(0, entry.write_buffer_bytes);
+    }
+}
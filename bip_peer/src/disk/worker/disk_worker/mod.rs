@@ -8,18 +8,26 @@ use disk::worker::shared::clients::Clients;
 use disk::worker::{ReserveBlockClientMetadata, SyncBlockMessage, AsyncBlockMessage, DiskMessage};
 use disk::worker::disk_worker::context::DiskWorkerContext;
 use disk::fs::{FileSystem};
+use disk::config::DiskConfig;
+use disk::hasher::PieceHasher;
 use disk;
+use metrics::Recorder;
 use token::{Token};
 
+mod cache;
 mod context;
 mod piece_checker;
 mod piece_accessor;
 
-pub fn spawn_disk_worker<F>(fs: F, clients: Arc<Clients<ReserveBlockClientMetadata>>, blocks: Arc<Blocks>, sync_worker: Sender<SyncBlockMessage>,
-    async_worker: Sender<AsyncBlockMessage>, disk_worker_namespace: Token) -> Sender<DiskMessage> where F: FileSystem + Send + Sync + 'static {
+pub use disk::worker::disk_worker::piece_checker::{expected_hashes, ExpectedHashes};
+
+pub fn spawn_disk_worker<F>(fs: F, config: DiskConfig, clients: Arc<Clients<ReserveBlockClientMetadata>>, blocks: Arc<Blocks>, sync_worker: Sender<SyncBlockMessage>,
+    async_worker: Sender<AsyncBlockMessage>, recorder: Arc<Recorder>, hasher: Arc<PieceHasher>, disk_worker_namespace: Token) -> Sender<DiskMessage>
+    where F: FileSystem + Send + Sync + 'static {
     let (send, recv) = chan::async();
 
-    let disk_context = Arc::new(DiskWorkerContext::new(send.clone(), fs, clients, blocks, sync_worker, async_worker, disk_worker_namespace));
+    let disk_context = Arc::new(DiskWorkerContext::new(send.clone(), fs, config, clients, blocks, sync_worker, async_worker, recorder, hasher,
+        disk_worker_namespace));
 
     for _ in 0..disk::DISK_MANAGER_WORKER_THREADS {
         let clone_disk_context = disk_context.clone();
@@ -28,12 +36,14 @@ pub fn spawn_disk_worker<F>(fs: F, clients: Arc<Clients<ReserveBlockClientMetada
         thread::spawn(move || {
             for msg in clone_recv {
                 match msg {
-                    DiskMessage::AddTorrent(namespace, metainfo)                => clone_disk_context.add_torrent(namespace, metainfo),
-                    DiskMessage::RemoveTorrent(namespace, hash)                 => clone_disk_context.remove_torrent(namespace, hash),
-                    DiskMessage::LoadBlock(namespace, request, hash, piece_msg) => clone_disk_context.load_block(namespace, request, hash, piece_msg),
-                    DiskMessage::ProcessBlock(namespace, request)               => clone_disk_context.process_block(namespace, request),
-                    DiskMessage::BlockReserved(namespace, request)              => clone_disk_context.block_reserved(namespace, request),
-                    DiskMessage::RequestError(request_error)                    => clone_disk_context.request_error(request_error)
+                    DiskMessage::AddTorrent(namespace, metainfo, priorities, layers) => clone_disk_context.add_torrent(namespace, metainfo, priorities, layers),
+                    DiskMessage::RemoveTorrent(namespace, hash, delete_files)        => clone_disk_context.remove_torrent(namespace, hash, delete_files),
+                    DiskMessage::ListTorrents(namespace)                             => clone_disk_context.list_torrents(namespace),
+                    DiskMessage::SetVerifyReads(hash, verify_reads)                  => clone_disk_context.set_verify_reads(hash, verify_reads),
+                    DiskMessage::LoadBlock(namespace, request, hash, piece_msg)      => clone_disk_context.load_block(namespace, request, hash, piece_msg),
+                    DiskMessage::ProcessBlock(namespace, request)                    => clone_disk_context.process_block(namespace, request),
+                    DiskMessage::BlockReserved(namespace, request)                   => clone_disk_context.block_reserved(namespace, request),
+                    DiskMessage::RequestError(request_error)                         => clone_disk_context.request_error(request_error)
                 }
             }
         });
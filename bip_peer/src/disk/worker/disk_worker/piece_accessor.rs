@@ -1,51 +1,313 @@
 use std::cmp;
+use std::ffi::OsStr;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use bip_metainfo::{InfoDictionary, File};
+use bip_util::sha::ShaHash;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 
-use disk::error::{TorrentResult};
+use disk::DEFAULT_BLOCK_SIZE;
+use disk::error::{TorrentResult, TorrentError, TorrentErrorKind};
 use disk::fs::{FileSystem};
+use disk::hasher::{PieceHasher, ShaPieceHasher};
+use disk::layers::{PieceLayers, MERKLE_BLOCK_SIZE, SHA256_HASH_LEN};
+use disk::location::DownloadLocation;
+use disk::preallocation::PreallocationMode;
+use disk::worker::disk_worker::piece_checker;
 use message::standard::PieceMessage;
 
 pub struct PieceAccessor<'a, F> {
     fs: F,
-    info_dict: &'a InfoDictionary
+    info_dict: &'a InfoDictionary,
+    preallocation: PreallocationMode,
+    location: DownloadLocation,
+    verify_reads: bool,
+    hasher: Arc<PieceHasher>,
+    piece_layers: Option<Arc<PieceLayers>>
 }
 
 impl<'a, F> PieceAccessor<'a, F> where F: FileSystem {
     pub fn new(fs: F, info_dict: &'a InfoDictionary) -> PieceAccessor<'a, F> {
+        PieceAccessor::with_preallocation(fs, info_dict, PreallocationMode::default())
+    }
+
+    /// Create a new PieceAccessor for files that were allocated using the given PreallocationMode.
+    ///
+    /// If files were not preallocated, they are allowed to be smaller than expected, and any bytes
+    /// past the end of a file are read as zeroes.
+    pub fn with_preallocation(fs: F, info_dict: &'a InfoDictionary, preallocation: PreallocationMode) -> PieceAccessor<'a, F> {
         PieceAccessor{
             fs: fs,
-            info_dict: info_dict
+            info_dict: info_dict,
+            preallocation: preallocation,
+            location: DownloadLocation::default(),
+            verify_reads: false,
+            hasher: Arc::new(ShaPieceHasher),
+            piece_layers: None
         }
     }
 
+    /// Sets where the files for the torrent are located within the file system.
+    pub fn set_download_location(&mut self, location: DownloadLocation) {
+        self.location = location;
+    }
+
+    /// Sets whether or not the whole piece is hashed and checked against the info dictionary on every read.
+    ///
+    /// This catches corruption that happened on disk after the piece was verified, at the
+    /// cost of reading in the whole piece when only part of it was asked for.
+    pub fn set_verify_reads(&mut self, verify_reads: bool) {
+        self.verify_reads = verify_reads;
+    }
+
+    /// Sets the hasher used to hash pieces.
+    pub fn set_hasher(&mut self, hasher: Arc<PieceHasher>) {
+        self.hasher = hasher;
+    }
+
+    /// Sets the piece layers that pieces are checked against, instead of the hashes in the info dictionary.
+    pub fn set_piece_layers(&mut self, piece_layers: Option<Arc<PieceLayers>>) {
+        self.piece_layers = piece_layers;
+    }
+
     pub fn read_piece(&self, piece_buffer: &mut [u8], message: &PieceMessage) -> TorrentResult<()> {
-        self.run_with_file_regions(message, |mut file, offset, begin, end| {
-            let bytes_read = try!(self.fs.read_file(&mut file, offset, &mut piece_buffer[begin..end]));
-            assert_eq!(bytes_read, end - begin);
+        try!(self.read_region(piece_buffer, message));
+
+        if self.verify_reads {
+            try!(self.verify_read(piece_buffer, message));
+        }
+
+        Ok(())
+    }
+
+    /// Check that the piece the message falls in still matches its hash, re-using the bytes we already read if
+    /// they make up the whole piece.
+    fn verify_read(&self, piece_buffer: &[u8], message: &PieceMessage) -> TorrentResult<()> {
+        let piece_index = message.piece_index();
+        let is_last_piece = piece_index as usize == self.info_dict.pieces().count() - 1;
+        let piece_length = if is_last_piece {
+            piece_checker::last_piece_size(self.info_dict)
+        } else {
+            self.info_dict.piece_length() as usize
+        };
+
+        let mut chunk_buffer = vec![0u8; cmp::min(piece_length, DEFAULT_BLOCK_SIZE)];
+        let is_good = match try!(self.check_piece_layers(&mut chunk_buffer[..], piece_index)) {
+            Some(is_good) => is_good,
+            None => {
+                let calculated_hash = if message.block_offset() == 0 && message.block_length() == piece_length {
+                    ShaHash::from(self.hasher.hash(piece_buffer))
+                } else {
+                    try!(self.hash_piece(&mut chunk_buffer[..], &PieceMessage::new(piece_index, 0, piece_length)))
+                };
+
+                piece_checker::verify_piece(self.info_dict, piece_index, calculated_hash)
+            }
+        };
 
+        if is_good {
             Ok(())
+        } else {
+            Err(TorrentError::from_kind(TorrentErrorKind::ReadHashMismatch{ piece_index: piece_index }))
+        }
+    }
+
+    /// Read the region given by the message, without any verification.
+    fn read_region(&self, piece_buffer: &mut [u8], message: &PieceMessage) -> TorrentResult<()> {
+        self.run_with_file_regions(message, |opt_file, region| {
+            let region_buffer = &mut piece_buffer[region.begin..region.end];
+            let mut file = match opt_file {
+                Some(file) => file,
+                None       => return Ok(zero_fill(region_buffer))
+            };
+            let mut bytes_read = try!(read_fully(&self.fs, &mut file, region.offset, &mut region_buffer[..]));
+
+            // Region hasn't been written out yet, so there is nothing there
+            if self.preallocation == PreallocationMode::None {
+                zero_fill(&mut region_buffer[bytes_read..]);
+                bytes_read = region_buffer.len();
+            }
+
+            self.check_region_accessed(&file, region, bytes_read)
         })
     }
 
+    /// Read the bytes starting at the given offset into the torrent's content (all files concatenated together).
+    ///
+    /// Returns an error if the range extends past the end of the torrent, or if any
+    /// piece covering the range is not good according to the given closure.
+    pub fn read_range<G>(&self, offset: u64, buffer: &mut [u8], is_good: G) -> TorrentResult<()>
+        where G: Fn(u32) -> bool {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        let piece_length = self.info_dict.piece_length() as u64;
+        let length = buffer.len() as u64;
+
+        let total_length: u64 = self.info_dict.files().map(|file| file.length() as u64).sum();
+        if offset + length > total_length {
+            return Err(TorrentError::from_kind(TorrentErrorKind::InvalidRange{
+                offset: offset,
+                length: length,
+                total_length: total_length
+            }));
+        }
+
+        let (first_piece, last_piece) = ((offset / piece_length) as u32, ((offset + length - 1) / piece_length) as u32);
+        if let Some(piece_index) = (first_piece..last_piece + 1).find(|index| !is_good(*index)) {
+            return Err(TorrentError::from_kind(TorrentErrorKind::UnverifiedRange{
+                offset: offset,
+                length: length,
+                piece_index: piece_index
+            }));
+        }
+
+        self.run_with_content_regions(offset, length, |opt_file, region| {
+            let region_buffer = &mut buffer[region.begin..region.end];
+            let mut file = match opt_file {
+                Some(file) => file,
+                None       => return Ok(zero_fill(region_buffer))
+            };
+            let bytes_read = try!(read_fully(&self.fs, &mut file, region.offset, &mut region_buffer[..]));
+
+            self.check_region_accessed(&file, region, bytes_read)
+        })
+    }
+
+    /// Hash the region given by the message, reading at most chunk_buffer.len() bytes into memory at a time.
+    pub fn hash_piece(&self, chunk_buffer: &mut [u8], message: &PieceMessage) -> TorrentResult<ShaHash> {
+        let mut piece_hash = self.hasher.begin();
+
+        let mut bytes_hashed = 0;
+        while bytes_hashed < message.block_length() {
+            let chunk_length = cmp::min(chunk_buffer.len(), message.block_length() - bytes_hashed);
+            let chunk_message = PieceMessage::new(message.piece_index(), message.block_offset() + bytes_hashed as u32, chunk_length);
+
+            try!(self.read_region(&mut chunk_buffer[..chunk_length], &chunk_message));
+            piece_hash.update(&chunk_buffer[..chunk_length]);
+
+            bytes_hashed += chunk_length;
+        }
+
+        Ok(ShaHash::from(piece_hash.finish()))
+    }
+
+    /// Check the file data in the given piece against the piece layers, reading at most chunk_buffer.len() bytes into memory at a time.
+    ///
+    /// Returns None if we were not given piece layers, in which case the piece should be checked against its v1 hash.
+    pub fn check_piece_layers(&self, chunk_buffer: &mut [u8], piece_index: u32) -> TorrentResult<Option<bool>> {
+        let piece_layers = match self.piece_layers {
+            Some(ref piece_layers) => piece_layers,
+            None => return Ok(None)
+        };
+        let data_length = match piece_layers.piece_data_length(piece_index) {
+            Some(data_length) => data_length,
+            None => return Ok(Some(false))
+        };
+
+        let mut block_hashes = Vec::with_capacity((data_length + MERKLE_BLOCK_SIZE - 1) / MERKLE_BLOCK_SIZE);
+        let mut block_hash = Sha256::new();
+        let mut bytes_hashed = 0;
+        while bytes_hashed < data_length {
+            // Never let a chunk cross into the next block, so each block is hashed on its own
+            let block_remaining = MERKLE_BLOCK_SIZE - bytes_hashed % MERKLE_BLOCK_SIZE;
+            let chunk_length = cmp::min(cmp::min(chunk_buffer.len(), block_remaining), data_length - bytes_hashed);
+            let chunk_message = PieceMessage::new(piece_index, bytes_hashed as u32, chunk_length);
+
+            try!(self.read_region(&mut chunk_buffer[..chunk_length], &chunk_message));
+            block_hash.input(&chunk_buffer[..chunk_length]);
+            bytes_hashed += chunk_length;
+
+            if bytes_hashed % MERKLE_BLOCK_SIZE == 0 || bytes_hashed == data_length {
+                let mut hash = [0u8; SHA256_HASH_LEN];
+                block_hash.result(&mut hash);
+                block_hash.reset();
+
+                block_hashes.push(hash);
+            }
+        }
+
+        Ok(Some(piece_layers.verify_piece(piece_index, &block_hashes)))
+    }
+
     pub fn write_piece(&self, piece_buffer: &[u8], message: &PieceMessage) -> TorrentResult<()> {
-        self.run_with_file_regions(message, |mut file, offset, begin, end| {
-            let bytes_written = try!(self.fs.write_file(&mut file, offset, &piece_buffer[begin..end]));
-            assert_eq!(bytes_written, end - begin);
+        self.run_with_file_regions(message, |opt_file, region| {
+            // Padding is never written out, peers should only ever send us zeroes for it anyways
+            let mut file = match opt_file {
+                Some(file) => file,
+                None       => return Ok(())
+            };
+            let bytes_written = try!(self.fs.write_file(&mut file, region.offset, &piece_buffer[region.begin..region.end]));
+
+            self.check_region_accessed(&file, region, bytes_written)
+        })
+    }
+
+    /// Sync every file that the region given by the message falls in to stable storage.
+    pub fn sync_piece(&self, message: &PieceMessage) -> TorrentResult<()> {
+        self.run_with_file_regions(message, |opt_file, _| {
+            if let Some(mut file) = opt_file {
+                try!(self.fs.sync_file(&mut file));
+            }
 
             Ok(())
         })
     }
 
-    /// Run the given closure with the file, the file offset, and the read/write buffer stard (inclusive) and end (exclusive) indices.
-    /// TODO: We do not detect when/if the file size changes after the initial file size check, so the returned number of 
-    fn run_with_file_regions<C>(&self, message: &PieceMessage, mut callback: C) -> TorrentResult<()>
-        where C: FnMut(F::File, u64, usize, usize) -> TorrentResult<()> {
+    /// Make sure the whole region was accessed, otherwise the file was likely truncated (or grew) underneath us.
+    ///
+    /// If the file size no longer matches what we expect, an error is returned with the file size we see now,
+    /// otherwise, if we still came up short, an error is returned with the number of bytes that were accessed.
+    fn check_region_accessed(&self, file: &F::File, region: FileRegion, bytes_accessed: usize) -> TorrentResult<()> {
+        let actual_size = try!(self.fs.file_size(file));
+        let size_matches = if self.preallocation == PreallocationMode::None {
+            actual_size <= region.file_size
+        } else {
+            actual_size == region.file_size
+        };
+
+        let expected_bytes = region.end - region.begin;
+
+        if !size_matches {
+            Err(TorrentError::from_kind(TorrentErrorKind::FileSizeChanged{
+                file_path: region.file_path,
+                offset: region.offset,
+                expected_size: region.file_size,
+                actual_size: actual_size
+            }))
+        } else if bytes_accessed != expected_bytes {
+            Err(TorrentError::from_kind(TorrentErrorKind::IncompleteAccess{
+                file_path: region.file_path,
+                offset: region.offset,
+                expected_bytes: expected_bytes as u64,
+                actual_bytes: bytes_accessed as u64
+            }))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Run the given closure with the file, and the region of the file and read/write buffer that the message maps to.
+    ///
+    /// Padding files are never opened, the closure is given no file for them, and should treat their regions as zeroes.
+    fn run_with_file_regions<C>(&self, message: &PieceMessage, callback: C) -> TorrentResult<()>
+        where C: FnMut(Option<F::File>, FileRegion) -> TorrentResult<()> {
         let piece_length = self.info_dict.piece_length() as u64;
+        let offset = (message.piece_index() as u64 * piece_length) + message.block_offset() as u64;
+
+        self.run_with_content_regions(offset, message.block_length() as u64, callback)
+    }
 
-        let mut total_bytes_to_skip = (message.piece_index() as u64 * piece_length) + message.block_offset() as u64;
+    /// Run the given closure with the file, and the region of the file and read/write buffer that the
+    /// range of content (all files concatenated together), starting at the given offset, maps to.
+    fn run_with_content_regions<C>(&self, offset: u64, length: u64, mut callback: C) -> TorrentResult<()>
+        where C: FnMut(Option<F::File>, FileRegion) -> TorrentResult<()> {
+        let mut total_bytes_to_skip = offset;
         let mut total_bytes_accessed = 0;
-        let total_block_length = message.block_length() as u64;
+        let total_block_length = length;
 
         for file in self.info_dict.files() {
             let total_file_size = file.length() as u64;
@@ -57,15 +319,25 @@ impl<'a, F> PieceAccessor<'a, F> where F: FileSystem {
             bytes_to_access -= min_bytes_to_skip;
 
             if bytes_to_access > 0 && total_bytes_accessed < total_block_length {
-                let file_path = build_path(self.info_dict.directory(), file);
-                let fs_file = try!(self.fs.open_file(Some(file_path)));
+                let file_path = self.location.file_path(self.info_dict, file);
+                let opt_fs_file = if is_padding_file(file) {
+                    None
+                } else {
+                    Some(try!(self.fs.open_file(Some(&file_path))))
+                };
 
                 let total_max_bytes_to_access = total_block_length - total_bytes_accessed;
                 let actual_bytes_to_access = cmp::min(total_max_bytes_to_access, bytes_to_access);
                 let offset = total_file_size - bytes_to_access;
                 
                 let (begin, end) = (total_bytes_accessed as usize, (total_bytes_accessed + actual_bytes_to_access) as usize);
-                try!(callback(fs_file, offset, begin, end));
+                try!(callback(opt_fs_file, FileRegion{
+                    file_path: file_path,
+                    file_size: total_file_size,
+                    offset: offset,
+                    begin: begin,
+                    end: end
+                }));
                 total_bytes_accessed += actual_bytes_to_access;
             }
         }
@@ -74,13 +346,54 @@ impl<'a, F> PieceAccessor<'a, F> where F: FileSystem {
     }
 }
 
-fn build_path(parent_directory: Option<&str>, file: &File) -> String {
-    let parent_directory = parent_directory.unwrap_or(".");
+/// Returns true if the file is a padding file (BEP 47), which only exists to align the file after it to a piece boundary.
+///
+/// Padding files are all zeroes, and are never stored on disk. Since the attr key of a file is not exposed to us, padding
+/// files are recognized by the paths given to them: ".pad/<length>" as recommended by BEP 47, or the older
+/// "_____padding_file_" prefix.
+pub fn is_padding_file(file: &File) -> bool {
+    let in_pad_dir = file.path().iter().next().map_or(false, |first| first == OsStr::new(".pad"));
+    let legacy_name = file.path()
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map_or(false, |name| name.starts_with("_____padding_file_"));
 
-    file.paths().fold(parent_directory.to_string(), |mut acc, item| {
-        acc.push_str("/");
-        acc.push_str(item);
+    in_pad_dir || legacy_name
+}
 
-        acc
-    })
-}
\ No newline at end of file
+fn zero_fill(buffer: &mut [u8]) {
+    for byte in buffer.iter_mut() {
+        *byte = 0;
+    }
+}
+
+/// Read from the file until the buffer is full or we hit the end of the file.
+///
+/// Some file systems, network mounts in particular, may return fewer bytes than asked for well before the end of
+/// the file, so a single short read doesn't tell us anything. Returns the number of bytes read.
+fn read_fully<F>(fs: &F, file: &mut F::File, offset: u64, buffer: &mut [u8]) -> io::Result<usize>
+    where F: FileSystem {
+    let mut bytes_read = 0;
+
+    while bytes_read < buffer.len() {
+        match fs.read_file(file, offset + bytes_read as u64, &mut buffer[bytes_read..]) {
+            Ok(0)                                                        => break,
+            Ok(read)                                                     => bytes_read += read,
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => (),
+            Err(error)                                                   => return Err(error)
+        }
+    }
+
+    Ok(bytes_read)
+}
+
+/// Region of a single file that a piece message maps to.
+struct FileRegion {
+    file_path: PathBuf,
+    file_size: u64,
+    // Offset into the file
+    offset:    u64,
+    // Start (inclusive) and end (exclusive) indices into the read/write buffer
+    begin:     usize,
+    end:       usize
+}
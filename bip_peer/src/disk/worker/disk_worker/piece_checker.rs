@@ -1,123 +1,354 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{mpsc, Arc};
 use std::cmp;
 
-use bip_metainfo::{InfoDictionary, File};
+use bip_bencode::{Bencode, Dictionary};
+use bip_metainfo::InfoDictionary;
 use bip_util::bt::InfoHash;
+use bip_util::sha::ShaHash;
+use crossbeam;
 
+use disk::DEFAULT_BLOCK_SIZE;
 use disk::error::{TorrentResult, TorrentError, TorrentErrorKind};
-use disk::worker::disk_worker::piece_accessor::PieceAccessor;
+use disk::hasher::{PieceHasher, ShaPieceHasher};
+use disk::layers::PieceLayers;
+use disk::worker::disk_worker::piece_accessor::{self, PieceAccessor};
+use disk::worker::shared::allocator::BlockAllocator;
 use disk::fs::{FileSystem};
+use disk::location::DownloadLocation;
+use disk::preallocation::PreallocationMode;
+use disk::priority::{FilePriority, FilePriorities};
+use disk::verification::VerificationOrder;
 use message::standard::PieceMessage;
 
+const RESUME_INFO_HASH_KEY: &'static [u8] = b"info_hash";
+const RESUME_GOOD_PIECES_KEY: &'static [u8] = b"good_pieces";
+
 /// Calculates hashes on existing files within the file system given and reports good/bad pieces.
 pub struct PieceChecker<'a, F> {
     fs:            F,
     info_dict:     &'a InfoDictionary,
+    priorities:    FilePriorities,
+    preallocation: PreallocationMode,
+    location:      DownloadLocation,
+    allocator:     Arc<BlockAllocator>,
+    hasher:        Arc<PieceHasher>,
+    piece_layers:  Option<Arc<PieceLayers>>,
     checker_state: PieceCheckerState
 }
 
 impl<'a, F> PieceChecker<'a, F> where F: FileSystem + 'a {
     /// Create a new PieceChecker with an initialized state.
     pub fn new(fs: F, info_dict: &'a InfoDictionary) -> TorrentResult<PieceChecker<'a, F>> {
+        PieceChecker::with_priorities(fs, info_dict, FilePriorities::new(), PreallocationMode::default(), DownloadLocation::default())
+    }
+
+    /// Create a new PieceChecker with an initialized state, ignoring pieces that only overlap skipped files.
+    ///
+    /// Skipped files will not be allocated unless they share a piece with a file we want, otherwise, files
+    /// are allocated, at the given DownloadLocation, according to the given PreallocationMode.
+    pub fn with_priorities(fs: F, info_dict: &'a InfoDictionary, priorities: FilePriorities, preallocation: PreallocationMode,
+        location: DownloadLocation) -> TorrentResult<PieceChecker<'a, F>> {
         let total_blocks = info_dict.pieces().count();
         let last_piece_size = last_piece_size(info_dict);
 
         let mut piece_checker = PieceChecker::with_state(fs, info_dict, PieceCheckerState::new(total_blocks, last_piece_size));
+        piece_checker.priorities = priorities;
+        piece_checker.preallocation = preallocation;
+        piece_checker.location = location;
         
+        try!(piece_checker.validate_piece_count());
         try!(piece_checker.validate_files_sizes());
         try!(piece_checker.fill_checker_state());
         
         Ok(piece_checker)
     }
 
+    /// Create a new PieceChecker with an initialized state, skipping pieces marked as good in the resume data.
+    ///
+    /// Only pieces not found in the resume data will be hashed when calculating the diff.
+    pub fn with_resume(fs: F, info_dict: &'a InfoDictionary, hash: InfoHash, resume: &[u8]) -> TorrentResult<PieceChecker<'a, F>> {
+        let total_blocks = info_dict.pieces().count();
+        let last_piece_size = last_piece_size(info_dict);
+
+        let checker_state = try!(PieceCheckerState::from_resume_bytes(resume, hash, total_blocks, last_piece_size));
+        let mut piece_checker = PieceChecker::with_state(fs, info_dict, checker_state);
+
+        try!(piece_checker.validate_piece_count());
+        try!(piece_checker.validate_files_sizes());
+        try!(piece_checker.fill_checker_state());
+
+        Ok(piece_checker)
+    }
+
     /// Create a new PieceChecker with the given state.
+    ///
+    /// Buffers used for hashing will be drawn from a new allocator, see `PieceChecker::set_allocator`.
     pub fn with_state(fs: F, info_dict: &'a InfoDictionary, checker_state: PieceCheckerState) -> PieceChecker<'a, F> {
+        let allocator = BlockAllocator::new(DEFAULT_BLOCK_SIZE, 1);
+
         PieceChecker {
             fs:            fs,
             info_dict:     info_dict,
+            priorities:    FilePriorities::new(),
+            preallocation: PreallocationMode::default(),
+            location:      DownloadLocation::default(),
+            allocator:     Arc::new(allocator),
+            hasher:        Arc::new(ShaPieceHasher),
+            piece_layers:  None,
             checker_state: checker_state
         }
     }
 
+    /// Sets the PreallocationMode that files were allocated with.
+    pub fn set_preallocation_mode(&mut self, preallocation: PreallocationMode) {
+        self.preallocation = preallocation;
+    }
+
+    /// Sets where the files for the torrent are located within the file system.
+    pub fn set_download_location(&mut self, location: DownloadLocation) {
+        self.location = location;
+    }
+
+    /// Sets the order in which pieces are hashed when more than one is ready to be checked.
+    pub fn set_verification_order(&mut self, order: VerificationOrder) {
+        self.checker_state.order = order;
+    }
+
+    /// Sets the allocator that buffers used for hashing will be drawn from.
+    ///
+    /// Pieces are read and hashed one block at a time, so only a single block sized buffer is
+    /// needed per thread; if the block size of the allocator is too small, buffers will not be re-used.
+    pub fn set_allocator(&mut self, allocator: Arc<BlockAllocator>) {
+        self.allocator = allocator;
+    }
+
+    /// Sets the hasher used to hash pieces, which is shared by every thread when hashing in parallel.
+    pub fn set_hasher(&mut self, hasher: Arc<PieceHasher>) {
+        self.hasher = hasher;
+    }
+
+    /// Sets the piece layers that pieces are checked against, instead of the hashes in the info dictionary.
+    pub fn set_piece_layers(&mut self, piece_layers: Option<Arc<PieceLayers>>) {
+        self.piece_layers = piece_layers;
+    }
+
     /// Calculate the diff of old to new good/bad pieces and store them in the piece checker state
     /// to be retrieved by the caller.
-    pub fn calculate_diff(mut self) -> TorrentResult<PieceCheckerState> {
+    pub fn calculate_diff(self) -> TorrentResult<PieceCheckerState> {
+        self.calculate_diff_with_progress(|_, _| ())
+    }
+
+    /// Same as calculate_diff, except the progress callback is invoked with the number of pieces
+    /// checked so far and the total number of pieces in the torrent after each piece is hashed.
+    pub fn calculate_diff_with_progress<P>(mut self, progress: P) -> TorrentResult<PieceCheckerState>
+        where P: FnMut(usize, usize) {
         let piece_length = self.info_dict.piece_length() as u64;
-        // TODO: Use Block Allocator
-        let mut piece_buffer = vec![0u8; piece_length as usize];
+        let allocator = self.allocator.clone();
+        let mut chunk_buffer = allocator.allocate(DEFAULT_BLOCK_SIZE);
 
         let info_dict = self.info_dict;
-        let piece_accessor = PieceAccessor::new(&self.fs, self.info_dict);
+        let mut piece_accessor = PieceAccessor::with_preallocation(&self.fs, self.info_dict, self.preallocation);
+        piece_accessor.set_download_location(self.location.clone());
+        piece_accessor.set_hasher(self.hasher.clone());
+        piece_accessor.set_piece_layers(self.piece_layers.clone());
         
-        try!(self.checker_state.run_with_whole_pieces(piece_length as usize, |message| {
-            try!(piece_accessor.read_piece(&mut piece_buffer[..message.block_length()], message));
-            
-            let calculated_hash = InfoHash::from_bytes(&piece_buffer[..message.block_length()]);
-            let expected_hash = InfoHash::from_hash(info_dict
-                .pieces()
-                .skip(message.piece_index() as usize)
-                .next()
-                .expect("bip_peer: Piece Checker Failed To Retrieve Expected Hash"))
-                .expect("bip_peer: Wrong Length Of Expected Hash Received");
-                
-            Ok(calculated_hash == expected_hash)
+        try!(self.checker_state.run_with_whole_pieces(piece_length as usize, progress, |message| {
+            check_piece(&piece_accessor, info_dict, &mut chunk_buffer, message)
         }));
 
         Ok(self.checker_state)
     }
 
+    /// Re-read and hash only the given piece, regardless of whether it was previously found good or bad.
+    ///
+    /// The result is stored in the piece checker state to be retrieved by the caller.
+    pub fn recheck_piece(mut self, piece_index: u32) -> TorrentResult<PieceCheckerState> {
+        let piece_length = self.info_dict.piece_length() as usize;
+        let allocator = self.allocator.clone();
+        let mut chunk_buffer = allocator.allocate(DEFAULT_BLOCK_SIZE);
+
+        let info_dict = self.info_dict;
+        let mut piece_accessor = PieceAccessor::with_preallocation(&self.fs, self.info_dict, self.preallocation);
+        piece_accessor.set_download_location(self.location.clone());
+        piece_accessor.set_hasher(self.hasher.clone());
+        piece_accessor.set_piece_layers(self.piece_layers.clone());
+
+        self.checker_state.recheck_piece(piece_index, piece_length);
+        try!(self.checker_state.run_with_piece(piece_index, piece_length, |message| {
+            check_piece(&piece_accessor, info_dict, &mut chunk_buffer, message)
+        }));
+
+        Ok(self.checker_state)
+    }
+
+    /// Same as calculate_diff_with_progress, except pieces are read and hashed across the given number of threads.
+    ///
+    /// Each thread hashes pieces using its own buffer, so the FileSystem must be able to be shared across threads.
+    pub fn calculate_diff_parallel<P>(mut self, num_threads: usize, mut progress: P) -> TorrentResult<PieceCheckerState>
+        where F: Sync,
+              P: FnMut(usize, usize)
+    {
+        let piece_length = self.info_dict.piece_length() as usize;
+        let whole_pieces = self.checker_state.whole_pieces(piece_length);
+
+        // Split pieces evenly across threads, making sure we don't spawn threads with no work
+        let num_threads = cmp::max(1, num_threads);
+        let pieces_per_thread = cmp::max(1, (whole_pieces.len() + num_threads - 1) / num_threads);
+
+        let info_dict = self.info_dict;
+        let fs = &self.fs;
+        let allocator = &*self.allocator;
+        let preallocation = self.preallocation;
+        let location = &self.location;
+        let hasher = &self.hasher;
+        let piece_layers = &self.piece_layers;
+        let total_blocks = self.checker_state.total_blocks;
+        let results: Vec<TorrentResult<Vec<PieceState>>> = crossbeam::scope(|scope| {
+            let (checked_send, checked_recv) = mpsc::channel();
+
+            let handles: Vec<_> = whole_pieces.chunks(pieces_per_thread)
+                .map(|messages| {
+                    let checked_send = checked_send.clone();
+
+                    scope.spawn(move || {
+                        let mut chunk_buffer = allocator.allocate(DEFAULT_BLOCK_SIZE);
+                        let mut piece_accessor = PieceAccessor::with_preallocation(fs, info_dict, preallocation);
+                        piece_accessor.set_download_location(location.clone());
+                        piece_accessor.set_hasher(hasher.clone());
+                        piece_accessor.set_piece_layers(piece_layers.clone());
+
+                        messages.iter()
+                            .map(|message| {
+                                check_piece(&piece_accessor, info_dict, &mut chunk_buffer, message).map(|is_good| {
+                                    let _ = checked_send.send(());
+
+                                    if is_good {
+                                        PieceState::Good(message.piece_index())
+                                    } else {
+                                        PieceState::Bad(message.piece_index())
+                                    }
+                                })
+                            })
+                            .collect::<TorrentResult<Vec<PieceState>>>()
+                    })
+                })
+                .collect();
+            // Once every thread has finished (or failed), all senders will be dropped
+            drop(checked_send);
+
+            for (checked, _) in checked_recv.iter().enumerate() {
+                progress(checked + 1, total_blocks);
+            }
+
+            handles.into_iter().map(|handle| handle.join()).collect()
+        });
+
+        for result in results {
+            for piece_state in try!(result) {
+                self.checker_state.add_piece_state(piece_state);
+            }
+        }
+
+        Ok(self.checker_state)
+    }
+
     /// Fill the PieceCheckerState with all piece messages for each file in our info dictionary.
     ///
     /// This is done once when a torrent file is added to see if we have any good pieces that
     /// the caller can use to skip (if the torrent was partially downloaded before).
     fn fill_checker_state(&mut self) -> TorrentResult<()> {
-        let piece_length = self.info_dict.piece_length() as u64;
-        let total_bytes: u64 = self.info_dict.files().map(|file| file.length() as u64).sum();
+        let piece_length = self.info_dict.piece_length() as usize;
+        let total_blocks = self.checker_state.total_blocks;
+        let last_piece_size = self.checker_state.last_block_size;
 
-        let full_pieces = total_bytes / piece_length;
-        let last_piece_size = last_piece_size(self.info_dict);
+        let info_dict = self.info_dict;
+        let priorities = &self.priorities;
+        let is_wanted = |piece_index: u32| priorities.piece_priority(info_dict, piece_index) != FilePriority::Skip;
 
-        for piece_index in 0..full_pieces {
-            self.checker_state.add_pending_block(PieceMessage::new(piece_index as u32, 0, piece_length as usize));
-        }
+        for piece_index in (0..total_blocks as u32).filter(|index| is_wanted(*index)) {
+            let block_length = if is_last_piece(total_blocks, piece_index) {
+                last_piece_size
+            } else {
+                piece_length
+            };
 
-        if last_piece_size != 0 {
-            self.checker_state.add_pending_block(PieceMessage::new(full_pieces as u32, 0, last_piece_size as usize));
+            self.checker_state.add_pending_block(PieceMessage::new(piece_index, 0, block_length));
         }
 
         Ok(())
     }
 
+    /// Validates that the number of piece hashes in our info dictionary matches the number of pieces its files span.
+    ///
+    /// The number of piece hashes decides which piece is the short last piece, so if the two disagree, a valid
+    /// piece could never be recognized as complete, leaving the torrent stuck just short of finishing.
+    fn validate_piece_count(&self) -> TorrentResult<()> {
+        let piece_length = self.info_dict.piece_length() as u64;
+        let total_bytes: u64 = self.info_dict.files().map(|file| file.length() as u64).sum();
+
+        // A torrent smaller than one piece has no full pieces, only a short last piece
+        let full_pieces = total_bytes / piece_length;
+        let expected_pieces = full_pieces + if total_bytes % piece_length != 0 { 1 } else { 0 };
+        let actual_pieces = self.checker_state.total_blocks as u64;
+
+        if expected_pieces != actual_pieces {
+            Err(TorrentError::from_kind(TorrentErrorKind::PieceCountMismatch{
+                expected_pieces: expected_pieces,
+                actual_pieces: actual_pieces
+            }))
+        } else {
+            Ok(())
+        }
+    }
+
     /// Validates the file sizes for the given torrent file and block allocates them if they do not exist.
     ///
-    /// This function will, if the file does not exist, or exists and is zero size, fill the file with zeroes.
-    /// Otherwise, if the file exists and it is of the correct size, it will be left alone. If it is of the wrong
-    /// size, an error will be thrown as we do not want to overwrite and existing file that maybe just had the same
-    /// name as a file in our dictionary.
+    /// This function will, if the file does not exist, or exists and is zero size, allocate the file according to
+    /// our PreallocationMode. Otherwise, if the file exists and it is of the correct size, it will be left alone. If
+    /// it is of the wrong size, an error will be thrown as we do not want to overwrite and existing file that maybe
+    /// just had the same name as a file in our dictionary. Files that were not preallocated are allowed to be smaller
+    /// than expected. Skipped files that we don't need in order to check a piece are left alone.
     fn validate_files_sizes(&mut self) -> TorrentResult<()> {
-        for file in self.info_dict.files() {
-            let file_path = build_path(self.info_dict.directory(), file);
+        let priorities = &self.priorities;
+        let preallocation = self.preallocation;
+        let info_dict = self.info_dict;
+
+        let wanted_files = info_dict.files().enumerate().filter(|&(index, _)| priorities.file_is_wanted(info_dict, index));
+        // Padding files are never stored on disk, so they are never created here
+        for (_, file) in wanted_files.filter(|&(_, file)| !piece_accessor::is_padding_file(file)) {
+            let file_path = self.location.file_path(self.info_dict, file);
             let expected_size = file.length() as u64;
 
             try!(self.fs.open_file(Some(&file_path))
                 .map_err(|err| err.into())
                 .and_then(|mut file| {
-                // File May Or May Not Have Existed Before, If The File Is Zero
-                // Length, Assume It Wasn't There (User Doesn't Lose Any Data)
                 let actual_size = try!(self.fs.file_size(&file));
 
+                // Opening the file already created it, so a legitimately empty file has nothing left to allocate
+                if expected_size == 0 && actual_size == 0 {
+                    return Ok(());
+                }
+
+                // File May Or May Not Have Existed Before, If The File Is Zero
+                // Length, Assume It Wasn't There (User Doesn't Lose Any Data)
                 let size_matches = actual_size == expected_size;
                 let size_is_zero = actual_size == 0;
+                let size_allowed = if preallocation == PreallocationMode::None {
+                    actual_size <= expected_size
+                } else {
+                    size_matches || size_is_zero
+                };
 
-                if !size_matches && size_is_zero {
-                    self.fs.write_file(&mut file, expected_size - 1, &[0])
-                        .expect("bip_peer: Failed To Create File When Validating Sizes");
-                } else if !size_matches {
+                if !size_allowed {
                     return Err(TorrentError::from_kind(TorrentErrorKind::ExistingFileSizeCheck{
                         file_path: file_path,
                         expected_size: expected_size,
                         actual_size: actual_size
                     }))
+                } else if size_is_zero {
+                    // Expected size is non zero here, otherwise we would have returned above
+                    try!(allocate_file(&self.fs, &mut file, expected_size, preallocation));
                 }
                 
                 Ok(())
@@ -128,36 +359,109 @@ impl<'a, F> PieceChecker<'a, F> where F: FileSystem + 'a {
     }
 }
 
-fn last_piece_size(info_dict: &InfoDictionary) -> usize {
-    let piece_length = info_dict.piece_length() as u64;
-    let total_bytes: u64 = info_dict.files().map(|file| file.length() as u64).sum();
+/// Allocate space for the given, empty, file using the given PreallocationMode.
+fn allocate_file<F>(fs: &F, file: &mut F::File, expected_size: u64, preallocation: PreallocationMode) -> TorrentResult<()>
+    where F: FileSystem {
+    match preallocation {
+        PreallocationMode::Full => {
+            let zeroes = [0u8; DEFAULT_BLOCK_SIZE];
+
+            let mut offset = 0;
+            while offset < expected_size {
+                let bytes_to_write = cmp::min(DEFAULT_BLOCK_SIZE as u64, expected_size - offset) as usize;
+                try!(fs.write_file(file, offset, &zeroes[..bytes_to_write]));
 
-    (total_bytes % piece_length) as usize
+                offset += bytes_to_write as u64;
+            }
+        },
+        PreallocationMode::Sparse => {
+            if let Some(last_offset) = expected_size.checked_sub(1) {
+                try!(fs.write_file(file, last_offset, &[0]));
+            }
+        },
+        PreallocationMode::None => ()
+    }
+
+    Ok(())
 }
 
-fn build_path(parent_directory: Option<&str>, file: &File) -> String {
-    let parent_directory = parent_directory.unwrap_or(".");
+/// Hash the whole piece given by the message, one chunk at a time, and check it against the expected hash.
+///
+/// If the piece accessor was given piece layers, the piece is checked against those instead of its v1 hash.
+fn check_piece<F>(piece_accessor: &PieceAccessor<F>, info_dict: &InfoDictionary, chunk_buffer: &mut [u8], message: &PieceMessage)
+    -> TorrentResult<bool> where F: FileSystem {
+    if let Some(is_good) = try!(piece_accessor.check_piece_layers(chunk_buffer, message.piece_index())) {
+        return Ok(is_good);
+    }
+
+    let calculated_hash = try!(piece_accessor.hash_piece(chunk_buffer, message));
 
-    file.paths().fold(parent_directory.to_string(), |mut acc, item| {
-        acc.push_str("/");
-        acc.push_str(item);
+    Ok(verify_piece(info_dict, message.piece_index(), calculated_hash))
+}
+
+/// Verify the hash of the whole piece against the v1 hash stored in the info dictionary.
+///
+/// Pieces of v2 (BEP 52) torrents are instead checked against their `PieceLayers`, see `PieceAccessor::check_piece_layers`.
+pub fn verify_piece(info_dict: &InfoDictionary, piece_index: u32, calculated_hash: ShaHash) -> bool {
+    let (_, expected_hash) = expected_hashes(info_dict)
+        .skip(piece_index as usize)
+        .next()
+        .expect("bip_peer: Piece Checker Failed To Retrieve Expected Hash");
+
+    calculated_hash == expected_hash
+}
+
+/// Iterator over the (piece index, expected hash) of every piece in the torrent.
+///
+/// These are the hashes that pieces are verified against, so they can be used to cross check our
+/// results, or to verify files independently of a `DiskManager`.
+pub fn expected_hashes(info_dict: &InfoDictionary) -> ExpectedHashes {
+    ExpectedHashes{ pieces: Box::new(info_dict.pieces().enumerate()) }
+}
+
+/// Iterator over the expected hash of each piece in a torrent, see `expected_hashes`.
+pub struct ExpectedHashes<'a> {
+    pieces: Box<Iterator<Item = (usize, &'a [u8])> + 'a>
+}
+
+impl<'a> Iterator for ExpectedHashes<'a> {
+    type Item = (u32, ShaHash);
+
+    fn next(&mut self) -> Option<(u32, ShaHash)> {
+        self.pieces.next().map(|(index, hash)| {
+            let expected_hash = ShaHash::from_hash(hash).expect("bip_peer: Wrong Length Of Expected Hash Received");
+
+            (index as u32, expected_hash)
+        })
+    }
+}
+
+/// Size of the last piece in the torrent, which will be the piece length if the total size is an exact multiple of it.
+pub fn last_piece_size(info_dict: &InfoDictionary) -> usize {
+    let piece_length = info_dict.piece_length() as u64;
+    let total_bytes: u64 = info_dict.files().map(|file| file.length() as u64).sum();
 
-        acc
-    })
+    match total_bytes % piece_length {
+        0 if total_bytes != 0 => piece_length as usize,
+        remainder             => remainder as usize
+    }
 }
 
 // ----------------------------------------------------------------------------//
 
 /// Stores state for the PieceChecker between invocations.
+#[derive(Clone)]
 pub struct PieceCheckerState {
     new_states:      Vec<PieceState>,
     old_states:      HashSet<PieceState>,
     pending_blocks:  HashMap<u32, Vec<PieceMessage>>,
     total_blocks:    usize,
-    last_block_size: usize
+    last_block_size: usize,
+    duplicates:      usize,
+    order:           VerificationOrder
 }
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub enum PieceState {
     /// Piece was discovered as good.
     Good(u32),
@@ -173,13 +477,124 @@ impl PieceCheckerState {
             old_states: HashSet::new(),
             pending_blocks: HashMap::new(),
             total_blocks: total_blocks,
-            last_block_size: last_block_size
+            last_block_size: last_block_size,
+            duplicates: 0,
+            order: VerificationOrder::default()
         }
     }
 
+    /// Create a new PieceCheckerState from resume data, with every piece in the resume data marked as good.
+    pub fn from_resume_bytes(bytes: &[u8], hash: InfoHash, total_blocks: usize, last_block_size: usize) -> TorrentResult<PieceCheckerState> {
+        let invalid_resume = || TorrentError::from_kind(TorrentErrorKind::InvalidResumeData{ hash: hash });
+
+        let bencode = try!(Bencode::decode(bytes).map_err(|_| invalid_resume()));
+        let root_dict = try!(bencode.dict().ok_or_else(&invalid_resume));
+
+        let resume_hash = root_dict.lookup(RESUME_INFO_HASH_KEY).and_then(|hash| hash.bytes());
+        if resume_hash != Some(hash.as_ref()) {
+            return Err(invalid_resume());
+        }
+
+        let good_pieces = try!(root_dict.lookup(RESUME_GOOD_PIECES_KEY).and_then(|pieces| pieces.list()).ok_or_else(&invalid_resume));
+        let mut checker_state = PieceCheckerState::new(total_blocks, last_block_size);
+        for piece in good_pieces {
+            match piece.int() {
+                Some(index) if index >= 0 && (index as u64) < total_blocks as u64 => {
+                    checker_state.old_states.insert(PieceState::Good(index as u32));
+                }
+                _ => return Err(invalid_resume()),
+            }
+        }
+
+        Ok(checker_state)
+    }
+
+    /// Serialize the pieces we have found to be good so that they can be skipped when re-adding the torrent.
+    pub fn to_resume_bytes(&self, hash: InfoHash) -> Vec<u8> {
+        let mut good_pieces: Vec<u32> = self.old_states.iter()
+            .chain(self.new_states.iter())
+            .filter_map(|state| match state {
+                &PieceState::Good(index) => Some(index),
+                &PieceState::Bad(_)      => None
+            })
+            .collect();
+        good_pieces.sort();
+        good_pieces.dedup();
+
+        let mut root_dict = BTreeMap::new();
+        root_dict.insert(RESUME_INFO_HASH_KEY, ben_bytes!(hash.as_ref()));
+        root_dict.insert(RESUME_GOOD_PIECES_KEY, Bencode::List(good_pieces.iter().map(|index| ben_int!(*index as i64)).collect()));
+
+        Bencode::Dict(root_dict).encode()
+    }
+
     /// Add a pending piece block to the current pending blocks.
+    ///
+    /// Blocks that are fully contained within a block we already have are dropped, and counted as duplicates.
     pub fn add_pending_block(&mut self, msg: PieceMessage) {
-        self.pending_blocks.entry(msg.piece_index()).or_insert(Vec::new()).push(msg);
+        let messages = self.pending_blocks.entry(msg.piece_index()).or_insert(Vec::new());
+
+        if messages.iter().any(|existing| block_contains(existing, &msg)) {
+            self.duplicates += 1;
+        } else {
+            messages.push(msg);
+        }
+    }
+
+    /// Whether or not every piece in the torrent has been found good.
+    ///
+    /// Pieces are only counted once they have been passed through `run_with_diff`.
+    pub fn is_complete(&self) -> bool {
+        let good_pieces = self.old_states.iter()
+            .filter(|state| match *state {
+                &PieceState::Good(_) => true,
+                &PieceState::Bad(_)  => false
+            })
+            .count();
+
+        good_pieces == self.total_blocks
+    }
+
+    /// Fraction of pieces in the torrent that are known to be good, from 0.0 to 1.0.
+    ///
+    /// Unlike `is_complete`, pieces found good that have not yet been passed through `run_with_diff` are counted.
+    pub fn completion(&self) -> f64 {
+        let good_pieces: HashSet<u32> = self.old_states.iter()
+            .chain(self.new_states.iter())
+            .filter_map(|state| match state {
+                &PieceState::Good(index) => Some(index),
+                &PieceState::Bad(_)      => None
+            })
+            .collect();
+
+        if self.total_blocks == 0 {
+            1.0
+        } else {
+            good_pieces.len() as f64 / self.total_blocks as f64
+        }
+    }
+
+    /// Whether or not the given piece has been found good.
+    ///
+    /// Pieces are only counted once they have been passed through `run_with_diff`.
+    pub fn is_good(&self, piece_index: u32) -> bool {
+        self.old_states.contains(&PieceState::Good(piece_index))
+    }
+
+    /// Whether or not we already have the data for the given block, either because its piece was
+    /// found good, or because it falls within a pending block.
+    pub fn has_block(&self, msg: &PieceMessage) -> bool {
+        self.is_good(msg.piece_index()) || self.pending_blocks(msg.piece_index()).iter().any(|existing| block_contains(existing, msg))
+    }
+
+    /// Blocks for the given piece that have been written out, but not yet checked.
+    pub fn pending_blocks(&self, piece_index: u32) -> &[PieceMessage] {
+        self.pending_blocks.get(&piece_index).map_or(&[][..], |messages| &messages[..])
+    }
+
+    /// Number of pending blocks that were dropped because we already had the data for them.
+    pub fn duplicate_blocks(&self) -> usize {
+        self.duplicates
     }
     
     /// Run the given closures against NewGood and NewBad messages. Each of the messages will
@@ -195,58 +610,138 @@ impl PieceCheckerState {
 
     /// Pass any pieces that have not been identified as OldGood into the callback which determines
     /// if the piece is good or bad so it can be marked as NewGood or NewBad.
-    fn run_with_whole_pieces<F>(&mut self, piece_length: usize, mut callback: F) -> TorrentResult<()>
+    ///
+    /// The progress callback is invoked with the number of pieces checked and the total number of pieces.
+    fn run_with_whole_pieces<P, F>(&mut self, piece_length: usize, mut progress: P, mut callback: F) -> TorrentResult<()>
+        where P: FnMut(usize, usize),
+              F: FnMut(&PieceMessage) -> TorrentResult<bool> {
+        let total_blocks = self.total_blocks;
+
+        let mut checked = 0;
+        for message in self.whole_pieces(piece_length) {
+            let is_good = try!(callback(&message));
+
+            if is_good {
+                self.add_piece_state(PieceState::Good(message.piece_index()));
+            } else {
+                self.add_piece_state(PieceState::Bad(message.piece_index()));
+            }
+
+            checked += 1;
+            progress(checked, total_blocks);
+        }
+        
+        Ok(())
+    }
+
+    /// Mark the whole piece as pending so that it will be checked again, forgetting its previous state.
+    pub fn recheck_piece(&mut self, piece_index: u32, piece_length: usize) {
+        self.old_states.remove(&PieceState::Good(piece_index));
+        self.old_states.remove(&PieceState::Bad(piece_index));
+
+        let block_length = if is_last_piece(self.total_blocks, piece_index) {
+            self.last_block_size
+        } else {
+            piece_length
+        };
+
+        self.pending_blocks.insert(piece_index, vec![PieceMessage::new(piece_index, 0, block_length)]);
+    }
+
+    /// Mark every piece as pending so that the whole torrent will be checked again, forgetting all previous states.
+    pub fn recheck_all(&mut self, piece_length: usize) {
+        for piece_index in 0..self.total_blocks as u32 {
+            self.recheck_piece(piece_index, piece_length);
+        }
+    }
+
+    /// Same as run_with_whole_pieces, except only the given piece is considered.
+    fn run_with_piece<F>(&mut self, piece_index: u32, piece_length: usize, mut callback: F) -> TorrentResult<()>
         where F: FnMut(&PieceMessage) -> TorrentResult<bool> {
         self.merge_pieces();
 
-        let mut new_states = &mut self.new_states;
-        let old_states = &self.old_states;
-
         let total_blocks = self.total_blocks;
         let last_block_size = self.last_block_size;
 
-        for messages in self.pending_blocks.values_mut()
-            .filter(|ref messages| piece_is_complete(total_blocks, last_block_size, piece_length, messages))
-            .filter(|ref messages| !old_states.contains(&PieceState::Good(messages[0].piece_index()))) {
-            let is_good = try!(callback(&messages[0]));
+        let opt_message = self.pending_blocks.get(&piece_index)
+            .and_then(|messages| {
+                if piece_is_complete(total_blocks, last_block_size, piece_length, messages) {
+                    Some(messages[0])
+                } else {
+                    None
+                }
+            });
+
+        if let Some(message) = opt_message {
+            let is_good = try!(callback(&message));
 
             if is_good {
-                new_states.push(PieceState::Good(messages[0].piece_index()));
+                self.add_piece_state(PieceState::Good(piece_index));
             } else {
-                new_states.push(PieceState::Bad(messages[0].piece_index()));
+                self.add_piece_state(PieceState::Bad(piece_index));
             }
+        }
+
+        Ok(())
+    }
+
+    /// Merge pending blocks and return a message for every piece that can be checked, without
+    /// removing them; callers should report back with add_piece_state once the piece is checked.
+    ///
+    /// Messages are returned in the order that pieces should be verified.
+    fn whole_pieces(&mut self, piece_length: usize) -> Vec<PieceMessage> {
+        self.merge_pieces();
+
+        let old_states = &self.old_states;
+        let total_blocks = self.total_blocks;
+        let last_block_size = self.last_block_size;
+
+        let mut messages: Vec<PieceMessage> = self.pending_blocks.values()
+            .filter(|messages| piece_is_complete(total_blocks, last_block_size, piece_length, messages))
+            .filter(|messages| !old_states.contains(&PieceState::Good(messages[0].piece_index())))
+            .map(|messages| messages[0])
+            .collect();
+
+        match self.order {
+            VerificationOrder::Unordered => (),
+            VerificationOrder::Ascending => messages.sort_by_key(|message| message.piece_index())
+        }
 
+        messages
+    }
+
+    /// Record the checked state of a piece, clearing any pending blocks for that piece.
+    fn add_piece_state(&mut self, piece_state: PieceState) {
+        let piece_index = match piece_state {
+            PieceState::Good(index) | PieceState::Bad(index) => index,
+        };
+
+        if let Some(messages) = self.pending_blocks.get_mut(&piece_index) {
             messages.clear();
         }
-        
-        Ok(())
+        self.new_states.push(piece_state);
     }
 
-    /// Merges all pending piece messages into a single messages if possible.
+    /// Merges all overlapping or adjacent pending piece messages, so that a whole piece ends up as a single message.
     fn merge_pieces(&mut self) {
-        for (_, ref mut messages) in self.pending_blocks.iter_mut() {
+        for messages in self.pending_blocks.values_mut() {
             // Sort the messages by their block offset
             messages.sort_by(|a, b| a.block_offset().cmp(&b.block_offset()));
 
-            let mut messages_len = messages.len();
-            let mut merge_success = true;
-            // See if we can merge all messages into a single message
-            while merge_success && messages_len > 1 {
-                let actual_last = messages.pop().expect("bip_peer: Failed To Merge Blocks");
-                let second_last = messages.pop().expect("bip_peer: Failed To Merge Blocks");
+            // Since messages are sorted, each message can only merge with the run of messages before it
+            let mut merged_messages: Vec<PieceMessage> = Vec::with_capacity(messages.len());
+            for message in messages.drain(..) {
+                let opt_merged = merged_messages.last().and_then(|last| merge_piece_messages(last, &message));
 
-                let opt_merged =  merge_piece_messages(&second_last, &actual_last);
                 if let Some(merged) = opt_merged {
-                    messages.push(merged);
+                    merged_messages.pop();
+                    merged_messages.push(merged);
                 } else {
-                    messages.push(second_last);
-                    messages.push(actual_last);
-
-                    merge_success = false;
+                    merged_messages.push(message);
                 }
-
-                messages_len = messages.len();
             }
+
+            *messages = merged_messages;
         }
     }
 }
@@ -258,15 +753,32 @@ fn piece_is_complete(total_blocks: usize, last_block_size: usize, piece_length:
         .map(|message| message.block_length() == piece_length)
         .unwrap_or(false);
     let is_last_block = messages.get(0)
-        .map(|message| message.piece_index() == (total_blocks - 1) as u32)
+        .map(|message| is_last_piece(total_blocks, message.piece_index()))
         .unwrap_or(false);
+    // Never consider a zero length last block complete, that would mean the torrent is empty
     let is_last_block_length = messages.get(0)
-        .map(|message| message.block_length() == last_block_size)
+        .map(|message| last_block_size != 0 && message.block_length() == last_block_size)
         .unwrap_or(false);
 
     is_single_message && (is_piece_length || (is_last_block && is_last_block_length))
 }
 
+/// True if the given piece is the last piece of a torrent with the given number of pieces.
+fn is_last_piece(total_blocks: usize, piece_index: u32) -> bool {
+    total_blocks.checked_sub(1).map_or(false, |last_index| piece_index as usize == last_index)
+}
+
+/// True if message b falls completely within message a.
+fn block_contains(message_a: &PieceMessage, message_b: &PieceMessage) -> bool {
+    let start_a = message_a.block_offset() as u64;
+    let end_a = start_a + message_a.block_length() as u64;
+
+    let start_b = message_b.block_offset() as u64;
+    let end_b = start_b + message_b.block_length() as u64;
+
+    start_b >= start_a && end_b <= end_a
+}
+
 /// Merge a piece message a with a piece message b if possible.
 ///
 /// First message's block offset should come before (or at) the block offset of the second message.
@@ -293,4 +805,331 @@ fn merge_piece_messages(message_a: &PieceMessage, message_b: &PieceMessage) -> O
     } else {
         None
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use bip_bencode::Bencode;
+    use bip_metainfo::MetainfoFile;
+    use bip_util::bt::InfoHash;
+
+    use disk::error::TorrentErrorKind;
+    use disk::fs::FileSystem;
+    use disk::fs::memory::InMemoryFileSystem;
+    use disk::fs::read_only::ReadOnlyFileSystem;
+    use disk::location::DownloadLocation;
+    use disk::preallocation::PreallocationMode;
+    use disk::priority::FilePriorities;
+    use disk::verification::VerificationOrder;
+    use disk::worker::disk_worker::piece_accessor::PieceAccessor;
+    use message::standard::PieceMessage;
+    use super::{PieceChecker, PieceCheckerState, PieceState};
+
+    const PIECE_LENGTH: usize = 8;
+
+    /// Create a multi file torrent with the given files, whose contents are concatenated into a
+    /// single stream of pieces, and write those files out to the given file system.
+    fn create_torrent(fs: &InMemoryFileSystem, files: &[(&str, &[u8])]) -> MetainfoFile {
+        let contents: Vec<u8> = files.iter().flat_map(|&(_, bytes)| bytes.iter().cloned()).collect();
+        let pieces: Vec<u8> = contents.chunks(PIECE_LENGTH)
+            .flat_map(|piece| InfoHash::from_bytes(piece).as_ref().to_vec())
+            .collect();
+
+        create_torrent_with_pieces(fs, files, &pieces)
+    }
+
+    /// Same as create_torrent, except the concatenated piece hashes are given rather than calculated.
+    fn create_torrent_with_pieces(fs: &InMemoryFileSystem, files: &[(&str, &[u8])], pieces: &[u8]) -> MetainfoFile {
+
+        let file_list = files.iter()
+            .map(|&(name, bytes)| {
+                let mut file_dict = BTreeMap::new();
+                file_dict.insert(&b"length"[..], ben_int!(bytes.len() as i64));
+                file_dict.insert(&b"path"[..], Bencode::List(vec![ben_bytes!(name.as_bytes())]));
+
+                Bencode::Dict(file_dict)
+            })
+            .collect();
+
+        let mut info_dict = BTreeMap::new();
+        info_dict.insert(&b"name"[..], ben_bytes!(&b"test"[..]));
+        info_dict.insert(&b"piece length"[..], ben_int!(PIECE_LENGTH as i64));
+        info_dict.insert(&b"pieces"[..], ben_bytes!(pieces));
+        info_dict.insert(&b"files"[..], Bencode::List(file_list));
+
+        let mut root_dict = BTreeMap::new();
+        root_dict.insert(&b"announce"[..], ben_bytes!(&b"udp://localhost:6969"[..]));
+        root_dict.insert(&b"info"[..], Bencode::Dict(info_dict));
+
+        for &(name, bytes) in files {
+            let mut file = fs.open_file(Some(format!("test/{}", name))).unwrap();
+            fs.write_file(&mut file, 0, bytes).unwrap();
+        }
+
+        MetainfoFile::from_bytes(Bencode::Dict(root_dict).encode()).unwrap()
+    }
+
+    /// Calculate the initial diff for the torrent, returning (good, bad) piece indices in increasing order.
+    fn calculate_pieces(fs: &InMemoryFileSystem, metainfo: &MetainfoFile) -> (Vec<u32>, Vec<u32>) {
+        let mut checker_state = PieceChecker::new(fs, metainfo.info())
+            .and_then(|checker| checker.calculate_diff())
+            .unwrap();
+
+        let (mut good, mut bad) = (Vec::new(), Vec::new());
+        checker_state.run_with_diff(|piece_state| {
+            match piece_state {
+                &PieceState::Good(index) => good.push(index),
+                &PieceState::Bad(index)  => bad.push(index)
+            }
+        });
+        good.sort();
+        bad.sort();
+
+        (good, bad)
+    }
+
+    #[test]
+    fn positive_merge_pieces_middle_block_last() {
+        let mut checker_state = PieceCheckerState::new(1, 12);
+
+        checker_state.add_pending_block(PieceMessage::new(0, 0, 4));
+        checker_state.add_pending_block(PieceMessage::new(0, 8, 4));
+        checker_state.add_pending_block(PieceMessage::new(0, 4, 4));
+
+        assert_eq!(vec![PieceMessage::new(0, 0, 12)], checker_state.whole_pieces(12));
+    }
+
+    #[test]
+    fn negative_merge_pieces_gap_between_blocks() {
+        let mut checker_state = PieceCheckerState::new(1, 12);
+
+        checker_state.add_pending_block(PieceMessage::new(0, 0, 4));
+        checker_state.add_pending_block(PieceMessage::new(0, 9, 3));
+        checker_state.add_pending_block(PieceMessage::new(0, 4, 4));
+
+        assert!(checker_state.whole_pieces(12).is_empty());
+        assert_eq!(2, checker_state.pending_blocks[&0].len());
+    }
+
+    #[test]
+    fn positive_add_pending_block_drops_duplicate() {
+        let mut checker_state = PieceCheckerState::new(1, 12);
+
+        checker_state.add_pending_block(PieceMessage::new(0, 0, 8));
+        checker_state.add_pending_block(PieceMessage::new(0, 4, 4));
+        checker_state.add_pending_block(PieceMessage::new(0, 0, 8));
+
+        assert_eq!(2, checker_state.duplicate_blocks());
+        assert_eq!(1, checker_state.pending_blocks[&0].len());
+    }
+
+    #[test]
+    fn positive_calculate_diff_multi_file_all_good() {
+        let fs = InMemoryFileSystem::new();
+        let metainfo = create_torrent(&fs, &[("a", &[1u8; 10][..]), ("b", &[2u8; 20][..])]);
+
+        assert_eq!((vec![0, 1, 2, 3], vec![]), calculate_pieces(&fs, &metainfo));
+    }
+
+    #[test]
+    fn positive_calculate_diff_exact_multiple_of_piece_length() {
+        let fs = InMemoryFileSystem::new();
+        let metainfo = create_torrent(&fs, &[("a", &[1u8; 12][..]), ("b", &[2u8; 12][..])]);
+
+        assert_eq!((vec![0, 1, 2], vec![]), calculate_pieces(&fs, &metainfo));
+    }
+
+    #[test]
+    fn positive_piece_is_complete_single_short_piece() {
+        let messages = [PieceMessage::new(0, 0, 5)];
+
+        assert!(super::piece_is_complete(1, 5, PIECE_LENGTH, &messages));
+        assert!(!super::piece_is_complete(1, 5, PIECE_LENGTH, &[PieceMessage::new(0, 0, 4)]));
+        assert!(!super::piece_is_complete(0, 5, PIECE_LENGTH, &messages));
+    }
+
+    #[test]
+    fn positive_calculate_diff_smaller_than_one_piece() {
+        let fs = InMemoryFileSystem::new();
+        let metainfo = create_torrent(&fs, &[("a", &[1u8; 5][..])]);
+
+        assert_eq!((vec![0], vec![]), calculate_pieces(&fs, &metainfo));
+    }
+
+    #[test]
+    fn negative_piece_count_mismatch() {
+        let fs = InMemoryFileSystem::new();
+        // Files span two pieces, but there are three piece hashes
+        let metainfo = create_torrent_with_pieces(&fs, &[("a", &[1u8; 10][..])], &[0u8; 60][..]);
+
+        let error = PieceChecker::new(&fs, metainfo.info()).err().expect("bip_peer: Piece Count Mismatch Was Not Detected");
+        match *error.kind() {
+            TorrentErrorKind::PieceCountMismatch{ expected_pieces: 2, actual_pieces: 3 } => (),
+            _ => panic!("bip_peer: Piece Count Mismatch Reported The Wrong Error")
+        }
+    }
+
+    #[test]
+    fn positive_calculate_diff_multi_file_bad_piece_across_boundary() {
+        let fs = InMemoryFileSystem::new();
+        let metainfo = create_torrent(&fs, &[("a", &[1u8; 10][..]), ("b", &[2u8; 20][..])]);
+
+        // Piece 1 covers bytes 8 through 15, which spans the end of a and the start of b
+        fs.run_with_file("test/b", |bytes| bytes[0] = 0).unwrap();
+
+        assert_eq!((vec![0, 2, 3], vec![1]), calculate_pieces(&fs, &metainfo));
+    }
+
+    #[test]
+    fn positive_is_complete_only_once_all_pieces_good() {
+        let fs = InMemoryFileSystem::new();
+        let metainfo = create_torrent(&fs, &[("a", &[1u8; 10][..]), ("b", &[2u8; 20][..])]);
+        fs.run_with_file("test/b", |bytes| bytes[0] = 0).unwrap();
+
+        let mut checker_state = PieceChecker::new(&fs, metainfo.info())
+            .and_then(|checker| checker.calculate_diff())
+            .unwrap();
+        checker_state.run_with_diff(|_| ());
+        assert!(!checker_state.is_complete());
+
+        fs.run_with_file("test/b", |bytes| bytes[0] = 2).unwrap();
+        checker_state.add_pending_block(PieceMessage::new(1, 0, PIECE_LENGTH));
+
+        let mut checker_state = PieceChecker::with_state(&fs, metainfo.info(), checker_state)
+            .calculate_diff()
+            .unwrap();
+        assert!(!checker_state.is_complete());
+        checker_state.run_with_diff(|_| ());
+        assert!(checker_state.is_complete());
+    }
+
+    #[test]
+    fn positive_recheck_all_finds_piece_corrupted_after_complete() {
+        let fs = InMemoryFileSystem::new();
+        let metainfo = create_torrent(&fs, &[("a", &[1u8; 10][..]), ("b", &[2u8; 20][..])]);
+
+        let mut checker_state = PieceChecker::new(&fs, metainfo.info())
+            .and_then(|checker| checker.calculate_diff())
+            .unwrap();
+        checker_state.run_with_diff(|_| ());
+        assert!(checker_state.is_complete());
+
+        fs.run_with_file("test/b", |bytes| bytes[19] = 0).unwrap();
+        checker_state.recheck_all(PIECE_LENGTH);
+
+        let mut checker_state = PieceChecker::with_state(&fs, metainfo.info(), checker_state)
+            .calculate_diff()
+            .unwrap();
+        let mut bad = Vec::new();
+        checker_state.run_with_diff(|piece_state| {
+            if let &PieceState::Bad(index) = piece_state {
+                bad.push(index);
+            }
+        });
+        assert_eq!(vec![3], bad);
+        assert!(!checker_state.is_complete());
+    }
+
+    #[test]
+    fn positive_completion_counts_undrained_pieces() {
+        let fs = InMemoryFileSystem::new();
+        let metainfo = create_torrent(&fs, &[("a", &[1u8; 10][..]), ("b", &[2u8; 20][..])]);
+        fs.run_with_file("test/b", |bytes| bytes[0] = 0).unwrap();
+
+        let mut checker_state = PieceChecker::new(&fs, metainfo.info())
+            .and_then(|checker| checker.calculate_diff())
+            .unwrap();
+        assert_eq!(0.75, checker_state.completion());
+
+        checker_state.run_with_diff(|_| ());
+        assert_eq!(0.75, checker_state.completion());
+    }
+
+    #[test]
+    fn positive_expected_hashes_match_file_contents() {
+        let fs = InMemoryFileSystem::new();
+        let metainfo = create_torrent(&fs, &[("a", &b"0123456789"[..])]);
+
+        let expected: Vec<(u32, InfoHash)> = vec![(0, InfoHash::from_bytes(b"01234567")), (1, InfoHash::from_bytes(b"89"))];
+        assert_eq!(expected, super::expected_hashes(metainfo.info()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn positive_whole_pieces_ascending_order() {
+        let mut checker_state = PieceCheckerState::new(40, 12);
+        checker_state.order = VerificationOrder::Ascending;
+
+        for piece_index in (0..40).rev() {
+            checker_state.add_pending_block(PieceMessage::new(piece_index, 0, 12));
+        }
+
+        let piece_indices: Vec<u32> = checker_state.whole_pieces(12).iter().map(|message| message.piece_index()).collect();
+        assert_eq!((0..40).collect::<Vec<u32>>(), piece_indices);
+    }
+
+    #[test]
+    fn positive_calculate_diff_padding_file_read_as_zeroes() {
+        let fs = InMemoryFileSystem::new();
+        let metainfo = create_torrent(&fs, &[("a", &[1u8; 10][..]), ("_____padding_file_0", &[0u8; 6][..]), ("b", &[2u8; 8][..])]);
+
+        // Whatever happens to be on disk in place of the padding file is not part of the content
+        let mut padding_file = fs.open_file(Some("test/_____padding_file_0")).unwrap();
+        fs.write_file(&mut padding_file, 0, &[3u8; 6]).unwrap();
+
+        assert_eq!((vec![0, 1, 2], vec![]), calculate_pieces(&fs, &metainfo));
+    }
+
+    #[test]
+    fn positive_validate_zero_length_file_not_extended() {
+        let fs = InMemoryFileSystem::new();
+        let metainfo = create_torrent(&InMemoryFileSystem::new(), &[("a", &[1u8; 10][..]), ("empty", &[0u8; 0][..]), ("b", &[2u8; 20][..])]);
+
+        for &preallocation in &[PreallocationMode::Sparse, PreallocationMode::Full, PreallocationMode::None] {
+            PieceChecker::with_priorities(&fs, metainfo.info(), FilePriorities::new(), preallocation, DownloadLocation::default()).unwrap();
+
+            let file = fs.open_file(Some("test/empty")).unwrap();
+            assert_eq!(0, fs.file_size(&file).unwrap());
+        }
+    }
+
+    #[test]
+    fn positive_calculate_diff_read_only_file_system() {
+        let fs = InMemoryFileSystem::new();
+        let metainfo = create_torrent(&fs, &[("a", &[1u8; 10][..]), ("b", &[2u8; 20][..])]);
+
+        let read_only_fs = ReadOnlyFileSystem::new(&fs);
+        let mut checker_state = PieceChecker::with_priorities(read_only_fs, metainfo.info(), FilePriorities::new(), PreallocationMode::None,
+                                                              DownloadLocation::default())
+            .and_then(|checker| checker.calculate_diff())
+            .unwrap();
+
+        let mut good = Vec::new();
+        checker_state.run_with_diff(|piece_state| {
+            if let &PieceState::Good(index) = piece_state {
+                good.push(index);
+            }
+        });
+        good.sort();
+
+        assert_eq!(vec![0, 1, 2, 3], good);
+    }
+
+    #[test]
+    fn positive_read_range_spans_files() {
+        let fs = InMemoryFileSystem::new();
+        let metainfo = create_torrent(&fs, &[("a", &b"0123456789"[..]), ("b", &b"abc"[..])]);
+
+        let mut checker_state = PieceChecker::new(&fs, metainfo.info())
+            .and_then(|checker| checker.calculate_diff())
+            .unwrap();
+        checker_state.run_with_diff(|_| ());
+
+        let accessor = PieceAccessor::new(&fs, metainfo.info());
+        let mut buffer = [0u8; 6];
+        accessor.read_range(7, &mut buffer, |index| checker_state.is_good(index)).unwrap();
+
+        assert_eq!(&b"789abc"[..], &buffer[..]);
+        assert!(accessor.read_range(8, &mut buffer, |index| checker_state.is_good(index)).is_err());
+    }
+}
@@ -8,6 +8,11 @@ use disk::worker::shared::blocks::Blocks;
 use disk::worker::shared::clients::Clients;
 use disk::error::RequestError;
 use disk::fs::{FileSystem};
+use disk::config::DiskConfig;
+use disk::hasher::PieceHasher;
+use disk::layers::PieceLayers;
+use disk::priority::FilePriorities;
+use metrics::Recorder;
 use token::Token;
 use message::standard::PieceMessage;
 
@@ -15,9 +20,13 @@ pub mod shared;
 mod block_worker;
 mod disk_worker;
 
+pub use disk::worker::disk_worker::{expected_hashes, ExpectedHashes};
+
 pub enum DiskMessage {
-    AddTorrent(Token, MetainfoFile),
-    RemoveTorrent(Token, InfoHash),
+    AddTorrent(Token, MetainfoFile, FilePriorities, Option<PieceLayers>),
+    RemoveTorrent(Token, InfoHash, bool),
+    ListTorrents(Token),
+    SetVerifyReads(InfoHash, bool),
     LoadBlock(Token, Token, InfoHash, PieceMessage),
     ProcessBlock(Token, Token),
     /// INTERNAL USE ONLY
@@ -47,13 +56,13 @@ impl ReserveBlockClientMetadata {
 
 // ----------------------------------------------------------------------------//
 
-pub fn create_workers<F>(fs: F, clients: Arc<Clients<ReserveBlockClientMetadata>>, blocks: Arc<Blocks>,
-    disk_worker_namespace: Token) -> (Sender<DiskMessage>, Sender<SyncBlockMessage>, Sender<AsyncBlockMessage>)
+pub fn create_workers<F>(fs: F, config: DiskConfig, clients: Arc<Clients<ReserveBlockClientMetadata>>, blocks: Arc<Blocks>,
+    recorder: Arc<Recorder>, hasher: Arc<PieceHasher>, disk_worker_namespace: Token) -> (Sender<DiskMessage>, Sender<SyncBlockMessage>, Sender<AsyncBlockMessage>)
     where F: FileSystem + Send + Sync + 'static {
     let sync_worker = block_worker::spawn_sync_block_worker(clients.clone(), blocks.clone());
     let async_worker = block_worker::spawn_async_block_worker(blocks.clone());
-    let disk_worker = disk_worker::spawn_disk_worker(fs, clients, blocks, sync_worker.clone(), async_worker.clone(),
-        disk_worker_namespace);
+    let disk_worker = disk_worker::spawn_disk_worker(fs, config, clients, blocks, sync_worker.clone(), async_worker.clone(),
+        recorder, hasher, disk_worker_namespace);
 
     (disk_worker, sync_worker, async_worker)
 }
\ No newline at end of file
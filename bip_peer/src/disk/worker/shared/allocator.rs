@@ -0,0 +1,97 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam::sync::MsQueue;
+
+/// Thread safe pool of re-usable, fixed size buffers.
+///
+/// Buffers are handed out through an `AllocatedBlock`, which returns the buffer to the pool when dropped.
+pub struct BlockAllocator {
+    free:       MsQueue<Vec<u8>>,
+    free_count: AtomicUsize,
+    max_free:   usize,
+    block_size: usize
+}
+
+impl BlockAllocator {
+    /// Create a new BlockAllocator that will hold on to at most max_free unused buffers of block_size bytes.
+    pub fn new(block_size: usize, max_free: usize) -> BlockAllocator {
+        if block_size == 0 {
+            panic!("bip_peer: BlockAllocator Created With A Block Size Of 0 Not Allowed")
+        }
+
+        BlockAllocator {
+            free: MsQueue::new(),
+            free_count: AtomicUsize::new(0),
+            max_free: max_free,
+            block_size: block_size
+        }
+    }
+
+    /// Size of the buffers held by the allocator.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Allocate a zeroed buffer of the given length.
+    ///
+    /// Lengths larger than the block size are allocated separately and will not be returned to the pool.
+    pub fn allocate(&self, length: usize) -> AllocatedBlock {
+        let pooled = length <= self.block_size;
+        let mut buffer = if !pooled {
+            Vec::with_capacity(length)
+        } else {
+            self.free.try_pop()
+                .map(|buffer| {
+                    self.free_count.fetch_sub(1, Ordering::SeqCst);
+                    buffer
+                })
+                .unwrap_or_else(|| Vec::with_capacity(self.block_size))
+        };
+        buffer.clear();
+        buffer.resize(length, 0);
+
+        AllocatedBlock{ allocator: self, buffer: Some(buffer), pooled: pooled }
+    }
+
+    /// Return the buffer to the pool, if there is room for it.
+    fn reclaim(&self, buffer: Vec<u8>) {
+        // Not an exact limit, but we only need to make sure the pool doesn't grow without bound
+        if self.free_count.fetch_add(1, Ordering::SeqCst) < self.max_free {
+            self.free.push(buffer);
+        } else {
+            self.free_count.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------//
+
+/// Buffer borrowed from a BlockAllocator, returned to the allocator when dropped.
+pub struct AllocatedBlock<'a> {
+    allocator: &'a BlockAllocator,
+    buffer:    Option<Vec<u8>>,
+    pooled:    bool
+}
+
+impl<'a> Deref for AllocatedBlock<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buffer.as_ref().expect("bip_peer: AllocatedBlock Buffer Accessed After Drop")
+    }
+}
+
+impl<'a> DerefMut for AllocatedBlock<'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buffer.as_mut().expect("bip_peer: AllocatedBlock Buffer Accessed After Drop")
+    }
+}
+
+impl<'a> Drop for AllocatedBlock<'a> {
+    fn drop(&mut self) {
+        if let (Some(buffer), true) = (self.buffer.take(), self.pooled) {
+            self.allocator.reclaim(buffer);
+        }
+    }
+}
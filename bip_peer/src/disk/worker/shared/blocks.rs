@@ -58,11 +58,23 @@ impl Blocks {
     ///
     /// This call will implicitly reclaim any active used blocks under the namespace.
     pub fn unregister_namespace(&self, namespace: Token) {
-        self.run_with_namespace_map(|namespace_map| {
-            if namespace_map.remove(&namespace).is_none() {
-                panic!("bip_peer: Blocks::unregister_namespace Failed To Remove Existing Token");
+        let request_map = self.run_with_namespace_map(|namespace_map| {
+            match namespace_map.remove(&namespace) {
+                Some(request_map) => request_map,
+                None              => panic!("bip_peer: Blocks::unregister_namespace Failed To Remove Existing Token")
             }
         });
+        let used_blocks = request_map.into_inner()
+            .expect("bip_peer: Blocks::unregister_namespace Failed To Lock Request Map");
+
+        // Clients that go away mid transfer (peers disconnecting) leave blocks behind, which still count against our total
+        for (_, buffers) in used_blocks {
+            buffers.unpack(|mut buffer| {
+                buffer.clear();
+
+                self.reuse_blocks(buffer);
+            });
+        }
     }
 
     /// Allocate a block with AT LEAST the given number of bytes under the namespace and
@@ -87,6 +99,11 @@ impl Blocks {
         });
     }
 
+    /// Whether or not a block is currently in use under the given namespace for the given request id.
+    pub fn is_used(&self, namespace: Token, request: Token) -> bool {
+        self.run_with_request_map(namespace, |request_map| request_map.contains_key(&request))
+    }
+
     /// Reclaim a block under the given namespace, corresponding to the given request id.
     pub fn reclaim_block(&self, namespace: Token, request: Token) {
         let buffers = self.remove_used_block(namespace, request);
@@ -101,12 +118,12 @@ impl Blocks {
     // ----- PRIVATE ----- //
 
     /// Run the given closure with a mutable reference to the namespace map.
-    fn run_with_namespace_map<F>(&self, accept: F)
-        where F: FnOnce(&mut HashMap<Token, Mutex<HashMap<Token, ContiguousBuffers<Vec<u8>>>>>) {
+    fn run_with_namespace_map<F, R>(&self, accept: F) -> R
+        where F: FnOnce(&mut HashMap<Token, Mutex<HashMap<Token, ContiguousBuffers<Vec<u8>>>>>) -> R {
         let mut namespace_map = self.used.write()
             .expect("bip_peer: Blocks::run_with_request_map Failed To Read From Used Map");
 
-        accept(&mut namespace_map);
+        accept(&mut namespace_map)
     }
 
     /// Run the given closure with a mutable reference to the request map under the given namespace.
@@ -284,6 +301,26 @@ mod tests {
         blocks.register_namespace(namespace);
     }
 
+    #[test]
+    fn positive_unregister_namespace_reclaims_used_blocks() {
+        let blocks = Blocks::new(1);
+        let mut generator = TokenGenerator::new();
+
+        let namespace = generator.generate();
+        blocks.register_namespace(namespace);
+        for _ in 0..super::MAX_TOTAL_COUNT_SIZE {
+            blocks.allocate_block(namespace, generator.generate(), 1);
+        }
+        blocks.unregister_namespace(namespace);
+
+        // Would block forever if the blocks under the old namespace were never given back
+        let other_namespace = generator.generate();
+        blocks.register_namespace(other_namespace);
+        for _ in 0..super::MAX_TOTAL_COUNT_SIZE {
+            blocks.allocate_block(other_namespace, generator.generate(), 1);
+        }
+    }
+
     #[test]
     #[should_panic]
     fn negative_unregister_namespace_duplicate() {
@@ -1,2 +1,3 @@
+pub mod allocator;
 pub mod blocks;
 pub mod clients;
\ No newline at end of file
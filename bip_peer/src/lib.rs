@@ -1,7 +1,12 @@
-/*extern crate bip_handshake;
+#[macro_use]
+extern crate bip_bencode;
+extern crate bip_handshake;
 extern crate bip_metainfo;
 extern crate bip_util;
 extern crate byteorder;
+extern crate crypto;
+extern crate futures;
+extern crate net2;
 extern crate rotor;
 extern crate rotor_stream;
 extern crate rand;
@@ -9,15 +14,22 @@ extern crate rand;
 extern crate nom;
 #[macro_use]
 extern crate error_chain;
+#[macro_use]
+extern crate log;
 extern crate chan;
 extern crate crossbeam;
+extern crate memmap;
+extern crate tokio_core;
+#[macro_use]
+extern crate tokio_io;
 
 pub mod disk;
 pub mod message;
+pub mod metrics;
 pub mod protocol;
 pub mod selector;
 
 mod registration;
 pub mod token;
 
-pub use registration::LayerRegistration;*/
\ No newline at end of file
+pub use registration::LayerRegistration;
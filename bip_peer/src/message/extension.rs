@@ -1,7 +1,9 @@
 //! Extended wire protocol message parsing and serializing.
 
+use std::collections::BTreeMap;
 use std::io::{self, Write};
 
+use bip_bencode::{Bencode, Dictionary};
 use byteorder::{WriteBytesExt, BigEndian};
 use nom::{IResult, be_u32, be_u8, be_u16};
 
@@ -10,10 +12,20 @@ use message;
 const PORT_MESSAGE_LEN: u32 = 3;
 
 const PORT_MESSAGE_ID: u8 = 9;
+const EXTENSION_MESSAGE_ID: u8 = 20;
 
-#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+/// Extension message id reserved for the extended handshake.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+const MESSAGE_IDS_KEY: &'static [u8] = b"m";
+const CLIENT_VERSION_KEY: &'static [u8] = b"v";
+const LISTEN_PORT_KEY: &'static [u8] = b"p";
+const METADATA_SIZE_KEY: &'static [u8] = b"metadata_size";
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub enum ExtensionType {
     Port(PortMessage),
+    Extension(ExtensionMessage),
 }
 
 impl ExtensionType {
@@ -26,6 +38,7 @@ impl ExtensionType {
     {
         match self {
             &ExtensionType::Port(msg) => msg.write_bytes(writer),
+            &ExtensionType::Extension(ref msg) => msg.write_bytes(writer),
         }
     }
 }
@@ -34,6 +47,9 @@ fn parse_extension(bytes: &[u8]) -> IResult<&[u8], ExtensionType> {
     switch!(bytes, tuple!(be_u32, be_u8),
         (PORT_MESSAGE_LEN, PORT_MESSAGE_ID) => map!(
             call!(PortMessage::from_bytes), |port| ExtensionType::Port(port)
+        ) |
+        (message_len, EXTENSION_MESSAGE_ID) => map!(
+            call!(ExtensionMessage::from_bytes, message_len.saturating_sub(2)), |ext| ExtensionType::Extension(ext)
         )
     )
 }
@@ -61,8 +77,225 @@ impl PortMessage {
 
         writer.write_u16::<BigEndian>(self.port)
     }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
 }
 
 fn parse_port(bytes: &[u8]) -> IResult<&[u8], PortMessage> {
     map!(bytes, be_u16, |port| PortMessage::new(port))
 }
+
+// ----------------------------------------------------------------------------//
+
+/// Message sent using the extension protocol (BEP 10).
+///
+/// The extension id is either `EXTENDED_HANDSHAKE_ID`, or an id that the
+/// receiving peer advertised for a given extension in its extended handshake.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct ExtensionMessage {
+    id: u8,
+    payload: Vec<u8>,
+}
+
+impl ExtensionMessage {
+    pub fn new(id: u8, payload: Vec<u8>) -> ExtensionMessage {
+        ExtensionMessage {
+            id: id,
+            payload: payload,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8], len: u32) -> IResult<&[u8], ExtensionMessage> {
+        parse_extension_message(bytes, message::u32_to_usize(len))
+    }
+
+    pub fn write_bytes<W>(&self, mut writer: W) -> io::Result<()>
+        where W: Write
+    {
+        let actual_length = (2 + self.payload.len()) as u32;
+        try!(message::write_length_id_pair(&mut writer, actual_length, Some(EXTENSION_MESSAGE_ID)));
+
+        try!(writer.write_u8(self.id));
+        writer.write_all(&self.payload)
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+fn parse_extension_message(bytes: &[u8], len: usize) -> IResult<&[u8], ExtensionMessage> {
+    chain!(bytes,
+        id:      be_u8 ~
+        payload: take!(len) ,
+        || { ExtensionMessage::new(id, (payload as &[u8]).to_vec()) }
+    )
+}
+
+// ----------------------------------------------------------------------------//
+
+/// Extended handshake dictionary exchanged after the peer handshake (BEP 10).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    ids: BTreeMap<String, u8>,
+    client: Option<String>,
+    port: Option<u16>,
+    metadata_size: Option<usize>,
+}
+
+impl ExtendedHandshake {
+    /// Create a new, empty ExtendedHandshake.
+    pub fn new() -> ExtendedHandshake {
+        ExtendedHandshake {
+            ids: BTreeMap::new(),
+            client: None,
+            port: None,
+            metadata_size: None,
+        }
+    }
+
+    /// Parse an ExtendedHandshake from the given ExtensionMessage.
+    ///
+    /// Returns None if the message is not an extended handshake or is malformed.
+    pub fn from_message(msg: &ExtensionMessage) -> Option<ExtendedHandshake> {
+        if msg.id() != EXTENDED_HANDSHAKE_ID {
+            return None;
+        }
+
+        Bencode::decode(msg.payload()).ok().and_then(|bencode| {
+            bencode.dict().map(|root_dict| {
+                let mut handshake = ExtendedHandshake::new();
+
+                // Per BEP 10, an id of zero means the extension has been disabled
+                if let Some(ids_dict) = root_dict.lookup(MESSAGE_IDS_KEY).and_then(|ids| ids.dict()) {
+                    for (name, id) in ids_dict.to_list() {
+                        let opt_name = String::from_utf8((*name).to_vec()).ok();
+                        let opt_id = id.int().and_then(|id| if id > 0 && id <= u8::max_value() as i64 { Some(id as u8) } else { None });
+
+                        if let (Some(name), Some(id)) = (opt_name, opt_id) {
+                            handshake.ids.insert(name, id);
+                        }
+                    }
+                }
+
+                handshake.client = root_dict.lookup(CLIENT_VERSION_KEY)
+                    .and_then(|client| client.str())
+                    .map(|client| client.to_owned());
+                handshake.port = root_dict.lookup(LISTEN_PORT_KEY)
+                    .and_then(|port| port.int())
+                    .and_then(|port| if port > 0 && port <= u16::max_value() as i64 { Some(port as u16) } else { None });
+                handshake.metadata_size = root_dict.lookup(METADATA_SIZE_KEY)
+                    .and_then(|size| size.int())
+                    .and_then(|size| if size > 0 { Some(size as usize) } else { None });
+
+                handshake
+            })
+        })
+    }
+
+    /// Serialize the ExtendedHandshake as an ExtensionMessage.
+    pub fn to_message(&self) -> ExtensionMessage {
+        let mut ids_dict = BTreeMap::new();
+        for (name, id) in self.ids.iter() {
+            ids_dict.insert(name.as_bytes(), ben_int!(*id as i64));
+        }
+
+        let mut root_dict = BTreeMap::new();
+        root_dict.insert(MESSAGE_IDS_KEY, Bencode::Dict(ids_dict));
+        self.client.as_ref().map(|client| root_dict.insert(CLIENT_VERSION_KEY, ben_bytes!(client)));
+        self.port.map(|port| root_dict.insert(LISTEN_PORT_KEY, ben_int!(port as i64)));
+        self.metadata_size.map(|size| root_dict.insert(METADATA_SIZE_KEY, ben_int!(size as i64)));
+
+        ExtensionMessage::new(EXTENDED_HANDSHAKE_ID, Bencode::Dict(root_dict).encode())
+    }
+
+    /// Advertise support for the named extension under the given message id.
+    pub fn add_extension(&mut self, name: &str, id: u8) {
+        self.ids.insert(name.to_owned(), id);
+    }
+
+    /// Message id that the named extension should be sent with, if supported.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.ids.get(name).map(|id| *id)
+    }
+
+    /// Set the client name and version.
+    pub fn set_client(&mut self, client: &str) {
+        self.client = Some(client.to_owned());
+    }
+
+    /// Client name and version, if advertised.
+    pub fn client(&self) -> Option<&str> {
+        self.client.as_ref().map(|client| &client[..])
+    }
+
+    /// Set the local TCP listen port.
+    pub fn set_port(&mut self, port: u16) {
+        self.port = Some(port);
+    }
+
+    /// Local TCP listen port, if advertised.
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// Set the size, in bytes, of the info dictionary for the torrent (BEP 9).
+    pub fn set_metadata_size(&mut self, size: usize) {
+        self.metadata_size = Some(size);
+    }
+
+    /// Size, in bytes, of the info dictionary for the torrent, if advertised.
+    pub fn metadata_size(&self) -> Option<usize> {
+        self.metadata_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nom::IResult;
+
+    use super::{ExtendedHandshake, ExtensionMessage, ExtensionType, EXTENDED_HANDSHAKE_ID};
+
+    #[test]
+    fn positive_extended_handshake_round_trip() {
+        let mut handshake = ExtendedHandshake::new();
+        handshake.add_extension("ut_metadata", 3);
+        handshake.set_client("bip 0.1.0");
+        handshake.set_port(6881);
+        handshake.set_metadata_size(31235);
+
+        let recv_handshake = ExtendedHandshake::from_message(&handshake.to_message()).unwrap();
+
+        assert_eq!(handshake, recv_handshake);
+        assert_eq!(Some(3), recv_handshake.extension_id("ut_metadata"));
+        assert_eq!(Some("bip 0.1.0"), recv_handshake.client());
+        assert_eq!(Some(6881), recv_handshake.port());
+        assert_eq!(Some(31235), recv_handshake.metadata_size());
+    }
+
+    #[test]
+    fn positive_extension_message_round_trip() {
+        let message = ExtensionMessage::new(EXTENDED_HANDSHAKE_ID, b"de".to_vec());
+
+        let mut bytes = Vec::new();
+        ExtensionType::Extension(message.clone()).write_bytes(&mut bytes).unwrap();
+
+        match ExtensionType::from_bytes(&bytes) {
+            IResult::Done(_, ExtensionType::Extension(recv_message)) => assert_eq!(message, recv_message),
+            _ => panic!("Failed To Parse ExtensionMessage"),
+        }
+    }
+
+    #[test]
+    fn negative_extended_handshake_wrong_id() {
+        let message = ExtensionMessage::new(1, b"de".to_vec());
+
+        assert!(ExtendedHandshake::from_message(&message).is_none());
+    }
+}
@@ -0,0 +1,129 @@
+//! Fast extension wire protocol message parsing and serializing.
+
+use std::io::{self, Write};
+
+use byteorder::{WriteBytesExt, BigEndian};
+use nom::{IResult, be_u32};
+
+use message;
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct SuggestPieceMessage {
+    piece_index: u32,
+}
+
+impl SuggestPieceMessage {
+    pub fn new(piece_index: u32) -> SuggestPieceMessage {
+        SuggestPieceMessage { piece_index: piece_index }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> IResult<&[u8], SuggestPieceMessage> {
+        parse_suggest_piece(bytes)
+    }
+
+    pub fn write_bytes<W>(&self, mut writer: W) -> io::Result<()>
+        where W: Write
+    {
+        try!(message::write_length_id_pair(&mut writer,
+                                           message::SUGGEST_PIECE_MESSAGE_LEN,
+                                           Some(message::SUGGEST_PIECE_MESSAGE_ID)));
+
+        writer.write_u32::<BigEndian>(self.piece_index)
+    }
+
+    pub fn piece_index(&self) -> u32 {
+        self.piece_index
+    }
+}
+
+fn parse_suggest_piece(bytes: &[u8]) -> IResult<&[u8], SuggestPieceMessage> {
+    map!(bytes, be_u32, |index| SuggestPieceMessage::new(index))
+}
+
+// ----------------------------------------------------------------------------//
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct RejectRequestMessage {
+    piece_index: u32,
+    block_offset: u32,
+    block_length: usize,
+}
+
+impl RejectRequestMessage {
+    pub fn new(piece_index: u32, block_offset: u32, block_length: usize) -> RejectRequestMessage {
+        RejectRequestMessage {
+            piece_index: piece_index,
+            block_offset: block_offset,
+            block_length: block_length,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> IResult<&[u8], RejectRequestMessage> {
+        parse_reject_request(bytes)
+    }
+
+    pub fn write_bytes<W>(&self, mut writer: W) -> io::Result<()>
+        where W: Write
+    {
+        try!(message::write_length_id_pair(&mut writer,
+                                           message::REJECT_REQUEST_MESSAGE_LEN,
+                                           Some(message::REJECT_REQUEST_MESSAGE_ID)));
+
+        try!(writer.write_u32::<BigEndian>(self.piece_index));
+        try!(writer.write_u32::<BigEndian>(self.block_offset));
+        writer.write_u32::<BigEndian>(self.block_length as u32)
+    }
+
+    pub fn piece_index(&self) -> u32 {
+        self.piece_index
+    }
+
+    pub fn block_offset(&self) -> u32 {
+        self.block_offset
+    }
+
+    pub fn block_length(&self) -> usize {
+        self.block_length
+    }
+}
+
+fn parse_reject_request(bytes: &[u8]) -> IResult<&[u8], RejectRequestMessage> {
+    map!(bytes,
+         tuple!(be_u32, be_u32, be_u32),
+         |(index, offset, length)| RejectRequestMessage::new(index, offset, message::u32_to_usize(length)))
+}
+
+// ----------------------------------------------------------------------------//
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct AllowedFastMessage {
+    piece_index: u32,
+}
+
+impl AllowedFastMessage {
+    pub fn new(piece_index: u32) -> AllowedFastMessage {
+        AllowedFastMessage { piece_index: piece_index }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> IResult<&[u8], AllowedFastMessage> {
+        parse_allowed_fast(bytes)
+    }
+
+    pub fn write_bytes<W>(&self, mut writer: W) -> io::Result<()>
+        where W: Write
+    {
+        try!(message::write_length_id_pair(&mut writer,
+                                           message::ALLOWED_FAST_MESSAGE_LEN,
+                                           Some(message::ALLOWED_FAST_MESSAGE_ID)));
+
+        writer.write_u32::<BigEndian>(self.piece_index)
+    }
+
+    pub fn piece_index(&self) -> u32 {
+        self.piece_index
+    }
+}
+
+fn parse_allowed_fast(bytes: &[u8]) -> IResult<&[u8], AllowedFastMessage> {
+    map!(bytes, be_u32, |index| AllowedFastMessage::new(index))
+}
@@ -0,0 +1,140 @@
+//! Metadata exchange extension message parsing and serializing (BEP 9).
+
+use std::collections::BTreeMap;
+
+use bip_bencode::{Bencode, Dictionary};
+
+use message::extension::ExtensionMessage;
+
+/// Name of the metadata extension, as advertised in the extended handshake.
+pub const METADATA_EXTENSION_NAME: &'static str = "ut_metadata";
+
+/// Size of each piece of metadata, except for the last piece, which may be shorter.
+pub const METADATA_PIECE_LEN: usize = 16 * 1024;
+
+const REQUEST_MESSAGE_TYPE: i64 = 0;
+const DATA_MESSAGE_TYPE: i64 = 1;
+const REJECT_MESSAGE_TYPE: i64 = 2;
+
+const MESSAGE_TYPE_KEY: &'static [u8] = b"msg_type";
+const PIECE_KEY: &'static [u8] = b"piece";
+const TOTAL_SIZE_KEY: &'static [u8] = b"total_size";
+
+/// Message sent between peers to exchange pieces of the info dictionary.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum MetadataMessage {
+    /// Request for the given piece of metadata.
+    Request(usize),
+    /// Piece of metadata, along with the total size of the metadata.
+    Data(usize, usize, Vec<u8>),
+    /// Rejection of a request for the given piece of metadata.
+    Reject(usize),
+}
+
+impl MetadataMessage {
+    /// Parse a MetadataMessage from the payload of an ExtensionMessage.
+    ///
+    /// Returns None if the payload is malformed.
+    pub fn from_payload(payload: &[u8]) -> Option<MetadataMessage> {
+        // Data messages have the raw metadata appended after the dictionary
+        let dict_len = match bencode_len(payload) {
+            Some(len) => len,
+            None => return None,
+        };
+
+        Bencode::decode(&payload[..dict_len]).ok().and_then(|bencode| {
+            bencode.dict().and_then(|root_dict| {
+                let opt_kind = root_dict.lookup(MESSAGE_TYPE_KEY).and_then(|kind| kind.int());
+                let opt_piece = root_dict.lookup(PIECE_KEY)
+                    .and_then(|piece| piece.int())
+                    .and_then(|piece| if piece >= 0 { Some(piece as usize) } else { None });
+                let opt_total_size = root_dict.lookup(TOTAL_SIZE_KEY)
+                    .and_then(|size| size.int())
+                    .and_then(|size| if size > 0 { Some(size as usize) } else { None });
+
+                match (opt_kind, opt_piece, opt_total_size) {
+                    (Some(REQUEST_MESSAGE_TYPE), Some(piece), _) => Some(MetadataMessage::Request(piece)),
+                    (Some(DATA_MESSAGE_TYPE), Some(piece), Some(total_size)) => {
+                        Some(MetadataMessage::Data(piece, total_size, payload[dict_len..].to_vec()))
+                    }
+                    (Some(REJECT_MESSAGE_TYPE), Some(piece), _) => Some(MetadataMessage::Reject(piece)),
+                    _ => None,
+                }
+            })
+        })
+    }
+
+    /// Serialize the MetadataMessage as an ExtensionMessage with the given (remote) extension id.
+    pub fn to_message(&self, id: u8) -> ExtensionMessage {
+        let mut root_dict = BTreeMap::new();
+
+        let (kind, piece) = match self {
+            &MetadataMessage::Request(piece) => (REQUEST_MESSAGE_TYPE, piece),
+            &MetadataMessage::Data(piece, total_size, _) => {
+                root_dict.insert(TOTAL_SIZE_KEY, ben_int!(total_size as i64));
+
+                (DATA_MESSAGE_TYPE, piece)
+            }
+            &MetadataMessage::Reject(piece) => (REJECT_MESSAGE_TYPE, piece),
+        };
+        root_dict.insert(MESSAGE_TYPE_KEY, ben_int!(kind));
+        root_dict.insert(PIECE_KEY, ben_int!(piece as i64));
+
+        let mut payload = Bencode::Dict(root_dict).encode();
+        if let &MetadataMessage::Data(_, _, ref data) = self {
+            payload.extend_from_slice(data);
+        }
+
+        ExtensionMessage::new(id, payload)
+    }
+}
+
+/// Length of the bencoded value at the start of the given bytes, if it is well formed.
+fn bencode_len(bytes: &[u8]) -> Option<usize> {
+    match bytes.first() {
+        Some(&b'i') => bytes.iter().position(|&byte| byte == b'e').map(|end| end + 1),
+        Some(&b'l') | Some(&b'd') => {
+            let mut offset = 1;
+
+            while bytes.get(offset).map_or(false, |&byte| byte != b'e') {
+                match bencode_len(&bytes[offset..]) {
+                    Some(len) => offset += len,
+                    None => return None,
+                }
+            }
+
+            if offset < bytes.len() { Some(offset + 1) } else { None }
+        }
+        Some(&byte) if byte >= b'0' && byte <= b'9' => {
+            bytes.iter().position(|&byte| byte == b':').and_then(|colon| {
+                String::from_utf8_lossy(&bytes[..colon])
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|len| if colon + 1 + len <= bytes.len() { Some(colon + 1 + len) } else { None })
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MetadataMessage;
+
+    #[test]
+    fn positive_data_message_round_trip() {
+        let message = MetadataMessage::Data(1, 16484, vec![55u8; 100]);
+
+        let ext_message = message.to_message(3);
+
+        assert_eq!(3, ext_message.id());
+        assert_eq!(Some(message), MetadataMessage::from_payload(ext_message.payload()));
+    }
+
+    #[test]
+    fn negative_data_message_missing_total_size() {
+        let payload = b"d8:msg_typei1e5:piecei0eeabcd";
+
+        assert_eq!(None, MetadataMessage::from_payload(&payload[..]));
+    }
+}
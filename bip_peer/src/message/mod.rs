@@ -7,6 +7,7 @@ use byteorder::{WriteBytesExt, BigEndian};
 use nom::{IResult, be_u32, be_u8};
 
 use message::extension::ExtensionType;
+use message::fast::{SuggestPieceMessage, RejectRequestMessage, AllowedFastMessage};
 use message::standard::{HaveMessage, BitFieldMessage, RequestMessage, PieceMessage, CancelMessage};
 
 pub const KEEP_ALIVE_MESSAGE_LEN: u32 = 0;
@@ -17,6 +18,11 @@ pub const UNINTERESTED_MESSAGE_LEN: u32 = 1;
 pub const HAVE_MESSAGE_LEN: u32 = 5;
 pub const REQUEST_MESSAGE_LEN: u32 = 13;
 pub const CANCEL_MESSAGE_LEN: u32 = 13;
+pub const SUGGEST_PIECE_MESSAGE_LEN: u32 = 5;
+pub const HAVE_ALL_MESSAGE_LEN: u32 = 1;
+pub const HAVE_NONE_MESSAGE_LEN: u32 = 1;
+pub const REJECT_REQUEST_MESSAGE_LEN: u32 = 13;
+pub const ALLOWED_FAST_MESSAGE_LEN: u32 = 5;
 
 pub const CHOKE_MESSAGE_ID: u8 = 0;
 pub const UNCHOKE_MESSAGE_ID: u8 = 1;
@@ -27,10 +33,18 @@ pub const BITFIELD_MESSAGE_ID: u8 = 5;
 pub const REQUEST_MESSAGE_ID: u8 = 6;
 pub const PIECE_MESSAGE_ID: u8 = 7;
 pub const CANCEL_MESSAGE_ID: u8 = 8;
+pub const SUGGEST_PIECE_MESSAGE_ID: u8 = 13;
+pub const HAVE_ALL_MESSAGE_ID: u8 = 14;
+pub const HAVE_NONE_MESSAGE_ID: u8 = 15;
+pub const REJECT_REQUEST_MESSAGE_ID: u8 = 16;
+pub const ALLOWED_FAST_MESSAGE_ID: u8 = 17;
 
 pub const MESSAGE_LENGTH_LEN_BYTES: usize = 4;
 
 pub mod extension;
+pub mod fast;
+pub mod metadata;
+pub mod pex;
 pub mod standard;
 
 /// Enumeration of all (shallow) peer wire protocol messages.
@@ -46,6 +60,11 @@ pub enum MessageType {
     Request(RequestMessage),
     Piece(PieceMessage),
     Cancel(CancelMessage),
+    SuggestPiece(SuggestPieceMessage),
+    HaveAll,
+    HaveNone,
+    RejectRequest(RejectRequestMessage),
+    AllowedFast(AllowedFastMessage),
     Extension(ExtensionType),
 }
 
@@ -54,6 +73,18 @@ impl MessageType {
         parse_message(bytes)
     }
 
+    /// Whether or not the message is part of the fast extension (BEP 6).
+    pub fn is_fast_message(&self) -> bool {
+        match self {
+            &MessageType::SuggestPiece(_) |
+            &MessageType::HaveAll |
+            &MessageType::HaveNone |
+            &MessageType::RejectRequest(_) |
+            &MessageType::AllowedFast(_) => true,
+            _ => false,
+        }
+    }
+
     pub fn write_bytes<W>(&self, mut writer: W) -> io::Result<()>
         where W: Write
     {
@@ -68,6 +99,11 @@ impl MessageType {
             &MessageType::Request(ref msg) => msg.write_bytes(writer),
             &MessageType::Piece(ref msg) => msg.write_bytes(writer),
             &MessageType::Cancel(ref msg) => msg.write_bytes(writer),
+            &MessageType::SuggestPiece(ref msg) => msg.write_bytes(writer),
+            &MessageType::HaveAll => write_length_id_pair(writer, HAVE_ALL_MESSAGE_LEN, Some(HAVE_ALL_MESSAGE_ID)),
+            &MessageType::HaveNone => write_length_id_pair(writer, HAVE_NONE_MESSAGE_LEN, Some(HAVE_NONE_MESSAGE_ID)),
+            &MessageType::RejectRequest(ref msg) => msg.write_bytes(writer),
+            &MessageType::AllowedFast(ref msg) => msg.write_bytes(writer),
             &MessageType::Extension(ref ext) => ext.write_bytes(writer),
         }
     }
@@ -148,6 +184,24 @@ fn parse_message(bytes: &[u8]) -> IResult<&[u8], MessageType> {
             (CANCEL_MESSAGE_LEN, Some(CANCEL_MESSAGE_ID)) => map!(
                 call!(CancelMessage::from_bytes),
                 |cancel| MessageType::Cancel(cancel)
+            ) |
+            (SUGGEST_PIECE_MESSAGE_LEN, Some(SUGGEST_PIECE_MESSAGE_ID)) => map!(
+                call!(SuggestPieceMessage::from_bytes),
+                |suggest| MessageType::SuggestPiece(suggest)
+            ) |
+            (HAVE_ALL_MESSAGE_LEN, Some(HAVE_ALL_MESSAGE_ID)) => value!(
+                MessageType::HaveAll
+            ) |
+            (HAVE_NONE_MESSAGE_LEN, Some(HAVE_NONE_MESSAGE_ID)) => value!(
+                MessageType::HaveNone
+            ) |
+            (REJECT_REQUEST_MESSAGE_LEN, Some(REJECT_REQUEST_MESSAGE_ID)) => map!(
+                call!(RejectRequestMessage::from_bytes),
+                |reject| MessageType::RejectRequest(reject)
+            ) |
+            (ALLOWED_FAST_MESSAGE_LEN, Some(ALLOWED_FAST_MESSAGE_ID)) => map!(
+                call!(AllowedFastMessage::from_bytes),
+                |allowed| MessageType::AllowedFast(allowed)
             )
          ) | map!(call!(ExtensionType::from_bytes), |ext_type| MessageType::Extension(ext_type)))
 }
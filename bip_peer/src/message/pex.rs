@@ -0,0 +1,177 @@
+//! Peer exchange extension message parsing and serializing (BEP 11).
+
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+
+use bip_bencode::{Bencode, Dictionary};
+
+use message::extension::ExtensionMessage;
+
+/// Name of the peer exchange extension, as advertised in the extended handshake.
+pub const PEX_EXTENSION_NAME: &'static str = "ut_pex";
+
+/// Maximum number of added, or dropped, peers that a single message should contain.
+pub const MAX_PEX_PEERS: usize = 50;
+
+const ADDED_KEY: &'static [u8] = b"added";
+const ADDED_FLAGS_KEY: &'static [u8] = b"added.f";
+const ADDED_V6_KEY: &'static [u8] = b"added6";
+const ADDED_V6_FLAGS_KEY: &'static [u8] = b"added6.f";
+const DROPPED_KEY: &'static [u8] = b"dropped";
+const DROPPED_V6_KEY: &'static [u8] = b"dropped6";
+
+const COMPACT_V4_LEN: usize = 6;
+const COMPACT_V6_LEN: usize = 18;
+
+/// Message sent between peers to advertise peers that were connected to, or dropped.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct PexMessage {
+    added: Vec<SocketAddr>,
+    dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    /// Create a new PexMessage.
+    ///
+    /// Panics if either list contains more than `MAX_PEX_PEERS` addresses.
+    pub fn new(added: Vec<SocketAddr>, dropped: Vec<SocketAddr>) -> PexMessage {
+        if added.len() > MAX_PEX_PEERS || dropped.len() > MAX_PEX_PEERS {
+            panic!("bip_peer: PexMessage Exceeds Max Peers")
+        }
+
+        PexMessage {
+            added: added,
+            dropped: dropped,
+        }
+    }
+
+    /// Parse a PexMessage from the payload of an ExtensionMessage.
+    ///
+    /// Returns None if the payload is malformed. Lists over `MAX_PEX_PEERS` are truncated.
+    pub fn from_payload(payload: &[u8]) -> Option<PexMessage> {
+        Bencode::decode(payload).ok().and_then(|bencode| {
+            bencode.dict().map(|root_dict| {
+                let lookup_bytes = |key| root_dict.lookup(key).and_then(|value| value.bytes()).unwrap_or(&[]);
+
+                let mut added = parse_compact_v4(lookup_bytes(ADDED_KEY));
+                added.extend(parse_compact_v6(lookup_bytes(ADDED_V6_KEY)));
+                added.truncate(MAX_PEX_PEERS);
+
+                let mut dropped = parse_compact_v4(lookup_bytes(DROPPED_KEY));
+                dropped.extend(parse_compact_v6(lookup_bytes(DROPPED_V6_KEY)));
+                dropped.truncate(MAX_PEX_PEERS);
+
+                PexMessage::new(added, dropped)
+            })
+        })
+    }
+
+    /// Serialize the PexMessage as an ExtensionMessage with the given (remote) extension id.
+    pub fn to_message(&self, id: u8) -> ExtensionMessage {
+        let (added_v4, added_v6) = write_compact(&self.added);
+        let (dropped_v4, dropped_v6) = write_compact(&self.dropped);
+
+        // We don't know anything about the peers we advertise, so leave all flags unset
+        let added_flags_v4 = vec![0u8; added_v4.len() / COMPACT_V4_LEN];
+        let added_flags_v6 = vec![0u8; added_v6.len() / COMPACT_V6_LEN];
+
+        let mut root_dict = BTreeMap::new();
+        root_dict.insert(ADDED_KEY, ben_bytes!(&added_v4[..]));
+        root_dict.insert(ADDED_FLAGS_KEY, ben_bytes!(&added_flags_v4[..]));
+        root_dict.insert(ADDED_V6_KEY, ben_bytes!(&added_v6[..]));
+        root_dict.insert(ADDED_V6_FLAGS_KEY, ben_bytes!(&added_flags_v6[..]));
+        root_dict.insert(DROPPED_KEY, ben_bytes!(&dropped_v4[..]));
+        root_dict.insert(DROPPED_V6_KEY, ben_bytes!(&dropped_v6[..]));
+
+        ExtensionMessage::new(id, Bencode::Dict(root_dict).encode())
+    }
+
+    /// Peers that the sender has connected to.
+    pub fn added(&self) -> &[SocketAddr] {
+        &self.added
+    }
+
+    /// Peers that the sender has disconnected from.
+    pub fn dropped(&self) -> &[SocketAddr] {
+        &self.dropped
+    }
+}
+
+fn parse_compact_v4(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes.chunks(COMPACT_V4_LEN)
+        .filter(|chunk| chunk.len() == COMPACT_V4_LEN)
+        .map(|chunk| {
+            let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+            let port = ((chunk[4] as u16) << 8) | chunk[5] as u16;
+
+            SocketAddr::V4(SocketAddrV4::new(ip, port))
+        })
+        .collect()
+}
+
+fn parse_compact_v6(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes.chunks(COMPACT_V6_LEN)
+        .filter(|chunk| chunk.len() == COMPACT_V6_LEN)
+        .map(|chunk| {
+            let mut segments = [0u16; 8];
+            for (index, segment) in segments.iter_mut().enumerate() {
+                *segment = ((chunk[index * 2] as u16) << 8) | chunk[index * 2 + 1] as u16;
+            }
+            let ip = Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                   segments[4], segments[5], segments[6], segments[7]);
+            let port = ((chunk[16] as u16) << 8) | chunk[17] as u16;
+
+            SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))
+        })
+        .collect()
+}
+
+/// Write the addresses in compact form, returning the bytes for IPv4 and IPv6 addresses respectively.
+fn write_compact(addrs: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let mut bytes_v4 = Vec::new();
+    let mut bytes_v6 = Vec::new();
+
+    for addr in addrs {
+        match addr {
+            &SocketAddr::V4(ref v4_addr) => {
+                bytes_v4.extend_from_slice(&v4_addr.ip().octets());
+                bytes_v4.extend_from_slice(&[(v4_addr.port() >> 8) as u8, v4_addr.port() as u8]);
+            }
+            &SocketAddr::V6(ref v6_addr) => {
+                for segment in v6_addr.ip().segments().iter() {
+                    bytes_v6.extend_from_slice(&[(*segment >> 8) as u8, *segment as u8]);
+                }
+                bytes_v6.extend_from_slice(&[(v6_addr.port() >> 8) as u8, v6_addr.port() as u8]);
+            }
+        }
+    }
+
+    (bytes_v4, bytes_v6)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+
+    use super::PexMessage;
+
+    #[test]
+    fn positive_pex_message_round_trip() {
+        let added = vec![SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881)),
+                         SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1), 6882, 0, 0))];
+        let dropped = vec![SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 5), 51413))];
+        let message = PexMessage::new(added, dropped);
+
+        let ext_message = message.to_message(1);
+
+        assert_eq!(Some(message), PexMessage::from_payload(ext_message.payload()));
+    }
+
+    #[test]
+    #[should_panic]
+    fn negative_pex_message_too_many_peers() {
+        let added = vec![SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881)); 51];
+
+        PexMessage::new(added, Vec::new());
+    }
+}
@@ -72,6 +72,36 @@ impl BitFieldMessage {
 
         writer.write_all(&self.bytes)
     }
+
+    /// Whether or not the bit for the given piece is set.
+    ///
+    /// Pieces past the end of the bitfield are treated as not set.
+    pub fn has_piece(&self, piece_index: u32) -> bool {
+        let (byte_index, bit_mask) = bit_position(piece_index);
+
+        self.bytes.get(byte_index).map_or(false, |byte| byte & bit_mask != 0)
+    }
+
+    /// Set the bit for the given piece.
+    ///
+    /// Panics if the piece is past the end of the bitfield.
+    pub fn set_piece(&mut self, piece_index: u32) {
+        let (byte_index, bit_mask) = bit_position(piece_index);
+
+        self.bytes[byte_index] |= bit_mask;
+    }
+
+    /// Raw bytes of the bitfield, the high bit of the first byte corresponds to piece zero.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+fn bit_position(piece_index: u32) -> (usize, u8) {
+    let byte_index = message::u32_to_usize(piece_index / BITS_PER_BYTE);
+    let bit_mask = 0x80 >> (piece_index % BITS_PER_BYTE);
+
+    (byte_index, bit_mask)
 }
 
 fn parse_bitfield(bytes: &[u8], len: usize) -> IResult<&[u8], BitFieldMessage> {
@@ -0,0 +1,51 @@
+//! Hooks for observing the internals of the wire and disk layers.
+
+use std::time::Duration;
+
+/// Metrics reported to a `Recorder` by the wire and disk layers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Metric {
+    /// A peer completed it's handshake, and was handed off to the wire protocol.
+    PeerConnected,
+    /// A peer was disconnected from the wire protocol.
+    PeerDisconnected,
+    /// Bytes read from peers, including message headers.
+    BytesRead,
+    /// Bytes written to peers, including message headers.
+    BytesWritten,
+    /// Block received from a peer.
+    BlockReceived,
+    /// Block sent to a peer.
+    BlockServed,
+    /// Downloaded piece passed hash verification.
+    PieceGood,
+    /// Downloaded piece failed hash verification.
+    PieceBad,
+    /// Block was loaded from disk, durations span the whole load.
+    DiskLoad,
+    /// Block was processed (buffered, written out, and verified as needed), durations span the whole process.
+    DiskProcess,
+}
+
+/// Receives metrics from the wire and disk layers, for forwarding to a monitoring system.
+///
+/// All methods default to doing nothing, so implementors only override what they care about.
+/// Recorders are shared between the event loop and the disk worker threads, and so are called concurrently.
+pub trait Recorder: Send + Sync {
+    /// Increment the counter for the given metric by one.
+    fn incr(&self, metric: Metric) {
+        self.add(metric, 1)
+    }
+
+    /// Increment the counter for the given metric by the given amount.
+    fn add(&self, _metric: Metric, _amount: u64) {}
+
+    /// Record a duration for the given metric.
+    fn record_duration(&self, _metric: Metric, _duration: Duration) {}
+}
+
+/// Recorder that ignores all metrics; used when no recorder is provided.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NoopRecorder;
+
+impl Recorder for NoopRecorder {}
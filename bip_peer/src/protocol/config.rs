@@ -0,0 +1,218 @@
+use std::time::Duration;
+use std::default::Default;
+
+use protocol::keep_alive::KeepAlivePolicy;
+
+// Since we check the peer timeout lazily (because we can't have more than one timer going
+// without reimplementing a timer wheel ourselves...) in the worst case we can assume a
+// peer hasn't sent us a message for 1:59 (right before a timeout) + 1:30 (our own timeout,
+// or, worst case time until the peer timeout is checked again) or 3 minutes and 29 seconds.
+const DEFAULT_PEER_TIMEOUT_MILLIS: u64 = 2 * 60 * 1000;
+const DEFAULT_KEEP_ALIVE_MILLIS: u64 = (30 + 60) * 1000;
+// Same as the interval the selection layer re-evaluates peers at.
+const DEFAULT_STATS_INTERVAL_MILLIS: u64 = 10 * 1000;
+
+// Large enough for piece messages carrying any reasonably sized block, as well as bitfields for
+// torrents with millions of pieces, while bounding the buffer a peer can make us allocate.
+const DEFAULT_MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+// Max messages incoming to our connection from both the selection thread and disk thread.
+const DEFAULT_MAX_INCOMING_MESSAGES: usize = 8;
+
+// Max blocks we will have the disk manager loading for a single connection at any one time.
+const DEFAULT_MAX_DISK_OPERATIONS: usize = 4;
+
+/// Configures the internals of a `WireProtocol`.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct WireConfig {
+    peer_timeout: Duration,
+    keep_alive_interval: Duration,
+    max_message_length: usize,
+    stats_interval: Duration,
+    full_duplex: bool,
+    max_incoming_messages: usize,
+    max_disk_operations: usize,
+    fast_extension: bool,
+    dht_extension: bool,
+    extension_protocol: bool,
+    keep_alive_policy: KeepAlivePolicy,
+    listen_port: Option<u16>,
+}
+
+impl WireConfig {
+    /// Sets the duration that a peer can go without sending us a
+    /// message before we consider them timed out and disconnect.
+    pub fn set_peer_timeout(&mut self, timeout: Duration) {
+        self.peer_timeout = timeout;
+    }
+
+    /// Gets the peer timeout.
+    pub fn peer_timeout(&self) -> Duration {
+        self.peer_timeout
+    }
+
+    /// Sets the interval at which we will send a keep alive message
+    /// to the peer (also used as the interval for checking the peer timeout).
+    pub fn set_keep_alive_interval(&mut self, interval: Duration) {
+        self.keep_alive_interval = interval;
+    }
+
+    /// Gets the keep alive interval.
+    pub fn keep_alive_interval(&self) -> Duration {
+        self.keep_alive_interval
+    }
+
+    /// Sets the maximum length of a message (not including the length prefix)
+    /// that we will accept from a peer before disconnecting from them.
+    pub fn set_max_message_length(&mut self, length: usize) {
+        self.max_message_length = length;
+    }
+
+    /// Gets the maximum message length.
+    pub fn max_message_length(&self) -> usize {
+        self.max_message_length
+    }
+
+    /// Sets the interval at which transfer statistics for
+    /// the peer will be sent to the selection layer.
+    pub fn set_stats_interval(&mut self, interval: Duration) {
+        self.stats_interval = interval;
+    }
+
+    /// Gets the stats interval.
+    pub fn stats_interval(&self) -> Duration {
+        self.stats_interval
+    }
+
+    /// Sets whether or not writes to the peer can overlap with reads from the peer.
+    ///
+    /// When disabled, a message is only written out once we are done reading the
+    /// current message, and no reads will take place until that write is flushed.
+    pub fn set_full_duplex(&mut self, full_duplex: bool) {
+        self.full_duplex = full_duplex;
+    }
+
+    /// Gets whether or not full duplex mode is enabled.
+    pub fn full_duplex(&self) -> bool {
+        self.full_duplex
+    }
+
+    /// Sets the maximum number of messages from the selection and disk layers
+    /// that can be waiting on a single connection before those layers have to back off.
+    ///
+    /// Connections moving a lot of data may benefit from a larger buffer here.
+    ///
+    /// Panics if max_messages is zero, since the selection layer would never be able to message the peer.
+    pub fn set_max_incoming_messages(&mut self, max_messages: usize) {
+        if max_messages == 0 {
+            panic!("bip_peer: WireConfig Max Incoming Messages Must Be Non Zero")
+        }
+
+        self.max_incoming_messages = max_messages;
+    }
+
+    /// Gets the maximum number of incoming messages.
+    pub fn max_incoming_messages(&self) -> usize {
+        self.max_incoming_messages
+    }
+
+    /// Sets the maximum number of blocks that the disk manager can be loading for a single connection.
+    ///
+    /// Once reached, blocks the selection layer wants sent to the peer are held back until the disk
+    /// manager has loaded the ones before them, which in turn holds back the selection layer.
+    ///
+    /// Panics if max_operations is zero, since we would never be able to send the peer a block.
+    pub fn set_max_disk_operations(&mut self, max_operations: usize) {
+        if max_operations == 0 {
+            panic!("bip_peer: WireConfig Max Disk Operations Must Be Non Zero")
+        }
+
+        self.max_disk_operations = max_operations;
+    }
+
+    /// Gets the maximum number of outstanding disk operations.
+    pub fn max_disk_operations(&self) -> usize {
+        self.max_disk_operations
+    }
+
+    /// Sets whether or not we will use the fast extension with peers.
+    ///
+    /// The extension is only used with peers that also negotiated it during the handshake.
+    pub fn set_fast_extension(&mut self, enabled: bool) {
+        self.fast_extension = enabled;
+    }
+
+    /// Gets whether or not the fast extension is enabled.
+    pub fn fast_extension(&self) -> bool {
+        self.fast_extension
+    }
+
+    /// Sets whether or not we advertise support for the DHT to peers.
+    ///
+    /// Port messages are only exchanged with peers that also advertised support during the handshake.
+    pub fn set_dht_extension(&mut self, enabled: bool) {
+        self.dht_extension = enabled;
+    }
+
+    /// Gets whether or not the dht extension is enabled.
+    pub fn dht_extension(&self) -> bool {
+        self.dht_extension
+    }
+
+    /// Sets whether or not we will send and receive extension protocol messages.
+    ///
+    /// Extension messages are only exchanged with peers that also advertised support during the handshake,
+    /// otherwise, extension messages from the selection layer are dropped, and ones from the peer are ignored.
+    pub fn set_extension_protocol(&mut self, enabled: bool) {
+        self.extension_protocol = enabled;
+    }
+
+    /// Gets whether or not the extension protocol is enabled.
+    pub fn extension_protocol(&self) -> bool {
+        self.extension_protocol
+    }
+
+    /// Sets the policy for keeping connections to peers alive.
+    ///
+    /// Idle peers are checked for at the keep alive interval, so they may be
+    /// kept around for up to one keep alive interval past their grace period.
+    pub fn set_keep_alive_policy(&mut self, policy: KeepAlivePolicy) {
+        self.keep_alive_policy = policy;
+    }
+
+    /// Gets the keep alive policy.
+    pub fn keep_alive_policy(&self) -> KeepAlivePolicy {
+        self.keep_alive_policy
+    }
+
+    /// Sets the port that we advertise to peers as our listen port in extended handshakes.
+    ///
+    /// Extended handshakes from the selection layer that don't already advertise a port will have it added.
+    pub fn set_listen_port(&mut self, port: Option<u16>) {
+        self.listen_port = port;
+    }
+
+    /// Gets the listen port.
+    pub fn listen_port(&self) -> Option<u16> {
+        self.listen_port
+    }
+}
+
+impl Default for WireConfig {
+    fn default() -> WireConfig {
+        WireConfig {
+            peer_timeout: Duration::from_millis(DEFAULT_PEER_TIMEOUT_MILLIS),
+            keep_alive_interval: Duration::from_millis(DEFAULT_KEEP_ALIVE_MILLIS),
+            max_message_length: DEFAULT_MAX_MESSAGE_LEN,
+            stats_interval: Duration::from_millis(DEFAULT_STATS_INTERVAL_MILLIS),
+            full_duplex: false,
+            max_incoming_messages: DEFAULT_MAX_INCOMING_MESSAGES,
+            max_disk_operations: DEFAULT_MAX_DISK_OPERATIONS,
+            fast_extension: true,
+            dht_extension: false,
+            extension_protocol: true,
+            keep_alive_policy: KeepAlivePolicy::default(),
+            listen_port: None,
+        }
+    }
+}
@@ -1,12 +1,21 @@
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::sync::mpsc::{Receiver, TryRecvError};
 
+use bip_metainfo::MetainfoFile;
+
 use bip_util::bt::{PeerId, InfoHash};
 use bip_util::send::TrySender;
-use rotor::{Machine, Void, Scope, Response, EventSet};
+use rotor::{Machine, Void, Scope, Response, EventSet, Time};
 use rotor_stream::{Accepted, StreamSocket};
 
 use disk::{DiskManagerRegistration, ODiskMessage, DiskManager, IDiskMessage, DiskManagerAccess};
+use metrics::{NoopRecorder, Recorder};
 use protocol::OProtocolMessage;
+use protocol::config::WireConfig;
+use protocol::layout::PieceLayout;
+use protocol::limiter::{RateLimiter, RateLimits};
+use protocol::timeout::{LoopClock, PeerClock};
 use selector::OSelectorMessage;
 use registration::LayerRegistration;
 
@@ -14,11 +23,40 @@ use registration::LayerRegistration;
 pub struct WireContext<DR> {
     disk: Box<LayerRegistration<ODiskMessage, IDiskMessage, SS2 = DR> + Send>,
     sele: Box<TrySender<OProtocolMessage> + Send>,
+    config: WireConfig,
+    layouts: HashMap<InfoHash, PieceLayout>,
+    upload_limit: Option<RateLimiter>,
+    download_limit: Option<RateLimiter>,
+    torrent_upload_limits: HashMap<InfoHash, RateLimiter>,
+    torrent_download_limits: HashMap<InfoHash, RateLimiter>,
+    recorder: Arc<Recorder>,
+    clock: Box<PeerClock>,
+}
+
+impl<DR> WireContext<DR> {
+    /// Time that peers see for their timeouts, given the time of the event loop.
+    pub fn peer_time(&self, loop_time: Time) -> Time {
+        self.clock.peer_time(loop_time)
+    }
+
+    /// Use the given clock for the time that peers see for their timeouts.
+    pub fn set_clock<C>(&mut self, clock: C)
+        where C: PeerClock + 'static
+    {
+        self.clock = Box::new(clock);
+    }
 }
 
 impl<DR> WireContext<DR>
     where DR: DiskManagerAccess + TrySender<IDiskMessage> {
-    pub fn new<D, S>(disk: D, mut selector: S) -> WireContext<DR>
+    pub fn new<D, S>(disk: D, selector: S) -> WireContext<DR>
+        where D: LayerRegistration<ODiskMessage, IDiskMessage, SS2 = DR> + 'static + Send,
+              S: LayerRegistration<OSelectorMessage, OProtocolMessage> + 'static + Send
+    {
+        WireContext::with_config(disk, selector, WireConfig::default())
+    }
+
+    pub fn with_config<D, S>(disk: D, mut selector: S, config: WireConfig) -> WireContext<DR>
         where D: LayerRegistration<ODiskMessage, IDiskMessage, SS2 = DR> + 'static + Send,
               S: LayerRegistration<OSelectorMessage, OProtocolMessage> + 'static + Send
     {
@@ -31,9 +69,74 @@ impl<DR> WireContext<DR>
         WireContext {
             disk: Box::new(disk),
             sele: sel_send,
+            config: config,
+            layouts: HashMap::new(),
+            upload_limit: None,
+            download_limit: None,
+            torrent_upload_limits: HashMap::new(),
+            torrent_download_limits: HashMap::new(),
+            recorder: Arc::new(NoopRecorder),
+            clock: Box::new(LoopClock),
         }
     }
 
+    /// Add the torrent so that block requests for it can be validated.
+    pub fn add_torrent(&mut self, metainfo: &MetainfoFile) {
+        self.layouts.insert(metainfo.info_hash(), PieceLayout::new(metainfo.info()));
+    }
+
+    /// Limit the rate at which blocks are sent to all peers, in bytes per second.
+    pub fn set_upload_limit(&mut self, bytes_per_sec: u64) {
+        self.upload_limit = Some(RateLimiter::new(bytes_per_sec));
+    }
+
+    /// Limit the rate at which blocks are received from all peers, in bytes per second.
+    pub fn set_download_limit(&mut self, bytes_per_sec: u64) {
+        self.download_limit = Some(RateLimiter::new(bytes_per_sec));
+    }
+
+    /// Limit the rate at which blocks are sent to peers of the given torrent, in bytes per second.
+    pub fn set_torrent_upload_limit(&mut self, hash: InfoHash, bytes_per_sec: u64) {
+        self.torrent_upload_limits.insert(hash, RateLimiter::new(bytes_per_sec));
+    }
+
+    /// Limit the rate at which blocks are received from peers of the given torrent, in bytes per second.
+    pub fn set_torrent_download_limit(&mut self, hash: InfoHash, bytes_per_sec: u64) {
+        self.torrent_download_limits.insert(hash, RateLimiter::new(bytes_per_sec));
+    }
+
+    /// Rate limits that apply to a peer connected for the given torrent.
+    pub fn rate_limits(&self, hash: InfoHash) -> RateLimits {
+        let mut limits = RateLimits::new();
+
+        for limiter in self.upload_limit.iter().chain(self.torrent_upload_limits.get(&hash)) {
+            limits.add_upload(limiter.clone());
+        }
+        for limiter in self.download_limit.iter().chain(self.torrent_download_limits.get(&hash)) {
+            limits.add_download(limiter.clone());
+        }
+
+        limits
+    }
+
+    /// Report connections and transfers of all peers to the given Recorder.
+    pub fn set_recorder(&mut self, recorder: Arc<Recorder>) {
+        self.recorder = recorder;
+    }
+
+    /// Recorder that peers report their connections and transfers to.
+    pub fn recorder(&self) -> Arc<Recorder> {
+        self.recorder.clone()
+    }
+
+    pub fn piece_layout(&self, hash: InfoHash) -> Option<PieceLayout> {
+        self.layouts.get(&hash).map(|layout| *layout)
+    }
+
+    pub fn config(&self) -> WireConfig {
+        self.config
+    }
+
     pub fn register_disk(&mut self, send: Box<TrySender<ODiskMessage>>) -> DR {
         self.disk.register(send)
     }
@@ -45,6 +148,146 @@ impl<DR> WireContext<DR>
 
 // ----------------------------------------------------------------------------//
 
+/// Builder for a `WireContext`, collecting the configuration, torrents, and rate limits in one place.
+///
+/// Extensions enabled here are advertised in the reserved bytes of our handshakes, and are only
+/// used with peers that advertised them back. Encrypted connections are not supported.
+pub struct WireContextBuilder {
+    config: WireConfig,
+    layouts: HashMap<InfoHash, PieceLayout>,
+    upload_limit: Option<u64>,
+    download_limit: Option<u64>,
+    torrent_upload_limits: HashMap<InfoHash, u64>,
+    torrent_download_limits: HashMap<InfoHash, u64>,
+    recorder: Option<Arc<Recorder>>,
+}
+
+impl WireContextBuilder {
+    /// Create a new WireContextBuilder using the default WireConfig.
+    pub fn new() -> WireContextBuilder {
+        WireContextBuilder {
+            config: WireConfig::default(),
+            layouts: HashMap::new(),
+            upload_limit: None,
+            download_limit: None,
+            torrent_upload_limits: HashMap::new(),
+            torrent_download_limits: HashMap::new(),
+            recorder: None,
+        }
+    }
+
+    /// Use the given WireConfig for all peers.
+    ///
+    /// Any extensions enabled or disabled on the builder before this call are overwritten.
+    pub fn with_config(mut self, config: WireConfig) -> WireContextBuilder {
+        self.config = config;
+        self
+    }
+
+    /// Enable or disable the fast extension.
+    pub fn with_fast_extension(mut self, enabled: bool) -> WireContextBuilder {
+        self.config.set_fast_extension(enabled);
+        self
+    }
+
+    /// Enable or disable advertising support for the DHT.
+    pub fn with_dht_extension(mut self, enabled: bool) -> WireContextBuilder {
+        self.config.set_dht_extension(enabled);
+        self
+    }
+
+    /// Enable or disable the extension protocol.
+    pub fn with_extension_protocol(mut self, enabled: bool) -> WireContextBuilder {
+        self.config.set_extension_protocol(enabled);
+        self
+    }
+
+    /// Advertise the given port to peers as our listen port.
+    pub fn with_listen_port(mut self, port: u16) -> WireContextBuilder {
+        self.config.set_listen_port(Some(port));
+        self
+    }
+
+    /// Add the torrent so that block requests for it can be validated.
+    pub fn with_torrent(mut self, metainfo: &MetainfoFile) -> WireContextBuilder {
+        self.layouts.insert(metainfo.info_hash(), PieceLayout::new(metainfo.info()));
+        self
+    }
+
+    /// Limit the rate at which blocks are sent to all peers, in bytes per second.
+    pub fn with_upload_limit(mut self, bytes_per_sec: u64) -> WireContextBuilder {
+        self.upload_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Limit the rate at which blocks are received from all peers, in bytes per second.
+    pub fn with_download_limit(mut self, bytes_per_sec: u64) -> WireContextBuilder {
+        self.download_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Limit the rate at which blocks are sent to peers of the given torrent, in bytes per second.
+    pub fn with_torrent_upload_limit(mut self, hash: InfoHash, bytes_per_sec: u64) -> WireContextBuilder {
+        self.torrent_upload_limits.insert(hash, bytes_per_sec);
+        self
+    }
+
+    /// Limit the rate at which blocks are received from peers of the given torrent, in bytes per second.
+    pub fn with_torrent_download_limit(mut self, hash: InfoHash, bytes_per_sec: u64) -> WireContextBuilder {
+        self.torrent_download_limits.insert(hash, bytes_per_sec);
+        self
+    }
+
+    /// Report connections and transfers of all peers to the given Recorder.
+    pub fn with_recorder(mut self, recorder: Arc<Recorder>) -> WireContextBuilder {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Build the WireContext, registering with the given disk and selection layers.
+    ///
+    /// Panics if the keep alive interval is not less than the peer timeout, since peers using
+    /// the same timeout as us would disconnect us before our keep alive messages are sent.
+    pub fn build<D, S, DR>(self, disk: D, selector: S) -> WireContext<DR>
+        where D: LayerRegistration<ODiskMessage, IDiskMessage, SS2 = DR> + 'static + Send,
+              S: LayerRegistration<OSelectorMessage, OProtocolMessage> + 'static + Send,
+              DR: DiskManagerAccess + TrySender<IDiskMessage>
+    {
+        if self.config.keep_alive_interval() >= self.config.peer_timeout() {
+            panic!("bip_peer: WireContextBuilder Keep Alive Interval Must Be Less Than Peer Timeout")
+        }
+
+        let mut context = WireContext::with_config(disk, selector, self.config);
+        context.layouts = self.layouts;
+
+        if let Some(bytes_per_sec) = self.upload_limit {
+            context.set_upload_limit(bytes_per_sec);
+        }
+        if let Some(bytes_per_sec) = self.download_limit {
+            context.set_download_limit(bytes_per_sec);
+        }
+        for (hash, bytes_per_sec) in self.torrent_upload_limits {
+            context.set_torrent_upload_limit(hash, bytes_per_sec);
+        }
+        for (hash, bytes_per_sec) in self.torrent_download_limits {
+            context.set_torrent_download_limit(hash, bytes_per_sec);
+        }
+        if let Some(recorder) = self.recorder {
+            context.set_recorder(recorder);
+        }
+
+        context
+    }
+}
+
+impl Default for WireContextBuilder {
+    fn default() -> WireContextBuilder {
+        WireContextBuilder::new()
+    }
+}
+
+// ----------------------------------------------------------------------------//
+
 struct UnusedSender;
 
 impl TrySender<OSelectorMessage> for UnusedSender {
@@ -1,4 +1,5 @@
 use std::error::Error;
+use std::io;
 use std::fmt::{self, Display, Formatter};
 
 use protocol::PeerIdentifier;
@@ -16,6 +17,10 @@ impl ProtocolError {
             kind: kind,
         }
     }
+
+    pub fn kind(&self) -> ProtocolErrorKind {
+        self.kind
+    }
 }
 
 impl Display for ProtocolError {
@@ -33,7 +38,23 @@ impl Error for ProtocolError {
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub enum ProtocolErrorKind {
     InvalidMessage,
+    IncompleteMessage,
+    InvalidRequest,
+    /// Peer sent us a block that we did not request from it.
+    UnsolicitedPiece,
+    MessageTooLarge,
     RemoteTimeout,
+    RemoteIdle,
     RemoteDisconnect,
     RemoteError,
+    /// Disk manager responded with a token that we were not waiting on.
+    InternalInconsistency,
+    /// Disk manager failed to load a block that the peer requested, and we can't reject the request.
+    LoadError,
+    /// Reading from the peer failed.
+    ReadError(io::ErrorKind),
+    /// Writing to the peer failed.
+    WriteError(io::ErrorKind),
+    /// Connecting to the peer failed.
+    ConnectError(io::ErrorKind),
 }
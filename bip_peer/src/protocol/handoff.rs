@@ -0,0 +1,417 @@
+//! Hands off connections that completed their handshake to the protocol event loop.
+//!
+//! Handshakes are driven by `bip_handshake` on its own tokio event loop, while peers are
+//! driven by rotor, so completed sockets are duplicated out of tokio and sent over to rotor.
+
+use std::io::{self, Read, Write};
+use std::net::{self, SocketAddr};
+use std::sync::mpsc::{self, Sender, Receiver, TryRecvError};
+use std::thread;
+
+use bip_handshake::{HandshakerBuilder, CompleteMessage, InitiateMessage, Protocol as HandshakeProtocol, Extensions, Extension, Transport,
+                    LocalAddr, DiscoveryInfo};
+use bip_util::bt::{PeerId, InfoHash};
+use bip_util::send::TrySender;
+use futures::{Future, Poll, Async};
+use futures::sink::Sink;
+use futures::stream::Stream;
+use futures::sync::mpsc::{self as futures_mpsc, UnboundedSender};
+use net2::TcpBuilder;
+use rotor::{self, Machine, Scope, Response, EventSet, Void, Notifier};
+use rotor::mio::tcp::TcpStream;
+use rotor_stream;
+use tokio_core::net::{TcpStream as TokioTcpStream, TcpListener as TokioTcpListener};
+use tokio_core::reactor::{Core, Handle};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use disk::{IDiskMessage, DiskManagerAccess};
+use protocol::config::WireConfig;
+use protocol::context::WireContext;
+use protocol::wire::WireProtocol;
+
+/// Information about a peer that completed its handshake with us.
+#[derive(Debug)]
+pub struct WireSeed {
+    addr: SocketAddr,
+    pid: PeerId,
+    hash: InfoHash,
+    extensions: Extensions,
+}
+
+impl WireSeed {
+    fn new(addr: SocketAddr, pid: PeerId, hash: InfoHash, extensions: Extensions) -> WireSeed {
+        WireSeed {
+            addr: addr,
+            pid: pid,
+            hash: hash,
+            extensions: extensions,
+        }
+    }
+
+    /// Address of the peer.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Peer id that the peer gave itself.
+    pub fn pid(&self) -> PeerId {
+        self.pid
+    }
+
+    /// Torrent that the peer connected for.
+    pub fn hash(&self) -> InfoHash {
+        self.hash
+    }
+
+    /// Extensions that the peer advertised in the reserved bytes of its handshake.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+}
+
+// ----------------------------------------------------------------------------//
+
+/// Handle to a running handshaker, which hands connected peers off to the protocol layer.
+#[derive(Clone)]
+pub struct WireHandshaker {
+    port: u16,
+    pid: PeerId,
+    send: UnboundedSender<InitiateMessage>,
+}
+
+impl WireHandshaker {
+    /// Connect to the peer at the given address for the given torrent.
+    ///
+    /// Returns false if the handshaker is no longer running.
+    pub fn connect(&self, hash: InfoHash, addr: SocketAddr) -> bool {
+        let msg = InitiateMessage::new(HandshakeProtocol::BitTorrent, hash, addr);
+
+        self.send.unbounded_send(msg).is_ok()
+    }
+}
+
+impl DiscoveryInfo for WireHandshaker {
+    fn port(&self) -> u16 {
+        self.port
+    }
+
+    fn peer_id(&self) -> PeerId {
+        self.pid
+    }
+}
+
+/// Spawn the protocol event loop, and a handshaker that hands connected peers off to it.
+pub fn spawn<DR>(listen: SocketAddr, pid: PeerId, wire_context: WireContext<DR>) -> io::Result<WireHandshaker>
+    where DR: DiskManagerAccess + TrySender<IDiskMessage> + 'static
+{
+    let extensions = handshake_extensions(wire_context.config());
+    let (handoff_send, handoff_recv) = mpsc::channel();
+
+    let notifier = try!(spawn_event_loop(handoff_recv, wire_context));
+
+    let (init_send, init_recv) = futures_mpsc::unbounded();
+    let (port_send, port_recv) = mpsc::channel();
+    thread::spawn(move || {
+        let (mut core, handshaker) = match build_handshaker(listen, pid, extensions) {
+            Ok(parts) => parts,
+            Err(error) => {
+                let _ = port_send.send(Err(error));
+                return;
+            }
+        };
+        let (sink, stream) = handshaker.into_parts();
+        let _ = port_send.send(Ok(sink.port()));
+
+        let initiate = init_recv.forward(sink.sink_map_err(|_| ())).map(|_| ());
+        let handoff = stream.for_each(move |complete| hand_off(complete, &handoff_send, &notifier));
+
+        // Only finishes once either the event loop, or every WireHandshaker, has gone away
+        let _ = core.run(initiate.select(handoff));
+    });
+
+    let port = try!(port_recv.recv().expect("bip_peer: Handshaker Thread Exited Before Sending Port"));
+
+    Ok(WireHandshaker {
+        port: port,
+        pid: pid,
+        send: init_send,
+    })
+}
+
+/// Extensions that we advertise in the reserved bytes of our handshakes.
+fn handshake_extensions(config: WireConfig) -> Extensions {
+    let mut extensions = Extensions::new();
+
+    if config.fast_extension() {
+        extensions.add(Extension::Fast);
+    }
+    if config.dht_extension() {
+        extensions.add(Extension::Dht);
+    }
+    if config.extension_protocol() {
+        extensions.add(Extension::ExtensionProtocol);
+    }
+
+    extensions
+}
+
+fn build_handshaker(listen: SocketAddr, pid: PeerId, extensions: Extensions)
+                    -> io::Result<(Core, ::bip_handshake::Handshaker<HandoffStream>)> {
+    let core = try!(Core::new());
+    let handshaker = try!(HandshakerBuilder::new()
+        .with_bind_addr(listen)
+        .with_peer_id(pid)
+        .with_extensions(extensions)
+        .build::<HandoffTransport>(core.handle()));
+
+    Ok((core, handshaker))
+}
+
+fn spawn_event_loop<DR>(handoff_recv: Receiver<Handoff>, wire_context: WireContext<DR>) -> io::Result<Notifier>
+    where DR: DiskManagerAccess + TrySender<IDiskMessage> + 'static
+{
+    let (noti_send, noti_recv) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut loop_creator = match rotor::Loop::new(&rotor::Config::new()) {
+            Ok(loop_creator) => loop_creator,
+            Err(error) => {
+                let _ = noti_send.send(Err(error));
+                return;
+            }
+        };
+
+        loop_creator.add_machine_with(|early_scope| {
+                let _ = noti_send.send(Ok(early_scope.notifier()));
+
+                Response::ok(WireMachine::Handoff(handoff_recv))
+            })
+            .expect("bip_peer: Failed To Add Handoff Machine To Event Loop");
+
+        loop_creator.run(wire_context).expect("bip_peer: Protocol Event Loop Failed");
+    });
+
+    noti_recv.recv().expect("bip_peer: Event Loop Thread Exited Before Sending Notifier")
+}
+
+/// Send a completed connection over to the event loop, and wake it up.
+fn hand_off(complete: CompleteMessage<HandoffStream>, send: &Sender<Handoff>, notifier: &Notifier) -> Result<(), ()> {
+    let remote_extensions = *complete.remote_extensions();
+    let (_, _, hash, pid, addr, sock) = complete.into_parts();
+
+    let seed = WireSeed::new(addr, pid, hash, remote_extensions);
+    try!(send.send((sock.into_std(), seed)).map_err(|_| ()));
+
+    notifier.wakeup().map_err(|_| {
+        error!("bip_peer: Failed To Wakeup Event Loop For Handed Off Connection");
+    })
+}
+
+// ----------------------------------------------------------------------------//
+
+type Handoff = (net::TcpStream, WireSeed);
+
+/// State machine for the protocol event loop, either receiving connections or running a peer.
+pub enum WireMachine<DR>
+    where DR: DiskManagerAccess + TrySender<IDiskMessage>
+{
+    Handoff(Receiver<Handoff>),
+    Peer(rotor_stream::Stream<WireProtocol<TcpStream, DR>>),
+}
+
+impl<DR> WireMachine<DR>
+    where DR: DiskManagerAccess + TrySender<IDiskMessage>
+{
+    /// Spawn a peer for the next connection that was handed off to us, if any.
+    fn receive(recv: Receiver<Handoff>) -> Response<WireMachine<DR>, (TcpStream, WireSeed)> {
+        loop {
+            match recv.try_recv() {
+                Ok((std_sock, seed)) => {
+                    match TcpStream::from_stream(std_sock) {
+                        Ok(sock) => return Response::spawn(WireMachine::Handoff(recv), (sock, seed)),
+                        Err(error) => warn!("bip_peer: Failed To Register Handed Off Connection: {}", error),
+                    }
+                }
+                Err(TryRecvError::Empty) => return Response::ok(WireMachine::Handoff(recv)),
+                Err(TryRecvError::Disconnected) => return Response::done(),
+            }
+        }
+    }
+}
+
+impl<DR> Machine for WireMachine<DR>
+    where DR: DiskManagerAccess + TrySender<IDiskMessage>
+{
+    type Context = WireContext<DR>;
+    type Seed = (TcpStream, WireSeed);
+
+    fn create(seed: Self::Seed, scope: &mut Scope<Self::Context>) -> Response<Self, Void> {
+        let (sock, wire_seed) = seed;
+
+        rotor_stream::Stream::new(sock, wire_seed, scope).wrap(WireMachine::Peer)
+    }
+
+    fn ready(self, events: EventSet, scope: &mut Scope<Self::Context>) -> Response<Self, Self::Seed> {
+        match self {
+            WireMachine::Handoff(recv) => Response::ok(WireMachine::Handoff(recv)),
+            WireMachine::Peer(peer) => peer.ready(events, scope).map(WireMachine::Peer, |seed| match seed {}),
+        }
+    }
+
+    fn spawned(self, scope: &mut Scope<Self::Context>) -> Response<Self, Self::Seed> {
+        match self {
+            // More connections may have been handed off than we were woken up for
+            WireMachine::Handoff(recv) => WireMachine::receive(recv),
+            WireMachine::Peer(peer) => peer.spawned(scope).map(WireMachine::Peer, |seed| match seed {}),
+        }
+    }
+
+    fn timeout(self, scope: &mut Scope<Self::Context>) -> Response<Self, Self::Seed> {
+        match self {
+            WireMachine::Handoff(recv) => Response::ok(WireMachine::Handoff(recv)),
+            WireMachine::Peer(peer) => peer.timeout(scope).map(WireMachine::Peer, |seed| match seed {}),
+        }
+    }
+
+    fn wakeup(self, scope: &mut Scope<Self::Context>) -> Response<Self, Self::Seed> {
+        match self {
+            WireMachine::Handoff(recv) => WireMachine::receive(recv),
+            WireMachine::Peer(peer) => peer.wakeup(scope).map(WireMachine::Peer, |seed| match seed {}),
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------//
+
+/// TCP transport whose sockets can be handed off once their handshake completes.
+struct HandoffTransport;
+
+impl Transport for HandoffTransport {
+    type Socket = HandoffStream;
+    type FutureSocket = Box<Future<Item = HandoffStream, Error = io::Error>>;
+    type Listener = HandoffListener;
+
+    fn connect(addr: &SocketAddr, handle: &Handle) -> io::Result<Self::FutureSocket> {
+        let builder = match *addr {
+            SocketAddr::V4(_) => try!(TcpBuilder::new_v4()),
+            SocketAddr::V6(_) => try!(TcpBuilder::new_v6()),
+        };
+        let std_sock = try!(builder.to_tcp_stream());
+        let handoff_sock = try!(std_sock.try_clone());
+
+        Ok(Box::new(TokioTcpStream::connect_stream(std_sock, addr, handle).map(move |sock| HandoffStream::new(sock, handoff_sock))))
+    }
+
+    fn listen(addr: &SocketAddr, handle: &Handle) -> io::Result<Self::Listener> {
+        let listener = try!(TokioTcpListener::bind(addr, handle));
+        let listen_addr = try!(listener.local_addr());
+
+        Ok(HandoffListener {
+            listener: listener,
+            listen_addr: listen_addr,
+            handle: handle.clone(),
+        })
+    }
+}
+
+/// Listener yielding sockets that can be handed off.
+struct HandoffListener {
+    listener: TokioTcpListener,
+    listen_addr: SocketAddr,
+    handle: Handle,
+}
+
+impl Stream for HandoffListener {
+    type Item = (HandoffStream, SocketAddr);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        let (std_sock, addr) = try_nb!(self.listener.accept_std());
+        let handoff_sock = try!(std_sock.try_clone());
+        let sock = try!(TokioTcpStream::from_stream(std_sock, &self.handle));
+
+        Ok(Async::Ready(Some((HandoffStream::new(sock, handoff_sock), addr))))
+    }
+}
+
+impl LocalAddr for HandoffListener {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.listen_addr)
+    }
+}
+
+/// Socket driven by tokio during the handshake, holding a duplicate of itself to hand off.
+///
+/// The handshaker never reads past the end of the handshake, so no bytes are lost in the handoff.
+struct HandoffStream {
+    sock: TokioTcpStream,
+    handoff_sock: net::TcpStream,
+}
+
+impl HandoffStream {
+    fn new(sock: TokioTcpStream, handoff_sock: net::TcpStream) -> HandoffStream {
+        HandoffStream {
+            sock: sock,
+            handoff_sock: handoff_sock,
+        }
+    }
+
+    /// Deregister the socket from tokio, returning the duplicate to hand off.
+    fn into_std(self) -> net::TcpStream {
+        self.handoff_sock
+    }
+}
+
+impl Read for HandoffStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.sock.read(buf)
+    }
+}
+
+impl Write for HandoffStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sock.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+impl AsyncRead for HandoffStream {}
+
+impl AsyncWrite for HandoffStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.sock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bip_handshake::Extension;
+
+    use protocol::config::WireConfig;
+
+    #[test]
+    fn positive_default_handshake_extensions() {
+        let extensions = super::handshake_extensions(WireConfig::default());
+
+        assert!(extensions.contains(Extension::Fast));
+        assert!(extensions.contains(Extension::ExtensionProtocol));
+        assert!(!extensions.contains(Extension::Dht));
+    }
+
+    #[test]
+    fn positive_toggled_handshake_extensions() {
+        let mut config = WireConfig::default();
+        config.set_fast_extension(false);
+        config.set_dht_extension(true);
+        config.set_extension_protocol(false);
+
+        let extensions = super::handshake_extensions(config);
+
+        assert!(!extensions.contains(Extension::Fast));
+        assert!(extensions.contains(Extension::Dht));
+        assert!(!extensions.contains(Extension::ExtensionProtocol));
+    }
+}
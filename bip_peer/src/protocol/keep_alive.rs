@@ -0,0 +1,30 @@
+use std::time::Duration;
+use std::default::Default;
+
+/// Enumeration of policies for keeping connections to peers alive.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KeepAlivePolicy {
+    /// Always keep the connection alive by sending keep alive messages; default.
+    Always,
+    /// Keep the connection alive, but disconnect from the peer if they have neither
+    /// requested a block from us, nor sent us a block, within the given grace period.
+    ///
+    /// Useful for seeders that want to free up connections held by idle peers.
+    DropIdle(Duration),
+}
+
+impl KeepAlivePolicy {
+    /// Returns the grace period for idle peers, if any.
+    pub fn idle_grace_period(&self) -> Option<Duration> {
+        match *self {
+            KeepAlivePolicy::Always => None,
+            KeepAlivePolicy::DropIdle(grace_period) => Some(grace_period),
+        }
+    }
+}
+
+impl Default for KeepAlivePolicy {
+    fn default() -> KeepAlivePolicy {
+        KeepAlivePolicy::Always
+    }
+}
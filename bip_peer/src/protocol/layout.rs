@@ -0,0 +1,45 @@
+use std::cmp;
+
+use bip_metainfo::InfoDictionary;
+
+/// Piece layout of a torrent, used to validate block requests from peers.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct PieceLayout {
+    piece_length: u64,
+    total_length: u64,
+    num_pieces: u32,
+}
+
+impl PieceLayout {
+    /// Create a new PieceLayout from the given InfoDictionary.
+    pub fn new(info_dict: &InfoDictionary) -> PieceLayout {
+        PieceLayout {
+            piece_length: info_dict.piece_length() as u64,
+            total_length: info_dict.files().map(|file| file.length() as u64).sum(),
+            num_pieces: info_dict.pieces().count() as u32,
+        }
+    }
+
+    /// Number of pieces in the torrent.
+    pub fn num_pieces(&self) -> u32 {
+        self.num_pieces
+    }
+
+    /// Size of the given piece, if the piece exists.
+    pub fn piece_size(&self, piece_index: u32) -> Option<u64> {
+        if piece_index >= self.num_pieces {
+            return None;
+        }
+        let piece_start = piece_index as u64 * self.piece_length;
+        let piece_end = cmp::min(piece_start + self.piece_length, self.total_length);
+
+        Some(piece_end.saturating_sub(piece_start))
+    }
+
+    /// Whether or not the block falls completely within the given piece.
+    pub fn contains_block(&self, piece_index: u32, block_offset: u32, block_length: usize) -> bool {
+        self.piece_size(piece_index).map_or(false, |piece_size| {
+            block_offset as u64 + block_length as u64 <= piece_size
+        })
+    }
+}
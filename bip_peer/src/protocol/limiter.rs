@@ -0,0 +1,144 @@
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use disk::DEFAULT_BLOCK_SIZE;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Token bucket rate limiter that can be shared across connections.
+///
+/// The bucket holds at most one second worth of bytes, so short bursts are allowed.
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+struct Bucket {
+    bytes_per_sec: u64,
+    capacity: u64,
+    available: u64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    /// Add bytes to the bucket for the time elapsed since the last refill.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed_nanos = elapsed.as_secs() * NANOS_PER_SEC + elapsed.subsec_nanos() as u64;
+
+        // Only move the refill time forward by the amount of time we actually gave out bytes for
+        let refill_bytes = elapsed_nanos.saturating_mul(self.bytes_per_sec) / NANOS_PER_SEC;
+        if refill_bytes != 0 {
+            self.available = cmp::min(self.capacity, self.available + refill_bytes);
+            self.last_refill = now;
+        }
+    }
+
+    /// Time until the given number of bytes will be available.
+    fn time_until(&self, bytes: u64) -> Duration {
+        let missing_bytes = bytes.saturating_sub(self.available);
+        let missing_nanos = missing_bytes.saturating_mul(NANOS_PER_SEC) / self.bytes_per_sec;
+
+        Duration::new(missing_nanos / NANOS_PER_SEC, (missing_nanos % NANOS_PER_SEC) as u32)
+    }
+}
+
+impl RateLimiter {
+    /// Create a new RateLimiter allowing the given number of bytes per second.
+    ///
+    /// The burst size is never smaller than a single block, otherwise blocks could never be transferred.
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        let bytes_per_sec = cmp::max(1, bytes_per_sec);
+        let capacity = cmp::max(bytes_per_sec, DEFAULT_BLOCK_SIZE as u64);
+
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                bytes_per_sec: bytes_per_sec,
+                capacity: capacity,
+                available: capacity,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    /// Bytes per second allowed by the limiter.
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.lock().bytes_per_sec
+    }
+
+    /// Time to wait until the given number of bytes can be consumed, or None if they can be consumed now.
+    fn time_until(&self, bytes: usize) -> Option<Duration> {
+        let mut bucket = self.lock();
+        bucket.refill(Instant::now());
+
+        // Anything larger than the bucket would never go through, so treat it as a full bucket
+        let bytes = cmp::min(bytes as u64, bucket.capacity) as usize;
+
+        if bucket.available >= bytes as u64 {
+            None
+        } else {
+            Some(bucket.time_until(bytes as u64))
+        }
+    }
+
+    /// Remove the given number of bytes from the bucket.
+    fn consume(&self, bytes: usize) {
+        let mut bucket = self.lock();
+
+        bucket.available = bucket.available.saturating_sub(bytes as u64);
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<Bucket> {
+        self.bucket.lock().expect("bip_peer: RateLimiter Failed To Lock Bucket")
+    }
+}
+
+// ----------------------------------------------------------------------------//
+
+/// Rate limiters that apply to a single connection, such as a global and per torrent limit.
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    upload: Vec<RateLimiter>,
+    download: Vec<RateLimiter>,
+}
+
+impl RateLimits {
+    /// Create a new RateLimits with no limits.
+    pub fn new() -> RateLimits {
+        RateLimits::default()
+    }
+
+    /// Add a limiter for bytes we send to the peer.
+    pub fn add_upload(&mut self, limiter: RateLimiter) {
+        self.upload.push(limiter);
+    }
+
+    /// Add a limiter for bytes we receive from the peer.
+    pub fn add_download(&mut self, limiter: RateLimiter) {
+        self.download.push(limiter);
+    }
+
+    /// Attempt to upload the given number of bytes, returning the time to wait if any limiter is exhausted.
+    pub fn try_upload(&self, bytes: usize) -> Option<Duration> {
+        try_consume(&self.upload, bytes)
+    }
+
+    /// Attempt to download the given number of bytes, returning the time to wait if any limiter is exhausted.
+    pub fn try_download(&self, bytes: usize) -> Option<Duration> {
+        try_consume(&self.download, bytes)
+    }
+}
+
+/// Consume bytes from every limiter only if all limiters have the bytes available.
+fn try_consume(limiters: &[RateLimiter], bytes: usize) -> Option<Duration> {
+    let opt_wait = limiters.iter().filter_map(|limiter| limiter.time_until(bytes)).max();
+
+    if opt_wait.is_none() {
+        for limiter in limiters {
+            limiter.consume(bytes);
+        }
+    }
+
+    opt_wait
+}
@@ -1,45 +1,104 @@
 //! Wire protocol implementation for the protocol layer.
 #![allow(unused)]
 
-use std::net::SocketAddr;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr};
 use std::sync::mpsc::SyncSender;
 use std::io;
 
-use bip_handshake::BTHandshaker;
+use bip_metainfo::MetainfoFile;
 use bip_util::bt::{PeerId, InfoHash};
 use bip_util::send::{TrySender, SplitSender};
 use rotor::Notifier;
-use rotor::mio::tcp::TcpListener;
 
 use disk::{DiskManager, IDiskMessage, ODiskMessage, DiskManagerAccess};
 use selector::{OSelectorMessage, OSelectorMessageKind};
 use message::standard::{HaveMessage, BitFieldMessage, RequestMessage, PieceMessage, CancelMessage};
+use message::extension::ExtensionMessage;
+use message::fast::{SuggestPieceMessage, RejectRequestMessage, AllowedFastMessage};
 use registration::LayerRegistration;
 use token::Token;
 
+mod config;
 mod context;
 mod error;
+mod handoff;
+mod keep_alive;
+mod layout;
+mod limiter;
+mod stats;
+mod timeout;
 mod wire;
 
-pub use protocol::context::WireContext;
+pub use protocol::config::WireConfig;
+pub use protocol::context::{WireContext, WireContextBuilder};
+pub use protocol::handoff::{WireHandshaker, WireSeed};
+pub use protocol::keep_alive::KeepAlivePolicy;
+pub use protocol::layout::PieceLayout;
+pub use protocol::limiter::{RateLimiter, RateLimits};
+pub use protocol::stats::PeerStats;
+pub use protocol::timeout::{LoopClock, PeerClock};
 pub use protocol::wire::WireProtocol;
 
 /// Spawn a TCP peer protocol handshaker.
-pub fn spawn_tcp_handshaker<S, M, DLR, DL, SL>(metadata: S,
-                                               listen: SocketAddr,
-                                               pid: PeerId,
-                                               disk: DL,
-                                               select: SL)
-                                               -> io::Result<BTHandshaker<S, M>>
-    where S: TrySender<M> + 'static,
-          M: Send,
-          DLR: DiskManagerAccess + TrySender<IDiskMessage> + 'static,
+pub fn spawn_tcp_handshaker<DLR, DL, SL>(listen: SocketAddr,
+                                         pid: PeerId,
+                                         disk: DL,
+                                         select: SL)
+                                         -> io::Result<WireHandshaker>
+    where DLR: DiskManagerAccess + TrySender<IDiskMessage> + 'static,
           DL: LayerRegistration<ODiskMessage, IDiskMessage, SS2 = DLR> + 'static + Send,
           SL: LayerRegistration<OSelectorMessage, OProtocolMessage> + 'static + Send
 {
-    let wire_context = WireContext::new(disk, select);
+    spawn_tcp_handshaker_with_config(listen, pid, disk, select, WireConfig::default())
+}
+
+/// Spawn a TCP peer protocol handshaker using the given WireConfig.
+pub fn spawn_tcp_handshaker_with_config<DLR, DL, SL>(listen: SocketAddr,
+                                                     pid: PeerId,
+                                                     disk: DL,
+                                                     select: SL,
+                                                     config: WireConfig)
+                                                     -> io::Result<WireHandshaker>
+    where DLR: DiskManagerAccess + TrySender<IDiskMessage> + 'static,
+          DL: LayerRegistration<ODiskMessage, IDiskMessage, SS2 = DLR> + 'static + Send,
+          SL: LayerRegistration<OSelectorMessage, OProtocolMessage> + 'static + Send
+{
+    spawn_tcp_handshaker_with_torrents(listen, pid, disk, select, config, Vec::new())
+}
+
+/// Spawn a TCP peer protocol handshaker using the given WireConfig.
+///
+/// Block requests from peers for any of the given torrents will be validated against the torrent's piece layout.
+pub fn spawn_tcp_handshaker_with_torrents<'a, DLR, DL, SL, I>(listen: SocketAddr,
+                                                             pid: PeerId,
+                                                             disk: DL,
+                                                             select: SL,
+                                                             config: WireConfig,
+                                                             torrents: I)
+                                                             -> io::Result<WireHandshaker>
+    where DLR: DiskManagerAccess + TrySender<IDiskMessage> + 'static,
+          DL: LayerRegistration<ODiskMessage, IDiskMessage, SS2 = DLR> + 'static + Send,
+          SL: LayerRegistration<OSelectorMessage, OProtocolMessage> + 'static + Send,
+          I: IntoIterator<Item = &'a MetainfoFile>
+{
+    let mut wire_context = WireContext::with_config(disk, select, config);
+    for metainfo in torrents {
+        wire_context.add_torrent(metainfo);
+    }
+
+    spawn_tcp_handshaker_with_context(listen, pid, wire_context)
+}
 
-    BTHandshaker::<S, M>::new::<WireProtocol<TcpListener, DLR>>(metadata, listen, pid, wire_context)
+/// Spawn a TCP peer protocol handshaker using the given WireContext.
+///
+/// Useful for configuring the context, such as setting rate limits, before any peers are connected.
+pub fn spawn_tcp_handshaker_with_context<DLR>(listen: SocketAddr,
+                                              pid: PeerId,
+                                              wire_context: WireContext<DLR>)
+                                              -> io::Result<WireHandshaker>
+    where DLR: DiskManagerAccess + TrySender<IDiskMessage> + 'static
+{
+    handoff::spawn(listen, pid, wire_context)
 }
 
 // ----------------------------------------------------------------------------//
@@ -49,19 +108,50 @@ pub fn spawn_tcp_handshaker<S, M, DLR, DL, SL>(metadata: S,
 /// Since peers could be connected to us over multiple connections
 /// but may advertise the same peer id, we need to dis ambiguate
 /// them by a combination of the address (ip + port) and peer id.
-#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PeerIdentifier {
     addr: SocketAddr,
     pid: PeerId,
 }
 
 impl PeerIdentifier {
+    /// Create a new PeerIdentifier.
+    ///
+    /// IPv6 addresses are normalized so that the same peer always compares equal: IPv4 mapped
+    /// addresses (seen on dual stack sockets) become IPv4 addresses, flow info is cleared, and the
+    /// scope id is only kept for link local addresses, where it identifies the interface to use.
     pub fn new(addr: SocketAddr, pid: PeerId) -> PeerIdentifier {
         PeerIdentifier {
-            addr: addr,
+            addr: normalize_addr(addr),
             pid: pid,
         }
     }
+
+    /// Address that the peer is connected to us from.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+fn normalize_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => addr,
+        SocketAddr::V6(v6_addr) => {
+            let segments = v6_addr.ip().segments();
+
+            let is_v4_mapped = segments[..5].iter().all(|segment| *segment == 0) && segments[5] == 0xffff;
+            if is_v4_mapped {
+                let v4_ip = Ipv4Addr::new((segments[6] >> 8) as u8, segments[6] as u8, (segments[7] >> 8) as u8, segments[7] as u8);
+
+                return SocketAddr::V4(SocketAddrV4::new(v4_ip, v6_addr.port()));
+            }
+
+            let is_link_local = segments[0] & 0xffc0 == 0xfe80;
+            let scope_id = if is_link_local { v6_addr.scope_id() } else { 0 };
+
+            SocketAddr::V6(SocketAddrV6::new(*v6_addr.ip(), v6_addr.port(), 0, scope_id))
+        }
+    }
 }
 
 // ----------------------------------------------------------------------------//
@@ -191,29 +281,53 @@ pub enum OProtocolMessageKind {
     PeerPiece(Token, PieceMessage),
     /// Message that a peer has cancelled a block request from us.
     PeerCancel(CancelMessage),
+    /// Message that a block requested by a peer has been flushed to them.
+    PeerPieceSent(RequestMessage),
+    /// Message that a block requested by a peer was dropped instead of being sent to them.
+    ///
+    /// This happens when the peer cancels the block, or the torrent is paused, before it was written out.
+    PeerPieceDropped(RequestMessage),
+    /// Message that a peer has suggested we download a piece from them.
+    PeerSuggestPiece(SuggestPieceMessage),
+    /// Message that a peer has all pieces.
+    PeerHaveAll,
+    /// Message that a peer has no pieces.
+    PeerHaveNone,
+    /// Message that a peer has rejected a block request from us.
+    PeerRejectRequest(RejectRequestMessage),
+    /// Message that a peer will allow us to request a piece while choked.
+    PeerAllowedFast(AllowedFastMessage),
+    /// Message that a peer has sent us an extension protocol message.
+    PeerExtension(ExtensionMessage),
+    /// Message that a peer is listening for DHT messages on the given port.
+    PeerPort(u16),
+    /// Message containing the transfer statistics for a peer, sent periodically.
+    PeerStats(PeerStats),
 }
 
 #[cfg(test)]
 mod tests {
     use std::sync::mpsc::{self, Sender, Receiver};
-    use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr, TcpStream};
+    use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, IpAddr, Ipv4Addr, Ipv6Addr, TcpStream};
     use std::io::{Write, Read};
     use std::thread;
     use std::time::Duration;
     use std::mem;
 
-    use bip_handshake::{Handshaker, BTHandshaker};
+    use bip_handshake::{DiscoveryInfo, Extensions, Extension};
     use bip_util::send::TrySender;
+    use net2::TcpStreamExt;
     use nom::IResult;
     use chan;
 
-    use token::{TokenGenerator, Token};
+    use token::{TokenGenerator, TokenPool, Token};
     use disk::{ODiskMessage, IDiskMessage, DiskManager, DiskManagerAccess};
-    use protocol::{OProtocolMessage, IProtocolMessage, OProtocolMessageKind, PeerIdentifier};
+    use protocol::{OProtocolMessage, IProtocolMessage, OProtocolMessageKind, PeerIdentifier, WireConfig, WireContext, WireHandshaker};
+    use protocol::timeout::FakeClock;
     use selector::{OSelectorMessage, ISelectorMessage, OSelectorMessageKind};
     use registration::LayerRegistration;
     use message::MessageType;
-    use message::standard::{HaveMessage, RequestMessage};
+    use message::standard::{HaveMessage, RequestMessage, BitFieldMessage};
 
     struct MockSender;
     impl<T: Send> TrySender<T> for MockSender {
@@ -223,11 +337,11 @@ mod tests {
     }
 
     struct MockDiskManager {
-        request_gen: TokenGenerator
+        request_pool: TokenPool
     }
     impl MockDiskManager {
         fn new() -> MockDiskManager {
-            MockDiskManager{ request_gen: TokenGenerator::new() }
+            MockDiskManager{ request_pool: TokenPool::new() }
         }
     }
     impl DiskManagerAccess for MockDiskManager {
@@ -240,7 +354,11 @@ mod tests {
         }
 
         fn new_request_token(&mut self) -> Token {
-            self.request_gen.generate()
+            self.request_pool.acquire()
+        }
+
+        fn release_request_token(&mut self, token: Token) {
+            self.request_pool.release(token);
         }
     }
     impl TrySender<IDiskMessage> for MockDiskManager {
@@ -271,32 +389,55 @@ mod tests {
         }
     }
 
-    fn mock_handshaker_setup() -> (BTHandshaker<Sender<()>, ()>, TcpStream, Receiver<OProtocolMessage>) {
-        let (m_send, _m_recv): (Sender<()>, Receiver<()>) = mpsc::channel();
+    fn mock_handshaker_setup() -> (WireHandshaker, TcpStream, Receiver<OProtocolMessage>) {
+        mock_handshaker_setup_with_config(WireConfig::default())
+    }
+
+    fn mock_handshaker_setup_with_config(config: WireConfig) -> (WireHandshaker, TcpStream, Receiver<OProtocolMessage>) {
+        mock_handshaker_setup_with_clock(config, FakeClock::new())
+    }
+
+    fn mock_handshaker_setup_with_clock(config: WireConfig, clock: FakeClock) -> (WireHandshaker, TcpStream, Receiver<OProtocolMessage>) {
+        mock_handshaker_setup_with_ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), config, clock)
+    }
 
-        let listen_ip = Ipv4Addr::new(127, 0, 0, 1);
-        let listen_addr = SocketAddr::V4(SocketAddrV4::new(listen_ip, 0));
+    fn mock_handshaker_setup_with_extensions(extensions: Extensions) -> (WireHandshaker, TcpStream, Receiver<OProtocolMessage>) {
+        mock_handshaker_setup_with_parts(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), WireConfig::default(), FakeClock::new(), extensions)
+    }
+
+    fn mock_handshaker_setup_with_ip(listen_ip: IpAddr, config: WireConfig, clock: FakeClock)
+                                     -> (WireHandshaker, TcpStream, Receiver<OProtocolMessage>) {
+        mock_handshaker_setup_with_parts(listen_ip, config, clock, Extensions::new())
+    }
+
+    fn mock_handshaker_setup_with_parts(listen_ip: IpAddr, config: WireConfig, clock: FakeClock, extensions: Extensions)
+                                        -> (WireHandshaker, TcpStream, Receiver<OProtocolMessage>) {
+        let listen_addr = SocketAddr::new(listen_ip, 0);
         let pid = [0u8; 20].into();
 
         let (protocol_send, protocol_recv) = mpsc::channel();
         let mock_select_registration = MockSelectionRegistration { send: protocol_send };
         let mock_disk_registration = MockDiskRegistration{ namespace_gen: TokenGenerator::new() };
 
-        let handshaker = super::spawn_tcp_handshaker(m_send, listen_addr, pid, mock_disk_registration, mock_select_registration).unwrap();
-        handshaker.register([0u8; 20].into());
+        // Peers see the time of the event loop, pushed ahead by however far the test advances the clock
+        let mut wire_context = WireContext::with_config(mock_disk_registration, mock_select_registration, config);
+        wire_context.set_clock(clock);
+
+        let handshaker = super::spawn_tcp_handshaker_with_context(listen_addr, pid, wire_context).unwrap();
 
-        let mut stream = TcpStream::connect(SocketAddr::V4(SocketAddrV4::new(listen_ip, handshaker.port()))).unwrap();
-        mock_initiate_handshake(&mut stream);
+        let mut stream = TcpStream::connect(SocketAddr::new(listen_ip, handshaker.port())).unwrap();
+        mock_initiate_handshake(&mut stream, extensions);
 
         thread::sleep(Duration::from_millis(100));
         
         (handshaker, stream, protocol_recv)
     }
 
-    fn mock_initiate_handshake(stream: &mut TcpStream) {
+    fn mock_initiate_handshake(stream: &mut TcpStream, extensions: Extensions) {
         stream.write_all(&[19]);
         stream.write_all(&b"BitTorrent protocol"[..]);
-        stream.write_all(&[0u8; 8 + 20 + 20][..]);
+        extensions.write_bytes(&mut *stream);
+        stream.write_all(&[0u8; 20 + 20][..]);
 
         stream.read(&mut [0u8; 1 + 19 + 8 + 20 + 20]);
     }
@@ -328,6 +469,33 @@ mod tests {
         assert!(peer_send.try_send(OSelectorMessage::new(peer_ident, OSelectorMessageKind::PeerDisconnect)).is_none());
     }
 
+    #[test]
+    fn positive_connect_ipv6() {
+        let (handshaker, stream, protocol_recv) = mock_handshaker_setup_with_ip(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), WireConfig::default(), FakeClock::new());
+        let (peer_ident, peer_send) = assert_peer_connect(&protocol_recv, &stream);
+
+        assert!(peer_ident.addr().ip().is_loopback());
+        assert!(peer_send.try_send(OSelectorMessage::new(peer_ident, OSelectorMessageKind::PeerDisconnect)).is_none());
+    }
+
+    #[test]
+    fn positive_peer_identifier_normalizes_ipv6() {
+        let pid = [0u8; 20].into();
+
+        let mapped_addr = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0x0a00, 0x0001), 6881, 0, 0));
+        let v4_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881));
+        assert_eq!(PeerIdentifier::new(v4_addr, pid), PeerIdentifier::new(mapped_addr, pid));
+
+        let global_ip = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        assert_eq!(PeerIdentifier::new(SocketAddr::V6(SocketAddrV6::new(global_ip, 6881, 0, 0)), pid),
+                   PeerIdentifier::new(SocketAddr::V6(SocketAddrV6::new(global_ip, 6881, 7, 3)), pid));
+
+        // Link local addresses on different interfaces are different peers
+        let link_local_ip = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+        assert!(PeerIdentifier::new(SocketAddr::V6(SocketAddrV6::new(link_local_ip, 6881, 0, 1)), pid) !=
+                PeerIdentifier::new(SocketAddr::V6(SocketAddrV6::new(link_local_ip, 6881, 0, 2)), pid));
+    }
+
     #[test]
     fn positive_send_keep_alive() {
         let (handshaker, mut stream, protocol_recv) = mock_handshaker_setup();
@@ -403,4 +571,88 @@ mod tests {
 
         assert!(peer_send.try_send(OSelectorMessage::new(peer_ident, OSelectorMessageKind::PeerDisconnect)).is_none());
     }
+
+    #[test]
+    fn positive_send_have_none_with_fast_extension() {
+        let mut extensions = Extensions::new();
+        extensions.add(Extension::Fast);
+
+        let (handshaker, mut stream, protocol_recv) = mock_handshaker_setup_with_extensions(extensions);
+        let (peer_ident, peer_send) = assert_peer_connect(&protocol_recv, &stream);
+
+        // Since the fast extension was negotiated, our empty bitfield should be sent as a HaveNone
+        let bitfield_message = BitFieldMessage::new(8);
+        assert!(peer_send.try_send(OSelectorMessage::new(peer_ident, OSelectorMessageKind::PeerBitField(bitfield_message))).is_none());
+        thread::sleep(Duration::from_millis(100));
+
+        let mut recv_buffer = vec![0u8; 4 + 1];
+        stream.read_exact(&mut recv_buffer[..]).unwrap();
+
+        match MessageType::from_bytes(&recv_buffer) {
+            IResult::Done(_, recv_message) => assert_eq!(recv_message, MessageType::HaveNone),
+            _ => panic!("Failed To Parse HaveNone Message"),
+        }
+        assert!(peer_send.try_send(OSelectorMessage::new(peer_ident, OSelectorMessageKind::PeerDisconnect)).is_none());
+    }
+
+    #[test]
+    fn positive_peer_timeout_disconnect() {
+        let mut config = WireConfig::default();
+        config.set_keep_alive_interval(Duration::from_millis(50));
+
+        let clock = FakeClock::new();
+        let (handshaker, stream, protocol_recv) = mock_handshaker_setup_with_clock(config, clock.clone());
+        let (peer_ident, peer_send) = assert_peer_connect(&protocol_recv, &stream);
+        assert!(protocol_recv.try_recv().is_err());
+
+        // Peer never sends us anything, so once we move past its timeout, our next keep alive timeout should detect it
+        clock.advance(config.peer_timeout() + Duration::from_millis(1));
+
+        let (disconnect_peer_ident, msg_kind) = protocol_recv.recv_timeout(Duration::from_secs(5)).unwrap().destroy();
+
+        assert_eq!(disconnect_peer_ident, peer_ident);
+        match msg_kind {
+            OProtocolMessageKind::PeerDisconnect => (),
+            _ => panic!("Failed To Receive OProtocolMessageKind::PeerDisconnect"),
+        }
+    }
+
+    #[test]
+    fn negative_message_too_large_disconnect() {
+        let mut config = WireConfig::default();
+        config.set_max_message_length(1024);
+
+        let (handshaker, mut stream, protocol_recv) = mock_handshaker_setup_with_config(config);
+        let (peer_ident, peer_send) = assert_peer_connect(&protocol_recv, &stream);
+
+        // Advertise a message length past our max, the payload never has to be sent
+        stream.write_all(&[0x00, 0x00, 0x04, 0x01]).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let (disconnect_peer_ident, msg_kind) = protocol_recv.try_recv().unwrap().destroy();
+
+        assert_eq!(disconnect_peer_ident, peer_ident);
+        match msg_kind {
+            OProtocolMessageKind::PeerDisconnect => (),
+            _ => panic!("Failed To Receive OProtocolMessageKind::PeerDisconnect"),
+        }
+    }
+
+    #[test]
+    fn negative_connection_reset_disconnect() {
+        let (handshaker, stream, protocol_recv) = mock_handshaker_setup();
+        let (peer_ident, peer_send) = assert_peer_connect(&protocol_recv, &stream);
+
+        // Closing with a zero linger resets the connection, so our next read fails with an I/O error
+        stream.set_linger(Some(Duration::from_secs(0))).unwrap();
+        drop(stream);
+
+        let (disconnect_peer_ident, msg_kind) = protocol_recv.recv_timeout(Duration::from_secs(5)).unwrap().destroy();
+
+        assert_eq!(disconnect_peer_ident, peer_ident);
+        match msg_kind {
+            OProtocolMessageKind::PeerDisconnect => (),
+            _ => panic!("Failed To Receive OProtocolMessageKind::PeerDisconnect"),
+        }
+    }
 }
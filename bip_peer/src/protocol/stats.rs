@@ -0,0 +1,71 @@
+use std::time::Instant;
+
+/// Transfer statistics for a single peer connection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PeerStats {
+    bytes_read: u64,
+    bytes_written: u64,
+    blocks_served: u64,
+    blocks_received: u64,
+    last_activity: Instant,
+}
+
+impl PeerStats {
+    /// Create a new PeerStats with all counters set to zero.
+    pub fn new() -> PeerStats {
+        PeerStats {
+            bytes_read: 0,
+            bytes_written: 0,
+            blocks_served: 0,
+            blocks_received: 0,
+            last_activity: Instant::now(),
+        }
+    }
+
+    /// Record that we read the given number of bytes from the peer.
+    pub fn add_read(&mut self, bytes: usize) {
+        self.bytes_read += bytes as u64;
+        self.last_activity = Instant::now();
+    }
+
+    /// Record that we wrote the given number of bytes to the peer.
+    pub fn add_written(&mut self, bytes: usize) {
+        self.bytes_written += bytes as u64;
+        self.last_activity = Instant::now();
+    }
+
+    /// Record that we sent a block to the peer.
+    pub fn add_block_served(&mut self) {
+        self.blocks_served += 1;
+    }
+
+    /// Record that we received a block from the peer.
+    pub fn add_block_received(&mut self) {
+        self.blocks_received += 1;
+    }
+
+    /// Total bytes read from the peer, including message overhead.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Total bytes written to the peer, including message overhead.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Number of blocks we have sent to the peer.
+    pub fn blocks_served(&self) -> u64 {
+        self.blocks_served
+    }
+
+    /// Number of blocks we have received from the peer.
+    pub fn blocks_received(&self) -> u64 {
+        self.blocks_received
+    }
+
+    /// Last time we read from or wrote to the peer.
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity
+    }
+}
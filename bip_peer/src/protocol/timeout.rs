@@ -0,0 +1,215 @@
+use rotor::{Scope, GenericScope, Time};
+
+use protocol::config::WireConfig;
+use protocol::context::WireContext;
+use protocol::error::ProtocolErrorKind;
+
+/// Source of the current time for the timeouts of a peer connection.
+pub trait Clock {
+    /// Current time, as seen by the event loop.
+    fn now(&self) -> Time;
+}
+
+/// Source of the time that peers see for their timeouts, given the time of the event loop.
+pub trait PeerClock: Send {
+    /// Time that peers see, given the current time of the event loop.
+    fn peer_time(&self, loop_time: Time) -> Time;
+}
+
+/// PeerClock that gives peers the time of the event loop as is.
+pub struct LoopClock;
+
+impl PeerClock for LoopClock {
+    fn peer_time(&self, loop_time: Time) -> Time {
+        loop_time
+    }
+}
+
+impl<'a, DR> Clock for Scope<'a, WireContext<DR>> {
+    fn now(&self) -> Time {
+        self.peer_time(GenericScope::now(self))
+    }
+}
+
+// ----------------------------------------------------------------------------//
+
+/// Tracks when we last exchanged messages with a peer, to decide when either side has timed out.
+pub struct PeerTimeouts {
+    config: WireConfig,
+    last_sent: Time,
+    last_recvd: Time,
+    // Last time the peer requested a block from us, or sent us a block.
+    last_active: Time,
+}
+
+impl PeerTimeouts {
+    /// Create a new PeerTimeouts for a peer that connected at the given time.
+    pub fn new(config: WireConfig, now: Time) -> PeerTimeouts {
+        PeerTimeouts {
+            config: config,
+            last_sent: now,
+            last_recvd: now,
+            last_active: now,
+        }
+    }
+
+    /// Record that we queued a message for the peer.
+    pub fn sent(&mut self, now: Time) {
+        self.last_sent = now;
+    }
+
+    /// Record that we received a message from the peer.
+    pub fn recvd(&mut self, now: Time) {
+        self.last_recvd = now;
+    }
+
+    /// Record that the peer requested a block from us, or sent us a block.
+    pub fn active(&mut self, now: Time) {
+        self.last_active = now;
+    }
+
+    /// Returns true if the peer has exceeded it's timeout (no message received for a while).
+    pub fn peer_timeout(&self, now: Time) -> bool {
+        // Since Time does not implement Sub, we convert (now - recvd > timeout) to (now > recvd + timeout)
+        now > self.last_recvd + self.config.peer_timeout()
+    }
+
+    /// Returns true if the peer has exceeded the idle grace period of our keep alive policy.
+    pub fn peer_idle(&self, now: Time) -> bool {
+        self.config
+            .keep_alive_policy()
+            .idle_grace_period()
+            .map_or(false, |grace_period| now > self.last_active + grace_period)
+    }
+
+    /// Returns the reason we should disconnect from the peer, if the peer has timed out.
+    ///
+    /// Idle peers are only disconnected when we are woken up by our own timeout.
+    pub fn expired(&self, now: Time, check_idle: bool) -> Option<ProtocolErrorKind> {
+        if self.peer_timeout(now) {
+            Some(ProtocolErrorKind::RemoteTimeout)
+        } else if check_idle && self.peer_idle(now) {
+            Some(ProtocolErrorKind::RemoteIdle)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the timeout for ourselves at which point we will send a keep alive message.
+    pub fn self_timeout(&self, now: Time) -> Time {
+        now + self.config.keep_alive_interval()
+    }
+
+    /// Returns true if we haven't sent the peer anything within the keep alive interval.
+    pub fn needs_keep_alive(&self, now: Time) -> bool {
+        now >= self.last_sent + self.config.keep_alive_interval()
+    }
+}
+
+// ----------------------------------------------------------------------------//
+
+/// Clock that only moves when told to, for driving timeouts in tests without sleeping.
+///
+/// Clones share the same time, so the clock can be advanced from outside of the event loop.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct FakeClock {
+    elapsed: ::std::sync::Arc<::std::sync::Mutex<::std::time::Duration>>,
+}
+
+#[cfg(test)]
+impl FakeClock {
+    pub fn new() -> FakeClock {
+        FakeClock { elapsed: ::std::sync::Arc::new(::std::sync::Mutex::new(::std::time::Duration::from_secs(0))) }
+    }
+
+    pub fn advance(&self, duration: ::std::time::Duration) {
+        *self.elapsed.lock().expect("bip_peer: FakeClock Lock Poisoned") += duration;
+    }
+
+    /// Total duration that the clock has been advanced by.
+    pub fn elapsed(&self) -> ::std::time::Duration {
+        *self.elapsed.lock().expect("bip_peer: FakeClock Lock Poisoned")
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> Time {
+        Time::zero() + self.elapsed()
+    }
+}
+
+/// Runs ahead of the event loop by however far the clock was advanced, so timeouts
+/// can be expired without sleeping, while deadlines given to the event loop stay sane.
+#[cfg(test)]
+impl PeerClock for FakeClock {
+    fn peer_time(&self, loop_time: Time) -> Time {
+        loop_time + self.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use protocol::config::WireConfig;
+    use protocol::error::ProtocolErrorKind;
+    use protocol::keep_alive::KeepAlivePolicy;
+    use super::{Clock, FakeClock, PeerTimeouts};
+
+    #[test]
+    fn positive_peer_timeout_at_boundary() {
+        let clock = FakeClock::new();
+        let config = WireConfig::default();
+        let timeouts = PeerTimeouts::new(config, clock.now());
+
+        clock.advance(config.peer_timeout());
+        assert_eq!(None, timeouts.expired(clock.now(), false));
+
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(Some(ProtocolErrorKind::RemoteTimeout), timeouts.expired(clock.now(), false));
+    }
+
+    #[test]
+    fn positive_peer_timeout_reset_by_recvd() {
+        let clock = FakeClock::new();
+        let config = WireConfig::default();
+        let mut timeouts = PeerTimeouts::new(config, clock.now());
+
+        clock.advance(config.peer_timeout());
+        timeouts.recvd(clock.now());
+        clock.advance(config.peer_timeout());
+        assert_eq!(None, timeouts.expired(clock.now(), false));
+    }
+
+    #[test]
+    fn positive_peer_timeout_worst_case_detection() {
+        let clock = FakeClock::new();
+        let config = WireConfig::default();
+        let timeouts = PeerTimeouts::new(config, clock.now());
+
+        // Our last wake up was right before the peer timed out, so the next one is a keep alive interval later
+        clock.advance(config.peer_timeout() - Duration::from_secs(1));
+        assert_eq!(None, timeouts.expired(clock.now(), true));
+        let next_wake_up = timeouts.self_timeout(clock.now());
+
+        clock.advance(config.keep_alive_interval());
+        assert!(clock.now() >= next_wake_up);
+        assert_eq!(Some(ProtocolErrorKind::RemoteTimeout), timeouts.expired(clock.now(), true));
+        assert!(config.peer_timeout() - Duration::from_secs(1) + config.keep_alive_interval() <= Duration::from_secs(3 * 60 + 29));
+    }
+
+    #[test]
+    fn positive_peer_idle_only_when_checked() {
+        let clock = FakeClock::new();
+        let mut config = WireConfig::default();
+        config.set_keep_alive_policy(KeepAlivePolicy::DropIdle(Duration::from_secs(30)));
+        let mut timeouts = PeerTimeouts::new(config, clock.now());
+
+        clock.advance(Duration::from_secs(31));
+        timeouts.recvd(clock.now());
+        assert_eq!(None, timeouts.expired(clock.now(), false));
+        assert_eq!(Some(ProtocolErrorKind::RemoteIdle), timeouts.expired(clock.now(), true));
+    }
+}
@@ -1,47 +1,52 @@
+use std::sync::Arc;
 use std::sync::mpsc::{self, Receiver};
 use std::error::Error;
-use std::collections::{VecDeque, HashMap};
+use std::collections::{VecDeque, HashMap, HashSet};
 use std::collections::hash_map::Entry;
 use std::time::Duration;
 use std::marker::PhantomData;
-use std::any::Any;
+use std::cmp;
 
-use bip_handshake::{BTContext, BTSeed};
-use bip_handshake::protocol::{PeerProtocol, LocalAddress, TryBind, TryAccept, TryConnect};
 use bip_util::bt::{PeerId, InfoHash};
 use bip_util::send::{TrySender, SplitSender};
 use rotor::{Scope, Time};
-use rotor::mio::Evented;
 use rotor::mio::tcp::TcpStream;
 use rotor_stream::{Protocol, Intent, Exception, Transport, Buf, StreamSocket, SocketError};
 use nom::IResult;
 
-use disk::{DiskManager, IDiskMessage, ODiskMessage, DiskManagerAccess};
+use disk::{DiskManager, IDiskMessage, ODiskMessage, DiskManagerAccess, DEFAULT_BLOCK_SIZE};
 use message::{self, MessageType};
+use message::extension::{ExtensionType, ExtensionMessage, ExtendedHandshake, PortMessage};
+use message::fast::RejectRequestMessage;
+use message::standard::{RequestMessage, BitFieldMessage, CancelMessage, PieceMessage};
+use metrics::{Metric, Recorder};
 use protocol::{PeerIdentifier, IProtocolMessage, ProtocolSender, OProtocolMessage, OProtocolMessageKind};
+use protocol::config::WireConfig;
 use protocol::context::WireContext;
+use protocol::handoff::WireSeed;
 use protocol::error::{ProtocolError, ProtocolErrorKind};
+use protocol::layout::PieceLayout;
+use protocol::limiter::RateLimits;
+use protocol::stats::PeerStats;
+use protocol::timeout::{Clock, PeerTimeouts};
 use selector::{OSelectorMessage, OSelectorMessageKind};
 use token::Token;
 
-// Max messages incoming to our connection from both the selection thread and disk thread.
-pub const MAX_INCOMING_MESSAGES: usize = 8;
+// Seconds after a peer chokes us that we still accept blocks we requested before the choke, which may have already been in flight.
+const CHOKE_GRACE_SECS: u64 = 30;
 
-// Since we check the peer timeout lazily (because we can't have more than one timer going
-// without reimplementing a timer wheel ourselves...) in the worst case we can assume a
-// peer hasn't sent us a message for 1:59 (right before a timeout) + 1:30 (our own timeout,
-// or, worst case time until the peer timeout is checked again) or 3 minutes and 29 seconds.
-const MAX_PEER_TIMEOUT_MILLIS: u64 = 2 * 60 * 1000;
-const MAX_SELF_TIMEOUT_MILLIS: u64 = (30 + 60) * 1000;
+// Max bytes we will buffer for the peer in full duplex mode before waiting for the transport to flush them.
+const FULL_DUPLEX_MAX_BUFFERED: usize = 4 * DEFAULT_BLOCK_SIZE;
 
 /// Implementation of the peer wire protocol.
-pub struct WireProtocol<L, DR> {
+pub struct WireProtocol<S, DR> {
     id: PeerIdentifier,
     hash: InfoHash,
     disk: DR,
     send: SplitSender<ProtocolSender>,
     recv: Receiver<IProtocolMessage>,
     state: WireState,
+    config: WireConfig,
     // Any writes that can immediately be executed are
     // placed inside of this queue, during a state transition
     // this queue will be checked and popped from.
@@ -51,9 +56,41 @@ pub struct WireProtocol<L, DR> {
     // When the disk manager responds, the message will be taken
     // out of this queue and placed at the end of the write queue.
     block_queue: HashMap<Token, MessageType>,
-    last_sent: Time,
-    last_recvd: Time,
-    _listener: PhantomData<L>,
+    // Blocks that the peer cancelled while the disk manager was loading them,
+    // which we will give back to the disk manager once they have been loaded.
+    cancelled_blocks: HashSet<Token>,
+    // Messages from the selection layer that are waiting on the disk manager to catch up
+    // before they are processed, so that we don't pile up work in the disk manager.
+    deferred: VecDeque<OSelectorMessage>,
+    // Blocks that we have requested from the peer, and are still waiting on.
+    requested: HashSet<RequestMessage>,
+    // Blocks that we requested before the peer choked us, and the time until which we will still accept them.
+    choked_requests: HashMap<RequestMessage, Time>,
+    timeouts: PeerTimeouts,
+    // Whether or not the fast extension was negotiated with the peer.
+    fast_extension: bool,
+    // Whether or not the dht extension was negotiated with the peer.
+    dht_extension: bool,
+    // Whether or not the extension protocol was negotiated with the peer.
+    extension_protocol: bool,
+    // Layout of the torrent, if known, for validating requests from the peer.
+    layout: Option<PieceLayout>,
+    // Limits on how fast we can send blocks to, or receive blocks from, the peer.
+    limits: RateLimits,
+    // If we are waiting on a rate limiter, the time at which we should try again.
+    throttled_until: Option<Time>,
+    stats: PeerStats,
+    next_stats: Time,
+    recorder: Arc<Recorder>,
+    // Whether or not the selection layer asked us to close the connection once our writes are flushed.
+    closing: bool,
+    // Whether or not the selection layer paused the torrent, in which case we only send keep alives.
+    paused: bool,
+    // Block that we are currently writing to the peer, reported to the selection layer once flushed.
+    piece_in_flight: Option<RequestMessage>,
+    // Blocks that we dropped instead of sending to the peer, reported to the selection layer on our next write.
+    pieces_dropped: Vec<RequestMessage>,
+    _socket: PhantomData<S>,
 }
 
 /// Enumeration for all states that a peer can be in in terms of messages being sent or received.
@@ -71,7 +108,7 @@ pub enum WireState {
     WritePayload,
 }
 
-impl<L, DR> WireProtocol<L, DR>
+impl<S, DR> WireProtocol<S, DR>
     where DR: TrySender<IDiskMessage> + DiskManagerAccess {
     /// Create a new WireConnection and return an Intent.
     fn new(id: PeerIdentifier,
@@ -79,37 +116,75 @@ impl<L, DR> WireProtocol<L, DR>
            disk: DR,
            send: SplitSender<ProtocolSender>,
            recv: Receiver<IProtocolMessage>,
+           fast_extension: bool,
+           dht_extension: bool,
+           extension_protocol: bool,
+           layout: Option<PieceLayout>,
+           limits: RateLimits,
+           config: WireConfig,
+           recorder: Arc<Recorder>,
            now: Time)
-           -> Intent<WireProtocol<L, DR>> {
+           -> Intent<WireProtocol<S, DR>> {
         let connection = WireProtocol {
             id: id,
             hash: hash,
             state: WireState::ReadLength,
+            config: config,
             disk: disk,
             send: send,
             recv: recv,
             write_queue: VecDeque::new(),
             block_queue: HashMap::new(),
-            last_sent: now,
-            last_recvd: now,
-            _listener: PhantomData,
+            cancelled_blocks: HashSet::new(),
+            deferred: VecDeque::new(),
+            requested: HashSet::new(),
+            choked_requests: HashMap::new(),
+            timeouts: PeerTimeouts::new(config, now),
+            fast_extension: fast_extension,
+            dht_extension: dht_extension,
+            extension_protocol: extension_protocol,
+            layout: layout,
+            limits: limits,
+            throttled_until: None,
+            stats: PeerStats::new(),
+            next_stats: now + config.stats_interval(),
+            recorder: recorder,
+            closing: false,
+            paused: false,
+            piece_in_flight: None,
+            pieces_dropped: Vec::new(),
+            _socket: PhantomData,
         };
 
-        let self_timeout = connection.self_timeout(now);
-        Intent::of(connection).expect_bytes(message::MESSAGE_LENGTH_LEN_BYTES).deadline(self_timeout)
+        let deadline = connection.next_deadline(now);
+        Intent::of(connection).expect_bytes(message::MESSAGE_LENGTH_LEN_BYTES).deadline(deadline)
     }
 
-    /// Returns true if the peer has exceeded it's timeout (no message received for a while).
-    fn peer_timeout(&self, now: Time) -> bool {
-        let max_peer_timeout = Duration::from_millis(MAX_PEER_TIMEOUT_MILLIS);
+    /// Move to the given state, logging the transition.
+    fn transition(&mut self, state: WireState) {
+        if self.state != state {
+            debug!("bip_peer: {:?} Transitioning From {:?} To {:?}", self.id, self.state, state);
+        }
 
-        // Since Time does not implement Sub, we convert (now - recvd > timeout) to (now > recvd + timeout)
-        now > self.last_recvd + max_peer_timeout
+        self.state = state;
     }
 
-    /// Returns the timeout for ourselves at which point we will send a keep alive message.
-    fn self_timeout(&self, now: Time) -> Time {
-        now + Duration::from_millis(MAX_SELF_TIMEOUT_MILLIS)
+    /// Returns the time at which we should next be woken up if no events occur.
+    fn next_deadline(&self, now: Time) -> Time {
+        let deadline = cmp::min(self.timeouts.self_timeout(now), self.next_stats);
+
+        self.throttled_until.map_or(deadline, |throttled_until| cmp::min(throttled_until, deadline))
+    }
+
+    /// Send our transfer statistics to the selection layer if the stats interval has elapsed.
+    fn send_stats<F>(&mut self, now: Time, sel_send: F)
+        where F: Fn(OProtocolMessage)
+    {
+        if now >= self.next_stats {
+            sel_send(OProtocolMessage::new(self.id, OProtocolMessageKind::PeerStats(self.stats)));
+
+            self.next_stats = now + self.config.stats_interval();
+        }
     }
 
     /// Send the message to the disk manager.
@@ -129,18 +204,44 @@ impl<L, DR> WireProtocol<L, DR>
                    msg.id(),
                    self.id);
         }
-        self.last_sent = now;
+        debug!("bip_peer: {:?} Processing {:?} From Selection Layer", self.id, msg.kind());
+
+        // Messages queued before a pause are still written, but anything after it is dropped; messages
+        // that we never write out are acked right away, so that they don't hold up the selection layer
+        if self.paused {
+            match msg.kind() {
+                OSelectorMessageKind::PeerKeepAlive |
+                OSelectorMessageKind::PeerDisconnect |
+                OSelectorMessageKind::PeerPause |
+                OSelectorMessageKind::PeerResume => (),
+                _ => {
+                    self.send.sender_ack().ack();
+
+                    return false;
+                }
+            }
+        }
+        self.timeouts.sent(now);
 
         match msg.kind() {
             OSelectorMessageKind::PeerKeepAlive => self.write_queue.push_back((MessageType::KeepAlive, None)),
-            OSelectorMessageKind::PeerDisconnect => (),
+            OSelectorMessageKind::PeerDisconnect => self.send.sender_ack().ack(),
             OSelectorMessageKind::PeerChoke => self.write_queue.push_back((MessageType::Choke, None)),
             OSelectorMessageKind::PeerUnChoke => self.write_queue.push_back((MessageType::UnChoke, None)),
             OSelectorMessageKind::PeerInterested => self.write_queue.push_back((MessageType::Interested, None)),
             OSelectorMessageKind::PeerNotInterested => self.write_queue.push_back((MessageType::UnInterested, None)),
             OSelectorMessageKind::PeerHave(have_msg) => self.write_queue.push_back((MessageType::Have(have_msg), None)),
-            OSelectorMessageKind::PeerBitField(bfield_msg) => self.write_queue.push_back((MessageType::BitField(bfield_msg), None)),
-            OSelectorMessageKind::PeerRequest(req_msg) => self.write_queue.push_back((MessageType::Request(req_msg), None)),
+            OSelectorMessageKind::PeerBitField(bfield_msg) => {
+                if let Some(msg_type) = map_bitfield_message(bfield_msg, self.fast_extension, self.layout) {
+                    self.write_queue.push_back((msg_type, None));
+                } else {
+                    self.send.sender_ack().ack();
+                }
+            }
+            OSelectorMessageKind::PeerRequest(req_msg) => {
+                self.requested.insert(req_msg);
+                self.write_queue.push_back((MessageType::Request(req_msg), None));
+            }
             OSelectorMessageKind::PeerPiece(piece_msg) => {
                 let token = self.disk.new_request_token();
 
@@ -149,15 +250,107 @@ impl<L, DR> WireProtocol<L, DR>
                 self.block_queue.insert(token, MessageType::Piece(piece_msg));
             }
             OSelectorMessageKind::PeerCancel(cancel_msg) => self.write_queue.push_back((MessageType::Cancel(cancel_msg), None)),
+            OSelectorMessageKind::PeerSuggestPiece(suggest_msg) => self.push_fast_message(MessageType::SuggestPiece(suggest_msg)),
+            OSelectorMessageKind::PeerHaveAll => self.push_fast_message(MessageType::HaveAll),
+            OSelectorMessageKind::PeerHaveNone => self.push_fast_message(MessageType::HaveNone),
+            OSelectorMessageKind::PeerRejectRequest(reject_msg) => self.push_fast_message(MessageType::RejectRequest(reject_msg)),
+            OSelectorMessageKind::PeerAllowedFast(allowed_msg) => self.push_fast_message(MessageType::AllowedFast(allowed_msg)),
+            OSelectorMessageKind::PeerExtension(ext_msg) => {
+                if self.extension_protocol {
+                    let ext_msg = map_extension_message(ext_msg, self.config.listen_port());
+
+                    self.write_queue.push_back((MessageType::Extension(ExtensionType::Extension(ext_msg)), None));
+                } else {
+                    self.send.sender_ack().ack();
+                }
+            }
+            OSelectorMessageKind::PeerPort(port) => {
+                if self.dht_extension {
+                    self.write_queue.push_back((MessageType::Extension(ExtensionType::Port(PortMessage::new(port))), None));
+                } else {
+                    self.send.sender_ack().ack();
+                }
+            }
+            OSelectorMessageKind::PeerPause => {
+                self.paused = true;
+                self.drop_blocks();
+                self.send.sender_ack().ack();
+            }
+            OSelectorMessageKind::PeerResume => {
+                self.paused = false;
+                self.send.sender_ack().ack();
+            }
         }
 
         msg.kind() == OSelectorMessageKind::PeerDisconnect
     }
 
+    /// Returns true if the disk manager is busy with as many of our operations as we allow it.
+    fn disk_busy(&self) -> bool {
+        let reserving = match self.state {
+            WireState::DiskReserve(..) => 1,
+            _ => 0,
+        };
+
+        self.block_queue.len() + self.cancelled_blocks.len() + reserving >= self.config.max_disk_operations()
+    }
+
+    /// Process the message from the selection layer, unless it needs the disk manager while it is busy with
+    /// our operations, in which case, it is deferred along with any messages after it to keep them in order.
+    ///
+    /// Returns true if a disconnect from the peer should be initiated.
+    fn process_or_defer(&mut self, now: Time, msg: OSelectorMessage) -> bool {
+        if !self.deferred.is_empty() || (needs_disk(&msg) && self.disk_busy()) {
+            self.deferred.push_back(msg);
+
+            false
+        } else {
+            self.process_message(now, msg)
+        }
+    }
+
+    /// Process deferred messages from the selection layer for as long as the disk manager has room for them.
+    ///
+    /// Returns true if a disconnect from the peer should be initiated.
+    fn process_deferred(&mut self, now: Time) -> bool {
+        let mut disconnect = false;
+
+        while let Some(msg) = self.deferred.pop_front() {
+            if needs_disk(&msg) && self.disk_busy() {
+                self.deferred.push_front(msg);
+
+                break;
+            }
+            disconnect |= self.process_message(now, msg);
+        }
+
+        disconnect
+    }
+
+    /// Queue the fast extension message to be written to the remote peer.
+    ///
+    /// If the fast extension was not negotiated with the peer, the message is dropped (and acked).
+    fn push_fast_message(&mut self, msg: MessageType) {
+        if self.fast_extension {
+            self.write_queue.push_back((msg, None));
+        } else {
+            self.send.sender_ack().ack();
+        }
+    }
+
     /// Process the disk event for the given token which may or may not advance our state.
-    fn process_disk(&mut self, in_buffer: &mut Buf, token: Token) {
+    ///
+    /// Returns an error if the token is not one that we were waiting on, in which case our state can no longer be trusted.
+    fn process_disk(&mut self, in_buffer: &mut Buf, token: Token) -> Result<(), ProtocolErrorKind> {
         let curr_state = self.state;
 
+        if self.cancelled_blocks.remove(&token) {
+            self.send_disk_message(IDiskMessage::ReclaimBlock(token));
+            self.disk.release_request_token(token);
+
+            return Ok(());
+        }
+
         let opt_message_type = self.block_queue.remove(&token);
         match (opt_message_type, curr_state) {
             (Some(message_type), _) => {
@@ -168,46 +361,215 @@ impl<L, DR> WireProtocol<L, DR>
                 // Disk manager has reserved a block for us to write our received block to
                 self.disk.write_block(token, &in_buffer[..len]);
                 self.send_disk_message(IDiskMessage::ProcessBlock(token));
+                self.disk.release_request_token(token);
 
                 in_buffer.consume(len);
-                self.state = WireState::ReadLength;
+                self.transition(WireState::ReadLength);
+            }
+            _ => {
+                // Stale or duplicate token, give the block back, but not the token, since we may have already reused it
+                self.send_disk_message(IDiskMessage::ReclaimBlock(token));
+
+                return Err(ProtocolErrorKind::InternalInconsistency);
+            }
+        };
+
+        Ok(())
+    }
+
+    /// Process the disk manager failing to load the block for the given token, which it has already reclaimed.
+    ///
+    /// If the fast extension was negotiated, we reject the peer's request, otherwise, we have no way of telling the
+    /// peer that it won't be getting the block, so an error is returned and we should disconnect from the peer.
+    fn process_load_failed(&mut self, token: Token) -> Result<(), ProtocolErrorKind> {
+        if self.cancelled_blocks.remove(&token) {
+            self.disk.release_request_token(token);
+
+            return Ok(());
+        }
+
+        let opt_message_type = self.block_queue.remove(&token);
+        if opt_message_type.is_some() {
+            self.disk.release_request_token(token);
+        }
+
+        match opt_message_type {
+            Some(MessageType::Piece(piece_msg)) if self.fast_extension => {
+                let reject_msg = RejectRequestMessage::new(piece_msg.piece_index(), piece_msg.block_offset(), piece_msg.block_length());
+
+                // The reject takes the place of the block, so it is acked once written
+                self.write_queue.push_back((MessageType::RejectRequest(reject_msg), None));
+                self.pieces_dropped.push(RequestMessage::new(piece_msg.piece_index(), piece_msg.block_offset(), piece_msg.block_length()));
+
+                Ok(())
+            }
+            Some(MessageType::Piece(_)) => Err(ProtocolErrorKind::LoadError),
+            _ => Err(ProtocolErrorKind::InternalInconsistency),
+        }
+    }
+
+    /// Drop the block matching the peer's cancel if we haven't started writing it out yet.
+    fn process_cancel(&mut self, cancel: CancelMessage) {
+        let matches_cancel = |msg: &MessageType| {
+            match msg {
+                &MessageType::Piece(ref piece_msg) => {
+                    piece_msg.piece_index() == cancel.piece_index() && piece_msg.block_offset() == cancel.block_offset() &&
+                    piece_msg.block_length() == cancel.block_length()
+                }
+                _ => false,
             }
-            (None, WireState::DiskReserve(tok, len)) => unreachable!("bip_peer: Token Returned By DiskManager Was Not Expected"),
-            _ => unreachable!("bip_peer: Called ProcessDisk In An Invalid State {:?}", curr_state),
         };
+
+        let opt_queued = self.write_queue.iter().position(|&(ref msg, opt_token)| opt_token.is_some() && matches_cancel(msg));
+        if let Some((MessageType::Piece(piece_msg), Some(token))) = opt_queued.and_then(|position| self.write_queue.remove(position)) {
+            self.send_disk_message(IDiskMessage::ReclaimBlock(token));
+            self.disk.release_request_token(token);
+            self.drop_piece(piece_msg);
+
+            return;
+        }
+
+        let opt_loading = self.block_queue.iter().find(|&(_, msg)| matches_cancel(msg)).map(|(token, _)| *token);
+        if let Some(token) = opt_loading {
+            if let Some(MessageType::Piece(piece_msg)) = self.block_queue.remove(&token) {
+                self.drop_piece(piece_msg);
+            }
+            self.cancelled_blocks.insert(token);
+        }
+    }
+
+    /// Give back every block that we were going to send to the peer, including those still being loaded.
+    fn drop_blocks(&mut self) {
+        let (blocks, others): (VecDeque<_>, VecDeque<_>) = self.write_queue.drain(..).partition(|&(_, opt_token)| opt_token.is_some());
+        self.write_queue = others;
+
+        for (msg, opt_token) in blocks {
+            if let (MessageType::Piece(piece_msg), Some(token)) = (msg, opt_token) {
+                self.send_disk_message(IDiskMessage::ReclaimBlock(token));
+                self.disk.release_request_token(token);
+                self.drop_piece(piece_msg);
+            }
+        }
+
+        let loading: Vec<(Token, MessageType)> = self.block_queue.drain().collect();
+        for (token, msg) in loading {
+            self.cancelled_blocks.insert(token);
+
+            if let MessageType::Piece(piece_msg) = msg {
+                self.drop_piece(piece_msg);
+            }
+        }
+    }
+
+    /// Stop waiting on the blocks we requested, since the peer choked us, except for any that were already in flight.
+    ///
+    /// Blocks the peer sent before it saw the choke are still accepted for a grace window.
+    fn choke_requests(&mut self, now: Time) {
+        let deadline = now + Duration::from_secs(CHOKE_GRACE_SECS);
+
+        self.choked_requests.retain(|_, request_deadline| now < *request_deadline);
+        for request in self.requested.drain() {
+            self.choked_requests.insert(request, deadline);
+        }
+    }
+
+    /// Ack the selection layer's message for a block that we won't be sending, and remember to tell it that we dropped it.
+    fn drop_piece(&mut self, piece_msg: PieceMessage) {
+        self.send.sender_ack().ack();
+        self.pieces_dropped.push(RequestMessage::new(piece_msg.piece_index(), piece_msg.block_offset(), piece_msg.block_length()));
+    }
+
+    /// Returns true if we are closing the connection and have nothing left to write to the peer.
+    fn is_closed(&self) -> bool {
+        self.closing && self.write_queue.is_empty() && self.block_queue.is_empty() && self.cancelled_blocks.is_empty() &&
+        self.deferred.is_empty() && self.state == WireState::ReadLength
     }
 
     /// Transition our state into a disconnected state.
-    fn advance_disconnect<F>(self, sel_send: F, error: ProtocolError) -> Intent<WireProtocol<L, DR>>
+    fn advance_disconnect<F>(self, sel_send: F, error: ProtocolError) -> Intent<WireProtocol<S, DR>>
         where F: Fn(OProtocolMessage)
     {
+        match error.kind() {
+            ProtocolErrorKind::RemoteDisconnect => info!("bip_peer: {:?} Disconnected: {:?}", self.id, error.kind()),
+            kind => warn!("bip_peer: {:?} Disconnected: {:?}", self.id, kind),
+        }
+
         sel_send(OProtocolMessage::new(self.id, OProtocolMessageKind::PeerDisconnect));
+        self.recorder.incr(Metric::PeerDisconnected);
 
         Intent::error(Box::new(error))
     }
 
     /// Attempts to advance our state from a read event.
-    fn advance_read<F>(mut self, now: Time, in_buffer: &mut Buf, out_buffer: &mut Buf, sel_send: F) -> Intent<WireProtocol<L, DR>>
+    fn advance_read<F>(mut self, now: Time, in_buffer: &mut Buf, out_buffer: &mut Buf, sel_send: F) -> Intent<WireProtocol<S, DR>>
         where F: Fn(OProtocolMessage)
     {
         let curr_state = self.state;
 
         match curr_state {
             WireState::ReadLength => {
+                let message_len = message::parse_message_length(&in_buffer[..]);
+                if message_len > self.config.max_message_length() {
+                    // Early return, peer is trying to get us to allocate an excessively large buffer
+                    let prot_error = ProtocolError::new(self.id, ProtocolErrorKind::MessageTooLarge);
+
+                    return self.advance_disconnect(sel_send, prot_error);
+                }
+
                 // Don't consume the bytes that make up the length, add that back into the expected length
-                let expected_len = message::parse_message_length(&in_buffer[..]) + message::MESSAGE_LENGTH_LEN_BYTES;
-                self.state = WireState::ReadPayload(expected_len);
+                let expected_len = message_len + message::MESSAGE_LENGTH_LEN_BYTES;
+                self.transition(WireState::ReadPayload(expected_len));
             }
             WireState::ReadPayload(len) => {
-                let res_opt_kind_msg = parse_kind_message(self.id, &in_buffer[..len], self.disk.new_request_token());
+                self.timeouts.recvd(now);
+
+                let request_token = self.disk.new_request_token();
+                let res_opt_kind_msg = parse_kind_message(self.id,
+                                                          &in_buffer[..len],
+                                                          request_token,
+                                                          self.fast_extension,
+                                                          self.dht_extension,
+                                                          self.extension_protocol,
+                                                          self.layout);
+
+                // Only blocks make use of the token, anything else can give it right back
+                match res_opt_kind_msg {
+                    Ok(Some(OProtocolMessageKind::PeerPiece(..))) => (),
+                    _ => self.disk.release_request_token(request_token),
+                }
 
                 // For whatever message we received, propogate it up a layer (it is impossible to
                 // receive a peer disconnect message off the wire, so we assume we arent propogating
                 // that message)
                 match res_opt_kind_msg {
                     Ok(Some(OProtocolMessageKind::PeerPiece(token, piece_msg))) => {
+                        let request = RequestMessage::new(piece_msg.piece_index(), piece_msg.block_offset(), piece_msg.block_length());
+                        let in_grace = self.choked_requests.get(&request).map_or(false, |&deadline| now < deadline);
+                        if !self.requested.contains(&request) && !in_grace {
+                            // Early return, don't reserve memory for a block that we never asked for
+                            let prot_error = ProtocolError::new(self.id, ProtocolErrorKind::UnsolicitedPiece);
+                            self.disk.release_request_token(token);
+
+                            return self.advance_disconnect(sel_send, prot_error);
+                        }
+
+                        if let Some(wait) = self.limits.try_download(piece_msg.block_length()) {
+                            // Early return, leave the block in our buffer until we are allowed to accept it
+                            self.throttled_until = Some(now + wait);
+                            self.disk.release_request_token(token);
+
+                            return Intent::of(self).sleep().deadline(now + wait);
+                        }
+
+                        self.timeouts.active(now);
+                        self.requested.remove(&request);
+                        self.choked_requests.remove(&request);
                         in_buffer.consume(len - piece_msg.block_length());
-                        self.state = WireState::DiskReserve(token, piece_msg.block_length());
+                        self.stats.add_read(len);
+                        self.stats.add_block_received();
+                        self.recorder.add(Metric::BytesRead, len as u64);
+                        self.recorder.incr(Metric::BlockReceived);
+                        self.transition(WireState::DiskReserve(token, piece_msg.block_length()));
 
                         // Disk manager will notify us when the memory is reserved
                         self.send_disk_message(IDiskMessage::ReserveBlock(token, self.hash, piece_msg));
@@ -215,7 +577,23 @@ impl<L, DR> WireProtocol<L, DR>
                     }
                     Ok(opt_kind) => {
                         in_buffer.consume(len);
-                        self.state = WireState::ReadLength;
+                        self.stats.add_read(len);
+                        self.recorder.add(Metric::BytesRead, len as u64);
+                        self.transition(WireState::ReadLength);
+
+                        match opt_kind {
+                            Some(OProtocolMessageKind::PeerCancel(cancel)) => self.process_cancel(cancel),
+                            Some(OProtocolMessageKind::PeerRequest(..)) => self.timeouts.active(now),
+                            // Without the fast extension, peers silently discard our requests when they choke us
+                            Some(OProtocolMessageKind::PeerChoke) if !self.fast_extension => self.choke_requests(now),
+                            Some(OProtocolMessageKind::PeerRejectRequest(ref reject)) => {
+                                let request = RequestMessage::new(reject.piece_index(), reject.block_offset(), reject.block_length());
+
+                                self.requested.remove(&request);
+                                self.choked_requests.remove(&request);
+                            }
+                            _ => (),
+                        }
 
                         if let Some(kind) = opt_kind {
                             sel_send(OProtocolMessage::new(self.id, kind));
@@ -230,7 +608,7 @@ impl<L, DR> WireProtocol<L, DR>
             _ => unreachable!("bip_peer: Called AdvanceRead In An Invalid State {:?}", curr_state),
         }
 
-        self.advance_write(now, out_buffer, false)
+        self.advance_write(now, out_buffer, false, sel_send)
     }
 
     /// Attempts to advance our state to/from a write event.
@@ -238,52 +616,204 @@ impl<L, DR> WireProtocol<L, DR>
     /// Since we are working with a half duplex abstraction, anytime we transition from some state back to WireState::ReadLength,
     /// we should attempt to transition into a write state (we aggressively try to transition to a write) because that is the only
     /// time we can take control of the stream and write to the peer. The upper layer will have to make sure that it doesn't starve
-    /// ourselves of reads (since there is no notion of "flow" control provided back to that layer). We may want to look at this again
-    /// in the future and provide the upper layer with feedback for when writes succeeded, although it would be preferrable to not do so.
-    fn advance_write(mut self, now: Time, mut out_buffer: &mut Buf, bytes_flushed: bool) -> Intent<WireProtocol<L, DR>> {
+    /// ourselves of reads; to help with that, we let it know whenever a block it queued has been flushed to the peer.
+    ///
+    /// In full duplex mode, messages are placed in the output buffer regardless of our read state, and the transport flushes them
+    /// in the background. We only wait on a flush when closing the connection, and blocks are reported as sent once buffered.
+    fn advance_write<F>(mut self, now: Time, mut out_buffer: &mut Buf, bytes_flushed: bool, sel_send: F) -> Intent<WireProtocol<S, DR>>
+        where F: Fn(OProtocolMessage)
+    {
+        let full_duplex = self.config.full_duplex();
+
         // First, check if this was called from a bytes flushed event
         if bytes_flushed {
             // "Reset" our state
-            self.state = WireState::ReadLength;
+            self.transition(WireState::ReadLength);
 
-            // Ack the write
-            self.send.sender_ack().ack();
+            // Ack the write, in full duplex mode writes were acked as they were buffered
+            if !full_duplex {
+                self.send.sender_ack().ack();
+            }
+            if let Some(request) = self.piece_in_flight.take() {
+                sel_send(OProtocolMessage::new(self.id, OProtocolMessageKind::PeerPieceSent(request)));
+            }
+        }
+        for request in self.pieces_dropped.drain(..) {
+            sel_send(OProtocolMessage::new(self.id, OProtocolMessageKind::PeerPieceDropped(request)));
         }
+        if self.throttled_until.map_or(false, |throttled_until| now >= throttled_until) {
+            self.throttled_until = None;
+        }
+
+        loop {
+            let can_write = if full_duplex {
+                self.state != WireState::WritePayload && out_buffer.len() < FULL_DUPLEX_MAX_BUFFERED
+            } else {
+                self.state == WireState::ReadLength
+            };
+
+            // Blocks we send count against our upload limits, so we may have to wait before writing one out
+            let upload_wait = match self.write_queue.front() {
+                Some(&(MessageType::Piece(ref piece_msg), Some(_))) if can_write => self.limits.try_upload(piece_msg.block_length()),
+                _ => None,
+            };
+            if let Some(wait) = upload_wait {
+                self.throttled_until = Some(now + wait);
+            }
 
-        // Next, check if we can transition to/back to a write event
-        if !self.write_queue.is_empty() && self.state == WireState::ReadLength {
+            // Next, check if we can transition to/back to a write event
+            if self.write_queue.is_empty() || !can_write || upload_wait.is_some() {
+                break;
+            }
             let (msg, opt_token) = self.write_queue.pop_front().unwrap();
 
             // We can write out this message, and an optional payload from disk
+            let start_len = out_buffer.len();
             msg.write_bytes(&mut out_buffer).unwrap();
+            debug!("bip_peer: {:?} Wrote {:?}", self.id, msg);
+            if let (&MessageType::Piece(ref piece_msg), Some(_)) = (&msg, opt_token) {
+                self.piece_in_flight = Some(RequestMessage::new(piece_msg.piece_index(), piece_msg.block_offset(), piece_msg.block_length()));
+            }
             if let Some(token) = opt_token {
                 self.disk.read_block(token, out_buffer);
                 self.send_disk_message(IDiskMessage::ReclaimBlock(token));
+                self.disk.release_request_token(token);
+
+                self.stats.add_block_served();
+                self.recorder.incr(Metric::BlockServed);
+            }
+            self.stats.add_written(out_buffer.len() - start_len);
+            self.recorder.add(Metric::BytesWritten, (out_buffer.len() - start_len) as u64);
+
+            if !full_duplex {
+                self.transition(WireState::WritePayload);
+
+                break;
             }
 
-            self.state = WireState::WritePayload;
+            self.send.sender_ack().ack();
+            if let Some(request) = self.piece_in_flight.take() {
+                sel_send(OProtocolMessage::new(self.id, OProtocolMessageKind::PeerPieceSent(request)));
+            }
+        }
+
+        // In full duplex mode, make sure everything we buffered reaches the peer before closing
+        if full_duplex && self.closing && out_buffer.len() > 0 && self.write_queue.is_empty() && self.block_queue.is_empty() &&
+           self.state == WireState::ReadLength {
+            self.transition(WireState::WritePayload);
+        }
+
+        // Selection layer wanted us gone, and we have flushed everything we had queued
+        if self.is_closed() {
+            info!("bip_peer: {:?} Closed At Request Of Selection Layer", self.id);
+            self.recorder.incr(Metric::PeerDisconnected);
+
+            return Intent::done();
         }
 
         // Figure our what intent we should return based on our CURRENT state, even if unchanged
-        let self_timeout = self.self_timeout(now);
+        let deadline = self.next_deadline(now);
         match self.state {
-            WireState::ReadLength => Intent::of(self).expect_bytes(message::MESSAGE_LENGTH_LEN_BYTES).deadline(self_timeout),
-            WireState::ReadPayload(len) => Intent::of(self).expect_bytes(len).deadline(self_timeout),
-            WireState::DiskReserve(..) => Intent::of(self).sleep().deadline(self_timeout),
-            WireState::WritePayload => Intent::of(self).expect_flush().deadline(self_timeout),
+            WireState::ReadLength => Intent::of(self).expect_bytes(message::MESSAGE_LENGTH_LEN_BYTES).deadline(deadline),
+            WireState::ReadPayload(len) => Intent::of(self).expect_bytes(len).deadline(deadline),
+            WireState::DiskReserve(..) => Intent::of(self).sleep().deadline(deadline),
+            WireState::WritePayload => Intent::of(self).expect_flush().deadline(deadline),
         }
     }
 }
 
+/// Returns true if processing the message from the selection layer would give the disk manager work to do.
+fn needs_disk(msg: &OSelectorMessage) -> bool {
+    match msg.kind() {
+        OSelectorMessageKind::PeerPiece(..) => true,
+        _ => false,
+    }
+}
+
+/// Maps the bitfield we are sending to the peer to the message that conveys it in the fewest bytes.
+///
+/// An empty bitfield becomes a HaveNone, and a complete bitfield (if we know the layout of the torrent)
+/// becomes a HaveAll, when the fast extension was negotiated. Otherwise, an empty bitfield is not sent at all.
+fn map_bitfield_message(msg: BitFieldMessage, fast_extension: bool, layout: Option<PieceLayout>) -> Option<MessageType> {
+    let is_empty = msg.bytes().iter().all(|byte| *byte == 0);
+    let is_complete = layout.map_or(false, |layout| (0..layout.num_pieces()).all(|index| msg.has_piece(index)));
+
+    match (is_empty, is_complete, fast_extension) {
+        (true, _, true) => Some(MessageType::HaveNone),
+        (true, _, false) => None,
+        (false, true, true) => Some(MessageType::HaveAll),
+        (false, _, _) => Some(MessageType::BitField(msg)),
+    }
+}
+
+/// Map the exception that caused a connection to fail to a ProtocolErrorKind.
+fn map_exception(reason: Exception) -> ProtocolErrorKind {
+    match reason {
+        Exception::EndOfStream => ProtocolErrorKind::RemoteDisconnect,
+        Exception::LimitReached => ProtocolErrorKind::MessageTooLarge,
+        Exception::ReadError(error) => ProtocolErrorKind::ReadError(error.kind()),
+        Exception::WriteError(error) => ProtocolErrorKind::WriteError(error.kind()),
+        Exception::ConnectError(error) => ProtocolErrorKind::ConnectError(error.kind()),
+    }
+}
+
+/// Map the extension message that the selection layer wants to send to the peer, filling in our listen port.
+///
+/// Only extended handshakes that don't already advertise a port are changed, any other message is sent as is.
+fn map_extension_message(msg: ExtensionMessage, opt_listen_port: Option<u16>) -> ExtensionMessage {
+    let opt_handshake = if opt_listen_port.is_some() { ExtendedHandshake::from_message(&msg) } else { None };
+
+    match (opt_handshake, opt_listen_port) {
+        (Some(mut handshake), Some(port)) if handshake.port().is_none() => {
+            handshake.set_port(port);
+
+            handshake.to_message()
+        }
+        _ => msg,
+    }
+}
+
 /// Attempt to parse the peer message as an OProtocolMessageKind.
-fn parse_kind_message(id: PeerIdentifier, bytes: &[u8], request_token: Token) -> Result<Option<OProtocolMessageKind>, ProtocolError> {
-    match MessageType::from_bytes(bytes) {
+///
+/// Fast extension, port, and extension protocol messages received when the respective extension was not negotiated
+/// (or enabled) are ignored.
+fn parse_kind_message(id: PeerIdentifier,
+                      bytes: &[u8],
+                      request_token: Token,
+                      fast_extension: bool,
+                      dht_extension: bool,
+                      extension_protocol: bool,
+                      layout: Option<PieceLayout>)
+                      -> Result<Option<OProtocolMessageKind>, ProtocolError> {
+    let res_msg_type = MessageType::from_bytes(bytes);
+    if let IResult::Done(_, ref msg_type) = res_msg_type {
+        debug!("bip_peer: {:?} Received {:?}", id, msg_type);
+    }
+
+    match res_msg_type {
+        IResult::Done(_, ref msg_type) if msg_type.is_fast_message() && !fast_extension => Ok(None),
+        IResult::Done(_, MessageType::Extension(ExtensionType::Port(_))) if !dht_extension => Ok(None),
+        IResult::Done(_, MessageType::Extension(ExtensionType::Extension(_))) if !extension_protocol => Ok(None),
+        IResult::Done(_, MessageType::Request(ref msg)) if !is_valid_request(msg, layout) => {
+            Err(ProtocolError::new(id, ProtocolErrorKind::InvalidRequest))
+        }
         IResult::Done(_, msg_type) => Ok(map_message_type(msg_type, request_token)),
-        IResult::Error(_) |
-        IResult::Incomplete(_) => Err(ProtocolError::new(id, ProtocolErrorKind::InvalidMessage)),
+        IResult::Error(_) => Err(ProtocolError::new(id, ProtocolErrorKind::InvalidMessage)),
+        // We only parse once we have all of the bytes the message length told us about, so
+        // the message is either truncated, or we sized our read incorrectly
+        IResult::Incomplete(_) => Err(ProtocolError::new(id, ProtocolErrorKind::IncompleteMessage)),
     }
 }
 
+/// Returns true if the request is for a sanely sized block that falls within the torrent.
+///
+/// If the layout of the torrent is not known, only the block length is checked.
+fn is_valid_request(msg: &RequestMessage, layout: Option<PieceLayout>) -> bool {
+    let valid_length = msg.block_length() > 0 && msg.block_length() <= DEFAULT_BLOCK_SIZE;
+
+    valid_length && layout.map_or(true, |layout| layout.contains_block(msg.piece_index(), msg.block_offset(), msg.block_length()))
+}
+
 /// Maps a message type as an OProtocolMessageKind.
 fn map_message_type(msg_type: MessageType, request_token: Token) -> Option<OProtocolMessageKind> {
     match msg_type {
@@ -297,55 +827,61 @@ fn map_message_type(msg_type: MessageType, request_token: Token) -> Option<OProt
         MessageType::Request(msg) => Some(OProtocolMessageKind::PeerRequest(msg)),
         MessageType::Piece(msg) => Some(OProtocolMessageKind::PeerPiece(request_token, msg)),
         MessageType::Cancel(msg) => Some(OProtocolMessageKind::PeerCancel(msg)),
-        MessageType::Extension(_) => unimplemented!(),
+        MessageType::SuggestPiece(msg) => Some(OProtocolMessageKind::PeerSuggestPiece(msg)),
+        MessageType::HaveAll => Some(OProtocolMessageKind::PeerHaveAll),
+        MessageType::HaveNone => Some(OProtocolMessageKind::PeerHaveNone),
+        MessageType::RejectRequest(msg) => Some(OProtocolMessageKind::PeerRejectRequest(msg)),
+        MessageType::AllowedFast(msg) => Some(OProtocolMessageKind::PeerAllowedFast(msg)),
+        MessageType::Extension(ExtensionType::Extension(msg)) => Some(OProtocolMessageKind::PeerExtension(msg)),
+        MessageType::Extension(ExtensionType::Port(msg)) => Some(OProtocolMessageKind::PeerPort(msg.port())),
     }
 }
 
-impl<L, DR> PeerProtocol for WireProtocol<L, DR>
-    where L: LocalAddress + TryBind + TryAccept + Evented + Any + Send,
-          L::Output: TryConnect + StreamSocket + Send,
+impl<S, DR> Protocol for WireProtocol<S, DR>
+    where S: StreamSocket,
           DR: DiskManagerAccess + TrySender<IDiskMessage>
 {
     type Context = WireContext<DR>;
+    type Socket = S;
+    type Seed = WireSeed;
 
-    type Protocol = Self;
-
-    type Listener = L;
+    fn create(seed: Self::Seed, sock: &mut Self::Socket, scope: &mut Scope<Self::Context>) -> Intent<Self> {
+        let id = PeerIdentifier::new(seed.addr(), seed.pid());
 
-    type Socket = L::Output;
-}
-
-impl<L, DR> Protocol for WireProtocol<L, DR>
-    where L: LocalAddress + TryBind + TryAccept + Evented + Any + Send,
-          L::Output: TryConnect + StreamSocket + Send,
-          DR: DiskManagerAccess + TrySender<IDiskMessage>
-{
-    type Context = BTContext<WireContext<DR>>;
-    type Socket = L::Output;
-    type Seed = BTSeed;
-
-    fn create(bt_seed: Self::Seed, sock: &mut Self::Socket, scope: &mut Scope<Self::Context>) -> Intent<Self> {
-        let id = PeerIdentifier::new(bt_seed.addr(), bt_seed.pid());
+        let config = scope.config();
+        let max_incoming_messages = config.max_incoming_messages();
 
         // Create a ProtocolSender for layers to send messages and wake us up
-        let (send, recv) = mpsc::sync_channel(MAX_INCOMING_MESSAGES + 1);
+        let (send, recv) = mpsc::sync_channel(max_incoming_messages + 1);
         let protocol_send = ProtocolSender::new(send, scope.notifier());
 
         // Using a SplitSender for the sender here so that we can defer message acking until the message is queued and written
-        let select_send = SplitSender::new(protocol_send.clone(), MAX_INCOMING_MESSAGES);
-        scope.send_selector(OProtocolMessage::new(id, OProtocolMessageKind::PeerConnect(Box::new(select_send.clone()), bt_seed.hash())));
+        let select_send = SplitSender::new(protocol_send.clone(), max_incoming_messages);
+        scope.send_selector(OProtocolMessage::new(id, OProtocolMessageKind::PeerConnect(Box::new(select_send.clone()), seed.hash())));
 
         let active_disk = scope.register_disk(Box::new(protocol_send));
 
-        WireProtocol::new(id, bt_seed.hash(), active_disk, select_send, recv, scope.now())
+        // Extensions are only used if both we and the peer advertised them in our handshakes
+        let fast_extension = config.fast_extension() && seed.extensions().supports_fast();
+        let dht_extension = config.dht_extension() && seed.extensions().supports_dht();
+        let extension_protocol = config.extension_protocol() && seed.extensions().supports_extended();
+
+        let layout = scope.piece_layout(seed.hash());
+        let limits = scope.rate_limits(seed.hash());
+
+        let recorder = scope.recorder();
+        recorder.incr(Metric::PeerConnected);
+
+        WireProtocol::new(id, seed.hash(), active_disk, select_send, recv, fast_extension, dht_extension, extension_protocol, layout, limits, config,
+                          recorder, Clock::now(scope))
     }
 
     fn bytes_read(self, transport: &mut Transport<Self::Socket>, end: usize, scope: &mut Scope<Self::Context>) -> Intent<Self> {
-        let now = scope.now();
+        let now = Clock::now(scope);
         let id = self.id;
 
-        if self.peer_timeout(now) {
-            self.advance_disconnect(|msg| scope.send_selector(msg), ProtocolError::new(id, ProtocolErrorKind::RemoteTimeout))
+        if let Some(kind) = self.timeouts.expired(now, false) {
+            self.advance_disconnect(|msg| scope.send_selector(msg), ProtocolError::new(id, kind))
         } else {
             let (input, output) = transport.buffers();
 
@@ -354,75 +890,123 @@ impl<L, DR> Protocol for WireProtocol<L, DR>
     }
 
     fn bytes_flushed(self, transport: &mut Transport<Self::Socket>, scope: &mut Scope<Self::Context>) -> Intent<Self> {
-        let now = scope.now();
+        let now = Clock::now(scope);
         let id = self.id;
 
-        if self.peer_timeout(now) {
-            self.advance_disconnect(|msg| scope.send_selector(msg), ProtocolError::new(id, ProtocolErrorKind::RemoteTimeout))
+        if let Some(kind) = self.timeouts.expired(now, false) {
+            self.advance_disconnect(|msg| scope.send_selector(msg), ProtocolError::new(id, kind))
         } else {
-            self.advance_write(now, transport.output(), true)
+            self.advance_write(now, transport.output(), true, |msg| scope.send_selector(msg))
         }
     }
 
     fn timeout(mut self, transport: &mut Transport<Self::Socket>, scope: &mut Scope<Self::Context>) -> Intent<Self> {
-        let now = scope.now();
+        let now = Clock::now(scope);
         let id = self.id;
 
-        if self.peer_timeout(now) {
-            self.advance_disconnect(|msg| scope.send_selector(msg), ProtocolError::new(id, ProtocolErrorKind::RemoteTimeout))
+        if let Some(kind) = self.timeouts.expired(now, true) {
+            self.advance_disconnect(|msg| scope.send_selector(msg), ProtocolError::new(id, kind))
         } else {
             // All we can do here is push a keep alive message on to our queue since we can't necessarily transition to a write payload state
             // for example, if we are still waiting on the disk manager. Also, we will update our message_sent whenever we push to the write
             // queue to make it easy for us to know what we mean when we talk about our write timeout.
             let id = self.id;
-            // Don't care if it didnt go through, that means there are pending writes
-            self.send.try_send(OSelectorMessage::new(id, OSelectorMessageKind::PeerKeepAlive));
+            // We may have been woken up early to retry a rate limited block or send stats
+            if self.timeouts.needs_keep_alive(now) {
+                // Don't care if it didnt go through, that means there are pending writes
+                self.send.try_send(OSelectorMessage::new(id, OSelectorMessageKind::PeerKeepAlive));
+            }
+            self.send_stats(now, |msg| scope.send_selector(msg));
 
-            self.advance_write(now, transport.output(), false)
+            self.advance_write(now, transport.output(), false, |msg| scope.send_selector(msg))
         }
     }
 
-    fn exception(self, _transport: &mut Transport<Self::Socket>, reason: Exception, _scope: &mut Scope<Self::Context>) -> Intent<Self> {
+    fn exception(self, _transport: &mut Transport<Self::Socket>, reason: Exception, scope: &mut Scope<Self::Context>) -> Intent<Self> {
         let id = self.id;
 
-        self.advance_disconnect(|msg| _scope.send_selector(msg), ProtocolError::new(id, ProtocolErrorKind::RemoteDisconnect))
+        self.advance_disconnect(|msg| scope.send_selector(msg), ProtocolError::new(id, map_exception(reason)))
     }
 
     fn fatal(self, reason: Exception, scope: &mut Scope<Self::Context>) -> Option<Box<Error>> {
         let id = self.id;
-        let _ = self.advance_disconnect(|msg| scope.send_selector(msg), ProtocolError::new(id, ProtocolErrorKind::RemoteError));
+        let prot_error = ProtocolError::new(id, map_exception(reason));
+        let _ = self.advance_disconnect(|msg| scope.send_selector(msg), prot_error);
 
-        None
+        Some(Box::new(prot_error))
     }
 
     fn wakeup(mut self, transport: &mut Transport<Self::Socket>, scope: &mut Scope<Self::Context>) -> Intent<Self> {
-        let now = scope.now();
+        let now = Clock::now(scope);
         let id = self.id;
 
-        if self.peer_timeout(now) {
-            self.advance_disconnect(|msg| scope.send_selector(msg), ProtocolError::new(id, ProtocolErrorKind::RemoteTimeout))
+        if let Some(kind) = self.timeouts.expired(now, false) {
+            self.advance_disconnect(|msg| scope.send_selector(msg), ProtocolError::new(id, kind))
         } else {
             while let Ok(msg) = self.recv.try_recv() {
                 match msg {
                     // We don't use the namespace here because we know it is the same (TODO, Should Pass Namespace)
                     IProtocolMessage::DiskManager(ODiskMessage::BlockLoaded(_namespace, token)) | 
                     IProtocolMessage::DiskManager(ODiskMessage::BlockReserved(_namespace, token)) => {
-                        self.process_disk(transport.input(), token);
+                        if let Err(kind) = self.process_disk(transport.input(), token) {
+                            // Early return, only this connection is affected
+                            return self.advance_disconnect(|msg| scope.send_selector(msg), ProtocolError::new(id, kind));
+                        }
+                    },
+                    IProtocolMessage::DiskManager(ODiskMessage::BlockLoadFailed(_namespace, token, error)) => {
+                        warn!("bip_peer: {:?} Failed To Load Block: {}", id, error);
+
+                        if let Err(kind) = self.process_load_failed(token) {
+                            // Early return, only this connection is affected
+                            return self.advance_disconnect(|msg| scope.send_selector(msg), ProtocolError::new(id, kind));
+                        }
                     },
-                    IProtocolMessage::DiskManager(_) => {
-                        panic!("bip_peer: WireProtocol Received Unexpected Message From DiskManager")
+                    IProtocolMessage::DiskManager(disk_msg) => {
+                        // Early return, the disk manager should only be sending us responses to our block requests
+                        warn!("bip_peer: {:?} Received Unexpected Message From DiskManager: {:?}", id, disk_msg);
+                        let prot_error = ProtocolError::new(id, ProtocolErrorKind::InternalInconsistency);
+
+                        return self.advance_disconnect(|msg| scope.send_selector(msg), prot_error);
                     },
                     IProtocolMessage::PieceManager(sel_msg) => {
-                        // If the selection layer sent us a disconnect message, handle it here
-                        if self.process_message(now, sel_msg) {
-                            // Since the selection layer initiated the disconnect, dont send the disconnect to them
-                            return self.advance_disconnect(|_| (), ProtocolError::new(id, ProtocolErrorKind::RemoteDisconnect));
+                        // If the selection layer sent us a disconnect message, close the connection once
+                        // our pending writes are flushed; this is not an error, so we don't report one, and
+                        // since the selection layer initiated the disconnect, we don't send the disconnect to them
+                        //
+                        // We can't leave messages in the channel while the disk manager is busy, since its responses
+                        // come in on the same channel, but deferred messages are not acked, so the selection layer
+                        // is still held back once it has filled up our share of the channel.
+                        if self.process_or_defer(now, sel_msg) {
+                            self.closing = true;
                         }
                     }
                 }
             }
+            // Disk manager responses may have freed up room for messages we deferred
+            if self.process_deferred(now) {
+                self.closing = true;
+            }
+            self.send_stats(now, |msg| scope.send_selector(msg));
 
-            self.advance_write(now, transport.output(), false)
+            self.advance_write(now, transport.output(), false, |msg| scope.send_selector(msg))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use rotor_stream::Exception;
+
+    use protocol::error::ProtocolErrorKind;
+
+    #[test]
+    fn positive_map_exception_keeps_io_error_kind() {
+        let reset_error = io::Error::new(io::ErrorKind::ConnectionReset, "connection reset by peer");
+
+        assert_eq!(ProtocolErrorKind::ReadError(io::ErrorKind::ConnectionReset),
+                   super::map_exception(Exception::ReadError(reset_error)));
+        assert_eq!(ProtocolErrorKind::RemoteDisconnect, super::map_exception(Exception::EndOfStream));
+    }
+}
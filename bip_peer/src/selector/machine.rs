@@ -0,0 +1,217 @@
+use std::collections::HashSet;
+use std::sync::mpsc::{self, SyncSender, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use rotor::{self, Machine, Void, Scope, EarlyScope, GenericScope, Response, EventSet, Notifier, Loop, Time};
+use rotor::void::unreachable;
+
+use protocol::{PeerIdentifier, OProtocolMessageKind};
+use bip_util::bt::InfoHash;
+
+use selector::{ISelectorMessage, OSelectorMessageKind};
+use selector::peers::SelectorPeers;
+use selector::share::ShareLimits;
+use selector::strategy::SelectionStrategy;
+
+const MAX_PENDING_SELECTOR_MESSAGES: usize = 1024;
+
+// Interval at which the strategy is ticked, this also drives the choker.
+const SELECTOR_TICK_MILLIS: u64 = 10 * 1000;
+
+/// Spawn the selector event loop running the given strategy, pausing torrents that reach the given share limits.
+///
+/// Returns the sender and notifier that can be used to communicate with the event loop.
+pub fn spawn_selector<S>(strategy: S, share: ShareLimits) -> (SyncSender<ISelectorMessage>, Notifier)
+    where S: SelectionStrategy + Send + 'static
+{
+    let (send, recv) = mpsc::sync_channel(MAX_PENDING_SELECTOR_MESSAGES);
+    let (noti_send, noti_recv) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut loop_creator = Loop::new(&rotor::Config::new()).expect("bip_peer: Failed To Create Selector Event Loop");
+
+        loop_creator.add_machine_with(|scope| {
+                noti_send.send(scope.notifier()).expect("bip_peer: Failed To Send Selector Notifier");
+
+                SelectorMachine::new(strategy, share, recv, scope)
+            })
+            .expect("bip_peer: Failed To Add Selector Machine");
+
+        loop_creator.run(()).expect("bip_peer: Selector Event Loop Failed");
+    });
+
+    let noti = noti_recv.recv().expect("bip_peer: Failed To Receive Selector Notifier");
+
+    (send, noti)
+}
+
+// ----------------------------------------------------------------------------//
+
+/// State machine that drains incoming selector messages and dispatches them to a strategy.
+pub struct SelectorMachine<S> {
+    strategy: S,
+    peers: SelectorPeers,
+    recv: Receiver<ISelectorMessage>,
+    next_tick: Time,
+    // Torrents that the user has paused, peers connecting for these are paused as well
+    paused: HashSet<InfoHash>,
+    share: ShareLimits,
+    // Torrents that we paused because they reached the share limits
+    limited: HashSet<InfoHash>,
+}
+
+impl<S> SelectorMachine<S>
+    where S: SelectionStrategy
+{
+    fn new(strategy: S, share: ShareLimits, recv: Receiver<ISelectorMessage>, scope: &mut EarlyScope) -> Response<SelectorMachine<S>, Void> {
+        let next_tick = scope.now() + Duration::from_millis(SELECTOR_TICK_MILLIS);
+
+        Response::ok(SelectorMachine {
+                strategy: strategy,
+                peers: SelectorPeers::new(),
+                recv: recv,
+                next_tick: next_tick,
+                paused: HashSet::new(),
+                share: share,
+                limited: HashSet::new(),
+            })
+            .deadline(next_tick)
+    }
+
+    fn process_message(&mut self, msg: ISelectorMessage) {
+        match msg {
+            ISelectorMessage::DiskManager(disk) => self.strategy.disk_message(disk, &mut self.peers),
+            ISelectorMessage::Protocol(_, prot) => {
+                let (id, kind) = prot.destroy();
+
+                // Any message from a peer is a good time to check if it has room for more messages
+                self.peers.flush(id);
+
+                match kind {
+                    OProtocolMessageKind::PeerConnect(send, hash) => {
+                        self.peers.add_peer(id, hash, send);
+                        self.strategy.peer_connect(id, hash, &mut self.peers);
+
+                        if self.paused.contains(&hash) {
+                            self.peers.send(id, OSelectorMessageKind::PeerPause);
+                        }
+                    }
+                    OProtocolMessageKind::PeerDisconnect => {
+                        self.strategy.peer_disconnect(id, &mut self.peers);
+                        self.peers.remove_peer(id);
+                    }
+                    other => {
+                        // Track choke state before the strategy sees the message, so that it can't send requests that would be ignored
+                        match other {
+                            OProtocolMessageKind::PeerChoke => self.peers.set_choking_us(id, true),
+                            OProtocolMessageKind::PeerUnChoke => self.peers.set_choking_us(id, false),
+                            OProtocolMessageKind::PeerPieceSent(request) => self.add_uploaded(id, request.block_length()),
+                            _ => (),
+                        }
+
+                        self.strategy.peer_message(id, other, &mut self.peers)
+                    }
+                }
+            }
+            ISelectorMessage::PauseTorrent(hash) => self.pause_torrent(hash),
+            ISelectorMessage::ResumeTorrent(hash) => {
+                // Torrents still over the share limits will be paused again on the next tick
+                self.limited.remove(&hash);
+                self.resume_torrent(hash);
+            }
+        }
+    }
+
+    fn pause_torrent(&mut self, hash: InfoHash) {
+        if !self.paused.insert(hash) {
+            return;
+        }
+
+        // Let the strategy choke peers and cancel requests before the connections stop sending them
+        self.strategy.pause_torrent(hash, &mut self.peers);
+        self.peers.send_torrent(hash, OSelectorMessageKind::PeerPause);
+    }
+
+    fn resume_torrent(&mut self, hash: InfoHash) {
+        if !self.paused.remove(&hash) {
+            return;
+        }
+
+        self.peers.send_torrent(hash, OSelectorMessageKind::PeerResume);
+        self.strategy.resume_torrent(hash, &mut self.peers);
+    }
+
+    /// Account for a block that was flushed to the peer, pausing its torrent if that put it over the share limits.
+    fn add_uploaded(&mut self, id: PeerIdentifier, bytes: usize) {
+        if let Some(hash) = self.peers.hash(id) {
+            self.share.add_uploaded(hash, bytes as u64);
+
+            if self.share.is_reached(hash) && !self.paused.contains(&hash) {
+                self.limited.insert(hash);
+                self.pause_torrent(hash);
+            }
+        }
+    }
+
+    /// Apply any changes to the share limits since we last checked.
+    fn check_share_limits(&mut self) {
+        for hash in self.share.torrents() {
+            let reached = self.share.is_reached(hash);
+
+            if reached && !self.paused.contains(&hash) {
+                self.limited.insert(hash);
+                self.pause_torrent(hash);
+            } else if !reached && self.limited.remove(&hash) {
+                self.resume_torrent(hash);
+            }
+        }
+    }
+}
+
+impl<S> Machine for SelectorMachine<S>
+    where S: SelectionStrategy
+{
+    type Context = ();
+    type Seed = Void;
+
+    fn create(seed: Self::Seed, _scope: &mut Scope<Self::Context>) -> Response<Self, Void> {
+        unreachable(seed)
+    }
+
+    fn ready(self, _events: EventSet, _scope: &mut Scope<Self::Context>) -> Response<Self, Self::Seed> {
+        let next_tick = self.next_tick;
+
+        Response::ok(self).deadline(next_tick)
+    }
+
+    fn spawned(self, _scope: &mut Scope<Self::Context>) -> Response<Self, Self::Seed> {
+        let next_tick = self.next_tick;
+
+        Response::ok(self).deadline(next_tick)
+    }
+
+    fn timeout(mut self, scope: &mut Scope<Self::Context>) -> Response<Self, Self::Seed> {
+        self.check_share_limits();
+        self.strategy.tick(&mut self.peers);
+        self.next_tick = scope.now() + Duration::from_millis(SELECTOR_TICK_MILLIS);
+
+        let next_tick = self.next_tick;
+        Response::ok(self).deadline(next_tick)
+    }
+
+    fn wakeup(mut self, _scope: &mut Scope<Self::Context>) -> Response<Self, Self::Seed> {
+        loop {
+            match self.recv.try_recv() {
+                Ok(msg) => self.process_message(msg),
+                Err(TryRecvError::Empty) => {
+                    let next_tick = self.next_tick;
+
+                    return Response::ok(self).deadline(next_tick);
+                }
+                // All senders have been dropped, no one is left to talk to us
+                Err(TryRecvError::Disconnected) => return Response::done(),
+            }
+        }
+    }
+}
@@ -1,18 +1,32 @@
 #![allow(unused)]
 
-use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::{SyncSender, TrySendError};
 
+use bip_util::bt::InfoHash;
 use bip_util::send::{TrySender, SplitSender};
 use rotor::Notifier;
 
 use disk::ODiskMessage;
 use protocol::{PeerIdentifier, OProtocolMessage};
 use message::standard::{HaveMessage, BitFieldMessage, RequestMessage, PieceMessage, CancelMessage};
+use message::extension::ExtensionMessage;
+use message::fast::{SuggestPieceMessage, RejectRequestMessage, AllowedFastMessage};
 use token::Token;
 
+mod machine;
+mod peers;
+mod share;
 mod strategy;
+#[cfg(test)]
+mod testing;
 
-pub use selector::strategy::PieceSelector;
+pub use selector::peers::SelectorPeers;
+pub use selector::share::ShareLimits;
+pub use selector::strategy::{PieceSelector, SelectionStrategy, PeerBitfields, Choker, PieceDownloader, PiecePicker, TorrentPieces};
+pub use selector::strategy::block_requests;
+pub use selector::strategy::{RarestFirstSelector, RarestFirstPicker, SequentialPieceSelector, SequentialPicker};
+pub use selector::strategy::{SuperSeedSelector, SuperSeeder};
+pub use selector::strategy::{MetadataSelector, MetadataDownloader, PeerExchange, PieceMap, PieceMaps, AnnounceConfig};
 
 pub enum ISelectorMessage {
     /// Message from the disk manager to the piece selector.
@@ -21,6 +35,10 @@ pub enum ISelectorMessage {
     ///
     /// Token is used to pin this message to a given channel.
     Protocol(Token, OProtocolMessage),
+    /// Message from the user to pause all activity for a torrent.
+    PauseTorrent(InfoHash),
+    /// Message from the user to resume activity for a paused torrent.
+    ResumeTorrent(InfoHash),
 }
 
 impl From<ODiskMessage> for ISelectorMessage {
@@ -29,6 +47,24 @@ impl From<ODiskMessage> for ISelectorMessage {
     }
 }
 
+impl ISelectorMessage {
+    /// Recover the disk message from a message that the selector channel handed back.
+    fn into_disk_message(self) -> Option<ODiskMessage> {
+        match self {
+            ISelectorMessage::DiskManager(disk) => Some(disk),
+            _ => None,
+        }
+    }
+
+    /// Recover the protocol message from a message that the selector channel handed back.
+    fn into_protocol_message(self) -> Option<OProtocolMessage> {
+        match self {
+            ISelectorMessage::Protocol(_, prot) => Some(prot),
+            _ => None,
+        }
+    }
+}
+
 // ----------------------------------------------------------------------------//
 
 pub struct SelectorSender {
@@ -37,34 +73,89 @@ pub struct SelectorSender {
     noti: Notifier,
 }
 
-impl<T> TrySender<T> for SelectorSender
-    where T: Into<ISelectorMessage> + Send
-{
-    fn try_send(&self, data: T) -> Option<T> {
-        self.send
-            .send(data.into())
-            .expect("bip_peer: SelectorSender Failed To Send Message");
+impl SelectorSender {
+    fn new(id: Token, send: SyncSender<ISelectorMessage>, noti: Notifier) -> SelectorSender {
+        SelectorSender {
+            id: id,
+            send: send,
+            noti: noti,
+        }
+    }
 
-        self.noti
-            .wakeup()
-            .expect("bip_peer: SelectorSender Failed To Send Wakeup");
+    /// Send the message to the selector and wake it up.
+    ///
+    /// Returns the message back if the selector channel is full, or if the selector has hung up.
+    fn send_message(&self, msg: ISelectorMessage) -> Option<ISelectorMessage> {
+        match self.send.try_send(msg) {
+            Ok(()) => (),
+            Err(TrySendError::Full(msg)) |
+            Err(TrySendError::Disconnected(msg)) => return Some(msg),
+        }
+
+        // A failed wakeup means the selector event loop has shut down, in which case the receiver
+        // will be dropped along with the message we queued, so there is nothing left to give back.
+        if self.noti.wakeup().is_err() {
+            warn!("bip_peer: Failed To Wake Up Selector, Message Was Dropped With The Event Loop");
+        }
 
         None
     }
 }
 
+// Messages are handed back exactly as we sent them, so recovering the original message never fails in practice
+impl TrySender<ODiskMessage> for SelectorSender {
+    fn try_send(&self, data: ODiskMessage) -> Option<ODiskMessage> {
+        self.send_message(ISelectorMessage::DiskManager(data)).and_then(ISelectorMessage::into_disk_message)
+    }
+}
+
 // Have to specialize the impl for protocol messages so we can insert the token
 impl TrySender<OProtocolMessage> for SelectorSender {
     fn try_send(&self, data: OProtocolMessage) -> Option<OProtocolMessage> {
-        self.send
-            .send(ISelectorMessage::Protocol(self.id, data))
-            .expect("bip_peer: SelectorSender Failed To Send Message");
+        self.send_message(ISelectorMessage::Protocol(self.id, data)).and_then(ISelectorMessage::into_protocol_message)
+    }
+}
 
-        self.noti
-            .wakeup()
-            .expect("bip_peer: SelectorSender Failed To Send Wakeup");
+// ----------------------------------------------------------------------------//
 
-        None
+/// Handle for controlling the torrents of a running selector.
+#[derive(Clone)]
+pub struct SelectorControl {
+    send: SyncSender<ISelectorMessage>,
+    noti: Notifier,
+}
+
+impl SelectorControl {
+    fn new(send: SyncSender<ISelectorMessage>, noti: Notifier) -> SelectorControl {
+        SelectorControl {
+            send: send,
+            noti: noti,
+        }
+    }
+
+    /// Pause the torrent, so that we stop requesting blocks from, and choke, all of its peers.
+    ///
+    /// Connections are kept open, but only keep alives are sent to the peers until the torrent is resumed.
+    ///
+    /// Returns false if the selector has shut down.
+    pub fn pause_torrent(&self, hash: InfoHash) -> bool {
+        self.send_message(ISelectorMessage::PauseTorrent(hash))
+    }
+
+    /// Resume a paused torrent, picking up from the pieces we have already verified.
+    ///
+    /// Returns false if the selector has shut down.
+    pub fn resume_torrent(&self, hash: InfoHash) -> bool {
+        self.send_message(ISelectorMessage::ResumeTorrent(hash))
+    }
+
+    fn send_message(&self, msg: ISelectorMessage) -> bool {
+        if self.send.send(msg).is_err() {
+            return false;
+        }
+
+        // Same as SelectorSender, if the wakeup fails then the message was dropped along with the event loop
+        self.noti.wakeup().is_ok()
     }
 }
 
@@ -120,4 +211,24 @@ pub enum OSelectorMessageKind {
     PeerPiece(PieceMessage),
     /// Message to send a block cancel to a peer.
     PeerCancel(CancelMessage),
+    /// Message to send a piece suggestion to a peer.
+    PeerSuggestPiece(SuggestPieceMessage),
+    /// Message to send a peer have all.
+    PeerHaveAll,
+    /// Message to send a peer have none.
+    PeerHaveNone,
+    /// Message to send a block request rejection to a peer.
+    PeerRejectRequest(RejectRequestMessage),
+    /// Message to send an allowed fast piece to a peer.
+    PeerAllowedFast(AllowedFastMessage),
+    /// Message to send an extension protocol message to a peer.
+    PeerExtension(ExtensionMessage),
+    /// Message to send our DHT port to a peer.
+    ///
+    /// Only sent if the peer advertised support for the DHT.
+    PeerPort(u16),
+    /// Message to stop sending the peer anything other than keep alives, dropping any blocks waiting to be sent.
+    PeerPause,
+    /// Message to resume sending the peer messages after a pause.
+    PeerResume,
 }
@@ -0,0 +1,224 @@
+use std::collections::{HashMap, VecDeque};
+
+use bip_util::bt::InfoHash;
+use bip_util::send::TrySender;
+
+use protocol::PeerIdentifier;
+use selector::{OSelectorMessage, OSelectorMessageKind};
+
+/// Channels back to each peer that is currently connected to the selector.
+///
+/// Peer channels are bounded, so messages that don't fit are queued up and
+/// flushed the next time the peer sends us a message.
+pub struct SelectorPeers {
+    peers: HashMap<PeerIdentifier, PeerEntry>,
+}
+
+struct PeerEntry {
+    send: Box<TrySender<OSelectorMessage>>,
+    hash: InfoHash,
+    queued: VecDeque<OSelectorMessageKind>,
+    // Peers start out choking us, until they send us an unchoke
+    choking_us: bool,
+    // Whether or not we last told the peer that we are interested in it
+    am_interested: bool,
+}
+
+impl PeerEntry {
+    fn flush(&mut self, id: PeerIdentifier) {
+        while let Some(kind) = self.queued.pop_front() {
+            if let Some(msg) = self.send.try_send(OSelectorMessage::new(id, kind)) {
+                self.queued.push_front(msg.kind());
+
+                break;
+            }
+        }
+    }
+}
+
+impl SelectorPeers {
+    pub fn new() -> SelectorPeers {
+        SelectorPeers { peers: HashMap::new() }
+    }
+
+    /// Add the peer along with the sender we can use to talk to it.
+    pub fn add_peer(&mut self, id: PeerIdentifier, hash: InfoHash, send: Box<TrySender<OSelectorMessage>>) {
+        self.peers.insert(id,
+                          PeerEntry {
+                              send: send,
+                              hash: hash,
+                              queued: VecDeque::new(),
+                              choking_us: true,
+                              am_interested: false,
+                          });
+    }
+
+    /// Remove the peer, dropping the sender for it along with any queued messages.
+    pub fn remove_peer(&mut self, id: PeerIdentifier) {
+        self.peers.remove(&id);
+    }
+
+    /// InfoHash that the peer is connected to us for.
+    pub fn hash(&self, id: PeerIdentifier) -> Option<InfoHash> {
+        self.peers.get(&id).map(|entry| entry.hash)
+    }
+
+    /// Identifiers for all connected peers.
+    pub fn ids(&self) -> Vec<PeerIdentifier> {
+        self.peers.keys().cloned().collect()
+    }
+
+    /// Record whether or not the peer is choking us.
+    ///
+    /// Peers discard requests they receive while choking us, so any requests still queued for the peer are dropped.
+    pub fn set_choking_us(&mut self, id: PeerIdentifier, choking: bool) {
+        if let Some(entry) = self.peers.get_mut(&id) {
+            entry.choking_us = choking;
+
+            if choking {
+                entry.queued.retain(|kind| !is_request(kind));
+            }
+        }
+    }
+
+    /// Returns true if the peer is connected and has unchoked us, meaning it will respond to our requests.
+    pub fn is_unchoking_us(&self, id: PeerIdentifier) -> bool {
+        self.peers.get(&id).map_or(false, |entry| !entry.choking_us)
+    }
+
+    /// Returns true if the peer is connected and we have told it that we are interested in it.
+    pub fn is_interested(&self, id: PeerIdentifier) -> bool {
+        self.peers.get(&id).map_or(false, |entry| entry.am_interested)
+    }
+
+    /// Identifiers for all connected peers that have unchoked us.
+    pub fn unchoking_us(&self) -> Vec<PeerIdentifier> {
+        self.peers.iter().filter(|&(_, entry)| !entry.choking_us).map(|(id, _)| *id).collect()
+    }
+
+    /// Send the message kind to the given peer, queueing it if the peer's channel is full.
+    ///
+    /// Returns false if the peer is not connected, or if the message is a request and the peer is either choking
+    /// us, or we haven't told it that we are interested in it.
+    pub fn send(&mut self, id: PeerIdentifier, kind: OSelectorMessageKind) -> bool {
+        match self.peers.get_mut(&id) {
+            Some(ref entry) if is_request(&kind) && (entry.choking_us || !entry.am_interested) => false,
+            Some(entry) => {
+                match kind {
+                    OSelectorMessageKind::PeerInterested => entry.am_interested = true,
+                    OSelectorMessageKind::PeerNotInterested => entry.am_interested = false,
+                    _ => (),
+                }
+                entry.queued.push_back(kind);
+                entry.flush(id);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Send the message kind to every peer connected for the given torrent.
+    pub fn send_torrent(&mut self, hash: InfoHash, kind: OSelectorMessageKind) {
+        for (&id, entry) in self.peers.iter_mut().filter(|&(_, ref entry)| entry.hash == hash) {
+            entry.queued.push_back(kind.clone());
+            entry.flush(id);
+        }
+    }
+
+    /// Send as many queued messages to the given peer as its channel has room for.
+    pub fn flush(&mut self, id: PeerIdentifier) {
+        if let Some(entry) = self.peers.get_mut(&id) {
+            entry.flush(id);
+        }
+    }
+
+    /// Remove the message kind from the queue for the given peer.
+    ///
+    /// Returns true if the message was found in the queue, meaning it was never sent to the peer.
+    pub fn remove_queued(&mut self, id: PeerIdentifier, kind: &OSelectorMessageKind) -> bool {
+        match self.peers.get_mut(&id) {
+            Some(entry) => {
+                match entry.queued.iter().position(|queued| queued == kind) {
+                    Some(position) => {
+                        entry.queued.remove(position);
+
+                        true
+                    }
+                    None => false,
+                }
+            }
+            None => false,
+        }
+    }
+
+    /// Retain only the queued messages for the given peer that match the predicate.
+    pub fn retain_queued<F>(&mut self, id: PeerIdentifier, mut f: F)
+        where F: FnMut(&OSelectorMessageKind) -> bool
+    {
+        if let Some(entry) = self.peers.get_mut(&id) {
+            entry.queued.retain(|kind| f(kind));
+        }
+    }
+}
+
+/// Returns true if the message kind is a block request.
+fn is_request(kind: &OSelectorMessageKind) -> bool {
+    match kind {
+        &OSelectorMessageKind::PeerRequest(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use bip_util::bt::InfoHash;
+
+    use message::standard::RequestMessage;
+    use selector::OSelectorMessageKind;
+    use selector::testing::peer_id;
+    use super::SelectorPeers;
+
+    #[test]
+    fn negative_request_refused_while_choked() {
+        let (send, recv) = mpsc::channel();
+        let request = OSelectorMessageKind::PeerRequest(RequestMessage::new(0, 0, 16 * 1024));
+
+        let mut peers = SelectorPeers::new();
+        peers.add_peer(peer_id(6881), InfoHash::from([1u8; 20]), Box::new(send));
+
+        assert!(peers.send(peer_id(6881), OSelectorMessageKind::PeerInterested));
+        assert_eq!(OSelectorMessageKind::PeerInterested, recv.try_recv().unwrap().kind());
+
+        assert!(!peers.send(peer_id(6881), request.clone()));
+        assert!(recv.try_recv().is_err());
+
+        peers.set_choking_us(peer_id(6881), false);
+        assert_eq!(vec![peer_id(6881)], peers.unchoking_us());
+        assert!(peers.send(peer_id(6881), request.clone()));
+        assert_eq!(request, recv.try_recv().unwrap().kind());
+    }
+
+    #[test]
+    fn negative_request_refused_while_not_interested() {
+        let (send, recv) = mpsc::channel();
+        let request = OSelectorMessageKind::PeerRequest(RequestMessage::new(0, 0, 16 * 1024));
+
+        let mut peers = SelectorPeers::new();
+        peers.add_peer(peer_id(6881), InfoHash::from([1u8; 20]), Box::new(send));
+        peers.set_choking_us(peer_id(6881), false);
+
+        assert!(!peers.send(peer_id(6881), request.clone()));
+
+        peers.send(peer_id(6881), OSelectorMessageKind::PeerInterested);
+        peers.send(peer_id(6881), OSelectorMessageKind::PeerNotInterested);
+        assert!(!peers.is_interested(peer_id(6881)));
+        assert!(!peers.send(peer_id(6881), request.clone()));
+
+        assert_eq!(OSelectorMessageKind::PeerInterested, recv.try_recv().unwrap().kind());
+        assert_eq!(OSelectorMessageKind::PeerNotInterested, recv.try_recv().unwrap().kind());
+        assert!(recv.try_recv().is_err());
+    }
+}
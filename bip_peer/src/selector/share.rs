@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bip_metainfo::MetainfoFile;
+use bip_util::bt::InfoHash;
+
+/// Handle for querying how much of each torrent we have uploaded, and adjusting when we stop seeding it.
+///
+/// Once a torrent reaches either limit, the selector pauses it, choking all of its peers. If the limits are
+/// later raised (or removed) so that the torrent is back under them, the selector resumes it on its next tick.
+///
+/// Cloned handles share the same limits and upload totals, so one can be handed to the user while the selector
+/// that owns the original keeps the totals up to date.
+#[derive(Clone)]
+pub struct ShareLimits {
+    shared: Arc<Mutex<SharedLimits>>,
+}
+
+struct SharedLimits {
+    max_ratio: Option<f64>,
+    max_uploaded: Option<u64>,
+    torrents: HashMap<InfoHash, TorrentShare>,
+}
+
+#[derive(Default)]
+struct TorrentShare {
+    // Total length of the torrent, if known, for calculating the share ratio
+    length: Option<u64>,
+    uploaded: u64,
+}
+
+impl ShareLimits {
+    /// Create a new ShareLimits, with no limits set.
+    pub fn new() -> ShareLimits {
+        ShareLimits {
+            shared: Arc::new(Mutex::new(SharedLimits {
+                max_ratio: None,
+                max_uploaded: None,
+                torrents: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Add the torrent so that its share ratio can be calculated.
+    ///
+    /// Torrents that are not added are still subject to the upload cap, but never to the ratio limit.
+    pub fn add_torrent(&self, metainfo: &MetainfoFile) {
+        let length: u64 = metainfo.info().files().map(|file| file.length() as u64).sum();
+
+        self.lock().torrents.entry(metainfo.info_hash()).or_insert_with(TorrentShare::default).length = Some(length);
+    }
+
+    /// Stop seeding a torrent once the bytes we uploaded for it reach the given multiple of its length.
+    pub fn set_max_ratio(&self, opt_ratio: Option<f64>) {
+        self.lock().max_ratio = opt_ratio;
+    }
+
+    /// Ratio at which we stop seeding a torrent.
+    pub fn max_ratio(&self) -> Option<f64> {
+        self.lock().max_ratio
+    }
+
+    /// Stop seeding a torrent once the bytes we uploaded for it reach the given amount.
+    pub fn set_max_uploaded(&self, opt_bytes: Option<u64>) {
+        self.lock().max_uploaded = opt_bytes;
+    }
+
+    /// Bytes uploaded at which we stop seeding a torrent.
+    pub fn max_uploaded(&self) -> Option<u64> {
+        self.lock().max_uploaded
+    }
+
+    /// Total bytes of blocks that we have uploaded for the torrent.
+    pub fn uploaded(&self, hash: InfoHash) -> u64 {
+        self.lock().torrents.get(&hash).map_or(0, |share| share.uploaded)
+    }
+
+    /// Bytes uploaded for the torrent as a multiple of its length, if the torrent was added.
+    pub fn ratio(&self, hash: InfoHash) -> Option<f64> {
+        self.lock().torrents.get(&hash).and_then(TorrentShare::ratio)
+    }
+
+    /// Returns true if the torrent has reached either of our limits.
+    pub fn is_reached(&self, hash: InfoHash) -> bool {
+        let shared = self.lock();
+        let share = match shared.torrents.get(&hash) {
+            Some(share) => share,
+            None => return false,
+        };
+
+        let ratio_reached = match (shared.max_ratio, share.ratio()) {
+            (Some(max_ratio), Some(ratio)) => ratio >= max_ratio,
+            _ => false,
+        };
+        let uploaded_reached = shared.max_uploaded.map_or(false, |max_uploaded| share.uploaded >= max_uploaded);
+
+        ratio_reached || uploaded_reached
+    }
+
+    /// Record that a block of the given length was uploaded for the torrent.
+    pub fn add_uploaded(&self, hash: InfoHash, bytes: u64) {
+        self.lock().torrents.entry(hash).or_insert_with(TorrentShare::default).uploaded += bytes;
+    }
+
+    /// Torrents that we have uploaded to, or that were added.
+    pub fn torrents(&self) -> Vec<InfoHash> {
+        self.lock().torrents.keys().cloned().collect()
+    }
+
+    fn lock(&self) -> MutexGuard<SharedLimits> {
+        self.shared.lock().expect("bip_peer: ShareLimits Lock Poisoned")
+    }
+}
+
+impl Default for ShareLimits {
+    fn default() -> ShareLimits {
+        ShareLimits::new()
+    }
+}
+
+impl TorrentShare {
+    fn ratio(&self) -> Option<f64> {
+        self.length.map(|length| if length == 0 { 0.0 } else { self.uploaded as f64 / length as f64 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bip_util::bt::InfoHash;
+
+    use super::ShareLimits;
+
+    #[test]
+    fn positive_max_uploaded_adjusted_at_runtime() {
+        let hash = InfoHash::from([1u8; 20]);
+        let limits = ShareLimits::new();
+
+        limits.set_max_uploaded(Some(32 * 1024));
+        limits.add_uploaded(hash, 16 * 1024);
+        assert!(!limits.is_reached(hash));
+
+        limits.add_uploaded(hash, 16 * 1024);
+        assert!(limits.is_reached(hash));
+
+        limits.set_max_uploaded(None);
+        assert!(!limits.is_reached(hash));
+        // Torrents that were never added have no ratio, so a ratio limit never applies to them
+        limits.set_max_ratio(Some(0.5));
+        assert_eq!(None, limits.ratio(hash));
+        assert!(!limits.is_reached(hash));
+    }
+}
@@ -0,0 +1,45 @@
+use std::default::Default;
+
+/// Configures how the pieces we have are announced to the peers of a torrent.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AnnounceConfig {
+    lazy_pieces: usize,
+    coalesce_haves: bool,
+}
+
+impl AnnounceConfig {
+    /// Sets the number of pieces, chosen at random, to leave out of the bitfield sent to new peers.
+    ///
+    /// Withheld pieces are announced to the peer with have messages on the next tick,
+    /// so that peers can't tell from our bitfield alone that we have the whole torrent.
+    pub fn set_lazy_pieces(&mut self, pieces: usize) {
+        self.lazy_pieces = pieces;
+    }
+
+    /// Gets the number of lazy pieces.
+    pub fn lazy_pieces(&self) -> usize {
+        self.lazy_pieces
+    }
+
+    /// Sets whether or not have messages for newly verified pieces are held until the next tick.
+    ///
+    /// When enabled, pieces are announced in a single burst per tick, and peers
+    /// that already have a piece are not sent a have message for it.
+    pub fn set_coalesce_haves(&mut self, coalesce: bool) {
+        self.coalesce_haves = coalesce;
+    }
+
+    /// Gets whether or not have messages are coalesced.
+    pub fn coalesce_haves(&self) -> bool {
+        self.coalesce_haves
+    }
+}
+
+impl Default for AnnounceConfig {
+    fn default() -> AnnounceConfig {
+        AnnounceConfig {
+            lazy_pieces: 0,
+            coalesce_haves: false,
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use message::standard::BitFieldMessage;
+use protocol::PeerIdentifier;
+
+/// Aggregates the pieces that each connected peer has for a single torrent.
+pub struct PeerBitfields {
+    peers: HashMap<PeerIdentifier, Vec<bool>>,
+    // Number of connected peers that have each piece
+    availability: Vec<u32>,
+}
+
+impl PeerBitfields {
+    /// Create a new PeerBitfields for a torrent with the given number of pieces.
+    pub fn new(num_pieces: u32) -> PeerBitfields {
+        PeerBitfields {
+            peers: HashMap::new(),
+            availability: vec![0; num_pieces as usize],
+        }
+    }
+
+    /// Start tracking the peer, peers start out with no pieces.
+    pub fn add_peer(&mut self, id: PeerIdentifier) {
+        let num_pieces = self.availability.len();
+
+        self.peers.entry(id).or_insert_with(|| vec![false; num_pieces]);
+    }
+
+    /// Stop tracking the peer, removing its pieces from the availability counts.
+    pub fn remove_peer(&mut self, id: PeerIdentifier) {
+        if let Some(pieces) = self.peers.remove(&id) {
+            for (index, _) in pieces.iter().enumerate().filter(|&(_, has)| *has) {
+                self.availability[index] -= 1;
+            }
+        }
+    }
+
+    /// Record that the peer has the given piece.
+    ///
+    /// Returns true if the peer did not previously have the piece.
+    pub fn peer_have(&mut self, id: PeerIdentifier, piece_index: u32) -> bool {
+        let index = piece_index as usize;
+
+        match self.peers.get_mut(&id) {
+            Some(pieces) if index < pieces.len() && !pieces[index] => {
+                pieces[index] = true;
+                self.availability[index] += 1;
+
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Record every piece set in the peer's bitfield.
+    ///
+    /// Pieces the peer has previously told us about are retained.
+    pub fn peer_bitfield(&mut self, id: PeerIdentifier, bitfield: &BitFieldMessage) {
+        for piece_index in 0..self.num_pieces() {
+            if bitfield.has_piece(piece_index) {
+                self.peer_have(id, piece_index);
+            }
+        }
+    }
+
+    /// Record that the peer has every piece.
+    pub fn peer_have_all(&mut self, id: PeerIdentifier) {
+        for piece_index in 0..self.num_pieces() {
+            self.peer_have(id, piece_index);
+        }
+    }
+
+    /// Total number of pieces in the torrent.
+    pub fn num_pieces(&self) -> u32 {
+        self.availability.len() as u32
+    }
+
+    /// Whether or not the peer has the given piece.
+    pub fn has_piece(&self, id: PeerIdentifier, piece_index: u32) -> bool {
+        self.peers
+            .get(&id)
+            .and_then(|pieces| pieces.get(piece_index as usize))
+            .map_or(false, |has| *has)
+    }
+
+    /// Pieces that the peer has, in increasing order.
+    pub fn peer_pieces(&self, id: PeerIdentifier) -> Vec<u32> {
+        self.peers.get(&id).map_or(Vec::new(), |pieces| {
+            pieces.iter()
+                .enumerate()
+                .filter(|&(_, has)| *has)
+                .map(|(index, _)| index as u32)
+                .collect()
+        })
+    }
+
+    /// Peers that have the given piece.
+    pub fn peers_with_piece(&self, piece_index: u32) -> Vec<PeerIdentifier> {
+        self.peers
+            .iter()
+            .filter(|&(_, pieces)| pieces.get(piece_index as usize).map_or(false, |has| *has))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Number of peers that have each piece.
+    pub fn availability(&self) -> &[u32] {
+        &self.availability
+    }
+
+    /// Fraction of pieces in the torrent that at least one peer has.
+    pub fn swarm_availability(&self) -> f64 {
+        if self.availability.is_empty() {
+            return 0.0;
+        }
+        let available = self.availability.iter().filter(|count| **count > 0).count();
+
+        available as f64 / self.availability.len() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use message::standard::BitFieldMessage;
+    use selector::testing::peer_id;
+    use super::PeerBitfields;
+
+    #[test]
+    fn positive_bitfield_after_haves() {
+        let mut bitfields = PeerBitfields::new(10);
+        let id = peer_id(6881);
+        bitfields.add_peer(id);
+
+        bitfields.peer_have(id, 1);
+        bitfields.peer_have(id, 3);
+
+        let mut bitfield = BitFieldMessage::new(10);
+        bitfield.set_piece(3);
+        bitfield.set_piece(9);
+        bitfields.peer_bitfield(id, &bitfield);
+
+        assert_eq!(vec![1, 3, 9], bitfields.peer_pieces(id));
+        assert_eq!(1, bitfields.availability()[3]);
+        assert_eq!(0.3, bitfields.swarm_availability());
+    }
+
+    #[test]
+    fn positive_remove_peer_clears_availability() {
+        let mut bitfields = PeerBitfields::new(4);
+        let (id_one, id_two) = (peer_id(6881), peer_id(6882));
+        bitfields.add_peer(id_one);
+        bitfields.add_peer(id_two);
+
+        bitfields.peer_have_all(id_one);
+        bitfields.peer_have(id_two, 2);
+        bitfields.remove_peer(id_one);
+
+        assert_eq!(&[0, 0, 1, 0], bitfields.availability());
+        assert_eq!(vec![id_two], bitfields.peers_with_piece(2));
+        assert!(!bitfields.has_piece(id_one, 2));
+    }
+}
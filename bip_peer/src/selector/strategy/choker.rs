@@ -0,0 +1,386 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use rand::{Rng, StdRng};
+
+use protocol::PeerIdentifier;
+use selector::OSelectorMessageKind;
+use selector::peers::SelectorPeers;
+use selector::strategy::random;
+
+// Number of peers that we will upload to at any given time.
+const DEFAULT_UNCHOKE_SLOTS: usize = 4;
+
+// Number of rounds before rotating the optimistic unchoke, with 10 second rounds this is every 30 seconds.
+const OPTIMISTIC_UNCHOKE_ROUNDS: u32 = 3;
+
+// Seconds without receiving a block, from a peer that has pieces we want, before we consider it to be snubbing us.
+const DEFAULT_SNUB_TIMEOUT_SECS: u64 = 60;
+
+/// Tit-for-tat choker that unchokes the interested peers we are downloading from the fastest.
+///
+/// Rates are measured as the number of bytes a peer has sent us since the last round. Peers of
+/// torrents we are seeding have nothing to send us, so they are ranked by the number of bytes we
+/// have sent them since the last round instead.
+///
+/// On top of the rate based slots, one randomly chosen choked peer is optimistically unchoked,
+/// and rotated every few rounds, so that we can discover faster peers and bootstrap new ones.
+///
+/// Peers that have pieces we want, but have not sent us a block within the snub timeout, are
+/// considered to be snubbing us and are choked (even if they held the optimistic unchoke) until
+/// they send us a block again.
+pub struct Choker {
+    unchoke_slots: usize,
+    snub_timeout: Duration,
+    peers: HashMap<PeerIdentifier, ChokerPeer>,
+    optimistic: Option<PeerIdentifier>,
+    rounds: u32,
+    rng: StdRng,
+}
+
+struct ChokerPeer {
+    interested: bool,
+    choked: bool,
+    downloaded: u64,
+    uploaded: u64,
+    // Whether or not we have every piece of the torrent the peer is connected for
+    seeding: bool,
+    // Whether or not the peer has pieces that we want
+    am_interested: bool,
+    // Last time the peer sent us a block, or we became interested in the peer
+    last_block: Instant,
+    // Whether or not the torrent the peer is connected for is paused, in which case it stays choked
+    paused: bool,
+}
+
+impl Choker {
+    /// Create a new Choker with the default number of unchoke slots.
+    pub fn new() -> Choker {
+        Choker::with_unchoke_slots(DEFAULT_UNCHOKE_SLOTS)
+    }
+
+    /// Create a new Choker that will unchoke at most the given number of peers.
+    pub fn with_unchoke_slots(unchoke_slots: usize) -> Choker {
+        Choker {
+            unchoke_slots: unchoke_slots,
+            snub_timeout: Duration::from_secs(DEFAULT_SNUB_TIMEOUT_SECS),
+            peers: HashMap::new(),
+            optimistic: None,
+            rounds: 0,
+            rng: random::entropy_rng(),
+        }
+    }
+
+    /// Sets how long a peer that has pieces we want can go without sending us a block before it is choked.
+    pub fn set_snub_timeout(&mut self, timeout: Duration) {
+        self.snub_timeout = timeout;
+    }
+
+    /// Gets the snub timeout.
+    pub fn snub_timeout(&self) -> Duration {
+        self.snub_timeout
+    }
+
+    /// Sets the seed used when choosing the optimistic unchoke, so that the same choices are made for the same peers.
+    pub fn set_seed(&mut self, seed: usize) {
+        self.rng = random::seeded_rng(seed);
+    }
+
+    /// Start tracking the peer, peers start out choked and not interested.
+    pub fn add_peer(&mut self, id: PeerIdentifier) {
+        self.peers.insert(id,
+                          ChokerPeer {
+                              interested: false,
+                              choked: true,
+                              downloaded: 0,
+                              uploaded: 0,
+                              seeding: false,
+                              am_interested: false,
+                              last_block: Instant::now(),
+                              paused: false,
+                          });
+    }
+
+    /// Stop tracking the peer.
+    pub fn remove_peer(&mut self, id: PeerIdentifier) {
+        self.peers.remove(&id);
+
+        if self.optimistic == Some(id) {
+            self.optimistic = None;
+        }
+    }
+
+    /// Set whether or not the peer is interested in us.
+    pub fn set_interested(&mut self, id: PeerIdentifier, interested: bool) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.interested = interested;
+        }
+    }
+
+    /// Set whether or not we are interested in the peer.
+    ///
+    /// Only peers that we are interested in can snub us, since we don't expect blocks from anyone else.
+    pub fn set_am_interested(&mut self, id: PeerIdentifier, interested: bool) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            // Give the peer a full snub timeout to start sending us blocks
+            if interested && !peer.am_interested {
+                peer.last_block = Instant::now();
+            }
+
+            peer.am_interested = interested;
+        }
+    }
+
+    /// Set whether or not the peer is paused, choking it right away if it is being paused.
+    ///
+    /// Paused peers are never unchoked; once resumed, they compete for an unchoke on the next round.
+    pub fn set_paused(&mut self, id: PeerIdentifier, paused: bool, peers: &mut SelectorPeers) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.paused = paused;
+
+            if paused && !peer.choked {
+                peer.choked = true;
+                peers.send(id, OSelectorMessageKind::PeerChoke);
+            }
+        }
+
+        if paused && self.optimistic == Some(id) {
+            self.optimistic = None;
+        }
+    }
+
+    /// Set whether or not we are seeding the torrent the peer is connected for.
+    ///
+    /// Seeding peers are ranked by the rate we upload to them, rather than the rate they send to us.
+    pub fn set_seeding(&mut self, id: PeerIdentifier, seeding: bool) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.seeding = seeding;
+        }
+    }
+
+    /// Whether or not we are currently choking the peer.
+    ///
+    /// Peers we aren't tracking are considered choked.
+    pub fn is_choked(&self, id: PeerIdentifier) -> bool {
+        self.peers.get(&id).map_or(true, |peer| peer.choked)
+    }
+
+    /// Record that the peer has sent us the given number of bytes.
+    pub fn add_downloaded(&mut self, id: PeerIdentifier, bytes: usize) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.downloaded += bytes as u64;
+            peer.last_block = Instant::now();
+        }
+    }
+
+    /// Record that we have sent the peer the given number of bytes.
+    pub fn add_uploaded(&mut self, id: PeerIdentifier, bytes: usize) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.uploaded += bytes as u64;
+        }
+    }
+
+    /// Re-rank peers, unchoking the fastest interested peers and choking everyone else.
+    pub fn run_round(&mut self, peers: &mut SelectorPeers) {
+        let snub_timeout = self.snub_timeout;
+        let snubbed: HashSet<PeerIdentifier> = self.peers
+            .iter()
+            .filter(|&(_, peer)| peer.am_interested && peer.last_block.elapsed() >= snub_timeout)
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut ranked: Vec<(PeerIdentifier, u64)> = self.peers
+            .iter()
+            .filter(|&(id, peer)| peer.interested && !peer.paused && !snubbed.contains(id))
+            .map(|(id, peer)| (*id, if peer.seeding { peer.uploaded } else { peer.downloaded }))
+            .collect();
+        // Ties are broken by peer, so that the same peers are ranked the same way regardless of how they are stored
+        ranked.sort_by(|&(a_id, a), &(b_id, b)| b.cmp(&a).then(a_id.cmp(&b_id)));
+        ranked.truncate(self.unchoke_slots);
+
+        // A snubbing peer gives up its optimistic unchoke right away, rather than at the end of its rotation
+        let optimistic_snubbed = self.optimistic.map_or(false, |id| snubbed.contains(&id));
+        if self.optimistic.is_none() || optimistic_snubbed || self.rounds % OPTIMISTIC_UNCHOKE_ROUNDS == 0 {
+            self.rotate_optimistic(&ranked, &snubbed);
+        }
+        self.rounds = self.rounds.wrapping_add(1);
+
+        for (id, peer) in self.peers.iter_mut() {
+            let should_unchoke = ranked.iter().any(|&(ranked_id, _)| ranked_id == *id) || self.optimistic == Some(*id);
+
+            if should_unchoke && peer.choked {
+                peer.choked = false;
+                peers.send(*id, OSelectorMessageKind::PeerUnChoke);
+            } else if !should_unchoke && !peer.choked {
+                peer.choked = true;
+                peers.send(*id, OSelectorMessageKind::PeerChoke);
+            }
+
+            peer.downloaded = 0;
+            peer.uploaded = 0;
+        }
+    }
+
+    /// Choose a new optimistic unchoke from the peers that did not make it into a rate based slot.
+    fn rotate_optimistic(&mut self, ranked: &[(PeerIdentifier, u64)], snubbed: &HashSet<PeerIdentifier>) {
+        let mut candidates: Vec<PeerIdentifier> = self.peers
+            .iter()
+            .filter(|&(id, peer)| {
+                peer.interested && !peer.paused && !snubbed.contains(id) && !ranked.iter().any(|&(ranked_id, _)| ranked_id == *id)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        // Iteration order of the map is randomized per process, so without sorting, a seeded rng would still pick differently
+        candidates.sort();
+
+        self.optimistic = if candidates.is_empty() {
+            None
+        } else {
+            let choice = self.rng.gen_range(0, candidates.len());
+
+            Some(candidates[choice])
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use selector::peers::SelectorPeers;
+    use selector::testing::peer_id;
+    use super::Choker;
+
+    /// Create a choker with the given number of rate based slots, where every peer is interested in us.
+    fn interested_choker(unchoke_slots: usize, ports: &[u16]) -> Choker {
+        let mut choker = Choker::with_unchoke_slots(unchoke_slots);
+        choker.set_seed(5);
+
+        for &port in ports {
+            choker.add_peer(peer_id(port));
+            choker.set_interested(peer_id(port), true);
+        }
+
+        choker
+    }
+
+    fn unchoked(choker: &Choker, ports: &[u16]) -> Vec<u16> {
+        ports.iter().cloned().filter(|&port| !choker.is_choked(peer_id(port))).collect()
+    }
+
+    #[test]
+    fn positive_fastest_peer_takes_rate_based_slot() {
+        let ports = [6881, 6882, 6883];
+        let mut choker = interested_choker(1, &ports);
+        let mut selector_peers = SelectorPeers::new();
+
+        choker.add_downloaded(peer_id(6881), 500);
+        choker.run_round(&mut selector_peers);
+        assert_eq!(2, unchoked(&choker, &ports).len());
+        assert!(!choker.is_choked(peer_id(6881)));
+
+        // The optimistic unchoke holds on to its slot, so the fastest peer out of the remaining two replaces the first
+        let faster = if choker.is_choked(peer_id(6882)) { 6882 } else { 6883 };
+        choker.add_downloaded(peer_id(faster), 500);
+        choker.run_round(&mut selector_peers);
+        assert!(choker.is_choked(peer_id(6881)));
+        assert!(!choker.is_choked(peer_id(faster)));
+    }
+
+    #[test]
+    fn positive_seeding_ranks_by_upload_rate() {
+        let ports = [6881, 6882, 6883];
+        let mut choker = interested_choker(1, &ports);
+        let mut selector_peers = SelectorPeers::new();
+        for &port in &ports {
+            choker.set_seeding(peer_id(port), true);
+        }
+
+        choker.add_uploaded(peer_id(6881), 500);
+        choker.run_round(&mut selector_peers);
+        assert!(!choker.is_choked(peer_id(6881)));
+
+        // Bytes the peer sent us are ignored while seeding
+        let faster = if choker.is_choked(peer_id(6882)) { 6882 } else { 6883 };
+        choker.add_downloaded(peer_id(6881), 1000);
+        choker.add_uploaded(peer_id(faster), 500);
+        choker.run_round(&mut selector_peers);
+        assert!(choker.is_choked(peer_id(6881)));
+        assert!(!choker.is_choked(peer_id(faster)));
+    }
+
+    #[test]
+    fn positive_optimistic_unchoke_rotates_every_few_rounds() {
+        let ports: Vec<u16> = (6881..6891).collect();
+        let mut choker = interested_choker(0, &ports);
+        let mut selector_peers = SelectorPeers::new();
+
+        let mut chosen = Vec::new();
+        for _ in 0..10 {
+            choker.run_round(&mut selector_peers);
+            let optimistic = unchoked(&choker, &ports);
+            assert_eq!(1, optimistic.len());
+
+            for _ in 1..super::OPTIMISTIC_UNCHOKE_ROUNDS {
+                choker.run_round(&mut selector_peers);
+                assert_eq!(optimistic, unchoked(&choker, &ports));
+            }
+            chosen.push(optimistic[0]);
+        }
+
+        chosen.sort();
+        chosen.dedup();
+        assert!(chosen.len() > 1);
+    }
+
+    #[test]
+    fn positive_snubbing_peer_choked() {
+        let ports = [6881, 6882];
+        let mut choker = interested_choker(2, &ports);
+        let mut selector_peers = SelectorPeers::new();
+
+        choker.set_snub_timeout(Duration::from_secs(0));
+        choker.set_am_interested(peer_id(6881), true);
+        choker.add_downloaded(peer_id(6881), 500);
+        choker.run_round(&mut selector_peers);
+
+        assert_eq!(vec![6882], unchoked(&choker, &ports));
+    }
+
+    #[test]
+    fn positive_snubbing_optimistic_unchoke_replaced() {
+        let ports = [6881, 6882];
+        let mut choker = interested_choker(0, &ports);
+        let mut selector_peers = SelectorPeers::new();
+
+        choker.run_round(&mut selector_peers);
+        let optimistic = unchoked(&choker, &ports);
+        assert_eq!(1, optimistic.len());
+
+        choker.set_snub_timeout(Duration::from_secs(0));
+        choker.set_am_interested(peer_id(optimistic[0]), true);
+        choker.run_round(&mut selector_peers);
+
+        let replacement = unchoked(&choker, &ports);
+        assert_eq!(1, replacement.len());
+        assert!(replacement != optimistic);
+    }
+
+    #[test]
+    fn positive_seeded_optimistic_unchoke_ignores_insertion_order() {
+        let ports: Vec<u16> = (6881..6901).collect();
+        let reversed: Vec<u16> = ports.iter().rev().cloned().collect();
+
+        let mut choker = interested_choker(0, &ports);
+        let mut reversed_choker = interested_choker(0, &reversed);
+
+        let mut selector_peers = SelectorPeers::new();
+        choker.run_round(&mut selector_peers);
+        reversed_choker.run_round(&mut selector_peers);
+
+        let unchoked: Vec<u16> = ports.iter().cloned().filter(|&port| !choker.is_choked(peer_id(port))).collect();
+        let reversed_unchoked: Vec<u16> = ports.iter().cloned().filter(|&port| !reversed_choker.is_choked(peer_id(port))).collect();
+        assert_eq!(1, unchoked.len());
+        assert_eq!(unchoked, reversed_unchoked);
+    }
+}
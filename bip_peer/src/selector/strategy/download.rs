@@ -0,0 +1,935 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+
+use bip_metainfo::MetainfoFile;
+use bip_util::bt::InfoHash;
+use rand::{Rng, StdRng};
+
+use disk::{ODiskMessage, FilePriorities, DEFAULT_BLOCK_SIZE};
+use message::standard::{HaveMessage, BitFieldMessage, RequestMessage, PieceMessage, CancelMessage};
+use protocol::{PeerIdentifier, OProtocolMessageKind};
+use selector::OSelectorMessageKind;
+use selector::peers::SelectorPeers;
+use selector::strategy::SelectionStrategy;
+use selector::strategy::announce::AnnounceConfig;
+use selector::strategy::bitfields::PeerBitfields;
+use selector::strategy::choker::Choker;
+use selector::strategy::piece_map::{PieceMap, PieceMaps};
+use selector::strategy::random;
+use selector::strategy::torrent::TorrentPieces;
+
+/// Trait for choosing which piece to download next.
+pub trait PiecePicker {
+    /// Pick one of the candidate pieces for the given torrent.
+    ///
+    /// Candidates are incomplete pieces, not being downloaded from any other peer, that the
+    /// peer has; they are given in increasing order. Availability holds the number of connected
+    /// peers that have each piece in the torrent.
+    fn pick<I>(&mut self, hash: InfoHash, candidates: I, availability: &[u32]) -> Option<u32>
+        where I: Iterator<Item = u32>;
+}
+
+// ----------------------------------------------------------------------------//
+
+// Number of blocks left in a torrent at which point we enter endgame mode.
+const DEFAULT_ENDGAME_THRESHOLD_BLOCKS: usize = 20;
+
+// Minimum number of requests that we keep outstanding to each peer.
+const DEFAULT_PIPELINE_DEPTH: usize = 5;
+// Upper bound on the number of requests outstanding to each peer, regardless of how fast it is.
+const MAX_PIPELINE_DEPTH: usize = 250;
+// Seconds worth of blocks (at the peer's observed download rate) that we keep requested from each peer.
+const PIPELINE_QUEUE_SECS: usize = 3;
+// Same as the interval at which we are ticked by the selector.
+const TICK_INTERVAL_SECS: usize = 10;
+
+/// Strategy that downloads whole pieces from peers, leaving the choice of piece to a `PiecePicker`.
+///
+/// Each unchoked peer is assigned a single piece at a time, and blocks for that piece are requested
+/// from the peer, in block order. Requests are pipelined, so that a number of them are outstanding
+/// at once; the depth of the pipeline is tuned to the rate at which each peer is sending us blocks.
+///
+/// Once the number of blocks left in a torrent drops to the endgame threshold, every remaining
+/// block is requested from every unchoked peer that has it, and duplicate requests are cancelled
+/// as blocks arrive.
+pub struct PieceDownloader<P> {
+    picker: P,
+    choker: Choker,
+    endgame_threshold: usize,
+    pipeline_depth: usize,
+    block_size: usize,
+    torrents: HashMap<InfoHash, TorrentEntry>,
+    peers: HashMap<PeerIdentifier, PeerState>,
+    piece_maps: PieceMaps,
+    rng: StdRng,
+}
+
+struct TorrentEntry {
+    pieces: TorrentPieces,
+    bitfields: PeerBitfields,
+    // Peer that we are currently downloading each piece from
+    in_progress: HashMap<u32, PeerIdentifier>,
+    // Offsets of blocks that we have received for incomplete pieces
+    received: HashMap<u32, HashSet<u32>>,
+    announce: AnnounceConfig,
+    // Pieces verified since the last tick that we have yet to announce
+    pending_haves: Vec<u32>,
+    paused: bool,
+}
+
+impl TorrentEntry {
+    /// Number of blocks that we have yet to receive for the torrent.
+    fn remaining_blocks(&self) -> usize {
+        (0..self.pieces.num_pieces())
+            .filter(|index| self.pieces.is_needed(*index))
+            .map(|index| {
+                let received = self.received.get(&index).map_or(0, |blocks| blocks.len());
+
+                self.pieces.num_blocks(index).saturating_sub(received)
+            })
+            .sum()
+    }
+
+    /// Requests for blocks in the given piece that we have yet to receive, in block order.
+    fn missing_requests(&self, piece_index: u32) -> Vec<RequestMessage> {
+        let received = self.received.get(&piece_index);
+
+        self.pieces
+            .piece_requests(piece_index)
+            .into_iter()
+            .filter(|request| received.map_or(true, |blocks| !blocks.contains(&request.block_offset())))
+            .collect()
+    }
+}
+
+struct PeerState {
+    hash: InfoHash,
+    interested: bool,
+    downloading: Option<u32>,
+    // Requests that have been sent to the peer that we haven't received a block for
+    requested: HashSet<RequestMessage>,
+    // Number of requests we want outstanding to the peer at once
+    pipeline_depth: usize,
+    // Bytes received from the peer since the last tick
+    downloaded: usize,
+    // Pieces left out of the bitfield we sent the peer, announced on the next tick
+    withheld: Vec<u32>,
+}
+
+impl<P> PieceDownloader<P>
+    where P: PiecePicker
+{
+    /// Create a new PieceDownloader for the given torrents.
+    pub fn new<'a, I>(torrents: I, picker: P) -> PieceDownloader<P>
+        where I: IntoIterator<Item = &'a MetainfoFile>
+    {
+        let torrents = torrents.into_iter()
+            .map(|metainfo| {
+                let pieces = TorrentPieces::new(metainfo.info());
+                let bitfields = PeerBitfields::new(pieces.num_pieces());
+
+                (metainfo.info_hash(),
+                 TorrentEntry {
+                     pieces: pieces,
+                     bitfields: bitfields,
+                     in_progress: HashMap::new(),
+                     received: HashMap::new(),
+                     announce: AnnounceConfig::default(),
+                     pending_haves: Vec::new(),
+                     paused: false,
+                 })
+            })
+            .collect();
+
+        let downloader = PieceDownloader {
+            picker: picker,
+            choker: Choker::new(),
+            endgame_threshold: DEFAULT_ENDGAME_THRESHOLD_BLOCKS,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            block_size: DEFAULT_BLOCK_SIZE,
+            torrents: torrents,
+            peers: HashMap::new(),
+            piece_maps: PieceMaps::new(),
+            rng: random::entropy_rng(),
+        };
+        for hash in downloader.torrents.keys() {
+            downloader.publish_piece_map(*hash);
+        }
+
+        downloader
+    }
+
+    /// Handle for polling the PieceMap of each torrent.
+    ///
+    /// Piece maps are updated as pieces are verified, while availability is updated every tick.
+    pub fn piece_maps(&self) -> PieceMaps {
+        self.piece_maps.clone()
+    }
+
+    /// Sets the number of blocks left in a torrent at which point we enter endgame mode.
+    pub fn set_endgame_threshold(&mut self, blocks: usize) {
+        self.endgame_threshold = blocks;
+    }
+
+    /// Gets the endgame threshold.
+    pub fn endgame_threshold(&self) -> usize {
+        self.endgame_threshold
+    }
+
+    /// Sets the minimum number of block requests we keep outstanding to each peer.
+    ///
+    /// Faster peers will have more requests outstanding, based on the rate at which they send us blocks.
+    pub fn set_pipeline_depth(&mut self, depth: usize) {
+        self.pipeline_depth = depth;
+    }
+
+    /// Gets the minimum pipeline depth.
+    pub fn pipeline_depth(&self) -> usize {
+        self.pipeline_depth
+    }
+
+    /// Sets the size of the blocks that we request from peers, for all torrents.
+    ///
+    /// Many peers will reject requests for blocks larger than the default of 16 KiB. This
+    /// should be set before any peers connect, since blocks already received are tracked by offset.
+    ///
+    /// Panics if block_size is zero.
+    pub fn set_block_size(&mut self, block_size: usize) {
+        if block_size == 0 {
+            panic!("bip_peer: PieceDownloader Block Size Must Be Non Zero")
+        }
+
+        self.block_size = block_size;
+        for torrent in self.torrents.values_mut() {
+            torrent.pieces.set_block_size(block_size);
+        }
+    }
+
+    /// Gets the block size.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Sets the choker used to decide which peers we upload to.
+    pub fn set_choker(&mut self, choker: Choker) {
+        self.choker = choker;
+    }
+
+    /// Sets the seed used for the random choices made by us and our choker.
+    ///
+    /// The picker is seeded separately, and a choker set after this call keeps its own seed.
+    pub fn set_seed(&mut self, seed: usize) {
+        self.rng = random::seeded_rng(seed);
+        self.choker.set_seed(seed);
+    }
+
+    /// Sets the file priorities for the given torrent.
+    ///
+    /// Pieces that only overlap skipped files will never be requested, and pieces overlapping
+    /// high priority files will be requested before any others.
+    pub fn set_file_priorities(&mut self, metainfo: &MetainfoFile, priorities: &FilePriorities) {
+        if let Some(torrent) = self.torrents.get_mut(&metainfo.info_hash()) {
+            torrent.pieces.set_file_priorities(metainfo.info(), priorities);
+        }
+    }
+
+    /// Sets how the pieces we have are announced to peers of the given torrent.
+    pub fn set_announce_config(&mut self, hash: InfoHash, config: AnnounceConfig) {
+        if let Some(torrent) = self.torrents.get_mut(&hash) {
+            torrent.announce = config;
+        }
+    }
+
+    /// Peer bitfields for the given torrent.
+    pub fn bitfields(&self, hash: InfoHash) -> Option<&PeerBitfields> {
+        self.torrents.get(&hash).map(|torrent| &torrent.bitfields)
+    }
+
+    /// Take a snapshot of the pieces we have, and their availability, for the given torrent.
+    fn publish_piece_map(&self, hash: InfoHash) {
+        if let Some(torrent) = self.torrents.get(&hash) {
+            let mut have = BitFieldMessage::new(torrent.pieces.num_pieces());
+            for piece_index in (0..torrent.pieces.num_pieces()).filter(|index| torrent.pieces.is_good(*index)) {
+                have.set_piece(piece_index);
+            }
+
+            self.piece_maps.update(hash, PieceMap::new(have, torrent.bitfields.availability().to_vec()));
+        }
+    }
+
+    /// Send our bitfield to a newly connected peer, withholding lazy pieces until the next tick.
+    fn send_bitfield(&mut self, id: PeerIdentifier, peers: &mut SelectorPeers) {
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let torrent = &self.torrents[&peer.hash];
+
+        let mut good_pieces: Vec<u32> = (0..torrent.pieces.num_pieces()).filter(|index| torrent.pieces.is_good(*index)).collect();
+        self.rng.shuffle(&mut good_pieces);
+
+        let num_withheld = cmp::min(torrent.announce.lazy_pieces(), good_pieces.len());
+        peer.withheld = good_pieces.split_off(good_pieces.len() - num_withheld);
+
+        let mut bitfield = BitFieldMessage::new(torrent.pieces.num_pieces());
+        for piece_index in good_pieces {
+            bitfield.set_piece(piece_index);
+        }
+        peers.send(id, OSelectorMessageKind::PeerBitField(bitfield));
+    }
+
+    /// Announce a newly verified piece to peers of the torrent, or hold on to it until the next tick.
+    fn announce_piece(&mut self, hash: InfoHash, piece_index: u32, peers: &mut SelectorPeers) {
+        let coalesce = match self.torrents.get_mut(&hash) {
+            Some(torrent) => {
+                if torrent.announce.coalesce_haves() {
+                    torrent.pending_haves.push(piece_index);
+                }
+
+                torrent.announce.coalesce_haves()
+            }
+            None => return,
+        };
+        if coalesce {
+            return;
+        }
+
+        for (&id, _) in self.peers.iter().filter(|&(_, ref peer)| peer.hash == hash) {
+            peers.send(id, OSelectorMessageKind::PeerHave(HaveMessage::new(piece_index)));
+        }
+    }
+
+    /// Announce any pieces withheld from peer bitfields, or held back since the last tick.
+    ///
+    /// Peers that already have a piece are not told about it.
+    fn flush_haves(&mut self, peers: &mut SelectorPeers) {
+        for (&id, peer) in self.peers.iter_mut() {
+            let torrent = &self.torrents[&peer.hash];
+            let pieces: Vec<u32> = peer.withheld
+                .drain(..)
+                .chain(torrent.pending_haves.iter().cloned())
+                .filter(|index| !torrent.bitfields.has_piece(id, *index))
+                .collect();
+
+            for piece_index in pieces {
+                peers.send(id, OSelectorMessageKind::PeerHave(HaveMessage::new(piece_index)));
+            }
+        }
+
+        for torrent in self.torrents.values_mut() {
+            torrent.pending_haves.clear();
+        }
+    }
+
+    /// Bitfields for the torrent that the peer is connected for.
+    fn peer_bitfields(&mut self, id: PeerIdentifier) -> Option<&mut PeerBitfields> {
+        let torrents = &mut self.torrents;
+
+        self.peers.get(&id).and_then(move |peer| torrents.get_mut(&peer.hash)).map(|torrent| &mut torrent.bitfields)
+    }
+
+    /// Stop downloading whatever piece the peer was assigned.
+    fn release_piece(&mut self, id: PeerIdentifier) {
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let torrent = self.torrents.get_mut(&peer.hash).expect("bip_peer: Peer Connected For Unknown Torrent");
+
+        if let Some(piece_index) = peer.downloading.take() {
+            torrent.in_progress.remove(&piece_index);
+        }
+    }
+
+    /// Update our interest in the peer based on whether it has pieces we still need.
+    fn update_interest(&mut self, id: PeerIdentifier, peers: &mut SelectorPeers) {
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let torrent = &self.torrents[&peer.hash];
+
+        let needs_piece = torrent.bitfields
+            .peer_pieces(id)
+            .into_iter()
+            .any(|index| torrent.pieces.is_needed(index));
+
+        if needs_piece != peer.interested {
+            peer.interested = needs_piece;
+            self.choker.set_am_interested(id, needs_piece);
+
+            let kind = if needs_piece {
+                OSelectorMessageKind::PeerInterested
+            } else {
+                OSelectorMessageKind::PeerNotInterested
+            };
+            peers.send(id, kind);
+        }
+    }
+
+    /// Assign a piece the peer has to the peer, or top up the requests for the piece it was already assigned.
+    fn request_piece(&mut self, id: PeerIdentifier, peers: &mut SelectorPeers) {
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let torrent = self.torrents.get_mut(&peer.hash).expect("bip_peer: Peer Connected For Unknown Torrent");
+        // Peers only serve requests while unchoking us, and we only request from peers we told we are interested in
+        if !peers.is_unchoking_us(id) || !peer.interested || torrent.paused {
+            return;
+        }
+
+        if let Some(piece_index) = peer.downloading {
+            fill_pipeline(id, peer, torrent, piece_index, peers);
+
+            return;
+        }
+
+        let opt_piece = {
+            let candidates: Vec<u32> = torrent.bitfields
+                .peer_pieces(id)
+                .into_iter()
+                .filter(|index| torrent.pieces.is_needed(*index) && !torrent.in_progress.contains_key(index))
+                .collect();
+            // Only let the picker choose from pieces with the highest priority available
+            match candidates.iter().map(|index| torrent.pieces.priority(*index)).max() {
+                Some(priority) => {
+                    let pieces = &torrent.pieces;
+                    let candidates = candidates.into_iter().filter(|index| pieces.priority(*index) == priority);
+
+                    self.picker.pick(peer.hash, candidates, torrent.bitfields.availability())
+                }
+                None => None,
+            }
+        };
+
+        if let Some(piece_index) = opt_piece {
+            peer.downloading = Some(piece_index);
+            torrent.in_progress.insert(piece_index, id);
+
+            fill_pipeline(id, peer, torrent, piece_index, peers);
+        } else if torrent.remaining_blocks() <= self.endgame_threshold {
+            // Nothing left to assign to the peer, so duplicate requests for blocks other peers are working on
+            let piece_indices: Vec<u32> = torrent.bitfields
+                .peer_pieces(id)
+                .into_iter()
+                .filter(|index| torrent.pieces.is_needed(*index))
+                .collect();
+
+            for piece_index in piece_indices {
+                for request in torrent.missing_requests(piece_index) {
+                    if peer.requested.insert(request) {
+                        peers.send(id, OSelectorMessageKind::PeerRequest(request));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Record the block the peer sent us, cancelling any duplicate requests sent to other peers.
+    fn receive_block(&mut self, id: PeerIdentifier, piece: PieceMessage, peers: &mut SelectorPeers) {
+        let request = RequestMessage::new(piece.piece_index(), piece.block_offset(), piece.block_length());
+        let hash = match self.peers.get_mut(&id) {
+            Some(peer) => {
+                peer.requested.remove(&request);
+
+                peer.hash
+            }
+            None => return,
+        };
+        let torrent = self.torrents.get_mut(&hash).expect("bip_peer: Peer Connected For Unknown Torrent");
+
+        if torrent.pieces.is_good(request.piece_index()) {
+            return;
+        }
+        torrent.received.entry(request.piece_index()).or_insert_with(HashSet::new).insert(request.block_offset());
+
+        for (&other_id, other_peer) in self.peers.iter_mut().filter(|&(_, ref peer)| peer.hash == hash) {
+            if other_peer.requested.remove(&request) {
+                // If the request never made it out of our queue, the peer doesn't know about it
+                if !peers.remove_queued(other_id, &OSelectorMessageKind::PeerRequest(request)) {
+                    let cancel = CancelMessage::new(request.piece_index(), request.block_offset(), request.block_length());
+
+                    peers.send(other_id, OSelectorMessageKind::PeerCancel(cancel));
+                }
+            }
+        }
+    }
+
+    /// Forget about any blocks received or requested for the given piece.
+    fn reset_piece(&mut self, hash: InfoHash, piece_index: u32) {
+        if let Some(torrent) = self.torrents.get_mut(&hash) {
+            torrent.received.remove(&piece_index);
+        }
+
+        for peer in self.peers.values_mut().filter(|peer| peer.hash == hash) {
+            peer.requested.retain(|request| request.piece_index() != piece_index);
+        }
+    }
+
+    /// Stop downloading the torrent, cancelling any requests still outstanding to its peers.
+    ///
+    /// Peers are kept around so that we continue seeding to them.
+    fn complete_torrent(&mut self, hash: InfoHash, peers: &mut SelectorPeers) {
+        let new_pieces: Vec<u32> = match self.torrents.get_mut(&hash) {
+            Some(torrent) => {
+                let new_pieces = (0..torrent.pieces.num_pieces()).filter(|index| !torrent.pieces.is_good(*index)).collect();
+
+                for piece_index in 0..torrent.pieces.num_pieces() {
+                    torrent.pieces.set_good(piece_index);
+                }
+                torrent.in_progress.clear();
+                torrent.received.clear();
+
+                new_pieces
+            }
+            None => return,
+        };
+        for piece_index in new_pieces {
+            self.announce_piece(hash, piece_index, peers);
+        }
+        self.cancel_requests(hash, peers);
+
+        // With nothing left to download, this lets every peer know we are no longer interested
+        self.update_torrent_peers(hash, peers);
+    }
+
+    /// Cancel any requests still outstanding to the peers of the given torrent, and unassign their pieces.
+    ///
+    /// The caller is responsible for clearing the pieces in progress for the torrent.
+    fn cancel_requests(&mut self, hash: InfoHash, peers: &mut SelectorPeers) {
+        for (&id, peer) in self.peers.iter_mut().filter(|&(_, ref peer)| peer.hash == hash) {
+            peer.downloading = None;
+
+            for request in peer.requested.drain() {
+                if !peers.remove_queued(id, &OSelectorMessageKind::PeerRequest(request)) {
+                    let cancel = CancelMessage::new(request.piece_index(), request.block_offset(), request.block_length());
+
+                    peers.send(id, OSelectorMessageKind::PeerCancel(cancel));
+                }
+            }
+        }
+    }
+
+    /// Identifiers of the peers connected for the given torrent.
+    fn torrent_peers(&self, hash: InfoHash) -> Vec<PeerIdentifier> {
+        self.peers
+            .iter()
+            .filter(|&(_, peer)| peer.hash == hash)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Re-evaluate interest and requests for every peer connected for the given torrent.
+    fn update_torrent_peers(&mut self, hash: InfoHash, peers: &mut SelectorPeers) {
+        for id in self.torrent_peers(hash) {
+            self.update_interest(id, peers);
+            self.request_piece(id, peers);
+        }
+    }
+}
+
+impl<P> SelectionStrategy for PieceDownloader<P>
+    where P: PiecePicker
+{
+    fn peer_connect(&mut self, id: PeerIdentifier, hash: InfoHash, peers: &mut SelectorPeers) {
+        let paused = match self.torrents.get_mut(&hash) {
+            Some(torrent) => {
+                torrent.bitfields.add_peer(id);
+
+                torrent.paused
+            }
+            None => {
+                // We have no idea what pieces make up the torrent, so we can't do anything with the peer
+                peers.send(id, OSelectorMessageKind::PeerDisconnect);
+                return;
+            }
+        };
+
+        self.peers.insert(id,
+                          PeerState {
+                              hash: hash,
+                              interested: false,
+                              downloading: None,
+                              requested: HashSet::new(),
+                              pipeline_depth: self.pipeline_depth,
+                              downloaded: 0,
+                              withheld: Vec::new(),
+                          });
+        self.choker.add_peer(id);
+        self.choker.set_paused(id, paused, peers);
+
+        self.send_bitfield(id, peers);
+    }
+
+    fn peer_disconnect(&mut self, id: PeerIdentifier, peers: &mut SelectorPeers) {
+        // Requests outstanding to the peer die with it, unassigning its piece puts those blocks back up for grabs
+        self.release_piece(id);
+        self.choker.remove_peer(id);
+
+        if let Some(bitfields) = self.peer_bitfields(id) {
+            bitfields.remove_peer(id);
+        }
+
+        // Hand the piece to another peer now, rather than leaving it to stall until the next tick
+        if let Some(peer) = self.peers.remove(&id) {
+            self.update_torrent_peers(peer.hash, peers);
+        }
+    }
+
+    fn peer_message(&mut self, id: PeerIdentifier, kind: OProtocolMessageKind, peers: &mut SelectorPeers) {
+        match kind {
+            OProtocolMessageKind::PeerHave(have) => {
+                if let Some(bitfields) = self.peer_bitfields(id) {
+                    bitfields.peer_have(id, have.piece_index());
+                }
+            }
+            OProtocolMessageKind::PeerBitField(bitfield) => {
+                if let Some(bitfields) = self.peer_bitfields(id) {
+                    bitfields.peer_bitfield(id, &bitfield);
+                }
+            }
+            OProtocolMessageKind::PeerHaveAll => {
+                if let Some(bitfields) = self.peer_bitfields(id) {
+                    bitfields.peer_have_all(id);
+                }
+            }
+            OProtocolMessageKind::PeerChoke => {
+                // Peers discard all pending requests when they choke us, queued requests were already dropped by the selector
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    peer.requested.clear();
+                }
+                self.release_piece(id);
+            }
+            OProtocolMessageKind::PeerUnChoke => (),
+            OProtocolMessageKind::PeerPiece(_, piece) => {
+                self.choker.add_downloaded(id, piece.block_length());
+                self.peers.get_mut(&id).map(|peer| peer.downloaded += piece.block_length());
+                self.receive_block(id, piece, peers);
+            }
+            OProtocolMessageKind::PeerPieceSent(request) => {
+                self.choker.add_uploaded(id, request.block_length());
+
+                return;
+            }
+            OProtocolMessageKind::PeerInterested => {
+                self.choker.set_interested(id, true);
+            }
+            OProtocolMessageKind::PeerUnInterested => {
+                self.choker.set_interested(id, false);
+            }
+            OProtocolMessageKind::PeerRejectRequest(reject) => {
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    peer.requested.remove(&RequestMessage::new(reject.piece_index(), reject.block_offset(), reject.block_length()));
+                }
+
+                let rejected_current = self.peers.get(&id).map_or(false, |peer| peer.downloading == Some(reject.piece_index()));
+
+                if rejected_current {
+                    self.release_piece(id);
+                }
+            }
+            _ => return,
+        }
+
+        self.update_interest(id, peers);
+        self.request_piece(id, peers);
+    }
+
+    fn disk_message(&mut self, msg: ODiskMessage, peers: &mut SelectorPeers) {
+        match msg {
+            ODiskMessage::FoundGoodPiece(hash, piece_index) => {
+                let opt_peer = self.torrents.get_mut(&hash).and_then(|torrent| {
+                    torrent.pieces.set_good(piece_index);
+
+                    torrent.in_progress.remove(&piece_index)
+                });
+
+                if let Some(peer) = opt_peer.and_then(|id| self.peers.get_mut(&id)) {
+                    peer.downloading = None;
+                }
+                self.reset_piece(hash, piece_index);
+                self.publish_piece_map(hash);
+                self.announce_piece(hash, piece_index, peers);
+
+                self.update_torrent_peers(hash, peers);
+            }
+            ODiskMessage::FoundBadPiece(hash, piece_index) => {
+                let opt_peer = self.torrents.get_mut(&hash).and_then(|torrent| {
+                    // Pieces verified earlier can still turn up bad when the torrent is verified on completion
+                    torrent.pieces.set_bad(piece_index);
+
+                    torrent.in_progress.remove(&piece_index)
+                });
+
+                if let Some(peer) = opt_peer.and_then(|id| self.peers.get_mut(&id)) {
+                    peer.downloading = None;
+                }
+                self.reset_piece(hash, piece_index);
+                self.publish_piece_map(hash);
+
+                self.update_torrent_peers(hash, peers);
+            }
+            ODiskMessage::TorrentComplete(hash) => {
+                self.complete_torrent(hash, peers);
+                self.publish_piece_map(hash);
+            }
+            _ => (),
+        }
+    }
+
+    fn pause_torrent(&mut self, hash: InfoHash, peers: &mut SelectorPeers) {
+        match self.torrents.get_mut(&hash) {
+            Some(torrent) => {
+                torrent.paused = true;
+                torrent.in_progress.clear();
+            }
+            None => return,
+        }
+        // Blocks we already received are kept, so pieces pick up where they left off once resumed
+        self.cancel_requests(hash, peers);
+
+        for id in self.torrent_peers(hash) {
+            self.choker.set_paused(id, true, peers);
+        }
+    }
+
+    fn resume_torrent(&mut self, hash: InfoHash, peers: &mut SelectorPeers) {
+        match self.torrents.get_mut(&hash) {
+            Some(torrent) => torrent.paused = false,
+            None => return,
+        }
+
+        for id in self.torrent_peers(hash) {
+            self.choker.set_paused(id, false, peers);
+        }
+        self.update_torrent_peers(hash, peers);
+    }
+
+    fn tick(&mut self, peers: &mut SelectorPeers) {
+        // Completion can be undone by a bad piece, or by changing file priorities, so check it every round
+        for (&id, peer) in self.peers.iter() {
+            self.choker.set_seeding(id, self.torrents[&peer.hash].pieces.is_complete());
+        }
+        self.choker.run_round(peers);
+        self.flush_haves(peers);
+
+        // Keep enough requests outstanding to cover a few seconds worth of blocks at the rate the peer is sending them
+        for peer in self.peers.values_mut() {
+            let blocks_per_sec = peer.downloaded / TICK_INTERVAL_SECS / self.block_size;
+
+            peer.pipeline_depth = cmp::min(cmp::max(self.pipeline_depth, blocks_per_sec * PIPELINE_QUEUE_SECS), MAX_PIPELINE_DEPTH);
+            peer.downloaded = 0;
+        }
+
+        let ids: Vec<PeerIdentifier> = self.peers.keys().cloned().collect();
+        for id in ids {
+            self.request_piece(id, peers);
+        }
+
+        for hash in self.torrents.keys() {
+            self.publish_piece_map(*hash);
+        }
+    }
+}
+
+/// Send requests for blocks in the given piece to the peer until its pipeline is full.
+fn fill_pipeline(id: PeerIdentifier, peer: &mut PeerState, torrent: &TorrentEntry, piece_index: u32, peers: &mut SelectorPeers) {
+    let free_slots = peer.pipeline_depth.saturating_sub(peer.requested.len());
+    let requests: Vec<RequestMessage> = torrent.missing_requests(piece_index)
+        .into_iter()
+        .filter(|request| !peer.requested.contains(request))
+        .take(free_slots)
+        .collect();
+
+    for request in requests {
+        peer.requested.insert(request);
+        peers.send(id, OSelectorMessageKind::PeerRequest(request));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::mpsc::{self, Receiver};
+
+    use bip_bencode::Bencode;
+    use bip_metainfo::MetainfoFile;
+    use bip_util::bt::InfoHash;
+
+    use disk::ODiskMessage;
+    use message::standard::{RequestMessage, PieceMessage, CancelMessage};
+    use protocol::OProtocolMessageKind;
+    use selector::{OSelectorMessage, OSelectorMessageKind};
+    use selector::peers::SelectorPeers;
+    use selector::strategy::SelectionStrategy;
+    use selector::testing::peer_id;
+    use token::TokenGenerator;
+    use super::{PieceDownloader, PiecePicker};
+
+    const BLOCK_SIZE: usize = 16 * 1024;
+    const BLOCKS_PER_PIECE: usize = 4;
+
+    /// Picks the lowest index candidate.
+    struct LowestPicker;
+
+    impl PiecePicker for LowestPicker {
+        fn pick<I>(&mut self, _hash: InfoHash, mut candidates: I, _availability: &[u32]) -> Option<u32>
+            where I: Iterator<Item = u32>
+        {
+            candidates.next()
+        }
+    }
+
+    /// Create a single file torrent made up of the given number of pieces.
+    fn create_metainfo(num_pieces: usize) -> MetainfoFile {
+        let piece_length = BLOCKS_PER_PIECE * BLOCK_SIZE;
+        let pieces = vec![0u8; num_pieces * 20];
+
+        let mut file_dict = BTreeMap::new();
+        file_dict.insert(&b"length"[..], ben_int!((num_pieces * piece_length) as i64));
+        file_dict.insert(&b"path"[..], Bencode::List(vec![ben_bytes!(&b"file"[..])]));
+
+        let mut info_dict = BTreeMap::new();
+        info_dict.insert(&b"name"[..], ben_bytes!(&b"test"[..]));
+        info_dict.insert(&b"piece length"[..], ben_int!(piece_length as i64));
+        info_dict.insert(&b"pieces"[..], ben_bytes!(&pieces[..]));
+        info_dict.insert(&b"files"[..], Bencode::List(vec![Bencode::Dict(file_dict)]));
+
+        let mut root_dict = BTreeMap::new();
+        root_dict.insert(&b"announce"[..], ben_bytes!(&b"udp://localhost:6969"[..]));
+        root_dict.insert(&b"info"[..], Bencode::Dict(info_dict));
+
+        MetainfoFile::from_bytes(Bencode::Dict(root_dict).encode()).unwrap()
+    }
+
+    /// Connect a peer that has every piece and has unchoked us, returning the messages sent to it.
+    fn connect_seed(downloader: &mut PieceDownloader<LowestPicker>, peers: &mut SelectorPeers, port: u16, hash: InfoHash)
+                    -> Receiver<OSelectorMessage> {
+        let (send, recv) = mpsc::channel();
+
+        peers.add_peer(peer_id(port), hash, Box::new(send));
+        peers.set_choking_us(peer_id(port), false);
+        downloader.peer_connect(peer_id(port), hash, peers);
+        downloader.peer_message(peer_id(port), OProtocolMessageKind::PeerHaveAll, peers);
+
+        recv
+    }
+
+    fn receive_block(downloader: &mut PieceDownloader<LowestPicker>, peers: &mut SelectorPeers, port: u16, request: RequestMessage) {
+        let piece = PieceMessage::new(request.piece_index(), request.block_offset(), request.block_length());
+
+        downloader.peer_message(peer_id(port), OProtocolMessageKind::PeerPiece(TokenGenerator::new().generate(), piece), peers);
+    }
+
+    fn drain(recv: &Receiver<OSelectorMessage>) -> Vec<OSelectorMessageKind> {
+        recv.try_iter().map(|msg| msg.kind()).collect()
+    }
+
+    fn requests(kinds: &[OSelectorMessageKind]) -> Vec<RequestMessage> {
+        kinds.iter()
+            .filter_map(|kind| match kind {
+                &OSelectorMessageKind::PeerRequest(request) => Some(request),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn block(piece_index: u32, block: usize) -> RequestMessage {
+        RequestMessage::new(piece_index, (block * BLOCK_SIZE) as u32, BLOCK_SIZE)
+    }
+
+    #[test]
+    fn positive_peers_assigned_separate_pieces() {
+        let metainfo = create_metainfo(2);
+        let mut downloader = PieceDownloader::new(Some(&metainfo), LowestPicker);
+        downloader.set_endgame_threshold(0);
+        downloader.set_pipeline_depth(2);
+        let mut peers = SelectorPeers::new();
+
+        let recv_one = connect_seed(&mut downloader, &mut peers, 6881, metainfo.info_hash());
+        let recv_two = connect_seed(&mut downloader, &mut peers, 6882, metainfo.info_hash());
+
+        assert_eq!(vec![block(0, 0), block(0, 1)], requests(&drain(&recv_one)));
+        assert_eq!(vec![block(1, 0), block(1, 1)], requests(&drain(&recv_two)));
+    }
+
+    #[test]
+    fn positive_received_block_refills_pipeline() {
+        let metainfo = create_metainfo(1);
+        let mut downloader = PieceDownloader::new(Some(&metainfo), LowestPicker);
+        downloader.set_endgame_threshold(0);
+        downloader.set_pipeline_depth(2);
+        let mut peers = SelectorPeers::new();
+
+        let recv = connect_seed(&mut downloader, &mut peers, 6881, metainfo.info_hash());
+        assert_eq!(vec![block(0, 0), block(0, 1)], requests(&drain(&recv)));
+
+        receive_block(&mut downloader, &mut peers, 6881, block(0, 0));
+        assert_eq!(vec![block(0, 2)], requests(&drain(&recv)));
+    }
+
+    #[test]
+    fn positive_endgame_entered_at_threshold() {
+        let metainfo = create_metainfo(1);
+        let mut downloader = PieceDownloader::new(Some(&metainfo), LowestPicker);
+        downloader.set_endgame_threshold(BLOCKS_PER_PIECE - 1);
+        downloader.set_pipeline_depth(BLOCKS_PER_PIECE);
+        let mut peers = SelectorPeers::new();
+
+        let recv_one = connect_seed(&mut downloader, &mut peers, 6881, metainfo.info_hash());
+        let recv_two = connect_seed(&mut downloader, &mut peers, 6882, metainfo.info_hash());
+        assert_eq!(BLOCKS_PER_PIECE, requests(&drain(&recv_one)).len());
+        assert!(requests(&drain(&recv_two)).is_empty());
+
+        // Dropping below the threshold lets the idle peer duplicate the blocks still outstanding
+        receive_block(&mut downloader, &mut peers, 6881, block(0, 0));
+        downloader.tick(&mut peers);
+
+        let mut duplicated = requests(&drain(&recv_two));
+        duplicated.sort_by_key(|request| request.block_offset());
+        assert_eq!(vec![block(0, 1), block(0, 2), block(0, 3)], duplicated);
+    }
+
+    #[test]
+    fn positive_endgame_cancels_duplicates_and_exits_on_good_piece() {
+        let metainfo = create_metainfo(1);
+        let mut downloader = PieceDownloader::new(Some(&metainfo), LowestPicker);
+        downloader.set_pipeline_depth(BLOCKS_PER_PIECE);
+        let mut peers = SelectorPeers::new();
+
+        let recv_one = connect_seed(&mut downloader, &mut peers, 6881, metainfo.info_hash());
+        let recv_two = connect_seed(&mut downloader, &mut peers, 6882, metainfo.info_hash());
+        assert_eq!(BLOCKS_PER_PIECE, requests(&drain(&recv_one)).len());
+        assert_eq!(BLOCKS_PER_PIECE, requests(&drain(&recv_two)).len());
+
+        receive_block(&mut downloader, &mut peers, 6881, block(0, 0));
+        let cancel = CancelMessage::new(0, 0, BLOCK_SIZE);
+        assert_eq!(vec![OSelectorMessageKind::PeerCancel(cancel)], drain(&recv_two));
+
+        downloader.disk_message(ODiskMessage::FoundGoodPiece(metainfo.info_hash(), 0), &mut peers);
+        downloader.tick(&mut peers);
+
+        assert!(requests(&drain(&recv_one)).is_empty());
+        assert!(requests(&drain(&recv_two)).is_empty());
+    }
+
+    #[test]
+    fn positive_disconnect_reassigns_piece() {
+        let metainfo = create_metainfo(1);
+        let mut downloader = PieceDownloader::new(Some(&metainfo), LowestPicker);
+        downloader.set_endgame_threshold(0);
+        downloader.set_pipeline_depth(2);
+        let mut peers = SelectorPeers::new();
+
+        let recv_one = connect_seed(&mut downloader, &mut peers, 6881, metainfo.info_hash());
+        let recv_two = connect_seed(&mut downloader, &mut peers, 6882, metainfo.info_hash());
+        assert_eq!(vec![block(0, 0), block(0, 1)], requests(&drain(&recv_one)));
+        assert!(requests(&drain(&recv_two)).is_empty());
+
+        peers.remove_peer(peer_id(6881));
+        downloader.peer_disconnect(peer_id(6881), &mut peers);
+
+        assert_eq!(vec![block(0, 0), block(0, 1)], requests(&drain(&recv_two)));
+    }
+}
@@ -0,0 +1,305 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bip_metainfo::MetainfoFile;
+use bip_util::bt::InfoHash;
+use bip_util::send::TrySender;
+
+use message::extension::{ExtendedHandshake, EXTENDED_HANDSHAKE_ID};
+use message::metadata::{MetadataMessage, METADATA_EXTENSION_NAME, METADATA_PIECE_LEN};
+use protocol::{PeerIdentifier, OProtocolMessage, OProtocolMessageKind};
+use registration::LayerRegistration;
+use selector::{OSelectorMessage, OSelectorMessageKind, SelectorSender};
+use selector::peers::SelectorPeers;
+use selector::strategy::{PieceSelector, SelectionStrategy};
+
+// Extension id that we ask peers to send metadata messages to us with.
+const LOCAL_METADATA_ID: u8 = 3;
+
+// Largest info dictionary that we will download, so peers can't make us allocate arbitrary amounts.
+const MAX_METADATA_SIZE: usize = 8 * 1024 * 1024;
+
+/// Selection layer that downloads the info dictionary for torrents from peers (BEP 9).
+///
+/// Useful for magnet links, where we only know the InfoHash of the torrent up front.
+pub struct MetadataSelector {
+    selector: PieceSelector,
+    completed: Arc<Mutex<HashMap<InfoHash, Vec<u8>>>>,
+}
+
+impl MetadataSelector {
+    /// Create a new MetadataSelector for the given torrents.
+    pub fn new<I>(hashes: I) -> MetadataSelector
+        where I: IntoIterator<Item = InfoHash>
+    {
+        let downloader = MetadataDownloader::new(hashes);
+        let completed = downloader.completed.clone();
+
+        MetadataSelector {
+            selector: PieceSelector::new(downloader),
+            completed: completed,
+        }
+    }
+
+    /// MetainfoFile for the given torrent, if its info dictionary has been downloaded and verified.
+    ///
+    /// The info dictionary can be passed on to the disk manager to check or download pieces.
+    pub fn metainfo(&self, hash: &InfoHash) -> Option<MetainfoFile> {
+        self.completed
+            .lock()
+            .expect("bip_peer: MetadataSelector Completed Lock Poisoned")
+            .get(hash)
+            .and_then(|info_bytes| metainfo_from_info(info_bytes))
+    }
+}
+
+impl LayerRegistration<OSelectorMessage, OProtocolMessage> for MetadataSelector {
+    type SS2 = SelectorSender;
+
+    fn register(&mut self, send: Box<TrySender<OSelectorMessage>>) -> SelectorSender {
+        self.selector.register(send)
+    }
+}
+
+/// Build a MetainfoFile from the bencoded info dictionary, which has no trackers attached.
+fn metainfo_from_info(info_bytes: &[u8]) -> Option<MetainfoFile> {
+    let mut root_bytes = Vec::with_capacity(info_bytes.len() + 8);
+    root_bytes.extend_from_slice(b"d4:info");
+    root_bytes.extend_from_slice(info_bytes);
+    root_bytes.push(b'e');
+
+    MetainfoFile::from_bytes(root_bytes).ok()
+}
+
+// ----------------------------------------------------------------------------//
+
+/// Strategy that requests pieces of the info dictionary from peers supporting the metadata extension.
+///
+/// Each peer is assigned a single piece of metadata at a time; once all pieces have been received,
+/// the info dictionary is checked against the InfoHash and, if it is bad, downloaded again.
+pub struct MetadataDownloader {
+    torrents: HashMap<InfoHash, MetadataEntry>,
+    peers: HashMap<PeerIdentifier, PeerState>,
+    completed: Arc<Mutex<HashMap<InfoHash, Vec<u8>>>>,
+}
+
+struct MetadataEntry {
+    size: Option<usize>,
+    pieces: Vec<Option<Vec<u8>>>,
+    // Verified info dictionary, which we can also serve to other peers
+    info: Option<Vec<u8>>,
+}
+
+impl MetadataEntry {
+    /// Size, in bytes, of the given metadata piece.
+    fn piece_len(&self, piece: usize) -> usize {
+        let size = self.size.unwrap_or(0);
+
+        cmp::min(size.saturating_sub(piece * METADATA_PIECE_LEN), METADATA_PIECE_LEN)
+    }
+}
+
+struct PeerState {
+    hash: InfoHash,
+    // Extension id that the peer wants metadata messages sent with
+    remote_id: Option<u8>,
+    requested: Option<usize>,
+    rejected: bool,
+}
+
+impl MetadataDownloader {
+    /// Create a new MetadataDownloader for the given torrents.
+    pub fn new<I>(hashes: I) -> MetadataDownloader
+        where I: IntoIterator<Item = InfoHash>
+    {
+        let torrents = hashes.into_iter()
+            .map(|hash| {
+                (hash,
+                 MetadataEntry {
+                     size: None,
+                     pieces: Vec::new(),
+                     info: None,
+                 })
+            })
+            .collect();
+
+        MetadataDownloader {
+            torrents: torrents,
+            peers: HashMap::new(),
+            completed: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Assign the next missing piece of metadata to the peer, if it is not already downloading one.
+    fn request_piece(&mut self, id: PeerIdentifier, peers: &mut SelectorPeers) {
+        let (hash, remote_id) = match self.peers.get(&id) {
+            Some(&PeerState { hash, remote_id: Some(remote_id), requested: None, rejected: false }) => (hash, remote_id),
+            _ => return,
+        };
+
+        let opt_piece = self.torrents.get(&hash).and_then(|entry| {
+            if entry.info.is_some() {
+                return None;
+            }
+
+            (0..entry.pieces.len()).find(|piece| {
+                entry.pieces[*piece].is_none() && !self.peers.values().any(|state| state.hash == hash && state.requested == Some(*piece))
+            })
+        });
+
+        if let Some(piece) = opt_piece {
+            self.peers.get_mut(&id).map(|state| state.requested = Some(piece));
+
+            peers.send(id, OSelectorMessageKind::PeerExtension(MetadataMessage::Request(piece).to_message(remote_id)));
+        }
+    }
+
+    fn extended_handshake(&mut self, id: PeerIdentifier, handshake: ExtendedHandshake, peers: &mut SelectorPeers) {
+        let hash = match self.peers.get_mut(&id) {
+            Some(state) => {
+                state.remote_id = handshake.extension_id(METADATA_EXTENSION_NAME);
+
+                state.hash
+            }
+            None => return,
+        };
+
+        if let (Some(entry), Some(size)) = (self.torrents.get_mut(&hash), handshake.metadata_size()) {
+            if entry.size.is_none() && size <= MAX_METADATA_SIZE {
+                entry.size = Some(size);
+                entry.pieces = vec![None; (size + METADATA_PIECE_LEN - 1) / METADATA_PIECE_LEN];
+            }
+        }
+
+        self.request_piece(id, peers);
+    }
+
+    fn metadata_message(&mut self, id: PeerIdentifier, msg: MetadataMessage, peers: &mut SelectorPeers) {
+        let (hash, remote_id, requested) = match self.peers.get(&id) {
+            Some(state) => (state.hash, state.remote_id, state.requested),
+            None => return,
+        };
+
+        match msg {
+            MetadataMessage::Request(piece) => {
+                if let (Some(entry), Some(remote_id)) = (self.torrents.get(&hash), remote_id) {
+                    let response = match entry.info {
+                        Some(ref info) if piece * METADATA_PIECE_LEN < info.len() => {
+                            let end = cmp::min(info.len(), (piece + 1) * METADATA_PIECE_LEN);
+
+                            MetadataMessage::Data(piece, info.len(), info[piece * METADATA_PIECE_LEN..end].to_vec())
+                        }
+                        _ => MetadataMessage::Reject(piece),
+                    };
+
+                    peers.send(id, OSelectorMessageKind::PeerExtension(response.to_message(remote_id)));
+                }
+            }
+            MetadataMessage::Data(piece, total_size, data) => {
+                if requested != Some(piece) {
+                    return;
+                }
+                self.peers.get_mut(&id).map(|state| state.requested = None);
+
+                if let Some(entry) = self.torrents.get_mut(&hash) {
+                    if entry.size == Some(total_size) && entry.piece_len(piece) == data.len() {
+                        entry.pieces[piece] = Some(data);
+                    }
+
+                    if entry.info.is_none() && entry.pieces.iter().all(|piece| piece.is_some()) {
+                        let info: Vec<u8> = entry.pieces.iter().flat_map(|piece| piece.as_ref().unwrap().iter().cloned()).collect();
+
+                        // If the info dictionary doesn't match the hash, we have no idea which piece was bad
+                        if InfoHash::from_bytes(&info) == hash && metainfo_from_info(&info).is_some() {
+                            self.completed
+                                .lock()
+                                .expect("bip_peer: MetadataDownloader Completed Lock Poisoned")
+                                .insert(hash, info.clone());
+                            entry.info = Some(info);
+                        } else {
+                            for piece in entry.pieces.iter_mut() {
+                                *piece = None;
+                            }
+                        }
+                    }
+                }
+
+                self.request_piece(id, peers);
+            }
+            MetadataMessage::Reject(piece) => {
+                if requested == Some(piece) {
+                    self.peers.get_mut(&id).map(|state| {
+                        state.requested = None;
+                        state.rejected = true;
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl SelectionStrategy for MetadataDownloader {
+    fn peer_connect(&mut self, id: PeerIdentifier, hash: InfoHash, peers: &mut SelectorPeers) {
+        let opt_size = match self.torrents.get(&hash) {
+            Some(entry) => entry.info.as_ref().map(|info| info.len()),
+            None => return,
+        };
+
+        self.peers.insert(id,
+                          PeerState {
+                              hash: hash,
+                              remote_id: None,
+                              requested: None,
+                              rejected: false,
+                          });
+
+        let mut handshake = ExtendedHandshake::new();
+        handshake.add_extension(METADATA_EXTENSION_NAME, LOCAL_METADATA_ID);
+        opt_size.map(|size| handshake.set_metadata_size(size));
+
+        peers.send(id, OSelectorMessageKind::PeerExtension(handshake.to_message()));
+    }
+
+    fn peer_disconnect(&mut self, id: PeerIdentifier, _peers: &mut SelectorPeers) {
+        self.peers.remove(&id);
+    }
+
+    fn peer_message(&mut self, id: PeerIdentifier, kind: OProtocolMessageKind, peers: &mut SelectorPeers) {
+        if let OProtocolMessageKind::PeerExtension(ext_msg) = kind {
+            if ext_msg.id() == EXTENDED_HANDSHAKE_ID {
+                if let Some(handshake) = ExtendedHandshake::from_message(&ext_msg) {
+                    self.extended_handshake(id, handshake, peers);
+                }
+            } else if ext_msg.id() == LOCAL_METADATA_ID {
+                if let Some(msg) = MetadataMessage::from_payload(ext_msg.payload()) {
+                    self.metadata_message(id, msg, peers);
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self, peers: &mut SelectorPeers) {
+        // Pieces held by peers that have since disconnected or sent bad data can be picked up again
+        for id in self.peers.keys().cloned().collect::<Vec<_>>() {
+            self.request_piece(id, peers);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MetadataEntry;
+
+    #[test]
+    fn positive_last_piece_len() {
+        let entry = MetadataEntry {
+            size: Some(16 * 1024 + 100),
+            pieces: vec![None, None],
+            info: None,
+        };
+
+        assert_eq!(16 * 1024, entry.piece_len(0));
+        assert_eq!(100, entry.piece_len(1));
+    }
+}
@@ -1,19 +1,176 @@
+use std::collections::HashMap;
+use std::sync::mpsc::SyncSender;
 
+use bip_util::bt::InfoHash;
 use bip_util::send::TrySender;
+use rotor::Notifier;
 
-use selector::{ISelectorMessage, OSelectorMessage, SelectorSender};
-use protocol::OProtocolMessage;
+use disk::ODiskMessage;
+use message::standard::HaveMessage;
+use selector::{ISelectorMessage, OSelectorMessage, OSelectorMessageKind, SelectorSender, SelectorControl};
+use selector::machine;
+use selector::peers::SelectorPeers;
+use selector::share::ShareLimits;
+use protocol::{PeerIdentifier, OProtocolMessage, OProtocolMessageKind};
 use registration::LayerRegistration;
-use token::Token;
+use token::{Token, TokenGenerator};
 
-pub struct PieceSelector;
+mod announce;
+mod bitfields;
+mod choker;
+mod download;
+mod metadata;
+mod pex;
+mod piece_map;
+mod random;
+mod rarest;
+mod sequential;
+mod super_seed;
+mod torrent;
 
-impl<T> LayerRegistration<OSelectorMessage, T> for PieceSelector
-    where T: Into<ISelectorMessage> + Send
-{
+pub use selector::strategy::announce::AnnounceConfig;
+pub use selector::strategy::bitfields::PeerBitfields;
+pub use selector::strategy::choker::Choker;
+pub use selector::strategy::download::{PieceDownloader, PiecePicker};
+pub use selector::strategy::metadata::{MetadataSelector, MetadataDownloader};
+pub use selector::strategy::pex::PeerExchange;
+pub use selector::strategy::piece_map::{PieceMap, PieceMaps};
+pub use selector::strategy::rarest::{RarestFirstSelector, RarestFirstPicker};
+pub use selector::strategy::sequential::{SequentialPieceSelector, SequentialPicker};
+pub use selector::strategy::super_seed::{SuperSeedSelector, SuperSeeder};
+pub use selector::strategy::torrent::{TorrentPieces, block_requests};
+
+/// Trait for deciding which pieces to request from which peers.
+///
+/// Callbacks are invoked from the selector event loop; any messages the strategy
+/// wishes to send to peers should be sent through the provided `SelectorPeers`.
+#[allow(unused)]
+pub trait SelectionStrategy {
+    /// Called when a peer has connected for the given InfoHash.
+    fn peer_connect(&mut self, id: PeerIdentifier, hash: InfoHash, peers: &mut SelectorPeers) {}
+
+    /// Called when a peer has disconnected.
+    fn peer_disconnect(&mut self, id: PeerIdentifier, peers: &mut SelectorPeers) {}
+
+    /// Called when a peer has sent us a message.
+    fn peer_message(&mut self, id: PeerIdentifier, kind: OProtocolMessageKind, peers: &mut SelectorPeers) {}
+
+    /// Called when the disk manager has sent us a message.
+    ///
+    /// By default, every peer connected for a torrent is sent a have message for each newly verified piece.
+    fn disk_message(&mut self, msg: ODiskMessage, peers: &mut SelectorPeers) {
+        announce_verified(msg, peers);
+    }
+
+    /// Called when the user has paused the given torrent.
+    ///
+    /// Strategies should stop requesting blocks from, and choke, the peers of the torrent until it is resumed.
+    fn pause_torrent(&mut self, hash: InfoHash, peers: &mut SelectorPeers) {}
+
+    /// Called when the user has resumed the given torrent.
+    fn resume_torrent(&mut self, hash: InfoHash, peers: &mut SelectorPeers) {}
+
+    /// Called periodically (every 10 seconds) by the selector event loop.
+    fn tick(&mut self, peers: &mut SelectorPeers) {}
+}
+
+// ----------------------------------------------------------------------------//
+
+/// Selection layer that runs a `SelectionStrategy` on its own event loop.
+pub struct PieceSelector {
+    send: SyncSender<ISelectorMessage>,
+    noti: Notifier,
+    token_gen: TokenGenerator,
+    upstream: HashMap<Token, Box<TrySender<OSelectorMessage>>>,
+    share: ShareLimits,
+}
+
+impl PieceSelector {
+    /// Create a new PieceSelector, spawning an event loop for the given strategy.
+    pub fn new<S>(strategy: S) -> PieceSelector
+        where S: SelectionStrategy + Send + 'static
+    {
+        let share = ShareLimits::new();
+        let (send, noti) = machine::spawn_selector(strategy, share.clone());
+
+        PieceSelector {
+            send: send,
+            noti: noti,
+            token_gen: TokenGenerator::new(),
+            upstream: HashMap::new(),
+            share: share,
+        }
+    }
+
+    /// Handle for pausing and resuming torrents.
+    pub fn control(&self) -> SelectorControl {
+        SelectorControl::new(self.send.clone(), self.noti.clone())
+    }
+
+    /// Handle for querying how much we have uploaded, and limiting how much we seed, for each torrent.
+    pub fn share_limits(&self) -> ShareLimits {
+        self.share.clone()
+    }
+
+    /// Sender to register with the disk manager, so that pieces it verifies are announced to peers.
+    ///
+    /// Torrents should be added to the disk manager through the registration for this sender, since
+    /// only the client that added a torrent is told about the pieces verified for it.
+    pub fn disk_sender(&mut self) -> SelectorSender {
+        SelectorSender::new(self.token_gen.generate(), self.send.clone(), self.noti.clone())
+    }
+}
+
+/// Send a have message to every peer of the torrent if the disk manager verified one of its pieces.
+pub fn announce_verified(msg: ODiskMessage, peers: &mut SelectorPeers) {
+    if let ODiskMessage::FoundGoodPiece(hash, piece_index) = msg {
+        peers.send_torrent(hash, OSelectorMessageKind::PeerHave(HaveMessage::new(piece_index)));
+    }
+}
+
+impl LayerRegistration<OSelectorMessage, OProtocolMessage> for PieceSelector {
     type SS2 = SelectorSender;
 
     fn register(&mut self, send: Box<TrySender<OSelectorMessage>>) -> SelectorSender {
-        unimplemented!();
+        let token = self.token_gen.generate();
+
+        // Peers are given their own sender through PeerConnect, but we hold on to the
+        // registration sender so that the registering layer's channel stays alive.
+        self.upstream.insert(token, send);
+
+        SelectorSender::new(token, self.send.clone(), self.noti.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use bip_util::bt::InfoHash;
+
+    use disk::ODiskMessage;
+    use message::standard::HaveMessage;
+    use selector::OSelectorMessageKind;
+    use selector::peers::SelectorPeers;
+    use selector::testing::peer_id;
+
+    #[test]
+    fn positive_announce_verified_only_torrent_peers() {
+        let (hash_one, hash_two) = (InfoHash::from([1u8; 20]), InfoHash::from([2u8; 20]));
+        let (send_one, recv_one) = mpsc::channel();
+        let (send_two, recv_two) = mpsc::channel();
+
+        let mut peers = SelectorPeers::new();
+        peers.add_peer(peer_id(6881), hash_one, Box::new(send_one));
+        peers.add_peer(peer_id(6882), hash_two, Box::new(send_two));
+
+        super::announce_verified(ODiskMessage::FoundGoodPiece(hash_one, 3), &mut peers);
+        super::announce_verified(ODiskMessage::FoundBadPiece(hash_two, 4), &mut peers);
+
+        let msg = recv_one.try_recv().unwrap();
+        assert_eq!(peer_id(6881), msg.id());
+        assert_eq!(OSelectorMessageKind::PeerHave(HaveMessage::new(3)), msg.kind());
+        assert!(recv_one.try_recv().is_err());
+        assert!(recv_two.try_recv().is_err());
     }
 }
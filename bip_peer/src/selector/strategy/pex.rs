@@ -0,0 +1,185 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::time::{Duration, Instant};
+
+use bip_util::bt::InfoHash;
+
+use disk::ODiskMessage;
+use message::extension::{ExtendedHandshake, EXTENDED_HANDSHAKE_ID};
+use message::pex::{PexMessage, PEX_EXTENSION_NAME, MAX_PEX_PEERS};
+use protocol::{PeerIdentifier, OProtocolMessageKind};
+use selector::OSelectorMessageKind;
+use selector::peers::SelectorPeers;
+use selector::strategy::SelectionStrategy;
+
+// Extension id that we ask peers to send pex messages to us with.
+const LOCAL_PEX_ID: u8 = 1;
+
+// Minimum time between pex messages sent to a single peer, as recommended by BEP 11.
+const PEX_INTERVAL_SECS: u64 = 60;
+
+/// Strategy that exchanges peer lists with connected peers (BEP 11), deferring everything else to an inner strategy.
+///
+/// Peers that we learn about are sent through the receiver returned on creation, and can be fed back into the handshaker.
+pub struct PeerExchange<S> {
+    strategy: S,
+    peers: HashMap<PeerIdentifier, PeerState>,
+    discovered: Sender<SocketAddr>,
+}
+
+struct PeerState {
+    hash: InfoHash,
+    // Extension id that the peer wants pex messages sent with
+    remote_id: Option<u8>,
+    // Address that the peer accepts connections on, which differs from the one
+    // they connected to us from if they advertise a listen port
+    listen_addr: SocketAddr,
+    // Peers that we have told the peer about
+    advertised: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+}
+
+impl<S> PeerExchange<S>
+    where S: SelectionStrategy
+{
+    /// Create a new PeerExchange wrapping the given strategy.
+    ///
+    /// Returns the strategy along with a receiver for peers discovered through peer exchange.
+    pub fn new(strategy: S) -> (PeerExchange<S>, Receiver<SocketAddr>) {
+        let (send, recv) = mpsc::channel();
+
+        (PeerExchange {
+            strategy: strategy,
+            peers: HashMap::new(),
+            discovered: send,
+        },
+         recv)
+    }
+
+    /// Send a pex message to each peer that supports it, if enough time has passed since the last one.
+    fn send_pex(&mut self, peers: &mut SelectorPeers) {
+        let now = Instant::now();
+
+        // Snapshot the connected peers for each torrent, so we know what to tell everyone
+        let mut connected: HashMap<InfoHash, HashSet<SocketAddr>> = HashMap::new();
+        for (id, state) in self.peers.iter() {
+            connected.entry(state.hash).or_insert_with(HashSet::new).insert(state.listen_addr);
+        }
+
+        for (id, state) in self.peers.iter_mut() {
+            let remote_id = match state.remote_id {
+                Some(remote_id) => remote_id,
+                None => continue,
+            };
+            if state.last_sent.map_or(false, |last_sent| now.duration_since(last_sent) < Duration::from_secs(PEX_INTERVAL_SECS)) {
+                continue;
+            }
+
+            let torrent_peers = &connected[&state.hash];
+            let added: Vec<SocketAddr> = torrent_peers.iter()
+                .filter(|addr| **addr != state.listen_addr && !state.advertised.contains(addr))
+                .take(MAX_PEX_PEERS)
+                .cloned()
+                .collect();
+            let dropped: Vec<SocketAddr> = state.advertised
+                .iter()
+                .filter(|addr| !torrent_peers.contains(addr))
+                .take(MAX_PEX_PEERS)
+                .cloned()
+                .collect();
+
+            // Anything that didn't fit will be picked up in the next message
+            if added.is_empty() && dropped.is_empty() {
+                continue;
+            }
+            for addr in added.iter() {
+                state.advertised.insert(*addr);
+            }
+            for addr in dropped.iter() {
+                state.advertised.remove(addr);
+            }
+            state.last_sent = Some(now);
+
+            peers.send(*id, OSelectorMessageKind::PeerExtension(PexMessage::new(added, dropped).to_message(remote_id)));
+        }
+    }
+}
+
+impl<S> SelectionStrategy for PeerExchange<S>
+    where S: SelectionStrategy
+{
+    fn peer_connect(&mut self, id: PeerIdentifier, hash: InfoHash, peers: &mut SelectorPeers) {
+        self.peers.insert(id,
+                          PeerState {
+                              hash: hash,
+                              remote_id: None,
+                              listen_addr: id.addr(),
+                              advertised: HashSet::new(),
+                              last_sent: None,
+                          });
+
+        let mut handshake = ExtendedHandshake::new();
+        handshake.add_extension(PEX_EXTENSION_NAME, LOCAL_PEX_ID);
+        peers.send(id, OSelectorMessageKind::PeerExtension(handshake.to_message()));
+
+        self.strategy.peer_connect(id, hash, peers);
+    }
+
+    fn peer_disconnect(&mut self, id: PeerIdentifier, peers: &mut SelectorPeers) {
+        self.peers.remove(&id);
+
+        self.strategy.peer_disconnect(id, peers);
+    }
+
+    fn peer_message(&mut self, id: PeerIdentifier, kind: OProtocolMessageKind, peers: &mut SelectorPeers) {
+        let opt_pex = match kind {
+            OProtocolMessageKind::PeerExtension(ref ext_msg) if ext_msg.id() == EXTENDED_HANDSHAKE_ID => {
+                if let (Some(state), Some(handshake)) = (self.peers.get_mut(&id), ExtendedHandshake::from_message(ext_msg)) {
+                    state.remote_id = handshake.extension_id(PEX_EXTENSION_NAME);
+                    if let Some(port) = handshake.port() {
+                        state.listen_addr = SocketAddr::new(id.addr().ip(), port);
+                    }
+                }
+
+                None
+            }
+            OProtocolMessageKind::PeerExtension(ref ext_msg) if ext_msg.id() == LOCAL_PEX_ID => PexMessage::from_payload(ext_msg.payload()),
+            _ => None,
+        };
+
+        match opt_pex {
+            Some(pex) => {
+                let connected: HashSet<SocketAddr> = self.peers
+                    .iter()
+                    .flat_map(|(id, state)| vec![id.addr(), state.listen_addr])
+                    .collect();
+
+                for addr in pex.added().iter().filter(|addr| !connected.contains(addr)) {
+                    // Receiver may have been dropped, in which case the user doesn't care about new peers
+                    let _ = self.discovered.send(*addr);
+                }
+            }
+            // Pex messages are ours, but the inner strategy may want to know about the extended handshake
+            None => self.strategy.peer_message(id, kind, peers),
+        }
+    }
+
+    fn disk_message(&mut self, msg: ODiskMessage, peers: &mut SelectorPeers) {
+        self.strategy.disk_message(msg, peers);
+    }
+
+    fn pause_torrent(&mut self, hash: InfoHash, peers: &mut SelectorPeers) {
+        self.strategy.pause_torrent(hash, peers);
+    }
+
+    fn resume_torrent(&mut self, hash: InfoHash, peers: &mut SelectorPeers) {
+        self.strategy.resume_torrent(hash, peers);
+    }
+
+    fn tick(&mut self, peers: &mut SelectorPeers) {
+        self.send_pex(peers);
+
+        self.strategy.tick(peers);
+    }
+}
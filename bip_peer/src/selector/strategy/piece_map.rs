@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bip_util::bt::InfoHash;
+
+use message::standard::BitFieldMessage;
+
+/// Snapshot of the pieces we have, and how available each piece is in the swarm, for a single torrent.
+///
+/// Useful for drawing a piece map in a user interface.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PieceMap {
+    have: BitFieldMessage,
+    availability: Vec<u32>,
+}
+
+impl PieceMap {
+    /// Create a new PieceMap from the pieces we have and the availability of each piece.
+    pub fn new(have: BitFieldMessage, availability: Vec<u32>) -> PieceMap {
+        PieceMap {
+            have: have,
+            availability: availability,
+        }
+    }
+
+    /// Pieces that we have downloaded and verified.
+    pub fn have(&self) -> &BitFieldMessage {
+        &self.have
+    }
+
+    /// Number of connected peers that have each piece.
+    pub fn availability(&self) -> &[u32] {
+        &self.availability
+    }
+
+    /// Total number of pieces in the torrent.
+    pub fn num_pieces(&self) -> u32 {
+        self.availability.len() as u32
+    }
+}
+
+/// Handle for polling the latest PieceMap for each torrent.
+///
+/// Cloned handles share the same piece maps, so one can be handed to a user interface while the
+/// strategy that owns the original keeps them up to date.
+#[derive(Clone)]
+pub struct PieceMaps {
+    maps: Arc<Mutex<HashMap<InfoHash, PieceMap>>>,
+}
+
+impl PieceMaps {
+    /// Create a new, empty, PieceMaps.
+    pub fn new() -> PieceMaps {
+        PieceMaps { maps: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Latest PieceMap for the given torrent.
+    pub fn get(&self, hash: &InfoHash) -> Option<PieceMap> {
+        self.maps
+            .lock()
+            .expect("bip_peer: PieceMaps Lock Poisoned")
+            .get(hash)
+            .cloned()
+    }
+
+    /// Replace the PieceMap for the given torrent.
+    pub fn update(&self, hash: InfoHash, map: PieceMap) {
+        self.maps
+            .lock()
+            .expect("bip_peer: PieceMaps Lock Poisoned")
+            .insert(hash, map);
+    }
+}
@@ -0,0 +1,11 @@
+use rand::{self, Rng, SeedableRng, StdRng};
+
+/// Random number generator seeded from the thread local generator, for use in production.
+pub fn entropy_rng() -> StdRng {
+    rand::thread_rng().gen()
+}
+
+/// Random number generator that produces the same sequence for the same seed, for reproducible selection.
+pub fn seeded_rng(seed: usize) -> StdRng {
+    StdRng::from_seed(&[seed][..])
+}
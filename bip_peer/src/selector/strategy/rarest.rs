@@ -0,0 +1,150 @@
+use bip_metainfo::MetainfoFile;
+use bip_util::bt::InfoHash;
+use bip_util::send::TrySender;
+use rand::{Rng, StdRng};
+
+use protocol::OProtocolMessage;
+use registration::LayerRegistration;
+use selector::{OSelectorMessage, SelectorSender, SelectorControl, ShareLimits};
+use selector::strategy::PieceSelector;
+use selector::strategy::download::{PieceDownloader, PiecePicker};
+use selector::strategy::piece_map::PieceMaps;
+use selector::strategy::random;
+
+/// Selection layer that requests the rarest pieces in the swarm first.
+pub struct RarestFirstSelector {
+    selector: PieceSelector,
+    piece_maps: PieceMaps,
+}
+
+impl RarestFirstSelector {
+    /// Create a new RarestFirstSelector for the given torrents.
+    pub fn new<'a, I>(torrents: I) -> RarestFirstSelector
+        where I: IntoIterator<Item = &'a MetainfoFile>
+    {
+        let downloader = PieceDownloader::new(torrents, RarestFirstPicker::new());
+
+        RarestFirstSelector::with_downloader(downloader)
+    }
+
+    /// Create a new RarestFirstSelector for the given torrents, where all random choices are derived from the seed.
+    ///
+    /// Selectors created with the same seed will make the same choices when given the same messages.
+    pub fn with_seed<'a, I>(torrents: I, seed: usize) -> RarestFirstSelector
+        where I: IntoIterator<Item = &'a MetainfoFile>
+    {
+        let mut downloader = PieceDownloader::new(torrents, RarestFirstPicker::with_seed(seed));
+        downloader.set_seed(seed);
+
+        RarestFirstSelector::with_downloader(downloader)
+    }
+
+    fn with_downloader(downloader: PieceDownloader<RarestFirstPicker>) -> RarestFirstSelector {
+        let piece_maps = downloader.piece_maps();
+
+        RarestFirstSelector {
+            selector: PieceSelector::new(downloader),
+            piece_maps: piece_maps,
+        }
+    }
+
+    /// Handle for polling the PieceMap of each torrent.
+    pub fn piece_maps(&self) -> PieceMaps {
+        self.piece_maps.clone()
+    }
+
+    /// Handle for pausing and resuming torrents.
+    pub fn control(&self) -> SelectorControl {
+        self.selector.control()
+    }
+
+    /// Handle for querying how much we have uploaded, and limiting how much we seed, for each torrent.
+    pub fn share_limits(&self) -> ShareLimits {
+        self.selector.share_limits()
+    }
+
+    /// Sender to register with the disk manager, so that pieces it verifies are announced to peers.
+    pub fn disk_sender(&mut self) -> SelectorSender {
+        self.selector.disk_sender()
+    }
+}
+
+impl LayerRegistration<OSelectorMessage, OProtocolMessage> for RarestFirstSelector {
+    type SS2 = SelectorSender;
+
+    fn register(&mut self, send: Box<TrySender<OSelectorMessage>>) -> SelectorSender {
+        self.selector.register(send)
+    }
+}
+
+// ----------------------------------------------------------------------------//
+
+/// Picks the piece with the lowest availability across peers, breaking ties randomly.
+pub struct RarestFirstPicker {
+    rng: StdRng,
+}
+
+impl RarestFirstPicker {
+    /// Create a new RarestFirstPicker that breaks ties using a randomly seeded generator.
+    pub fn new() -> RarestFirstPicker {
+        RarestFirstPicker { rng: random::entropy_rng() }
+    }
+
+    /// Create a new RarestFirstPicker that breaks ties using a generator with the given seed.
+    pub fn with_seed(seed: usize) -> RarestFirstPicker {
+        RarestFirstPicker { rng: random::seeded_rng(seed) }
+    }
+}
+
+impl PiecePicker for RarestFirstPicker {
+    fn pick<I>(&mut self, _hash: InfoHash, candidates: I, availability: &[u32]) -> Option<u32>
+        where I: Iterator<Item = u32>
+    {
+        pick_rarest(candidates, availability, &mut self.rng)
+    }
+}
+
+/// Pick the candidate with the lowest availability, breaking ties randomly.
+pub fn pick_rarest<I, R>(candidates: I, availability: &[u32], rng: &mut R) -> Option<u32>
+    where I: Iterator<Item = u32>,
+          R: Rng
+{
+    let mut rarest = Vec::new();
+    let mut rarest_count = u32::max_value();
+
+    for piece_index in candidates {
+        let count = availability[piece_index as usize];
+
+        if count < rarest_count {
+            rarest.clear();
+            rarest_count = count;
+        }
+        if count == rarest_count {
+            rarest.push(piece_index);
+        }
+    }
+
+    if rarest.is_empty() {
+        None
+    } else {
+        let choice = rng.gen_range(0, rarest.len());
+
+        Some(rarest[choice])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use selector::strategy::random;
+
+    #[test]
+    fn positive_pick_rarest_same_seed_same_choices() {
+        let availability = vec![1; 100];
+        let (mut rng_one, mut rng_two) = (random::seeded_rng(5), random::seeded_rng(5));
+
+        for _ in 0..10 {
+            assert_eq!(super::pick_rarest(0..100, &availability, &mut rng_one),
+                       super::pick_rarest(0..100, &availability, &mut rng_two));
+        }
+    }
+}
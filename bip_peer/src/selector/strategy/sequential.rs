@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bip_metainfo::MetainfoFile;
+use bip_util::bt::InfoHash;
+use bip_util::send::TrySender;
+
+use protocol::OProtocolMessage;
+use registration::LayerRegistration;
+use selector::{OSelectorMessage, SelectorSender, SelectorControl, ShareLimits};
+use selector::strategy::PieceSelector;
+use selector::strategy::download::{PieceDownloader, PiecePicker};
+use selector::strategy::piece_map::PieceMaps;
+
+/// Selection layer that requests pieces in order, starting from a read head.
+///
+/// Useful for streaming, where pieces closest to the current playback position matter most.
+pub struct SequentialPieceSelector {
+    selector: PieceSelector,
+    read_heads: Arc<Mutex<HashMap<InfoHash, u32>>>,
+    piece_maps: PieceMaps,
+}
+
+impl SequentialPieceSelector {
+    /// Create a new SequentialPieceSelector for the given torrents.
+    pub fn new<'a, I>(torrents: I) -> SequentialPieceSelector
+        where I: IntoIterator<Item = &'a MetainfoFile>
+    {
+        let read_heads = Arc::new(Mutex::new(HashMap::new()));
+        let picker = SequentialPicker { read_heads: read_heads.clone() };
+
+        let downloader = PieceDownloader::new(torrents, picker);
+        let piece_maps = downloader.piece_maps();
+
+        SequentialPieceSelector {
+            selector: PieceSelector::new(downloader),
+            read_heads: read_heads,
+            piece_maps: piece_maps,
+        }
+    }
+
+    /// Handle for polling the PieceMap of each torrent.
+    pub fn piece_maps(&self) -> PieceMaps {
+        self.piece_maps.clone()
+    }
+
+    /// Handle for pausing and resuming torrents.
+    pub fn control(&self) -> SelectorControl {
+        self.selector.control()
+    }
+
+    /// Handle for querying how much we have uploaded, and limiting how much we seed, for each torrent.
+    pub fn share_limits(&self) -> ShareLimits {
+        self.selector.share_limits()
+    }
+
+    /// Sender to register with the disk manager, so that pieces it verifies are announced to peers.
+    pub fn disk_sender(&mut self) -> SelectorSender {
+        self.selector.disk_sender()
+    }
+
+    /// Set the piece that reading is currently taking place at for the given torrent.
+    ///
+    /// Pieces at or after the read head will be requested before any pieces that come before it.
+    pub fn set_read_head(&self, hash: InfoHash, piece_index: u32) {
+        self.read_heads
+            .lock()
+            .expect("bip_peer: SequentialPieceSelector Read Heads Lock Poisoned")
+            .insert(hash, piece_index);
+    }
+}
+
+impl LayerRegistration<OSelectorMessage, OProtocolMessage> for SequentialPieceSelector {
+    type SS2 = SelectorSender;
+
+    fn register(&mut self, send: Box<TrySender<OSelectorMessage>>) -> SelectorSender {
+        self.selector.register(send)
+    }
+}
+
+// ----------------------------------------------------------------------------//
+
+/// Picks the lowest index piece at or after the read head, wrapping around if there are none.
+pub struct SequentialPicker {
+    read_heads: Arc<Mutex<HashMap<InfoHash, u32>>>,
+}
+
+impl PiecePicker for SequentialPicker {
+    fn pick<I>(&mut self, hash: InfoHash, mut candidates: I, _availability: &[u32]) -> Option<u32>
+        where I: Iterator<Item = u32>
+    {
+        let read_head = self.read_heads
+            .lock()
+            .expect("bip_peer: SequentialPicker Read Heads Lock Poisoned")
+            .get(&hash)
+            .map_or(0, |index| *index);
+
+        // Candidates are in increasing order, so the first one we see is the wrap around piece
+        let first = candidates.next();
+        if first.map_or(false, |index| index >= read_head) {
+            return first;
+        }
+
+        candidates.find(|index| *index >= read_head).or(first)
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::{HashMap, HashSet};
+
+use bip_metainfo::MetainfoFile;
+use bip_util::bt::InfoHash;
+use bip_util::send::TrySender;
+use rand::{Rng, StdRng};
+
+use disk::ODiskMessage;
+use message::standard::{HaveMessage, BitFieldMessage, PieceMessage};
+use protocol::{PeerIdentifier, OProtocolMessage, OProtocolMessageKind};
+use registration::LayerRegistration;
+use selector::{OSelectorMessage, OSelectorMessageKind, SelectorSender, SelectorControl, ShareLimits};
+use selector::peers::SelectorPeers;
+use selector::strategy::{PieceSelector, SelectionStrategy};
+use selector::strategy::bitfields::PeerBitfields;
+use selector::strategy::choker::Choker;
+use selector::strategy::random;
+use selector::strategy::rarest;
+
+/// Selection layer for the initial seeder of a torrent, which reveals pieces to peers one at a time.
+pub struct SuperSeedSelector {
+    selector: PieceSelector,
+}
+
+impl SuperSeedSelector {
+    /// Create a new SuperSeedSelector for the given torrents, all of which we must have in full.
+    pub fn new<'a, I>(torrents: I) -> SuperSeedSelector
+        where I: IntoIterator<Item = &'a MetainfoFile>
+    {
+        SuperSeedSelector { selector: PieceSelector::new(SuperSeeder::new(torrents)) }
+    }
+
+    /// Handle for pausing and resuming torrents.
+    pub fn control(&self) -> SelectorControl {
+        self.selector.control()
+    }
+
+    /// Handle for querying how much we have uploaded, and limiting how much we seed, for each torrent.
+    pub fn share_limits(&self) -> ShareLimits {
+        self.selector.share_limits()
+    }
+}
+
+impl LayerRegistration<OSelectorMessage, OProtocolMessage> for SuperSeedSelector {
+    type SS2 = SelectorSender;
+
+    fn register(&mut self, send: Box<TrySender<OSelectorMessage>>) -> SelectorSender {
+        self.selector.register(send)
+    }
+}
+
+// ----------------------------------------------------------------------------//
+
+/// Strategy that seeds torrents while revealing as little of them as possible (super seeding).
+///
+/// Peers are sent an empty bitfield, and are then gifted a single piece at a time with a have
+/// message, preferring the rarest pieces that haven't been gifted to anyone else. A peer is only
+/// gifted its next piece once some other peer announces that it has the current one, meaning
+/// the piece was shared onward rather than just downloaded from us.
+///
+/// Only pieces that have been gifted to a peer are uploaded to it.
+pub struct SuperSeeder {
+    choker: Choker,
+    torrents: HashMap<InfoHash, PeerBitfields>,
+    peers: HashMap<PeerIdentifier, SeedPeer>,
+    paused: HashSet<InfoHash>,
+    rng: StdRng,
+}
+
+struct SeedPeer {
+    hash: InfoHash,
+    // Piece that we are waiting to see shared onward before gifting the peer another
+    gifted: Option<u32>,
+    // Every piece that we have announced to the peer
+    announced: HashSet<u32>,
+}
+
+impl SuperSeeder {
+    /// Create a new SuperSeeder for the given torrents.
+    pub fn new<'a, I>(torrents: I) -> SuperSeeder
+        where I: IntoIterator<Item = &'a MetainfoFile>
+    {
+        let torrents = torrents.into_iter()
+            .map(|metainfo| (metainfo.info_hash(), PeerBitfields::new(metainfo.info().pieces().count() as u32)))
+            .collect();
+
+        SuperSeeder {
+            choker: Choker::new(),
+            torrents: torrents,
+            peers: HashMap::new(),
+            paused: HashSet::new(),
+            rng: random::entropy_rng(),
+        }
+    }
+
+    /// Sets the choker used to decide which peers we upload to.
+    pub fn set_choker(&mut self, choker: Choker) {
+        self.choker = choker;
+    }
+
+    /// Sets the seed used for the random choices made by us and our choker.
+    pub fn set_seed(&mut self, seed: usize) {
+        self.rng = random::seeded_rng(seed);
+        self.choker.set_seed(seed);
+    }
+
+    /// Gift the peer the rarest piece it doesn't have, announcing it with a have message.
+    ///
+    /// Peers of paused torrents are not gifted anything, they will be gifted on the first tick after resuming.
+    fn gift_piece(&mut self, id: PeerIdentifier, peers: &mut SelectorPeers) {
+        let hash = match self.peers.get(&id) {
+            Some(peer) if !self.paused.contains(&peer.hash) => peer.hash,
+            _ => return,
+        };
+        let gifted: HashSet<u32> = self.peers
+            .iter()
+            .filter(|&(other_id, other_peer)| *other_id != id && other_peer.hash == hash)
+            .filter_map(|(_, other_peer)| other_peer.gifted)
+            .collect();
+
+        let opt_piece = choose_gift(&self.torrents[&hash], id, &gifted, &mut self.rng);
+        let peer = self.peers.get_mut(&id).expect("bip_peer: SuperSeeder Failed To Find Peer");
+
+        peer.gifted = opt_piece;
+        if let Some(piece_index) = opt_piece {
+            peer.announced.insert(piece_index);
+
+            peers.send(id, OSelectorMessageKind::PeerHave(HaveMessage::new(piece_index)));
+        }
+    }
+
+    /// Gift the peer a different piece if it turns out it already had the one we gifted it.
+    fn check_gift(&mut self, id: PeerIdentifier, peers: &mut SelectorPeers) {
+        let has_gift = match self.peers.get(&id) {
+            Some(peer) => peer.gifted.map_or(false, |piece_index| self.torrents[&peer.hash].has_piece(id, piece_index)),
+            None => return,
+        };
+
+        if has_gift {
+            self.gift_piece(id, peers);
+        }
+    }
+
+    /// Gift the next piece to any peer whose current gift the given peer now has.
+    fn piece_shared(&mut self, id: PeerIdentifier, piece_index: u32, peers: &mut SelectorPeers) {
+        let hash = match self.peers.get(&id) {
+            Some(peer) => peer.hash,
+            None => return,
+        };
+        let gifters: Vec<PeerIdentifier> = self.peers
+            .iter()
+            .filter(|&(other_id, other_peer)| *other_id != id && other_peer.hash == hash && other_peer.gifted == Some(piece_index))
+            .map(|(other_id, _)| *other_id)
+            .collect();
+
+        for gifter in gifters {
+            self.gift_piece(gifter, peers);
+        }
+    }
+}
+
+impl SelectionStrategy for SuperSeeder {
+    fn peer_connect(&mut self, id: PeerIdentifier, hash: InfoHash, peers: &mut SelectorPeers) {
+        let num_pieces = match self.torrents.get_mut(&hash) {
+            Some(bitfields) => {
+                bitfields.add_peer(id);
+
+                bitfields.num_pieces()
+            }
+            None => {
+                peers.send(id, OSelectorMessageKind::PeerDisconnect);
+                return;
+            }
+        };
+
+        self.peers.insert(id,
+                          SeedPeer {
+                              hash: hash,
+                              gifted: None,
+                              announced: HashSet::new(),
+                          });
+        self.choker.add_peer(id);
+        self.choker.set_seeding(id, true);
+        self.choker.set_paused(id, self.paused.contains(&hash), peers);
+
+        peers.send(id, OSelectorMessageKind::PeerBitField(BitFieldMessage::new(num_pieces)));
+        self.gift_piece(id, peers);
+    }
+
+    fn peer_disconnect(&mut self, id: PeerIdentifier, _peers: &mut SelectorPeers) {
+        if let Some(peer) = self.peers.remove(&id) {
+            self.torrents.get_mut(&peer.hash).map(|bitfields| bitfields.remove_peer(id));
+        }
+        self.choker.remove_peer(id);
+    }
+
+    fn peer_message(&mut self, id: PeerIdentifier, kind: OProtocolMessageKind, peers: &mut SelectorPeers) {
+        let hash = match self.peers.get(&id) {
+            Some(peer) => peer.hash,
+            None => return,
+        };
+
+        match kind {
+            OProtocolMessageKind::PeerHave(have) => {
+                let is_new = self.torrents.get_mut(&hash).map_or(false, |bitfields| bitfields.peer_have(id, have.piece_index()));
+
+                if is_new {
+                    self.piece_shared(id, have.piece_index(), peers);
+                }
+            }
+            OProtocolMessageKind::PeerBitField(bitfield) => {
+                self.torrents.get_mut(&hash).map(|bitfields| bitfields.peer_bitfield(id, &bitfield));
+                self.check_gift(id, peers);
+            }
+            OProtocolMessageKind::PeerHaveAll => {
+                self.torrents.get_mut(&hash).map(|bitfields| bitfields.peer_have_all(id));
+                self.check_gift(id, peers);
+            }
+            OProtocolMessageKind::PeerInterested => {
+                self.choker.set_interested(id, true);
+            }
+            OProtocolMessageKind::PeerUnInterested => {
+                self.choker.set_interested(id, false);
+            }
+            OProtocolMessageKind::PeerPieceSent(request) => {
+                self.choker.add_uploaded(id, request.block_length());
+            }
+            OProtocolMessageKind::PeerRequest(request) => {
+                let announced = self.peers[&id].announced.contains(&request.piece_index());
+
+                if announced && !self.choker.is_choked(id) {
+                    let piece = PieceMessage::new(request.piece_index(), request.block_offset(), request.block_length());
+
+                    peers.send(id, OSelectorMessageKind::PeerPiece(piece));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn disk_message(&mut self, _msg: ODiskMessage, _peers: &mut SelectorPeers) {
+        // Pieces are only ever revealed as gifts, so verified pieces are not announced
+    }
+
+    fn pause_torrent(&mut self, hash: InfoHash, peers: &mut SelectorPeers) {
+        self.paused.insert(hash);
+
+        for (id, _) in self.peers.iter().filter(|&(_, peer)| peer.hash == hash) {
+            self.choker.set_paused(*id, true, peers);
+        }
+    }
+
+    fn resume_torrent(&mut self, hash: InfoHash, peers: &mut SelectorPeers) {
+        self.paused.remove(&hash);
+
+        for (id, _) in self.peers.iter().filter(|&(_, peer)| peer.hash == hash) {
+            self.choker.set_paused(*id, false, peers);
+        }
+    }
+
+    fn tick(&mut self, peers: &mut SelectorPeers) {
+        self.choker.run_round(peers);
+
+        // Peers that had every piece we could gift them may need one now that other peers have come and gone
+        let ungifted: Vec<PeerIdentifier> = self.peers
+            .iter()
+            .filter(|&(_, peer)| peer.gifted.is_none())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ungifted {
+            self.gift_piece(id, peers);
+        }
+    }
+}
+
+/// Choose the rarest piece the peer doesn't have, preferring pieces that are not gifted to other peers.
+fn choose_gift<R>(bitfields: &PeerBitfields, id: PeerIdentifier, gifted: &HashSet<u32>, rng: &mut R) -> Option<u32>
+    where R: Rng
+{
+    let missing: Vec<u32> = (0..bitfields.num_pieces()).filter(|index| !bitfields.has_piece(id, *index)).collect();
+
+    rarest::pick_rarest(missing.iter().cloned().filter(|index| !gifted.contains(index)),
+                        bitfields.availability(),
+                        rng)
+        .or_else(|| rarest::pick_rarest(missing.into_iter(), bitfields.availability(), rng))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use selector::strategy::bitfields::PeerBitfields;
+    use selector::strategy::random;
+    use selector::testing::peer_id;
+    use super::choose_gift;
+
+    #[test]
+    fn positive_choose_gift_prefers_ungifted_rarest() {
+        let mut bitfields = PeerBitfields::new(3);
+        let (id_one, id_two) = (peer_id(6881), peer_id(6882));
+        bitfields.add_peer(id_one);
+        bitfields.add_peer(id_two);
+
+        bitfields.peer_have(id_two, 0);
+
+        let mut gifted = HashSet::new();
+        gifted.insert(2);
+        let mut rng = random::seeded_rng(0);
+
+        // Pieces 1 and 2 are the rarest, but piece 2 is already gifted to another peer
+        assert_eq!(Some(1), choose_gift(&bitfields, id_one, &gifted, &mut rng));
+        // Once every piece the peer is missing is gifted, fall back to any of them
+        bitfields.peer_have(id_one, 0);
+        bitfields.peer_have(id_one, 1);
+        assert_eq!(Some(2), choose_gift(&bitfields, id_one, &gifted, &mut rng));
+    }
+}
@@ -0,0 +1,200 @@
+use std::cmp;
+
+use bip_metainfo::InfoDictionary;
+
+use disk::{DEFAULT_BLOCK_SIZE, FilePriority, FilePriorities};
+use message::standard::RequestMessage;
+
+/// Piece layout and download progress for a single torrent.
+pub struct TorrentPieces {
+    piece_length: u64,
+    total_length: u64,
+    block_size: usize,
+    good: Vec<bool>,
+    priorities: Vec<FilePriority>,
+}
+
+impl TorrentPieces {
+    /// Create a new TorrentPieces with no good pieces.
+    pub fn new(info_dict: &InfoDictionary) -> TorrentPieces {
+        let total_pieces = info_dict.pieces().count();
+
+        TorrentPieces {
+            piece_length: info_dict.piece_length() as u64,
+            total_length: info_dict.files().map(|file| file.length() as u64).sum(),
+            block_size: DEFAULT_BLOCK_SIZE,
+            good: vec![false; total_pieces],
+            priorities: vec![FilePriority::Normal; total_pieces],
+        }
+    }
+
+    /// Sets the size of the blocks that pieces are carved into when requesting them.
+    ///
+    /// Panics if block_size is zero.
+    pub fn set_block_size(&mut self, block_size: usize) {
+        if block_size == 0 {
+            panic!("bip_peer: TorrentPieces Block Size Must Be Non Zero")
+        }
+
+        self.block_size = block_size;
+    }
+
+    /// Gets the block size.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Update the priority of each piece from the given file priorities.
+    pub fn set_file_priorities(&mut self, info_dict: &InfoDictionary, priorities: &FilePriorities) {
+        for (piece_index, priority) in self.priorities.iter_mut().enumerate() {
+            *priority = priorities.piece_priority(info_dict, piece_index as u32);
+        }
+    }
+
+    /// Priority of the given piece.
+    pub fn priority(&self, piece_index: u32) -> FilePriority {
+        self.priorities.get(piece_index as usize).map_or(FilePriority::Skip, |priority| *priority)
+    }
+
+    /// Whether or not we still need to download the given piece.
+    pub fn is_needed(&self, piece_index: u32) -> bool {
+        !self.is_good(piece_index) && self.priority(piece_index) != FilePriority::Skip
+    }
+
+    /// Total number of pieces in the torrent.
+    pub fn num_pieces(&self) -> u32 {
+        self.good.len() as u32
+    }
+
+    /// Whether or not we have verified the given piece.
+    pub fn is_good(&self, piece_index: u32) -> bool {
+        self.good.get(piece_index as usize).map_or(false, |good| *good)
+    }
+
+    /// Mark the given piece as verified.
+    pub fn set_good(&mut self, piece_index: u32) {
+        if let Some(good) = self.good.get_mut(piece_index as usize) {
+            *good = true;
+        }
+    }
+
+    /// Mark the given piece as needing to be downloaded again.
+    pub fn set_bad(&mut self, piece_index: u32) {
+        if let Some(good) = self.good.get_mut(piece_index as usize) {
+            *good = false;
+        }
+    }
+
+    /// Whether or not every piece that isn't skipped has been verified.
+    pub fn is_complete(&self) -> bool {
+        (0..self.num_pieces()).all(|index| !self.is_needed(index))
+    }
+
+    /// Size of the given piece, in bytes.
+    pub fn piece_size(&self, piece_index: u32) -> usize {
+        piece_size(piece_index, self.piece_length, self.total_length)
+    }
+
+    /// Number of blocks that make up the given piece.
+    pub fn num_blocks(&self, piece_index: u32) -> usize {
+        let piece_size = self.piece_size(piece_index);
+
+        (piece_size + self.block_size - 1) / self.block_size
+    }
+
+    /// Requests for every block in the given piece, in block order.
+    pub fn piece_requests(&self, piece_index: u32) -> Vec<RequestMessage> {
+        block_requests(piece_index, self.piece_length, self.total_length, self.block_size)
+    }
+}
+
+/// Size of the given piece, in bytes, for a torrent of the given total length.
+///
+/// Every piece but the last is piece_length bytes, pieces past the end of the torrent are zero bytes.
+fn piece_size(piece_index: u32, piece_length: u64, total_length: u64) -> usize {
+    let piece_start = piece_index as u64 * piece_length;
+    let piece_end = cmp::min(piece_start + piece_length, total_length);
+
+    piece_end.saturating_sub(piece_start) as usize
+}
+
+/// Requests for every block in the given piece, in block order, for a torrent of the given total length.
+///
+/// Blocks are block_size bytes and aligned to block_size within the piece, only the last block of the last
+/// piece may be shorter. Pieces past the end of the torrent have no blocks.
+///
+/// Panics if block_size is zero.
+pub fn block_requests(piece_index: u32, piece_length: u64, total_length: u64, block_size: usize) -> Vec<RequestMessage> {
+    if block_size == 0 {
+        panic!("bip_peer: Block Requests Block Size Must Be Non Zero")
+    }
+    let piece_size = piece_size(piece_index, piece_length, total_length);
+    let mut requests = Vec::new();
+
+    let mut block_offset = 0;
+    while block_offset < piece_size {
+        let block_length = cmp::min(block_size, piece_size - block_offset);
+        requests.push(RequestMessage::new(piece_index, block_offset as u32, block_length));
+
+        block_offset += block_length;
+    }
+
+    requests
+}
+
+#[cfg(test)]
+mod tests {
+    use message::standard::RequestMessage;
+    use super::block_requests;
+
+    const BLOCK_SIZE: usize = 16 * 1024;
+
+    #[test]
+    fn positive_block_requests_whole_piece() {
+        let requests = block_requests(0, 4 * BLOCK_SIZE as u64, 8 * BLOCK_SIZE as u64, BLOCK_SIZE);
+
+        let expected: Vec<RequestMessage> = (0..4).map(|block| RequestMessage::new(0, (block * BLOCK_SIZE) as u32, BLOCK_SIZE)).collect();
+        assert_eq!(expected, requests);
+    }
+
+    #[test]
+    fn positive_block_requests_short_last_block_of_last_piece() {
+        // Last piece holds one full block, followed by a 100 byte block
+        let total_length = (4 * BLOCK_SIZE + BLOCK_SIZE + 100) as u64;
+        let requests = block_requests(1, 4 * BLOCK_SIZE as u64, total_length, BLOCK_SIZE);
+
+        assert_eq!(vec![RequestMessage::new(1, 0, BLOCK_SIZE), RequestMessage::new(1, BLOCK_SIZE as u32, 100)], requests);
+    }
+
+    #[test]
+    fn positive_block_requests_last_piece_exact_multiple_of_block_size() {
+        let requests = block_requests(1, 4 * BLOCK_SIZE as u64, 6 * BLOCK_SIZE as u64, BLOCK_SIZE);
+
+        assert_eq!(vec![RequestMessage::new(1, 0, BLOCK_SIZE), RequestMessage::new(1, BLOCK_SIZE as u32, BLOCK_SIZE)], requests);
+    }
+
+    #[test]
+    fn positive_block_requests_piece_smaller_than_block() {
+        let requests = block_requests(0, 4 * BLOCK_SIZE as u64, 10, BLOCK_SIZE);
+
+        assert_eq!(vec![RequestMessage::new(0, 0, 10)], requests);
+    }
+
+    #[test]
+    fn positive_block_requests_piece_length_not_multiple_of_block_size() {
+        let requests = block_requests(2, (BLOCK_SIZE + 5) as u64, 10 * BLOCK_SIZE as u64, BLOCK_SIZE);
+
+        assert_eq!(vec![RequestMessage::new(2, 0, BLOCK_SIZE), RequestMessage::new(2, BLOCK_SIZE as u32, 5)], requests);
+    }
+
+    #[test]
+    fn negative_block_requests_piece_past_end() {
+        assert!(block_requests(2, 4 * BLOCK_SIZE as u64, 8 * BLOCK_SIZE as u64, BLOCK_SIZE).is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn negative_block_requests_zero_block_size() {
+        block_requests(0, 4 * BLOCK_SIZE as u64, 8 * BLOCK_SIZE as u64, 0);
+    }
+}
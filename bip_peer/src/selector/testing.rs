@@ -0,0 +1,14 @@
+//! Helpers shared by the tests of the selection layer.
+
+use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
+
+use bip_util::bt::PeerId;
+
+use protocol::PeerIdentifier;
+
+/// Identifier for a local peer connected from the given port.
+pub fn peer_id(port: u16) -> PeerIdentifier {
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port));
+
+    PeerIdentifier::new(addr, PeerId::from([0u8; 20]))
+}
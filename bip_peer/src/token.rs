@@ -1,5 +1,7 @@
 #![allow(unused)]
 
+use std::collections::HashSet;
+
 use bip_util::trans::{TransactionIds, SequentialIds};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -20,3 +22,73 @@ impl TokenGenerator {
         Token { id: self.generator.generate() }
     }
 }
+
+/// Generates tokens, recycling those that have been released back to the pool.
+///
+/// A token is never handed out while it is still outstanding, so no two
+/// in flight operations will ever share the same token.
+pub struct TokenPool {
+    generator: TokenGenerator,
+    free: Vec<Token>,
+    outstanding: HashSet<Token>
+}
+
+impl TokenPool {
+    pub fn new() -> TokenPool {
+        TokenPool { generator: TokenGenerator::new(), free: Vec::new(), outstanding: HashSet::new() }
+    }
+
+    /// Take a token out of the pool, generating a new one if none are free.
+    pub fn acquire(&mut self) -> Token {
+        let token = self.free.pop().unwrap_or_else(|| self.generator.generate());
+
+        if !self.outstanding.insert(token) {
+            panic!("bip_peer: TokenPool Handed Out An Outstanding Token")
+        }
+
+        token
+    }
+
+    /// Return the token to the pool so that it can be handed out again.
+    pub fn release(&mut self, token: Token) {
+        if !self.outstanding.remove(&token) {
+            panic!("bip_peer: TokenPool Released A Token That Was Not Outstanding")
+        }
+
+        self.free.push(token);
+    }
+
+    /// Number of tokens that have been acquired but not yet released.
+    pub fn num_outstanding(&self) -> usize {
+        self.outstanding.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenPool;
+
+    #[test]
+    fn positive_release_recycles_token() {
+        let mut pool = TokenPool::new();
+
+        let token = pool.acquire();
+        let other_token = pool.acquire();
+        assert!(token != other_token);
+
+        pool.release(token);
+        assert_eq!(1, pool.num_outstanding());
+        assert_eq!(token, pool.acquire());
+        assert_eq!(2, pool.num_outstanding());
+    }
+
+    #[test]
+    #[should_panic]
+    fn negative_release_unknown_token() {
+        let mut pool = TokenPool::new();
+
+        let token = pool.acquire();
+        pool.release(token);
+        pool.release(token);
+    }
+}
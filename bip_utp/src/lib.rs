@@ -1,3 +1,16 @@
-#[test]
-fn it_works() {
-}
+//! Implementation of the uTorrent Transport Protocol (BEP 29).
+//!
+//! `UtpStream` and `UtpListener` are blocking, and mirror the api of the standard library's `TcpStream`
+//! and `TcpListener`, so they are meant for code running each connection on its own thread; they do not
+//! integrate with an event loop. The packet format they are built on is exposed in `packet`.
+
+// Keeping to the style of the rest of bip-rs (try!, explicit field names, and no helpers newer than
+// our minimum compiler), which newer toolchains lint against
+#![allow(deprecated)]
+#![allow(clippy::redundant_field_names, clippy::manual_clamp, clippy::manual_abs_diff, clippy::unnecessary_map_or)]
+
+mod mux;
+pub mod packet;
+mod socket;
+
+pub use socket::{UtpStream, UtpListener};
@@ -0,0 +1,132 @@
+//! Demultiplexing of packets from a single UDP socket to the connections sharing it.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use packet::{Packet, PacketType};
+
+// Largest datagram that we will receive.
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+// How often the reader thread checks whether every handle to the socket was dropped.
+const CLOSED_POLL_MILLIS: u64 = 100;
+
+/// Connection requests from peers, along with the address they came from.
+pub type Incoming = Receiver<(SocketAddr, Packet)>;
+
+struct Shared {
+    routes:   Mutex<HashMap<(SocketAddr, u16), Sender<Packet>>>,
+    incoming: Option<Mutex<Sender<(SocketAddr, Packet)>>>,
+    closed:   AtomicBool
+}
+
+/// UDP socket shared by any number of uTP connections.
+///
+/// Packets are read on a background thread, which routes them to connections based on the address
+/// they came from, and the connection id they were sent with.
+pub struct Multiplexer {
+    socket: UdpSocket,
+    shared: Arc<Shared>
+}
+
+impl Multiplexer {
+    /// Bind a new `Multiplexer` to the given address.
+    ///
+    /// If accepting connections, connection requests from peers are sent to the returned `Incoming`.
+    pub fn bind(addr: SocketAddr, accept: bool) -> io::Result<(Arc<Multiplexer>, Option<Incoming>)> {
+        let socket = try!(UdpSocket::bind(addr));
+        let thread_socket = try!(socket.try_clone());
+        try!(thread_socket.set_read_timeout(Some(Duration::from_millis(CLOSED_POLL_MILLIS))));
+
+        let (opt_incoming_send, opt_incoming_recv) = if accept {
+            let (send, recv) = mpsc::channel();
+
+            (Some(Mutex::new(send)), Some(recv))
+        } else {
+            (None, None)
+        };
+        let shared = Arc::new(Shared{ routes: Mutex::new(HashMap::new()), incoming: opt_incoming_send, closed: AtomicBool::new(false) });
+
+        let thread_shared = shared.clone();
+        thread::spawn(move || run_reader(&thread_socket, &thread_shared));
+
+        Ok((Arc::new(Multiplexer{ socket: socket, shared: shared }), opt_incoming_recv))
+    }
+
+    /// Local address of the underlying socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Send the packet to the given address.
+    pub fn send_to(&self, packet: &Packet, addr: SocketAddr) -> io::Result<()> {
+        self.socket.send_to(&packet.to_bytes(), addr).map(|_| ())
+    }
+
+    /// Route packets from the given address, sent with the given connection id, to the returned `Receiver`.
+    ///
+    /// Returns None if a connection is already using the route.
+    pub fn add_route(&self, addr: SocketAddr, connection_id: u16) -> Option<Receiver<Packet>> {
+        let mut routes = self.shared.routes.lock().expect("bip_utp: Failed To Lock Routes");
+
+        match routes.entry((addr, connection_id)) {
+            Entry::Occupied(_) => None,
+            Entry::Vacant(vacant) => {
+                let (send, recv) = mpsc::channel();
+                vacant.insert(send);
+
+                Some(recv)
+            }
+        }
+    }
+
+    /// Stop routing packets from the given address, sent with the given connection id.
+    pub fn remove_route(&self, addr: SocketAddr, connection_id: u16) {
+        self.shared.routes.lock().expect("bip_utp: Failed To Lock Routes").remove(&(addr, connection_id));
+    }
+}
+
+impl Drop for Multiplexer {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+fn run_reader(socket: &UdpSocket, shared: &Shared) {
+    let mut buffer = vec![0u8; MAX_DATAGRAM_LEN];
+
+    while !shared.closed.load(Ordering::SeqCst) {
+        // Errors are either our read timeout, or transient (such as an ICMP port unreachable)
+        if let Ok((len, addr)) = socket.recv_from(&mut buffer) {
+            if let Ok(packet) = Packet::from_bytes(&buffer[..len]) {
+                route_packet(shared, addr, packet);
+            }
+        }
+    }
+}
+
+fn route_packet(shared: &Shared, addr: SocketAddr, packet: Packet) {
+    // Connection requests carry the id that the peer receives on, and we receive on the one after it
+    let connection_id = match packet.kind() {
+        PacketType::Syn => packet.connection_id().wrapping_add(1),
+        _               => packet.connection_id()
+    };
+
+    // Retransmitted connection requests go to the connection we already accepted, if any
+    if let Some(send) = shared.routes.lock().expect("bip_utp: Failed To Lock Routes").get(&(addr, connection_id)) {
+        let _ = send.send(packet);
+
+        return
+    }
+
+    if let (PacketType::Syn, Some(incoming)) = (packet.kind(), shared.incoming.as_ref()) {
+        let _ = incoming.lock().expect("bip_utp: Failed To Lock Incoming").send((addr, packet));
+    }
+}
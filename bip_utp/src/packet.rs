@@ -0,0 +1,322 @@
+//! Packet format for the uTorrent Transport Protocol (BEP 29).
+
+use std::error::Error;
+use std::fmt;
+
+/// Length of a packet header, not including any extensions.
+pub const HEADER_LEN: usize = 20;
+
+/// Version of the protocol that we speak.
+pub const UTP_VERSION: u8 = 1;
+
+/// Maximum number of bytes that a single extension can carry.
+pub const MAX_EXTENSION_LEN: usize = 255;
+
+/// Type of a uTP packet.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PacketType {
+    /// Regular data packet.
+    Data,
+    /// Finalize the connection; the last packet.
+    Fin,
+    /// State packet, used to acknowledge packets without sending data.
+    State,
+    /// Terminate the connection forcefully.
+    Reset,
+    /// Initiate a connection.
+    Syn
+}
+
+impl PacketType {
+    fn from_nibble(nibble: u8) -> Option<PacketType> {
+        match nibble {
+            0 => Some(PacketType::Data),
+            1 => Some(PacketType::Fin),
+            2 => Some(PacketType::State),
+            3 => Some(PacketType::Reset),
+            4 => Some(PacketType::Syn),
+            _ => None
+        }
+    }
+
+    fn to_nibble(self) -> u8 {
+        match self {
+            PacketType::Data  => 0,
+            PacketType::Fin   => 1,
+            PacketType::State => 2,
+            PacketType::Reset => 3,
+            PacketType::Syn   => 4
+        }
+    }
+}
+
+/// Error parsing a uTP packet.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PacketError {
+    /// Packet was shorter than its header or extensions specify.
+    TooShort,
+    /// Packet has a type we don't know about.
+    InvalidType(u8),
+    /// Packet has a version we don't speak.
+    InvalidVersion(u8),
+    /// Extension carries more bytes than its length can describe.
+    ExtensionTooLong(usize)
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PacketError::TooShort                => write!(f, "Packet Too Short"),
+            PacketError::InvalidType(kind)       => write!(f, "Packet Has Invalid Type {}", kind),
+            PacketError::InvalidVersion(version) => write!(f, "Packet Has Invalid Version {}", version),
+            PacketError::ExtensionTooLong(len)   => write!(f, "Packet Extension Has Invalid Length {}", len)
+        }
+    }
+}
+
+impl Error for PacketError {
+    fn description(&self) -> &str {
+        match *self {
+            PacketError::TooShort          => "Packet Too Short",
+            PacketError::InvalidType(_)    => "Packet Has Invalid Type",
+            PacketError::InvalidVersion(_) => "Packet Has Invalid Version",
+            PacketError::ExtensionTooLong(_) => "Packet Extension Has Invalid Length"
+        }
+    }
+}
+
+/// Extension attached to a uTP packet header.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PacketExtension {
+    kind: u8,
+    data: Vec<u8>
+}
+
+impl PacketExtension {
+    /// Create a new `PacketExtension`.
+    ///
+    /// Returns an error if the data is longer than `MAX_EXTENSION_LEN`, since the length is encoded in a single byte.
+    pub fn new(kind: u8, data: Vec<u8>) -> Result<PacketExtension, PacketError> {
+        if data.len() > MAX_EXTENSION_LEN {
+            return Err(PacketError::ExtensionTooLong(data.len()))
+        }
+
+        Ok(PacketExtension{ kind: kind, data: data })
+    }
+
+    /// Type of the extension.
+    pub fn kind(&self) -> u8 {
+        self.kind
+    }
+
+    /// Data carried by the extension.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// uTP packet, consisting of a header, extensions, and a payload.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Packet {
+    kind:           PacketType,
+    connection_id:  u16,
+    timestamp:      u32,
+    timestamp_diff: u32,
+    window_size:    u32,
+    seq_nr:         u16,
+    ack_nr:         u16,
+    extensions:     Vec<PacketExtension>,
+    payload:        Vec<u8>
+}
+
+impl Packet {
+    /// Create a new `Packet` with no extensions, timestamps, or payload.
+    pub fn new(kind: PacketType, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Packet {
+        Packet{ kind: kind, connection_id: connection_id, timestamp: 0, timestamp_diff: 0, window_size: 0,
+                seq_nr: seq_nr, ack_nr: ack_nr, extensions: Vec::new(), payload: Vec::new() }
+    }
+
+    /// Parse a `Packet` from the given bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Packet, PacketError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(PacketError::TooShort)
+        }
+
+        let version = bytes[0] & 0x0F;
+        if version != UTP_VERSION {
+            return Err(PacketError::InvalidVersion(version))
+        }
+        let kind = try!(PacketType::from_nibble(bytes[0] >> 4).ok_or(PacketError::InvalidType(bytes[0] >> 4)));
+
+        // Extensions form a linked list, where each extension points to the type of the next
+        let mut extensions = Vec::new();
+        let mut next_kind = bytes[1];
+        let mut offset = HEADER_LEN;
+        while next_kind != 0 {
+            if bytes.len() < offset + 2 || bytes.len() < offset + 2 + bytes[offset + 1] as usize {
+                return Err(PacketError::TooShort)
+            }
+            let (following_kind, len) = (bytes[offset], bytes[offset + 1] as usize);
+
+            extensions.push(PacketExtension{ kind: next_kind, data: bytes[offset + 2..offset + 2 + len].to_vec() });
+            next_kind = following_kind;
+            offset += 2 + len;
+        }
+
+        Ok(Packet{ kind: kind, connection_id: read_u16(&bytes[2..]), timestamp: read_u32(&bytes[4..]),
+                   timestamp_diff: read_u32(&bytes[8..]), window_size: read_u32(&bytes[12..]), seq_nr: read_u16(&bytes[16..]),
+                   ack_nr: read_u16(&bytes[18..]), extensions: extensions, payload: bytes[offset..].to_vec() })
+    }
+
+    /// Serialize the `Packet` to bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len());
+
+        bytes.push((self.kind.to_nibble() << 4) | UTP_VERSION);
+        bytes.push(self.extensions.first().map(|ext| ext.kind).unwrap_or(0));
+        write_u16(&mut bytes, self.connection_id);
+        write_u32(&mut bytes, self.timestamp);
+        write_u32(&mut bytes, self.timestamp_diff);
+        write_u32(&mut bytes, self.window_size);
+        write_u16(&mut bytes, self.seq_nr);
+        write_u16(&mut bytes, self.ack_nr);
+
+        for (index, extension) in self.extensions.iter().enumerate() {
+            let following_kind = self.extensions.get(index + 1).map(|ext| ext.kind).unwrap_or(0);
+
+            bytes.push(following_kind);
+            // Extensions are checked against MAX_EXTENSION_LEN when they are created
+            bytes.push(extension.data.len() as u8);
+            bytes.extend_from_slice(&extension.data);
+        }
+        bytes.extend_from_slice(&self.payload);
+
+        bytes
+    }
+
+    /// Type of the packet.
+    pub fn kind(&self) -> PacketType {
+        self.kind
+    }
+
+    /// Connection id that the packet belongs to.
+    pub fn connection_id(&self) -> u16 {
+        self.connection_id
+    }
+
+    /// Set the time, in microseconds, that the packet was sent.
+    pub fn set_timestamp(&mut self, timestamp: u32) {
+        self.timestamp = timestamp;
+    }
+
+    /// Time, in microseconds, that the packet was sent.
+    pub fn timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    /// Set the difference between the local time and the timestamp of the last packet received.
+    pub fn set_timestamp_diff(&mut self, timestamp_diff: u32) {
+        self.timestamp_diff = timestamp_diff;
+    }
+
+    /// Difference between the sender's time and the timestamp of the last packet it received.
+    pub fn timestamp_diff(&self) -> u32 {
+        self.timestamp_diff
+    }
+
+    /// Set the number of bytes the sender has room for in its receive window.
+    pub fn set_window_size(&mut self, window_size: u32) {
+        self.window_size = window_size;
+    }
+
+    /// Number of bytes the sender has room for in its receive window.
+    pub fn window_size(&self) -> u32 {
+        self.window_size
+    }
+
+    /// Sequence number of the packet.
+    pub fn seq_nr(&self) -> u16 {
+        self.seq_nr
+    }
+
+    /// Set the sequence number of the last packet the sender received.
+    pub fn set_ack_nr(&mut self, ack_nr: u16) {
+        self.ack_nr = ack_nr;
+    }
+
+    /// Sequence number of the last packet the sender received.
+    pub fn ack_nr(&self) -> u16 {
+        self.ack_nr
+    }
+
+    /// Add an extension to the packet.
+    pub fn add_extension(&mut self, extension: PacketExtension) {
+        self.extensions.push(extension);
+    }
+
+    /// Extensions attached to the packet.
+    pub fn extensions(&self) -> &[PacketExtension] {
+        &self.extensions
+    }
+
+    /// Set the payload of the packet.
+    pub fn set_payload(&mut self, payload: Vec<u8>) {
+        self.payload = payload;
+    }
+
+    /// Payload of the packet.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    ((bytes[0] as u16) << 8) | bytes[1] as u16
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    ((read_u16(bytes) as u32) << 16) | read_u16(&bytes[2..]) as u32
+}
+
+fn write_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.push((value >> 8) as u8);
+    bytes.push(value as u8);
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: u32) {
+    write_u16(bytes, (value >> 16) as u16);
+    write_u16(bytes, value as u16);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Packet, PacketType, PacketExtension, PacketError};
+
+    #[test]
+    fn positive_packet_round_trip() {
+        let mut packet = Packet::new(PacketType::Data, 1234, 5, 4);
+        packet.set_timestamp(1000);
+        packet.set_window_size(65535);
+        packet.add_extension(PacketExtension::new(1, vec![0u8; 4]).unwrap());
+        packet.set_payload(b"Hello".to_vec());
+
+        let bytes = packet.to_bytes();
+
+        assert_eq!(0x01, bytes[0]);
+        assert_eq!(packet, Packet::from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn negative_packet_invalid_version() {
+        let mut bytes = Packet::new(PacketType::Syn, 1, 1, 0).to_bytes();
+        bytes[0] = (bytes[0] & 0xF0) | 0x02;
+
+        assert_eq!(Err(PacketError::InvalidVersion(2)), Packet::from_bytes(&bytes));
+    }
+
+    #[test]
+    fn negative_extension_too_long() {
+        assert!(PacketExtension::new(1, vec![0u8; super::MAX_EXTENSION_LEN]).is_ok());
+        assert_eq!(Err(PacketError::ExtensionTooLong(256)), PacketExtension::new(1, vec![0u8; 256]));
+    }
+}
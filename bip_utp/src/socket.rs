@@ -0,0 +1,651 @@
+//! Reliable, ordered streams of bytes over uTP.
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use mux::{Multiplexer, Incoming};
+use packet::{Packet, PacketType, HEADER_LEN};
+
+// Largest packet we send, chosen to fit in the MTU of most links.
+const MAX_PACKET_LEN: usize = 1400;
+
+// Largest payload we send in a single packet.
+const MAX_PAYLOAD_LEN: usize = MAX_PACKET_LEN - HEADER_LEN;
+
+// Bytes we will buffer for the application before advertising a full receive window.
+const RECV_WINDOW: usize = 1024 * 1024;
+
+// Bytes we will have in flight, regardless of the window the peer advertises.
+const MAX_SEND_WINDOW: usize = 256 * 1024;
+
+// Packets we will buffer that arrived ahead of the next one we are waiting on.
+const MAX_OUT_OF_ORDER: usize = RECV_WINDOW / MAX_PAYLOAD_LEN;
+
+const INITIAL_TIMEOUT_MILLIS: u64 = 1000;
+const MIN_TIMEOUT_MILLIS: u64 = 500;
+const MAX_TIMEOUT_MILLIS: u64 = 8000;
+
+// Times we will retransmit a packet before giving up on the connection.
+const MAX_RETRANSMITS: usize = 5;
+
+// Repeated acknowledgements of the same packet before we resend the one after it.
+const DUPLICATE_ACKS_BEFORE_RESEND: usize = 3;
+
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+
+/// Blocking stream of bytes to a peer over uTP, with an api similar to `TcpStream`.
+///
+/// Lost packets are retransmitted, and out of order packets are reassembled, but congestion
+/// control (LEDBAT) is not implemented; instead, a fixed number of bytes are allowed in flight.
+///
+/// Dropping the stream closes it, blocking until everything written has been acknowledged,
+/// or the peer stops responding; use `UtpStream::close` to find out which one happened.
+pub struct UtpStream {
+    mux:            Arc<Multiplexer>,
+    remote:         SocketAddr,
+    recv:           Receiver<Packet>,
+    recv_id:        u16,
+    send_id:        u16,
+    // Sequence number of the next packet we send.
+    seq_nr:         u16,
+    // Sequence number of the last packet we received in order.
+    ack_nr:         u16,
+    state:          StreamState,
+    unacked:        VecDeque<SentPacket>,
+    out_of_order:   HashMap<u16, Packet>,
+    read_buffer:    VecDeque<u8>,
+    read_timeout:   Option<Duration>,
+    peer_window:    usize,
+    timestamp_diff: u32,
+    // Smoothed round trip time and its variance, in milliseconds, once we have a sample.
+    rtt:            Option<(u64, u64)>,
+    timeout:        Duration,
+    retransmits:    usize,
+    duplicate_acks: usize,
+    // Last packet we had sent when we resent a lost one, which we resend every packet up to as acknowledgements arrive.
+    recovery:       Option<u16>
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum StreamState {
+    Connected,
+    // Peer will not be sending us any more data.
+    FinReceived,
+    Closed,
+    Reset
+}
+
+struct SentPacket {
+    packet:        Packet,
+    sent:          Instant,
+    retransmitted: bool
+}
+
+impl UtpStream {
+    /// Connect to the peer at the given address.
+    pub fn connect<A>(addr: A) -> io::Result<UtpStream>
+        where A: ToSocketAddrs {
+        UtpStream::connect_timeout(&try!(resolve(addr)), Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS))
+    }
+
+    /// Connect to the peer at the given address, giving up if it doesn't respond within the timeout.
+    pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> io::Result<UtpStream> {
+        let bind_addr = match *addr {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0"
+        };
+        let (mux, _) = try!(Multiplexer::bind(bind_addr.parse().expect("bip_utp: Failed To Parse Bind Address"), false));
+
+        let recv_id = random_u16();
+        let recv = mux.add_route(*addr, recv_id).expect("bip_utp: New Multiplexer Already Had A Route");
+
+        let mut stream = UtpStream::new(mux, *addr, recv, recv_id, recv_id.wrapping_add(1), 1, 0);
+        try!(stream.initiate(timeout));
+
+        Ok(stream)
+    }
+
+    /// Accept the connection request from the peer at the given address.
+    fn respond(mux: Arc<Multiplexer>, addr: SocketAddr, recv: Receiver<Packet>, syn: &Packet) -> io::Result<UtpStream> {
+        let mut stream = UtpStream::new(mux, addr, recv, syn.connection_id().wrapping_add(1), syn.connection_id(),
+                                        random_u16(), syn.seq_nr());
+        stream.timestamp_diff = now_micros().wrapping_sub(syn.timestamp());
+        stream.peer_window = syn.window_size() as usize;
+
+        // Our first data packet will have the same sequence number as this one
+        try!(stream.send_state());
+
+        Ok(stream)
+    }
+
+    fn new(mux: Arc<Multiplexer>, remote: SocketAddr, recv: Receiver<Packet>, recv_id: u16, send_id: u16, seq_nr: u16, ack_nr: u16)
+        -> UtpStream {
+        UtpStream{ mux: mux, remote: remote, recv: recv, recv_id: recv_id, send_id: send_id, seq_nr: seq_nr, ack_nr: ack_nr,
+                   state: StreamState::Connected, unacked: VecDeque::new(),
+                   out_of_order: HashMap::new(), read_buffer: VecDeque::new(), read_timeout: None, peer_window: MAX_PACKET_LEN,
+                   timestamp_diff: 0, rtt: None, timeout: Duration::from_millis(INITIAL_TIMEOUT_MILLIS), retransmits: 0,
+                   duplicate_acks: 0, recovery: None }
+    }
+
+    /// Address of the peer that we are connected to.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
+    /// Local address that we are connected from.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.mux.local_addr()
+    }
+
+    /// Set the timeout for reads, after which they fail with `io::ErrorKind::WouldBlock`.
+    ///
+    /// A timeout of None (the default) means reads block until data is available.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Timeout for reads.
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    /// Close the connection, once everything written to it has been acknowledged by the peer.
+    pub fn close(&mut self) -> io::Result<()> {
+        match self.state {
+            StreamState::Closed | StreamState::Reset => return Ok(()),
+            StreamState::Connected | StreamState::FinReceived => ()
+        }
+        try!(self.flush());
+
+        let fin = self.new_packet(PacketType::Fin);
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        try!(self.send_reliable(fin));
+
+        // If the peer already closed its side, it may not stick around to acknowledge ours
+        if self.state == StreamState::Connected {
+            try!(self.flush());
+        }
+        self.state = StreamState::Closed;
+
+        Ok(())
+    }
+
+    /// Send our connection request, and wait for the peer to accept it.
+    fn initiate(&mut self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+
+        // Connection requests are sent with the id we receive on, instead of the one we send on
+        let mut syn = Packet::new(PacketType::Syn, self.recv_id, self.seq_nr, 0);
+        syn.set_window_size(self.recv_window());
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                // Nothing to close, since the peer never knew about us
+                self.state = StreamState::Closed;
+
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "bip_utp: Peer Did Not Accept Connection"))
+            }
+            try!(self.send(syn.clone()));
+
+            let retry = cmp::min(now + self.timeout, deadline);
+            while let Some(packet) = try!(self.recv_until(retry)) {
+                match packet.kind() {
+                    PacketType::State if packet.ack_nr() == syn.seq_nr() => {
+                        // Peer doesn't advance its sequence number for this packet, so its first data packet has the same one
+                        self.ack_nr = packet.seq_nr().wrapping_sub(1);
+                        self.timestamp_diff = now_micros().wrapping_sub(packet.timestamp());
+                        self.peer_window = packet.window_size() as usize;
+
+                        return Ok(())
+                    },
+                    PacketType::Reset => {
+                        self.state = StreamState::Reset;
+
+                        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "bip_utp: Peer Refused Connection"))
+                    },
+                    _ => ()
+                }
+            }
+            self.timeout = cmp::min(self.timeout * 2, Duration::from_millis(MAX_TIMEOUT_MILLIS));
+        }
+    }
+
+    /// Wait for a packet from the peer until the given time, returning None if we timed out.
+    fn recv_until(&self, deadline: Instant) -> io::Result<Option<Packet>> {
+        let now = Instant::now();
+        let wait = if deadline > now { deadline - now } else { Duration::from_millis(0) };
+
+        match self.recv.recv_timeout(wait) {
+            Ok(packet)                          => Ok(Some(packet)),
+            Err(RecvTimeoutError::Timeout)      => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "bip_utp: Multiplexer Was Closed"))
+        }
+    }
+
+    /// Process a single packet from the peer, retransmitting packets as their timeouts expire.
+    ///
+    /// Returns false if the deadline passed before a packet arrived.
+    fn poll(&mut self, opt_deadline: Option<Instant>) -> io::Result<bool> {
+        loop {
+            let now = Instant::now();
+            if opt_deadline.map_or(false, |deadline| now >= deadline) {
+                return Ok(false)
+            }
+
+            let opt_retransmit = self.unacked.front().map(|sent| sent.sent + self.timeout);
+            let opt_wake = match (opt_retransmit, opt_deadline) {
+                (Some(retransmit), Some(deadline)) => Some(cmp::min(retransmit, deadline)),
+                (opt_retransmit, opt_deadline)     => opt_retransmit.or(opt_deadline)
+            };
+
+            let opt_packet = match opt_wake {
+                Some(wake) => try!(self.recv_until(wake)),
+                None       => Some(try!(self.recv.recv().map_err(|_| {
+                    io::Error::new(io::ErrorKind::BrokenPipe, "bip_utp: Multiplexer Was Closed")
+                })))
+            };
+
+            match opt_packet {
+                Some(packet) => {
+                    try!(self.process_packet(packet));
+
+                    return Ok(true)
+                },
+                None => try!(self.retransmit())
+            }
+        }
+    }
+
+    /// Retransmit every unacknowledged packet if the timeout for the oldest one expired.
+    ///
+    /// Losses tend to come in bursts, so resending everything recovers from them in a single timeout.
+    fn retransmit(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        if self.unacked.front().map_or(true, |sent| now < sent.sent + self.timeout) {
+            return Ok(())
+        }
+
+        self.retransmits += 1;
+        if self.retransmits > MAX_RETRANSMITS {
+            self.state = StreamState::Reset;
+
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "bip_utp: Peer Stopped Acknowledging Packets"))
+        }
+        self.timeout = cmp::min(self.timeout * 2, Duration::from_millis(MAX_TIMEOUT_MILLIS));
+        self.recovery = None;
+
+        let num_unacked = self.unacked.len();
+        self.resend(num_unacked)
+    }
+
+    /// Resend the given number of the oldest unacknowledged packets.
+    fn resend(&mut self, count: usize) -> io::Result<()> {
+        let (now, ack_nr, window) = (Instant::now(), self.ack_nr, self.recv_window());
+        let packets: Vec<Packet> = self.unacked.iter_mut().take(count).map(|sent| {
+            sent.packet.set_ack_nr(ack_nr);
+            sent.packet.set_window_size(window);
+            sent.sent = now;
+            sent.retransmitted = true;
+
+            sent.packet.clone()
+        }).collect();
+
+        for packet in packets {
+            try!(self.send(packet));
+        }
+
+        Ok(())
+    }
+
+    fn process_packet(&mut self, packet: Packet) -> io::Result<()> {
+        match packet.kind() {
+            PacketType::Reset => {
+                self.state = StreamState::Reset;
+
+                return Ok(())
+            },
+            // Peer didn't get our reply to its connection request
+            PacketType::Syn => return self.send_state(),
+            PacketType::Data | PacketType::Fin | PacketType::State => ()
+        }
+        self.timestamp_diff = now_micros().wrapping_sub(packet.timestamp());
+        self.peer_window = packet.window_size() as usize;
+        try!(self.process_ack(packet.kind(), packet.ack_nr()));
+
+        match packet.kind() {
+            PacketType::Data | PacketType::Fin => self.process_data(packet),
+            _                                  => Ok(())
+        }
+    }
+
+    fn process_ack(&mut self, kind: PacketType, ack_nr: u16) -> io::Result<()> {
+        let mut acked = false;
+
+        while self.unacked.front().map_or(false, |sent| seq_at_or_before(sent.packet.seq_nr(), ack_nr)) {
+            let sent = self.unacked.pop_front().expect("bip_utp: Unacked Packets Were Empty");
+
+            // Samples from retransmitted packets are ambiguous, since we don't know which one was acknowledged
+            if !sent.retransmitted {
+                self.update_rtt(sent.sent.elapsed());
+            }
+            self.retransmits = 0;
+            acked = true;
+        }
+
+        // Peers acknowledge every packet they receive out of order, so repeated acknowledgements of the same packet
+        // mean that the one after it was likely lost, and we can resend it without waiting for it to time out
+        if acked {
+            self.duplicate_acks = 0;
+
+            // Losses come in bursts, so if the acknowledgement stopped short of what we had sent, the next one was lost too
+            match self.recovery {
+                Some(recovery) if !seq_at_or_before(recovery, ack_nr) => return self.resend(1),
+                _ => self.recovery = None
+            }
+        } else if kind == PacketType::State && !self.unacked.is_empty() {
+            self.duplicate_acks += 1;
+
+            if self.duplicate_acks == DUPLICATE_ACKS_BEFORE_RESEND && self.recovery.is_none() {
+                self.recovery = Some(self.seq_nr.wrapping_sub(1));
+
+                return self.resend(1)
+            }
+        }
+
+        Ok(())
+    }
+
+    fn process_data(&mut self, packet: Packet) -> io::Result<()> {
+        let next_seq_nr = self.ack_nr.wrapping_add(1);
+
+        if packet.seq_nr() == next_seq_nr {
+            self.deliver(packet);
+
+            while let Some(packet) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+                self.deliver(packet);
+            }
+        } else if seq_at_or_before(next_seq_nr, packet.seq_nr()) && self.out_of_order.len() < MAX_OUT_OF_ORDER {
+            self.out_of_order.insert(packet.seq_nr(), packet);
+        }
+
+        // Duplicates are acknowledged again, in case our last acknowledgement was lost
+        self.send_state()
+    }
+
+    fn deliver(&mut self, packet: Packet) {
+        self.ack_nr = packet.seq_nr();
+
+        match (packet.kind(), self.state) {
+            (PacketType::Fin, StreamState::Connected) => self.state = StreamState::FinReceived,
+            (PacketType::Data, StreamState::Connected) => self.read_buffer.extend(packet.payload()),
+            _ => ()
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let sample = sample.as_secs() * 1000 + u64::from(sample.subsec_nanos()) / 1_000_000;
+
+        let (rtt, rtt_var) = match self.rtt {
+            Some((rtt, rtt_var)) => {
+                let deviation = if rtt > sample { rtt - sample } else { sample - rtt };
+
+                ((rtt * 7 + sample) / 8, (rtt_var * 3 + deviation) / 4)
+            },
+            None => (sample, sample / 2)
+        };
+        self.rtt = Some((rtt, rtt_var));
+
+        self.timeout = Duration::from_millis(cmp::max(MIN_TIMEOUT_MILLIS, cmp::min(rtt + rtt_var * 4, MAX_TIMEOUT_MILLIS)));
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        match self.state {
+            StreamState::Connected | StreamState::FinReceived => Ok(()),
+            StreamState::Closed => Err(io::Error::new(io::ErrorKind::NotConnected, "bip_utp: Stream Was Closed")),
+            StreamState::Reset  => Err(io::Error::new(io::ErrorKind::ConnectionReset, "bip_utp: Stream Was Reset"))
+        }
+    }
+
+    /// Bytes that we have sent which the peer hasn't acknowledged.
+    fn in_flight(&self) -> usize {
+        self.unacked.iter().map(|sent| sent.packet.payload().len()).sum()
+    }
+
+    /// Bytes we can have in flight; always enough for at least one packet, so that we learn when the peer's window opens.
+    fn send_window(&self) -> usize {
+        cmp::max(MAX_PAYLOAD_LEN, cmp::min(self.peer_window, MAX_SEND_WINDOW))
+    }
+
+    fn recv_window(&self) -> u32 {
+        RECV_WINDOW.saturating_sub(self.read_buffer.len()) as u32
+    }
+
+    fn new_packet(&self, kind: PacketType) -> Packet {
+        let mut packet = Packet::new(kind, self.send_id, self.seq_nr, self.ack_nr);
+        packet.set_window_size(self.recv_window());
+
+        packet
+    }
+
+    fn send_state(&self) -> io::Result<()> {
+        self.send(self.new_packet(PacketType::State))
+    }
+
+    /// Send the packet, retransmitting it until it is acknowledged.
+    fn send_reliable(&mut self, packet: Packet) -> io::Result<()> {
+        try!(self.send(packet.clone()));
+        self.unacked.push_back(SentPacket{ packet: packet, sent: Instant::now(), retransmitted: false });
+
+        Ok(())
+    }
+
+    fn send(&self, mut packet: Packet) -> io::Result<()> {
+        packet.set_timestamp(now_micros());
+        packet.set_timestamp_diff(self.timestamp_diff);
+
+        self.mux.send_to(&packet, self.remote)
+    }
+}
+
+impl Read for UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let opt_deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+
+        while self.read_buffer.is_empty() {
+            match self.state {
+                StreamState::Connected => (),
+                StreamState::FinReceived | StreamState::Closed => return Ok(0),
+                StreamState::Reset => return Err(io::Error::new(io::ErrorKind::ConnectionReset, "bip_utp: Stream Was Reset"))
+            }
+
+            if !try!(self.poll(opt_deadline)) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "bip_utp: Read Timed Out"))
+            }
+        }
+
+        let len = cmp::min(buf.len(), self.read_buffer.len());
+        for (dst, src) in buf.iter_mut().zip(self.read_buffer.drain(..len)) {
+            *dst = src;
+        }
+
+        Ok(len)
+    }
+}
+
+impl Write for UtpStream {
+    /// Writes up to a single packet worth of bytes, blocking while the send window is full.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.check_writable());
+        if buf.is_empty() {
+            return Ok(0)
+        }
+
+        let len = cmp::min(buf.len(), MAX_PAYLOAD_LEN);
+        while self.in_flight() + len > self.send_window() {
+            try!(self.poll(None));
+            try!(self.check_writable());
+        }
+
+        let mut packet = self.new_packet(PacketType::Data);
+        packet.set_payload(buf[..len].to_vec());
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        try!(self.send_reliable(packet));
+
+        Ok(len)
+    }
+
+    /// Blocks until everything written has been acknowledged by the peer.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.unacked.is_empty() {
+            try!(self.check_writable());
+            try!(self.poll(None));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let _ = self.close();
+
+        self.mux.remove_route(self.remote, self.recv_id);
+    }
+}
+
+//----------------------------------------------------------------------------//
+
+/// Blocking listener for uTP connections, with an api similar to `TcpListener`.
+pub struct UtpListener {
+    mux:      Arc<Multiplexer>,
+    incoming: Incoming
+}
+
+impl UtpListener {
+    /// Create a new `UtpListener` bound to the given address.
+    pub fn bind<A>(addr: A) -> io::Result<UtpListener>
+        where A: ToSocketAddrs {
+        let (mux, opt_incoming) = try!(Multiplexer::bind(try!(resolve(addr)), true));
+
+        Ok(UtpListener{ mux: mux, incoming: opt_incoming.expect("bip_utp: Multiplexer Did Not Accept Connections") })
+    }
+
+    /// Local address that the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.mux.local_addr()
+    }
+
+    /// Block until a peer connects to us, returning the stream for it along with its address.
+    pub fn accept(&self) -> io::Result<(UtpStream, SocketAddr)> {
+        loop {
+            let (addr, syn) = try!(self.incoming.recv().map_err(|_| {
+                io::Error::new(io::ErrorKind::BrokenPipe, "bip_utp: Multiplexer Was Closed")
+            }));
+
+            // Peers retransmit connection requests until we reply, so we may have accepted this one already
+            if let Some(recv) = self.mux.add_route(addr, syn.connection_id().wrapping_add(1)) {
+                return UtpStream::respond(self.mux.clone(), addr, recv, &syn).map(|stream| (stream, addr))
+            }
+        }
+    }
+}
+
+//----------------------------------------------------------------------------//
+
+/// Returns true if sequence number `a` is at or before sequence number `b`, accounting for wrap around.
+fn seq_at_or_before(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a) < 0x8000
+}
+
+fn resolve<A>(addr: A) -> io::Result<SocketAddr>
+    where A: ToSocketAddrs {
+    try!(addr.to_socket_addrs()).next().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bip_utp: Address Did Not Resolve"))
+}
+
+fn random_u16() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
+
+/// Current time in microseconds, truncated to fit in a packet header.
+fn now_micros() -> u32 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_else(|_| Duration::from_secs(0));
+
+    (since_epoch.as_secs() * 1_000_000 + u64::from(since_epoch.subsec_nanos()) / 1000) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::Duration;
+
+    use packet::{Packet, PacketType};
+    use super::{UtpStream, UtpListener};
+
+    #[test]
+    fn positive_connect_write_read() {
+        let listener = UtpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Large enough to fill the send window a few times over
+        let expected: Vec<u8> = (0..1024 * 1024).map(|index| index as u8).collect();
+        let write_bytes = expected.clone();
+        let writer = thread::spawn(move || {
+            let mut stream = UtpStream::connect(addr).unwrap();
+
+            stream.write_all(&write_bytes).unwrap();
+            stream.close().unwrap();
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+
+        writer.join().unwrap();
+        assert!(received == expected);
+    }
+
+    #[test]
+    fn positive_out_of_order_packets_read_in_order() {
+        let listener = UtpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.send_to(&Packet::new(PacketType::Syn, 10, 1, 0).to_bytes(), addr).unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = [0u8; 1500];
+        let (len, _) = peer.recv_from(&mut buffer).unwrap();
+        let state = Packet::from_bytes(&buffer[..len]).unwrap();
+        assert_eq!(PacketType::State, state.kind());
+        assert_eq!(1, state.ack_nr());
+
+        for &(seq_nr, kind, payload) in &[(4, PacketType::Fin, &b""[..]), (3, PacketType::Data, &b"World"[..]), (2, PacketType::Data, &b"Hello "[..])] {
+            let mut packet = Packet::new(kind, 11, seq_nr, state.seq_nr());
+            packet.set_payload(payload.to_vec());
+
+            peer.send_to(&packet.to_bytes(), addr).unwrap();
+        }
+
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        assert_eq!("Hello World", received);
+    }
+
+    #[test]
+    fn negative_connect_times_out() {
+        let silent_peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let result = UtpStream::connect_timeout(&silent_peer.local_addr().unwrap(), Duration::from_millis(200));
+        assert!(result.is_err());
+    }
+}